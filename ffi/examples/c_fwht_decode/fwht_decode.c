@@ -74,6 +74,11 @@ static bool drain_completed = false;
 
 const char *device_path = "/dev/video1";
 
+static void on_log(void *ptr, enum v4l2r_log_level level, const char *target,
+                   const char *message) {
+  fprintf(stderr, "[v4l2r %d %s] %s\n", level, target, message);
+}
+
 static void on_input_done(void *ptr, const struct v4l2_buffer *buffer) {
   printf("Input buffer %d done\n", buffer->index);
 }
@@ -129,6 +134,14 @@ void on_event(void *ptr, struct v4l2r_decoder_event *event) {
     printf("Drain completed!\n");
     drain_completed = true;
     break;
+  case MappedFrameDecoded:
+    /* Only sent if the decoder is created with V4L2R_MEMORY_MMAP. */
+    break;
+  case Error:
+    fprintf(stderr, "Decoder error %d: %s\n", event->error.code,
+            event->error.message);
+    drain_completed = true;
+    break;
   }
 }
 
@@ -147,11 +160,15 @@ int main() {
     return 1;
   }
 
-  v4l2r_init();
+  v4l2r_set_log_cb(on_log, NULL, V4L2R_LOG_LEVEL_INFO);
 
-  struct v4l2r_decoder *decoder =
-      v4l2r_decoder_new(device_path, V4L2_PIX_FMT_FWHT, 1, 0, 0, on_input_done,
-                        on_event, (void *)0xdeadbeef);
+  struct v4l2r_decoder *decoder = v4l2r_decoder_new(
+      device_path, V4L2_PIX_FMT_FWHT, 1, 0, 0, V4L2R_MEMORY_DMABUF, 0,
+      on_input_done, on_event, (void *)0xdeadbeef, &ret);
+  if (!decoder) {
+    fprintf(stderr, "Cannot create decoder: %d\n", ret);
+    return 1;
+  }
 
   ret = v4l2r_decoder_get_input_format(decoder, &output_format);
   if (ret < 0)
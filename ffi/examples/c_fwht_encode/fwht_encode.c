@@ -0,0 +1,139 @@
+#include <linux/videodev2.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "v4l2r.h"
+
+#define WIDTH 640
+#define HEIGHT 480
+#define NUM_FRAMES 20
+
+static const char *output_file_path = "output.fwht";
+
+const char *device_path = "/dev/video0";
+
+static FILE *output_file = NULL;
+static size_t encoded_bytes = 0;
+
+static void on_log(void *ptr, enum v4l2r_log_level level, const char *target,
+                   const char *message) {
+  fprintf(stderr, "[v4l2r %d %s] %s\n", level, target, message);
+}
+
+static void on_input_done(void *ptr, const struct v4l2_buffer *buffer) {
+  printf("Input frame %ld done\n", buffer->timestamp.tv_sec);
+}
+
+static void
+on_frame_encoded(void *ptr,
+                 const struct v4l2r_encoder_frame_encoded_event *event) {
+  printf("Frame %ld encoded, size: %zu%s\n", event->buffer->timestamp.tv_sec,
+         event->size, event->is_keyframe ? " (keyframe)" : "");
+
+  if (fwrite(event->data, event->size, 1, output_file) != 1)
+    perror("Error writing encoded frame");
+  encoded_bytes += event->size;
+}
+
+void on_event(void *ptr, struct v4l2r_encoder_event *event) {
+  switch (event->tag) {
+  case FrameEncoded:
+    on_frame_encoded(ptr, &event->frame_encoded);
+    break;
+  case FatalError:
+    fprintf(stderr, "Encoder error %d: %s\n", event->fatal_error.code,
+            event->fatal_error.message);
+    break;
+  }
+}
+
+// Fill the planes of `format` with a pattern that moves with `frame`.
+static void fill_frame(const struct v4l2_format *format, uint8_t **planes,
+                       int frame) {
+  const struct v4l2_pix_format_mplane *pix_mp = &format->fmt.pix_mp;
+  int i;
+  size_t j;
+
+  for (i = 0; i < pix_mp->num_planes; i++) {
+    const struct v4l2_plane_pix_format *plane = &pix_mp->plane_fmt[i];
+
+    for (j = 0; j < plane->sizeimage; j++) {
+      size_t x = j % plane->bytesperline;
+      size_t y = j / plane->bytesperline;
+
+      planes[i][j] = (uint8_t)(x + y * 2 + frame * 3);
+    }
+  }
+}
+
+int main() {
+  struct v4l2_format input_format;
+  struct v4l2r_encoder_plane planes[VIDEO_MAX_PLANES];
+  uint8_t *plane_data[VIDEO_MAX_PLANES];
+  size_t num_planes;
+  char fmt[4];
+  int i;
+  int ret;
+
+  output_file = fopen(output_file_path, "w");
+  if (!output_file) {
+    perror("Cannot open output file");
+    return 1;
+  }
+
+  v4l2r_set_log_cb(on_log, NULL, V4L2R_LOG_LEVEL_INFO);
+
+  struct v4l2r_encoder *encoder = v4l2r_encoder_new(
+      device_path, V4L2_PIX_FMT_YUV420, WIDTH, HEIGHT, V4L2_PIX_FMT_FWHT, 2,
+      V4L2R_MEMORY_MMAP, 2, on_input_done, on_event, NULL, &ret);
+  if (!encoder) {
+    fprintf(stderr, "Cannot create encoder: %d\n", ret);
+    return 1;
+  }
+
+  ret = v4l2r_encoder_get_input_format(encoder, &input_format);
+  if (ret < 0)
+    return 1;
+  *((uint32_t *)&fmt) = input_format.fmt.pix_mp.pixelformat;
+  num_planes = input_format.fmt.pix_mp.num_planes;
+  printf("Reported OUTPUT format: %c%c%c%c, %dx%d, %zu planes\n", fmt[0],
+         fmt[1], fmt[2], fmt[3], input_format.fmt.pix_mp.width,
+         input_format.fmt.pix_mp.height, num_planes);
+
+  for (i = 0; i < num_planes; i++) {
+    planes[i].size = input_format.fmt.pix_mp.plane_fmt[i].sizeimage;
+    plane_data[i] = malloc(planes[i].size);
+    if (!plane_data[i]) {
+      perror("Cannot allocate frame");
+      return 1;
+    }
+    planes[i].data = plane_data[i];
+  }
+
+  for (i = 0; i < NUM_FRAMES; i++) {
+    fill_frame(&input_format, plane_data, i);
+
+    // The data of the planes is copied, so they can be reused right away.
+    ret = v4l2r_encoder_encode_mmap(encoder, i, planes, num_planes);
+    if (ret < 0) {
+      fprintf(stderr, "Cannot encode frame %d: %d\n", i, ret);
+      return 1;
+    }
+  }
+
+  ret = v4l2r_encoder_drain(encoder);
+  if (ret < 0)
+    fprintf(stderr, "Error while draining encoder: %d\n", ret);
+
+  v4l2r_encoder_destroy(encoder);
+  printf("Encoding complete, %zu bytes written to %s\n", encoded_bytes,
+         output_file_path);
+
+  for (i = 0; i < num_planes; i++)
+    free(plane_data[i]);
+  fclose(output_file);
+
+  return 0;
+}
@@ -10,47 +10,90 @@
 //! frame being decoded, or a change in the output format (due to e.g. a dynamic
 //! resolution change). The output format is initially undefined and a format
 //! change event will be produced before any frame can be decoded.
+//!
+//! Decoded frames can either be written into DMABUF frames provided by the
+//! client, or into MMAP buffers allocated by the decoder and mapped into the
+//! address space of the client. This is selected at creation time.
 #![allow(non_camel_case_types)]
 
 use log::{debug, error, info, warn};
 use nix::sys::time::{TimeVal, TimeValLike};
 use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
     ffi::CStr,
     mem::MaybeUninit,
     os::raw::{c_char, c_int, c_uint, c_void},
     path::Path,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 use v4l2r::{
     bindings,
     decoder::{
-        stateful::{Decoder, Decoding, DrainError},
-        CompletedInputBuffer, DecoderEvent, DecoderEventCallback, FormatChangedCallback,
-        FormatChangedReply, InputDoneCallback,
+        stateful::{Decoder, Decoding, DrainError, ReadyToDecode},
+        CaptureFormatChange, CompletedInputBuffer, DecoderEvent, DecoderEventCallback,
+        FormatChangedCallback, FormatChangedReply, InputDoneCallback,
+    },
+    device::{
+        queue::{
+            buffer::PlaneMappingRef,
+            direction::Capture,
+            dqbuf::DqBuffer,
+            handles_provider::{HandlesProvider, MmapProvider},
+            qbuf::OutputQueueable,
+        },
+        FatalError,
     },
-    device::queue::{direction::Capture, dqbuf::DqBuffer, qbuf::OutputQueueable, FormatBuilder},
-    memory::DmaBufHandle,
-    PixelFormat, PlaneLayout, Rect,
+    memory::{DmaBufHandle, MemoryType, MmapHandle, PrimitiveBufferHandles},
+    Format, PixelFormat, PlaneLayout, QueueType, Rect,
 };
 
-use crate::memory::{
-    v4l2r_video_frame, v4l2r_video_frame_provider, v4l2r_video_frame_provider_queue_frame,
-    DmaBufFd, VideoFrameMemoryType,
+use crate::{
+    error::{arg_mut, arg_ref, log_error, set_error, v4l2r_error, v4l2r_error_event},
+    memory::{
+        v4l2r_memory_type, v4l2r_video_frame, v4l2r_video_frame_provider,
+        v4l2r_video_frame_provider_queue_frame, DmaBufFd, VideoFrameMemoryType,
+    },
+    SendablePtr,
 };
 
-type DynCbDecoder = Decoder<
+type DynCbDecoder<P> = Decoder<
     Decoding<
         Vec<DmaBufHandle<DmaBufFd>>,
-        Arc<v4l2r_video_frame_provider>,
+        P,
         Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>>,
-        Box<dyn DecoderEventCallback<Arc<v4l2r_video_frame_provider>>>,
-        Box<dyn FormatChangedCallback<Arc<v4l2r_video_frame_provider>>>,
+        Box<dyn DecoderEventCallback<P>>,
+        Box<dyn FormatChangedCallback<P>>,
     >,
 >;
 
+/// A decoded frame passed to the client in MMAP mode.
+struct MappedFrame {
+    // Declared before `_buffer` so the mappings are dropped before the buffer
+    // is returned to the decoder.
+    _mappings: Vec<PlaneMappingRef<'static>>,
+    _buffer: DqBuffer<Capture, Vec<MmapHandle>>,
+}
+
+/// Decoded frames that have been passed to the client in MMAP mode and not
+/// released yet.
+#[derive(Default)]
+struct MappedFrames {
+    next_id: u64,
+    frames: BTreeMap<u64, MappedFrame>,
+}
+
+enum DecoderInstance {
+    DmaBuf(DynCbDecoder<Arc<v4l2r_video_frame_provider>>),
+    Mmap {
+        decoder: DynCbDecoder<MmapProvider>,
+        frames: Arc<Mutex<MappedFrames>>,
+    },
+}
+
 /// A V4L2 decoder instance.
 pub struct v4l2r_decoder {
-    decoder: DynCbDecoder,
+    decoder: DecoderInstance,
     // Reference to the video frame provider for our callbacks.
     provider: Option<Arc<v4l2r_video_frame_provider>>,
     // Keep the size of input buffers at hand.
@@ -83,6 +126,37 @@ pub struct v4l2r_decoder_frame_decoded_event {
     frame: v4l2r_video_frame,
 }
 
+/// A decoded frame allocated by the decoder and mapped into the address space
+/// of the client.
+#[repr(C)]
+pub struct v4l2r_mapped_frame {
+    /// Identifier of the frame, to be passed to [`v4l2r_decoder_release_frame`]
+    /// once the client is done with it. Identifiers are never reused by a
+    /// decoder.
+    pub id: u64,
+    /// Number of valid entries in `planes` and `sizes`.
+    pub num_planes: usize,
+    /// Decoded data of each plane.
+    pub planes: [*const u8; 4],
+    /// Size in bytes of the decoded data of each plane.
+    pub sizes: [usize; 4],
+}
+
+/// Event produced for every decoded frame when the decoder has been created
+/// with `V4L2R_MEMORY_MMAP`.
+#[repr(C)]
+pub struct v4l2r_decoder_mapped_frame_decoded_event {
+    /// Dequeued V4L2 buffer that has produced the frame. Useful to check for
+    /// flags and errors.
+    buffer: *const bindings::v4l2_buffer,
+    /// The decoded frame. Its data remains valid and untouched by the decoder
+    /// until the client passes its `id` to [`v4l2r_decoder_release_frame`],
+    /// even across format changes. The decoder cannot reuse the underlying
+    /// buffer until then, so the client must release frames as soon as
+    /// possible to avoid starving it.
+    frame: v4l2r_mapped_frame,
+}
+
 /// Event produced every time the output format of the stream changes.
 /// This includes when the initial format is determined by the decoder, and any
 /// subsequent dynamic resolution change in the stream.
@@ -93,7 +167,8 @@ pub struct v4l2r_decoder_format_changed_event {
     /// Visible rectangle for decoded frames produced after this event.
     visible_rect: bindings::v4l2_rect,
     /// Pointer to the video frame provider the client must use to provide
-    /// frames to decode into.
+    /// frames to decode into. Always NULL if the decoder has been created
+    /// with `V4L2R_MEMORY_MMAP`.
     ///
     /// When the client receives this event, it must stop using the previous
     /// video frame provider (if any) as soon as possible and destroy it using
@@ -114,8 +189,12 @@ pub struct v4l2r_decoder_format_changed_event {
     /// properly.
     ///
     /// The client must allocate at least `min_num_frames` (but no more than
-    /// 32), otherwise the decoder might starve.
+    /// `max_num_frames`), otherwise the decoder might starve.
     min_num_frames: c_uint,
+    /// Number of V4L2 buffers requested for decoded frames. In DMABUF mode, the
+    /// client must not allocate more frames than this, and their identifiers
+    /// must be smaller than this value.
+    max_num_frames: c_uint,
 }
 
 /// Decoding-related events. These events can be produced at any time between
@@ -128,28 +207,100 @@ pub enum v4l2r_decoder_event {
     FrameDecoded(v4l2r_decoder_frame_decoded_event),
     FormatChanged(v4l2r_decoder_format_changed_event),
     EndOfStream,
+    MappedFrameDecoded(v4l2r_decoder_mapped_frame_decoded_event),
+    /// The decoder has met an unrecoverable error and won't produce any more
+    /// frames. The client can only destroy it.
+    Error(v4l2r_error_event),
 }
 
 /// Events callback. This callback is guaranteed to always be called from the
 /// same thread, i.e. events are completely sequential.
 pub type v4l2r_decoder_event_cb = extern "C" fn(*mut c_void, *mut v4l2r_decoder_event);
 
+/// Number of CAPTURE buffers to request: the number requested by the client,
+/// but not less than what the decoder requires, nor more than V4L2 allows.
+fn num_capture_buffers(requested: usize, min_num_buffers: usize) -> usize {
+    requested
+        .max(min_num_buffers)
+        .min(bindings::VIDEO_MAX_FRAME as usize)
+}
+
+fn report_error<E: std::fmt::Display>(
+    code: v4l2r_error,
+    error: E,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: *mut c_void,
+) {
+    v4l2r_error_event::with(code, error, |event| {
+        event_cb(cb_data, &mut v4l2r_decoder_event::Error(event))
+    });
+}
+
+/// Returns the decoder behind the `decoder` pointer passed to our callbacks,
+/// or reports an error through `event_cb` if it is NULL.
+fn callback_decoder<'a>(
+    decoder: *mut v4l2r_decoder,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: *mut c_void,
+) -> Option<&'a mut v4l2r_decoder> {
+    // Safe unless the C part did something funny with the decoder returned by
+    // `v4l2r_decoder_new`.
+    let decoder = unsafe { decoder.as_mut() };
+    if decoder.is_none() {
+        let message = "Decoder callback invoked without a decoder";
+        error!("{}", message);
+        report_error(v4l2r_error::V4L2R_ERROR_UNKNOWN, message, event_cb, cb_data);
+    }
+
+    decoder
+}
+
+/// Apply the format of `change` and return it in both the Rust and V4L2
+/// representations.
+fn apply_capture_format(
+    change: CaptureFormatChange,
+    desired_pixel_format: Option<PixelFormat>,
+) -> anyhow::Result<(Format, bindings::v4l2_format)> {
+    let format: Format = match change {
+        CaptureFormatChange::Reallocate(f) => match desired_pixel_format {
+            Some(format) => f.set_pixelformat(format).apply()?,
+            None => f.apply()?,
+        },
+        // The current buffers are kept, so the format cannot be changed. It
+        // still uses the pixel format we set when they were allocated.
+        CaptureFormatChange::Reuse(format) => format,
+    };
+    let v4l2_format = bindings::v4l2_format::try_from((QueueType::VideoCaptureMplane, &format))?;
+
+    Ok((format, v4l2_format))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn set_capture_format_cb(
-    f: FormatBuilder,
+    change: CaptureFormatChange,
     desired_pixel_format: Option<PixelFormat>,
     visible_rect: Rect,
     min_num_buffers: usize,
+    num_buffers: usize,
     decoder: *mut v4l2r_decoder,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
 ) -> anyhow::Result<FormatChangedReply<Arc<v4l2r_video_frame_provider>>> {
-    // Safe unless the C part did something funny with the decoder returned by
-    // `v4l2r_decoder_new`.
-    let decoder = unsafe { decoder.as_mut().unwrap() };
-    let mut v4l2_format: bindings::v4l2_format = match desired_pixel_format {
-        Some(format) => f.set_pixelformat(format).apply()?,
-        None => f.apply()?,
+    let decoder = callback_decoder(decoder, event_cb, cb_data)
+        .ok_or_else(|| anyhow::anyhow!("Decoder callback invoked without a decoder"))?;
+    let (_, mut v4l2_format) = match apply_capture_format(change, desired_pixel_format) {
+        Ok(format) => format,
+        Err(e) => {
+            report_error(
+                v4l2r_error::V4L2R_ERROR_UNSUPPORTED_FORMAT,
+                &e,
+                event_cb,
+                cb_data,
+            );
+            return Err(e);
+        }
     };
+    let num_buffers = num_capture_buffers(num_buffers, min_num_buffers);
 
     // Create new memory provider on the heap and update our internal pointer.
     let new_provider = Arc::new(v4l2r_video_frame_provider::new());
@@ -160,7 +311,6 @@ fn set_capture_format_cb(
     // `v4l2r_video_frame_provider_drop`.
     let provider_client_ref = Arc::clone(&new_provider);
 
-    // TODO check return value.
     event_cb(
         cb_data,
         &mut v4l2r_decoder_event::FormatChanged(v4l2r_decoder_format_changed_event {
@@ -168,6 +318,7 @@ fn set_capture_format_cb(
             visible_rect: visible_rect.into(),
             new_provider: Arc::into_raw(provider_client_ref),
             min_num_frames: min_num_buffers as c_uint,
+            max_num_frames: num_buffers as c_uint,
         }),
     );
 
@@ -176,20 +327,70 @@ fn set_capture_format_cb(
         // TODO: can't the provider report the memory type that it is
         // actually serving itself?
         mem_type: VideoFrameMemoryType,
-        // Since we are using DMABUF, always allocate the maximum number of
-        // V4L2 buffers (32) since they are virtually free. This gives more
-        // flexibility for the client as to how many frames it can allocate.
-        num_buffers: bindings::VIDEO_MAX_FRAME as usize,
+        num_buffers,
+    })
+}
+
+fn set_mmap_capture_format_cb(
+    change: CaptureFormatChange,
+    desired_pixel_format: Option<PixelFormat>,
+    visible_rect: Rect,
+    min_num_buffers: usize,
+    num_buffers: usize,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: *mut c_void,
+) -> anyhow::Result<FormatChangedReply<MmapProvider>> {
+    let (format, mut v4l2_format) = match apply_capture_format(change, desired_pixel_format) {
+        Ok(format) => format,
+        Err(e) => {
+            report_error(
+                v4l2r_error::V4L2R_ERROR_UNSUPPORTED_FORMAT,
+                &e,
+                event_cb,
+                cb_data,
+            );
+            return Err(e);
+        }
+    };
+    let num_buffers = num_capture_buffers(num_buffers, min_num_buffers);
+
+    event_cb(
+        cb_data,
+        &mut v4l2r_decoder_event::FormatChanged(v4l2r_decoder_format_changed_event {
+            new_format: &mut v4l2_format,
+            visible_rect: visible_rect.into(),
+            new_provider: std::ptr::null(),
+            min_num_frames: min_num_buffers as c_uint,
+            max_num_frames: num_buffers as c_uint,
+        }),
+    );
+
+    Ok(FormatChangedReply {
+        provider: MmapProvider::new(&format),
+        mem_type: MemoryType::Mmap,
+        num_buffers,
     })
 }
 
 fn frame_decoded_cb(
-    decoder: &mut v4l2r_decoder,
+    decoder: *mut v4l2r_decoder,
     mut dqbuf: DqBuffer<Capture, v4l2r_video_frame>,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
 ) {
-    let frame = dqbuf.take_handles().unwrap();
+    let decoder = match callback_decoder(decoder, event_cb, cb_data) {
+        Some(decoder) => decoder,
+        None => return,
+    };
+    let frame = match dqbuf.take_handles() {
+        Some(frame) => frame,
+        None => {
+            let message = "Decoded V4L2 buffer has no frame attached";
+            error!("{}", message);
+            report_error(v4l2r_error::V4L2R_ERROR_UNKNOWN, message, event_cb, cb_data);
+            return;
+        }
+    };
     debug!(
         "Video frame {} ({}) decoded from V4L2 buffer {} (flags: {:?})",
         frame.id,
@@ -221,7 +422,6 @@ fn frame_decoded_cb(
             }
         }
     } else {
-        // TODO check return value?
         event_cb(
             cb_data,
             &mut v4l2r_decoder_event::FrameDecoded(v4l2r_decoder_frame_decoded_event {
@@ -232,153 +432,185 @@ fn frame_decoded_cb(
     }
 }
 
-// A void pointer that can be sent across threads. This is usually not allowed
-// by Rust, but is necessary for us to call back into the V4L2RustDecoder.
-struct SendablePtr<T>(*mut T);
-impl<T> Clone for SendablePtr<T> {
-    fn clone(&self) -> Self {
-        *self
-    }
-}
-impl<T> Copy for SendablePtr<T> {}
-unsafe impl<T> Send for SendablePtr<T> {}
-unsafe impl<T> Sync for SendablePtr<T> {}
-
-#[allow(clippy::too_many_arguments)]
-fn v4l2r_decoder_new_safe(
-    path: &Path,
-    input_format_fourcc: u32,
-    num_input_buffers: usize,
-    input_buffer_size: usize,
-    output_format_fourcc: u32,
-    input_done_cb: v4l2r_decoder_input_done_cb,
+fn mapped_frame_decoded_cb(
+    frames: &Mutex<MappedFrames>,
+    dqbuf: DqBuffer<Capture, Vec<MmapHandle>>,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
-) -> *mut v4l2r_decoder {
-    let decoder = match Decoder::open(path) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("failed to open decoder {}: {:#?}", path.display(), e);
-            return std::ptr::null_mut();
-        }
-    };
-
-    info!(
-        "Opened decoder {} with format {}, {} input buffers of size {}",
-        path.display(),
-        v4l2r::PixelFormat::from(input_format_fourcc),
-        num_input_buffers,
-        input_buffer_size
+) {
+    debug!(
+        "Frame ({}) decoded into V4L2 buffer {} (flags: {:?})",
+        dqbuf.data.timestamp().tv_sec,
+        dqbuf.data.index(),
+        dqbuf.data.flags(),
     );
+    let v4l2_data = dqbuf.data.clone();
 
-    let decoder = match decoder.set_output_format(|f| {
-        let pixel_format = input_format_fourcc.into();
-        let format = match f
-            .set_pixelformat(pixel_format)
-            .set_planes_layout(vec![PlaneLayout {
-                sizeimage: input_buffer_size as u32,
-                ..Default::default()
-            }])
-            .apply::<v4l2r::Format>()
-        {
-            Ok(format) if format.pixelformat == pixel_format => format,
-            Ok(_) => {
-                return Err(anyhow::anyhow!(
-                    "Unrecognized OUTPUT format {:?}",
-                    pixel_format
-                ))
-            }
-            Err(e) => return Err(e.into()),
-        };
+    // Immediately recycle empty frames by dropping them.
+    if v4l2_data.get_first_plane().bytesused() == 0 {
         debug!(
-            "Decoder requires input buffer size of: {}",
-            format.plane_fmt[0].sizeimage
+            "Immediately recycling zero-sized V4L2 buffer {} {}",
+            v4l2_data.index(),
+            v4l2_data.is_last()
         );
-        Ok(())
-    }) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("Error while setting output format: {}", e);
-            return std::ptr::null_mut();
-        }
+        return;
+    }
+
+    let mut frame = v4l2r_mapped_frame {
+        id: 0,
+        num_planes: v4l2_data.num_planes(),
+        planes: [std::ptr::null(); 4],
+        sizes: [0; 4],
     };
+    if frame.num_planes > frame.planes.len() {
+        error!(
+            "V4L2 buffer {} has too many planes ({})",
+            v4l2_data.index(),
+            frame.num_planes
+        );
+        return;
+    }
 
-    let output_format = match output_format_fourcc {
-        0 => None,
-        fourcc => Some(PixelFormat::from(fourcc)),
+    let mappings = match (0..frame.num_planes)
+        .map(|i| dqbuf.get_plane_mapping(i))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(mappings) => mappings,
+        None => {
+            error!("Cannot map V4L2 buffer {}", v4l2_data.index());
+            return;
+        }
+    };
+    for (i, mapping) in mappings.iter().enumerate() {
+        frame.planes[i] = mapping.as_ptr();
+        frame.sizes[i] = mapping.len();
+    }
+    // Safe because the mappings are stored alongside the buffer they borrow,
+    // and dropped before it.
+    let mappings = unsafe {
+        std::mem::transmute::<Vec<PlaneMappingRef<'_>>, Vec<PlaneMappingRef<'static>>>(mappings)
     };
 
-    let cb_data = SendablePtr(cb_data);
+    // Keep the frame before passing it to the client, which may release it
+    // from within the callback.
+    {
+        let mut frames = frames.lock().unwrap();
+        frame.id = frames.next_id;
+        frames.next_id += 1;
+        frames.frames.insert(
+            frame.id,
+            MappedFrame {
+                _mappings: mappings,
+                _buffer: dqbuf,
+            },
+        );
+    }
 
-    let decoder =
-        match decoder.allocate_output_buffers::<Vec<DmaBufHandle<DmaBufFd>>>(num_input_buffers) {
-            Ok(decoder) => decoder,
-            Err(e) => {
-                error!("Error while allocating OUTPUT buffers: {}", e);
-                return std::ptr::null_mut();
+    event_cb(
+        cb_data,
+        &mut v4l2r_decoder_event::MappedFrameDecoded(v4l2r_decoder_mapped_frame_decoded_event {
+            buffer: v4l2_data.as_raw_v4l2_buffer(),
+            frame,
+        }),
+    );
+}
+
+fn input_done_cb_wrapper(
+    input_done_cb: v4l2r_decoder_input_done_cb,
+    cb_data: SendablePtr<c_void>,
+) -> Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>> {
+    Box::new(
+        move |buf: CompletedInputBuffer<Vec<DmaBufHandle<DmaBufFd>>>| {
+            match buf {
+                CompletedInputBuffer::Dequeued(dqbuf) => {
+                    debug!("Input buffer {} done", dqbuf.data.index());
+                    input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
+                }
+                // Just drop canceled buffers for now - the client will remove
+                // them on its side as well.
+                // TODO add a status parameter to the callback and invoke it?
+                // that way the client does not need to clear its own list...
+                CompletedInputBuffer::Canceled(_) => (),
             }
-        };
+        },
+    )
+}
+
+fn fatal_error_cb(error: FatalError, event_cb: v4l2r_decoder_event_cb, cb_data: *mut c_void) {
+    error!("Fatal decoder error: {}", error);
+    report_error(v4l2r_error::V4L2R_ERROR_DEVICE, error, event_cb, cb_data);
+}
 
+#[allow(clippy::too_many_arguments)]
+fn start_dmabuf_decoder(
+    decoder: Decoder<ReadyToDecode<Vec<DmaBufHandle<DmaBufFd>>>>,
+    output_format: Option<PixelFormat>,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_decoder_input_done_cb,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: SendablePtr<c_void>,
+) -> Result<Box<v4l2r_decoder>, v4l2r_error> {
     // Reserve memory on the heap for our decoder and take a pointer that we
     // can use in our callbacks.
     let mut decoder_box = Box::new(MaybeUninit::<v4l2r_decoder>::uninit());
     let decoder_ptr = SendablePtr(decoder_box.as_mut_ptr());
 
-    let decoder = match decoder.start(
-        Box::new(
-            move |buf: CompletedInputBuffer<Vec<DmaBufHandle<DmaBufFd>>>| {
-                match buf {
-                    CompletedInputBuffer::Dequeued(dqbuf) => {
-                        debug!("Input buffer {} done", dqbuf.data.index());
-                        // TODO check return value?
-                        input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
-                    }
-                    // Just drop canceled buffers for now - the client will remove
-                    // them on its side as well.
-                    // TODO add a status parameter to the callback and invoke it?
-                    // that way the client does not need to clear its own list...
-                    CompletedInputBuffer::Canceled(_) => (),
-                }
-            },
-        ) as Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>>,
-        Box::new(move |event: DecoderEvent<Arc<v4l2r_video_frame_provider>>| {
-            let decoder = unsafe { decoder_ptr.0.as_mut().unwrap() };
-
-            match event {
-                DecoderEvent::FrameDecoded(dqbuf) => frame_decoded_cb(decoder, dqbuf, event_cb, cb_data.0),
-                DecoderEvent::EndOfStream =>
-                    event_cb(cb_data.0,
-                        &mut v4l2r_decoder_event::EndOfStream)
-            };
-        }) as Box<dyn DecoderEventCallback<Arc<v4l2r_video_frame_provider>>>,
-        Box::new(
-            move |f: FormatBuilder,
-                  visible_rect: Rect,
-                  min_num_buffers: usize|
-                  -> anyhow::Result<FormatChangedReply<Arc<v4l2r_video_frame_provider>>> {
-                set_capture_format_cb(
-                    f,
-                    output_format,
-                    visible_rect,
-                    min_num_buffers,
-                    decoder_ptr.0,
-                    event_cb,
-                    cb_data.0,
+    let decoder =
+        decoder
+            .start(
+                input_done_cb_wrapper(input_done_cb, cb_data),
+                Box::new(
+                    move |event: DecoderEvent<Arc<v4l2r_video_frame_provider>>| {
+                        match event {
+                            DecoderEvent::FrameDecoded { buffer, .. } => {
+                                frame_decoded_cb(decoder_ptr.0, buffer, event_cb, cb_data.0)
+                            }
+                            DecoderEvent::EndOfStream => {
+                                event_cb(cb_data.0, &mut v4l2r_decoder_event::EndOfStream)
+                            }
+                            // The client can find the error flag in the V4L2 buffer.
+                            DecoderEvent::FrameError { buffer, .. } => {
+                                frame_decoded_cb(decoder_ptr.0, buffer, event_cb, cb_data.0)
+                            }
+                            // We do not attach metadata to frames, so there is nothing to report.
+                            DecoderEvent::FrameDropped(()) => (),
+                            DecoderEvent::FatalError(e) => fatal_error_cb(e, event_cb, cb_data.0),
+                        };
+                    },
+                ) as Box<dyn DecoderEventCallback<Arc<v4l2r_video_frame_provider>>>,
+                Box::new(
+                    move |change: CaptureFormatChange,
+                          visible_rect: Rect,
+                          min_num_buffers: usize|
+                          -> anyhow::Result<
+                        FormatChangedReply<Arc<v4l2r_video_frame_provider>>,
+                    > {
+                        set_capture_format_cb(
+                            change,
+                            output_format,
+                            visible_rect,
+                            min_num_buffers,
+                            num_output_buffers,
+                            decoder_ptr.0,
+                            event_cb,
+                            cb_data.0,
+                        )
+                    },
                 )
-            },
-        ) as Box<dyn FormatChangedCallback<Arc<v4l2r_video_frame_provider>>>,
-    ) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("Cannot start decoder: {}", e);
-            return std::ptr::null_mut();
-        }
-    };
+                    as Box<dyn FormatChangedCallback<Arc<v4l2r_video_frame_provider>>>,
+            )
+            .map_err(log_error(
+                v4l2r_error::V4L2R_ERROR_DEVICE,
+                "Cannot start decoder",
+            ))?;
 
-    let input_format: v4l2r::Format = decoder.get_output_format().unwrap();
+    let input_format: Format = decoder.get_output_format().map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Error while getting OUTPUT format",
+    ))?;
 
     let decoder = v4l2r_decoder {
-        decoder,
+        decoder: DecoderInstance::DmaBuf(decoder),
         provider: None,
         input_buf_size: input_format.plane_fmt[0].sizeimage as u64,
     };
@@ -393,43 +625,203 @@ fn v4l2r_decoder_new_safe(
         Box::from_raw(Box::into_raw(decoder_box) as *mut v4l2r_decoder)
     };
 
-    info!("Decoder {:p}: successfully started", decoder_box.as_ref());
+    Ok(decoder_box)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_mmap_decoder(
+    decoder: Decoder<ReadyToDecode<Vec<DmaBufHandle<DmaBufFd>>>>,
+    output_format: Option<PixelFormat>,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_decoder_input_done_cb,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: SendablePtr<c_void>,
+) -> Result<Box<v4l2r_decoder>, v4l2r_error> {
+    let frames = Arc::new(Mutex::new(MappedFrames::default()));
+    let cb_frames = Arc::clone(&frames);
+
+    let decoder = decoder
+        .start(
+            input_done_cb_wrapper(input_done_cb, cb_data),
+            Box::new(move |event: DecoderEvent<MmapProvider>| {
+                match event {
+                    // The client can find the error flag in the V4L2 buffer.
+                    DecoderEvent::FrameDecoded { buffer, .. }
+                    | DecoderEvent::FrameError { buffer, .. } => {
+                        mapped_frame_decoded_cb(&cb_frames, buffer, event_cb, cb_data.0)
+                    }
+                    DecoderEvent::EndOfStream => {
+                        event_cb(cb_data.0, &mut v4l2r_decoder_event::EndOfStream)
+                    }
+                    // We do not attach metadata to frames, so there is nothing to report.
+                    DecoderEvent::FrameDropped(()) => (),
+                    DecoderEvent::FatalError(e) => fatal_error_cb(e, event_cb, cb_data.0),
+                };
+            }) as Box<dyn DecoderEventCallback<MmapProvider>>,
+            Box::new(
+                move |change: CaptureFormatChange,
+                      visible_rect: Rect,
+                      min_num_buffers: usize|
+                      -> anyhow::Result<FormatChangedReply<MmapProvider>> {
+                    set_mmap_capture_format_cb(
+                        change,
+                        output_format,
+                        visible_rect,
+                        min_num_buffers,
+                        num_output_buffers,
+                        event_cb,
+                        cb_data.0,
+                    )
+                },
+            ) as Box<dyn FormatChangedCallback<MmapProvider>>,
+        )
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Cannot start decoder",
+        ))?;
+
+    let input_format: Format = decoder.get_output_format().map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Error while getting OUTPUT format",
+    ))?;
+
+    Ok(Box::new(v4l2r_decoder {
+        decoder: DecoderInstance::Mmap { decoder, frames },
+        provider: None,
+        input_buf_size: input_format.plane_fmt[0].sizeimage as u64,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn v4l2r_decoder_new_safe(
+    path: &Path,
+    input_format_fourcc: u32,
+    num_input_buffers: usize,
+    input_buffer_size: usize,
+    output_format_fourcc: u32,
+    output_memory: v4l2r_memory_type,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_decoder_input_done_cb,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: *mut c_void,
+) -> Result<Box<v4l2r_decoder>, v4l2r_error> {
+    let input_buffer_size = u32::try_from(input_buffer_size).map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT,
+        "Invalid input buffer size",
+    ))?;
+    let decoder = Decoder::open(path).map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Failed to open decoder",
+    ))?;
+
+    info!(
+        "Opened decoder {} with format {}, {} input buffers of size {}, {:?} output frames",
+        path.display(),
+        v4l2r::PixelFormat::from(input_format_fourcc),
+        num_input_buffers,
+        input_buffer_size,
+        output_memory,
+    );
+
+    let decoder = decoder
+        .set_output_format(|f| {
+            let pixel_format = input_format_fourcc.into();
+            let format = match f
+                .set_pixelformat(pixel_format)
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: input_buffer_size,
+                    ..Default::default()
+                }])
+                .apply::<v4l2r::Format>()
+            {
+                Ok(format) if format.pixelformat == pixel_format => format,
+                Ok(_) => {
+                    return Err(anyhow::anyhow!(
+                        "Unrecognized OUTPUT format {:?}",
+                        pixel_format
+                    ))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            debug!(
+                "Decoder requires input buffer size of: {}",
+                format.plane_fmt[0].sizeimage
+            );
+            Ok(())
+        })
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_UNSUPPORTED_FORMAT,
+            "Error while setting output format",
+        ))?;
+
+    let output_format = match output_format_fourcc {
+        0 => None,
+        fourcc => Some(PixelFormat::from(fourcc)),
+    };
+
+    let cb_data = SendablePtr(cb_data);
+
+    let decoder = decoder
+        .allocate_output_buffers::<Vec<DmaBufHandle<DmaBufFd>>>(num_input_buffers)
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Error while allocating OUTPUT buffers",
+        ))?;
 
-    Box::into_raw(decoder_box)
+    match output_memory {
+        v4l2r_memory_type::V4L2R_MEMORY_DMABUF => start_dmabuf_decoder(
+            decoder,
+            output_format,
+            // DMABUF buffers are virtually free, so allocate the maximum
+            // number of V4L2 buffers by default. This gives more flexibility
+            // for the client as to how many frames it can allocate.
+            match num_output_buffers {
+                0 => bindings::VIDEO_MAX_FRAME as usize,
+                n => n,
+            },
+            input_done_cb,
+            event_cb,
+            cb_data,
+        ),
+        v4l2r_memory_type::V4L2R_MEMORY_MMAP => start_mmap_decoder(
+            decoder,
+            output_format,
+            num_output_buffers,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        ),
+    }
 }
 
-fn v4l2r_decoder_decode_safe(
-    decoder: &mut v4l2r_decoder,
+fn v4l2r_decoder_decode_safe<P: HandlesProvider>(
+    decoder: &mut DynCbDecoder<P>,
+    input_buf_size: u64,
     bitstream_id: i32,
     fd: c_int,
     bytes_used: usize,
-) -> c_int {
-    let v4l2_buffer = match decoder.decoder.get_buffer() {
-        Ok(buffer) => buffer,
-        Err(e) => {
-            error!("Error obtaining V4L2 buffer: {}", e);
-            return -1;
-        }
-    };
+) -> Result<c_int, v4l2r_error>
+where
+    P::HandleType: PrimitiveBufferHandles,
+{
+    let v4l2_buffer = decoder.get_buffer().map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Error obtaining V4L2 buffer",
+    ))?;
     let v4l2_buffer_id = v4l2_buffer.index();
 
-    match v4l2_buffer
+    v4l2_buffer
         .set_timestamp(TimeVal::seconds(bitstream_id as i64))
         .queue_with_handles(
-            vec![DmaBufHandle::from(DmaBufFd::new(
-                fd,
-                decoder.input_buf_size,
-            ))],
+            vec![DmaBufHandle::from(DmaBufFd::new(fd, input_buf_size))],
             &[bytes_used],
-        ) {
-        Ok(()) => (),
-        Err(e) => {
-            error!("Error while queueing buffer: {}", e);
-            return -1;
-        }
-    };
+        )
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Error while queueing buffer",
+        ))?;
 
-    v4l2_buffer_id as c_int
+    Ok(v4l2_buffer_id as c_int)
 }
 
 /// Create a new decoder for a given encoded format.
@@ -446,6 +838,17 @@ fn v4l2r_decoder_decode_safe(
 /// * `output_format_fourcc` is the FOURCC code of the desired pixel format for
 ///   output frames (e.g. "NV12"). It can also be 0, in which case the decoder
 ///   will use whichever pixel format is active by default.
+/// * `output_memory` is the memory type of output frames. With
+///   `V4L2R_MEMORY_DMABUF`, the client provides the frames to decode into
+///   through the provider passed with the format change event, and receives
+///   them with `FrameDecoded` events. With `V4L2R_MEMORY_MMAP`, the decoder
+///   allocates the frames itself and passes them to the client mapped into its
+///   address space with `MappedFrameDecoded` events.
+/// * `num_output_buffers` is the number of V4L2 buffers to allocate for
+///   output frames. The decoder always allocates at least the number of
+///   buffers it requires. 0 lets the decoder decide, which means the maximum
+///   number of buffers supported by V4L2 in DMABUF mode, and the minimum
+///   number of buffers required by the decoder in MMAP mode.
 /// * `input_done_cb` is a pointer to a callback function to be called whenever
 ///   an encoded input buffer is done being processed. This callback is
 ///   guaranteed to be invoked during calls to [`v4l2r_decoder_decode`] or
@@ -459,10 +862,17 @@ fn v4l2r_decoder_decode_safe(
 ///   different threads).
 /// * `cb_data` is a pointer that will always be passed as the first parameter
 ///   of the `input_done_cb` and `events_cb`.
+/// * `error` is a pointer that receives 0 if the decoder has been created
+///   successfully, or one of the codes of `v4l2r_error` otherwise. It can be
+///   NULL if the client is not interested in the reason of a failure.
+///
+/// Returns NULL if the decoder could not be created.
 ///
 /// # Safety
-/// The passed `path` must be a valid, zero-terminated C string containining the
-/// path to the device. Expect a crash if passing an invalid string.
+/// The passed `path` must be NULL or a valid, zero-terminated C string
+/// containining the path to the device. Expect a crash if passing an invalid
+/// string. `error` must be NULL or point to valid memory that can receive a
+/// `c_int`.
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_decoder_new(
     path: *const c_char,
@@ -470,52 +880,93 @@ pub unsafe extern "C" fn v4l2r_decoder_new(
     num_input_buffers: usize,
     input_buffer_size: usize,
     output_format_fourcc: u32,
+    output_memory: v4l2r_memory_type,
+    num_output_buffers: usize,
     input_done_cb: v4l2r_decoder_input_done_cb,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
+    error: *mut c_int,
 ) -> *mut v4l2r_decoder {
-    let cstr = CStr::from_ptr(path);
-    let rstr = cstr.to_str().unwrap();
-    let path = Path::new(&rstr);
+    let res = arg_ref(path, "path")
+        .and_then(|path| {
+            CStr::from_ptr(path).to_str().map_err(log_error(
+                v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT,
+                "Invalid device path",
+            ))
+        })
+        .and_then(|rstr| {
+            v4l2r_decoder_new_safe(
+                Path::new(rstr),
+                input_format_fourcc,
+                num_input_buffers,
+                input_buffer_size,
+                output_format_fourcc,
+                output_memory,
+                num_output_buffers,
+                input_done_cb,
+                event_cb,
+                cb_data,
+            )
+        });
 
-    v4l2r_decoder_new_safe(
-        path,
-        input_format_fourcc,
-        num_input_buffers,
-        input_buffer_size,
-        output_format_fourcc,
-        input_done_cb,
-        event_cb,
-        cb_data,
-    )
+    match res {
+        Ok(decoder) => {
+            info!("Decoder {:p}: successfully started", decoder.as_ref());
+            set_error(error, v4l2r_error::V4L2R_SUCCESS);
+            Box::into_raw(decoder)
+        }
+        Err(code) => {
+            set_error(error, code);
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// Stop and destroy a decoder.
 ///
 /// Stop `decoder` and destroy it. This function DOES take ownership of
-/// `decoder`, which must absolutely not be used after this call.
+/// `decoder`, which must absolutely not be used after this call. In MMAP mode,
+/// the frames that have not been released by the client are released, and
+/// their data must not be accessed anymore.
 ///
 /// It is guaranteed that none of the callbacks passed to [`v4l2r_decoder_new`]
 /// will be called after this function has returned.
 ///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occurred while stopping the decoder. The decoder is destroyed in both
+/// cases.
+///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// `v4l2r_decoder_new`. Passing a NULL or invalid pointer will cause a crash.
+/// `v4l2r_decoder_new`. Passing an invalid pointer will cause a crash.
 /// `decoder` must not be used again after this function is called.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) {
+pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) -> c_int {
     info!("Decoder {:p}: destroying", decoder);
 
     if decoder.is_null() {
         warn!("Trying to destroy a NULL decoder");
-        return;
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
     }
 
     let decoder = Box::from_raw(decoder);
-    match decoder.decoder.stop() {
-        Ok(_) => (),
-        Err(e) => error!("Error while stopping decoder: {}", e),
+    let res = match decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => decoder.stop().map(|_| ()),
+        DecoderInstance::Mmap { decoder, frames } => {
+            // Release the frames still held by the client so their buffers
+            // are unmapped before the CAPTURE queue is freed.
+            let frames = std::mem::take(&mut frames.lock().unwrap().frames);
+            drop(frames);
+            decoder.stop().map(|_| ())
+        }
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
+        Err(e) => {
+            error!("Error while stopping decoder: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
     }
 }
 
@@ -525,8 +976,8 @@ pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) {
 /// This function can be called at any time since a decoder always have a valid
 /// input format.
 ///
-/// Returns 0 in case of success, -1 if an error occured, in which case `format`
-/// is not overwritten.
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occured, in which case `format` is not overwritten.
 ///
 /// # Safety
 ///
@@ -537,21 +988,28 @@ pub unsafe extern "C" fn v4l2r_decoder_get_input_format(
     decoder: *const v4l2r_decoder,
     format: *mut bindings::v4l2_format,
 ) -> c_int {
-    assert!(!decoder.is_null());
-    assert!(!format.is_null());
-
-    let decoder = &*decoder;
-    let format = &mut *format;
+    let decoder = match arg_ref(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
+    let format = match arg_mut(format, "format") {
+        Ok(format) => format,
+        Err(e) => return e.into(),
+    };
 
-    *format = match decoder.decoder.get_output_format() {
+    let res = match &decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => decoder.get_output_format(),
+        DecoderInstance::Mmap { decoder, .. } => decoder.get_output_format(),
+    };
+    *format = match res {
         Ok(format) => format,
         Err(e) => {
             error!("Error while getting output format: {}", e);
-            return -1;
+            return v4l2r_error::V4L2R_ERROR_DEVICE.into();
         }
     };
 
-    0
+    v4l2r_error::V4L2R_SUCCESS.into()
 }
 
 /// Decode the encoded data referenced by `fd`.
@@ -568,12 +1026,12 @@ pub unsafe extern "C" fn v4l2r_decoder_get_input_format(
 /// the same index will be passed as argument to the *input done callback* when
 /// this is the case.
 ///
-/// In case of error, -1 is returned.
+/// In case of error, one of the (negative) codes of `v4l2r_error` is returned.
 ///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 /// `fd` is expected to be a valid DMABUF FD backed by enough memory for the
 /// expected input buffer size. Failure to provide a valid FD will return in an
 /// ioctl error (but no crash).
@@ -588,10 +1046,21 @@ pub unsafe extern "C" fn v4l2r_decoder_decode(
         "Decoder {:p}: decoding bitstream id {}",
         decoder, bitstream_id
     );
-    assert!(!decoder.is_null());
-    let decoder = &mut *decoder;
+    let decoder = match arg_mut(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
 
-    v4l2r_decoder_decode_safe(decoder, bitstream_id, fd, bytes_used)
+    let input_buf_size = decoder.input_buf_size;
+    let res = match &mut decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => {
+            v4l2r_decoder_decode_safe(decoder, input_buf_size, bitstream_id, fd, bytes_used)
+        }
+        DecoderInstance::Mmap { decoder, .. } => {
+            v4l2r_decoder_decode_safe(decoder, input_buf_size, bitstream_id, fd, bytes_used)
+        }
+    };
+    res.unwrap_or_else(Into::into)
 }
 
 /// Kick the decoder and see if some input buffers fall as a result.
@@ -611,24 +1080,33 @@ pub unsafe extern "C" fn v4l2r_decoder_decode(
 /// [`v4l2r_decoder_frame_decoded_event`]. That way the client can recycle its
 /// input buffers and the decoding process does not get stuck.
 ///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error`.
+///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_kick(decoder: *const v4l2r_decoder) {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
+pub unsafe extern "C" fn v4l2r_decoder_kick(decoder: *const v4l2r_decoder) -> c_int {
+    let decoder = match arg_ref(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
 
-    match decoder.decoder.kick() {
-        Ok(()) => (),
+    let res = match &decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => decoder.kick(),
+        DecoderInstance::Mmap { decoder, .. } => decoder.kick(),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
         Err(e) => {
             error!("Error while kicking decoder: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
         }
     }
 }
 
-/// Possible responses for the [`v4l2r_decoder_drain`] commmand.
+/// Possible successful responses for the [`v4l2r_decoder_drain`] commmand.
 #[repr(C)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum v4l2r_decoder_drain_response {
@@ -637,49 +1115,114 @@ pub enum v4l2r_decoder_drain_response {
     /// The drain has started but will be completed when we receive a
     /// [`v4l2r_decoder_event::EndOfStream`] event.
     DRAIN_STARTED,
-    /// Drain cannot be done at the moment because not enough input buffers
-    /// have been processed to know the output format.
-    TRY_AGAIN,
-    /// An error has occurred.
-    ERROR,
 }
 
+/// Start draining `decoder`, waiting for the drain to complete if `blocking`
+/// is true.
+///
+/// Returns one of the values of [`v4l2r_decoder_drain_response`] in case of
+/// success. If not enough input buffers have been processed to know the output
+/// format yet, the drain cannot be done and `V4L2R_ERROR_TRY_AGAIN` is
+/// returned. Other errors are reported using the other codes of `v4l2r_error`.
+///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_decoder_drain(
     decoder: *const v4l2r_decoder,
     blocking: bool,
-) -> v4l2r_decoder_drain_response {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
-
-    match decoder.decoder.drain(blocking) {
-        Ok(true) => v4l2r_decoder_drain_response::DRAIN_COMPLETED,
-        Ok(false) => v4l2r_decoder_drain_response::DRAIN_STARTED,
-        Err(DrainError::TryAgain) => v4l2r_decoder_drain_response::TRY_AGAIN,
+) -> c_int {
+    let decoder = match arg_ref(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
+
+    let res = match &decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => decoder.drain(blocking),
+        DecoderInstance::Mmap { decoder, .. } => decoder.drain(blocking),
+    };
+    match res {
+        Ok(true) => v4l2r_decoder_drain_response::DRAIN_COMPLETED as c_int,
+        Ok(false) => v4l2r_decoder_drain_response::DRAIN_STARTED as c_int,
+        Err(DrainError::TryAgain) => v4l2r_error::V4L2R_ERROR_TRY_AGAIN.into(),
         Err(e) => {
             error!("Error while draining decoder: {}", e);
-            v4l2r_decoder_drain_response::ERROR
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
         }
     }
 }
 
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error`.
+///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_flush(decoder: *const v4l2r_decoder) {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
+pub unsafe extern "C" fn v4l2r_decoder_flush(decoder: *const v4l2r_decoder) -> c_int {
+    let decoder = match arg_ref(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
 
-    match decoder.decoder.flush() {
-        Ok(()) => (),
+    let res = match &decoder.decoder {
+        DecoderInstance::DmaBuf(decoder) => decoder.flush(),
+        DecoderInstance::Mmap { decoder, .. } => decoder.flush(),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
         Err(e) => {
             error!("Error while flushing decoder: {:#?}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
+    }
+}
+
+/// Give back a frame received with a `MappedFrameDecoded` event to `decoder`.
+///
+/// `frame_id` is the `id` member of the frame. Its data must not be accessed
+/// after this call, as the decoder will decode new frames into it. This
+/// function can safely be called from any thread, including from the events
+/// callback.
+///
+/// Returns 0 in case of success, `V4L2R_ERROR_INVALID_STATE` if the decoder
+/// has not been created with `V4L2R_MEMORY_MMAP`, or
+/// `V4L2R_ERROR_INVALID_ARGUMENT` if `frame_id` is not the identifier of a
+/// frame currently held by the client.
+///
+/// # Safety
+///
+/// `decoder` must be a valid pointer to a decoder returned by
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_decoder_release_frame(
+    decoder: *const v4l2r_decoder,
+    frame_id: u64,
+) -> c_int {
+    debug!("Decoder {:p}: releasing frame {}", decoder, frame_id);
+    let decoder = match arg_ref(decoder, "decoder") {
+        Ok(decoder) => decoder,
+        Err(e) => return e.into(),
+    };
+
+    let frames = match &decoder.decoder {
+        DecoderInstance::Mmap { frames, .. } => frames,
+        DecoderInstance::DmaBuf(_) => {
+            error!("Decoder has not been created for MMAP output");
+            return v4l2r_error::V4L2R_ERROR_INVALID_STATE.into();
+        }
+    };
+
+    // Take the frame out of the lock before dropping it, which returns its
+    // buffer to the decoder.
+    let frame = frames.lock().unwrap().frames.remove(&frame_id);
+    match frame {
+        Some(_) => v4l2r_error::V4L2R_SUCCESS.into(),
+        None => {
+            error!("Frame {} is not held by the client", frame_id);
+            v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into()
         }
     }
 }
@@ -0,0 +1,802 @@
+//! Module for creating and controlling V4L2 encoders.
+//!
+//! Encoders are created using [`v4l2r_encoder_new`] and remain
+//! active until being given to [`v4l2r_encoder_destroy`]. They expect
+//! to be fed raw frames in the format specified at creation time using
+//! [`v4l2r_encoder_encode_dmabuf`] or [`v4l2r_encoder_encode_mmap`],
+//! depending on the memory type selected for input frames.
+//!
+//! Encoders communicate with the client using an event callback that is invoked
+//! on a dedicated thread. This callback signals events of interest, like a
+//! frame being encoded, or an unrecoverable error.
+#![allow(non_camel_case_types)]
+
+use log::{debug, error, info, warn};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::{
+    convert::TryFrom,
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_void},
+    path::Path,
+    slice,
+};
+use v4l2r::{
+    bindings,
+    device::queue::{handles_provider::MmapProvider, qbuf::OutputQueueable},
+    encoder::{CompletedOutputBuffer, EncodedFrame, Encoder, Encoding},
+    memory::{DmaBufHandle, MmapHandle, PrimitiveBufferHandles},
+    Format, PixelFormat, QueueType,
+};
+
+use crate::{
+    error::{arg_mut, arg_ref, log_error, set_error, v4l2r_error, v4l2r_error_event},
+    memory::{v4l2r_memory_type, v4l2r_video_frame, DmaBufFd},
+    SendablePtr,
+};
+
+type DynCbEncoder<OP> = Encoder<
+    Encoding<
+        OP,
+        MmapProvider,
+        Box<dyn Fn(CompletedOutputBuffer<OP>)>,
+        Box<dyn FnMut(EncodedFrame<Vec<MmapHandle>>) + Send>,
+    >,
+>;
+
+enum EncoderInstance {
+    Mmap(DynCbEncoder<Vec<MmapHandle>>),
+    DmaBuf(DynCbEncoder<Vec<DmaBufHandle<DmaBufFd>>>),
+}
+
+/// A V4L2 encoder instance.
+pub struct v4l2r_encoder {
+    encoder: EncoderInstance,
+    // Keep the input format at hand, as it gives us the size of input planes.
+    input_format: Format,
+}
+
+/// A plane of a raw frame passed to [`v4l2r_encoder_encode_mmap`].
+#[repr(C)]
+pub struct v4l2r_encoder_plane {
+    /// Pointer to the data of the plane.
+    pub data: *const u8,
+    /// Size in bytes of the data pointed to by `data`.
+    pub size: usize,
+}
+
+/// Callback called when the encoder is done with a frame submitted using
+/// [`v4l2r_encoder_encode_dmabuf`] or [`v4l2r_encoder_encode_mmap`].
+///
+/// The first argument is the `cb_data` pointer given
+/// to [`v4l2r_encoder_new`]. The second argument is the dequeued V4L2 buffer.
+/// The client can use the `timestamp.tv_sec` member of `buffer` to match this
+/// buffer with the `frame_id` parameter of the encode functions and
+/// understand which frame has just completed.
+///
+/// This callback is only called during calls to [`v4l2r_encoder_encode_dmabuf`]
+/// and [`v4l2r_encoder_encode_mmap`].
+pub type v4l2r_encoder_input_done_cb = extern "C" fn(*mut c_void, *const bindings::v4l2_buffer);
+
+#[repr(C)]
+pub struct v4l2r_encoder_frame_encoded_event {
+    /// Dequeued V4L2 buffer that contains the encoded data. Useful to check for
+    /// flags and errors. Its `timestamp.tv_sec` member is the `frame_id` of the
+    /// frame this data has been produced from.
+    buffer: *const bindings::v4l2_buffer,
+    /// Encoded data. Only valid for the duration of the callback, the client
+    /// must copy it if it needs to keep it.
+    data: *const u8,
+    /// Size in bytes of the encoded data.
+    size: usize,
+    /// Whether the encoded data is a key frame.
+    is_keyframe: bool,
+    /// Whether the encoded data only contains the codec configuration (e.g.
+    /// SPS and PPS), and no frame.
+    is_codec_config: bool,
+}
+
+/// Encoding-related events. These events can be produced at any time between
+/// calls to [`v4l2r_encoder_new`] and [`v4l2r_encoder_destroy`] and
+/// are passed to the events callback.
+#[repr(C)]
+pub enum v4l2r_encoder_event {
+    FrameEncoded(v4l2r_encoder_frame_encoded_event),
+    /// The encoder has met an unrecoverable error and won't produce any more
+    /// frames. The client can only destroy it.
+    FatalError(v4l2r_error_event),
+}
+
+/// Events callback. This callback is guaranteed to always be called from the
+/// same thread, i.e. events are completely sequential.
+pub type v4l2r_encoder_event_cb = extern "C" fn(*mut c_void, *mut v4l2r_encoder_event);
+
+fn frame_encoded_cb(
+    frame: EncodedFrame<Vec<MmapHandle>>,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+) {
+    debug!(
+        "Frame {} encoded into V4L2 buffer {} ({} bytes, flags: {:?})",
+        frame.timestamp().tv_sec(),
+        frame.buffer().data.index(),
+        frame.payload_size(),
+        frame.buffer().data.flags(),
+    );
+
+    // Empty buffers, e.g. the LAST one signaling the end of a drain, carry
+    // nothing of interest for the client.
+    if frame.payload_size() == 0 {
+        return;
+    }
+
+    let payload = match frame.payload() {
+        Some(payload) => payload,
+        None => {
+            error!("Cannot map encoded buffer {}", frame.buffer().data.index());
+            return;
+        }
+    };
+
+    event_cb(
+        cb_data,
+        &mut v4l2r_encoder_event::FrameEncoded(v4l2r_encoder_frame_encoded_event {
+            buffer: frame.buffer().data.as_raw_v4l2_buffer(),
+            data: payload.as_ref().as_ptr(),
+            size: payload.as_ref().len(),
+            is_keyframe: frame.is_keyframe(),
+            is_codec_config: frame.is_codec_config(),
+        }),
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_encoder<OP: PrimitiveBufferHandles + 'static>(
+    path: &Path,
+    input_format: PixelFormat,
+    width: u32,
+    height: u32,
+    output_format: PixelFormat,
+    num_input_buffers: usize,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_encoder_input_done_cb,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+) -> Result<(DynCbEncoder<OP>, Format), v4l2r_error> {
+    let encoder = Encoder::open(path)
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Failed to open encoder",
+        ))?
+        .set_capture_format(|f| {
+            f.set_pixelformat(output_format)
+                .set_size(width as usize, height as usize)
+                .apply::<Format>()?;
+            Ok(())
+        })
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_UNSUPPORTED_FORMAT,
+            "Error while setting capture format",
+        ))?
+        .set_output_format(|f| {
+            let format: Format = f
+                .set_pixelformat(input_format)
+                .set_size(width as usize, height as usize)
+                .apply()?;
+            if format.pixelformat != input_format {
+                return Err(anyhow::anyhow!(
+                    "Unsupported OUTPUT format {:?}",
+                    input_format
+                ));
+            }
+            Ok(())
+        })
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_UNSUPPORTED_FORMAT,
+            "Error while setting output format",
+        ))?;
+
+    let input_format = encoder.get_output_format().map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Error while getting output format",
+    ))?;
+    let capture_format = encoder.get_capture_format().map_err(log_error(
+        v4l2r_error::V4L2R_ERROR_DEVICE,
+        "Error while getting capture format",
+    ))?;
+    debug!(
+        "Encoder input format: {:?}, output format: {:?}",
+        input_format, capture_format
+    );
+
+    let cb_data = SendablePtr(cb_data);
+
+    let encoder = encoder
+        .allocate_output_buffers::<OP>(num_input_buffers)
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Error while allocating OUTPUT buffers",
+        ))?
+        .allocate_capture_buffers(num_output_buffers, MmapProvider::new(&capture_format))
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Error while allocating CAPTURE buffers",
+        ))?
+        .set_fatal_error_cb(move |e| {
+            error!("Fatal encoder error: {}", e);
+            v4l2r_error_event::with(v4l2r_error::V4L2R_ERROR_DEVICE, e, |event| {
+                event_cb(cb_data.0, &mut v4l2r_encoder_event::FatalError(event))
+            });
+        })
+        .start(
+            Box::new(move |buf: CompletedOutputBuffer<OP>| {
+                match buf {
+                    CompletedOutputBuffer::Dequeued(dqbuf) => {
+                        debug!("Input buffer {} done", dqbuf.data.index());
+                        input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
+                    }
+                    // Just drop canceled buffers for now - the client will remove
+                    // them on its side as well.
+                    CompletedOutputBuffer::Canceled(_) => (),
+                }
+            }) as Box<dyn Fn(CompletedOutputBuffer<OP>)>,
+            Box::new(move |frame: EncodedFrame<Vec<MmapHandle>>| {
+                frame_encoded_cb(frame, event_cb, cb_data.0)
+            }) as Box<dyn FnMut(EncodedFrame<Vec<MmapHandle>>) + Send>,
+        )
+        .map_err(log_error(
+            v4l2r_error::V4L2R_ERROR_DEVICE,
+            "Cannot start encoder",
+        ))?;
+
+    Ok((encoder, input_format))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn v4l2r_encoder_new_safe(
+    path: &Path,
+    input_format_fourcc: u32,
+    width: u32,
+    height: u32,
+    output_format_fourcc: u32,
+    num_input_buffers: usize,
+    input_memory: v4l2r_memory_type,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_encoder_input_done_cb,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+) -> Result<Box<v4l2r_encoder>, v4l2r_error> {
+    let input_format = PixelFormat::from(input_format_fourcc);
+    let output_format = PixelFormat::from(output_format_fourcc);
+
+    info!(
+        "Opening encoder {} from {} {}x{} to {}, {} {:?} input buffers, {} output buffers",
+        path.display(),
+        input_format,
+        width,
+        height,
+        output_format,
+        num_input_buffers,
+        input_memory,
+        num_output_buffers
+    );
+
+    let (encoder, input_format) = match input_memory {
+        v4l2r_memory_type::V4L2R_MEMORY_MMAP => start_encoder::<Vec<MmapHandle>>(
+            path,
+            input_format,
+            width,
+            height,
+            output_format,
+            num_input_buffers,
+            num_output_buffers,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        )
+        .map(|(encoder, format)| (EncoderInstance::Mmap(encoder), format))?,
+        v4l2r_memory_type::V4L2R_MEMORY_DMABUF => start_encoder::<Vec<DmaBufHandle<DmaBufFd>>>(
+            path,
+            input_format,
+            width,
+            height,
+            output_format,
+            num_input_buffers,
+            num_output_buffers,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        )
+        .map(|(encoder, format)| (EncoderInstance::DmaBuf(encoder), format))?,
+    };
+
+    Ok(Box::new(v4l2r_encoder {
+        encoder,
+        input_format,
+    }))
+}
+
+fn v4l2r_encoder_encode_dmabuf_safe(
+    encoder: &mut v4l2r_encoder,
+    frame_id: i32,
+    frame: &v4l2r_video_frame,
+) -> c_int {
+    let encoder_dmabuf = match &mut encoder.encoder {
+        EncoderInstance::DmaBuf(encoder) => encoder,
+        EncoderInstance::Mmap(_) => {
+            error!("Encoder has not been created for DMABUF input");
+            return v4l2r_error::V4L2R_ERROR_INVALID_STATE.into();
+        }
+    };
+
+    let plane_fmt = &encoder.input_format.plane_fmt;
+    if frame.num_planes != plane_fmt.len() || frame.num_planes > frame.planes.len() {
+        error!(
+            "Frame has {} planes, but the input format requires {}",
+            frame.num_planes,
+            plane_fmt.len()
+        );
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+    }
+
+    let v4l2_buffer = match encoder_dmabuf.get_buffer() {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            error!("Error obtaining V4L2 buffer: {}", e);
+            return v4l2r_error::V4L2R_ERROR_DEVICE.into();
+        }
+    };
+    let v4l2_buffer_id = v4l2_buffer.index();
+
+    let handles = frame.planes[..frame.num_planes]
+        .iter()
+        .zip(plane_fmt)
+        .map(|(&fd, plane)| DmaBufHandle::from(DmaBufFd::new(fd, plane.sizeimage as u64)))
+        .collect::<Vec<_>>();
+    let bytes_used = plane_fmt
+        .iter()
+        .map(|plane| plane.sizeimage as usize)
+        .collect::<Vec<_>>();
+
+    match v4l2_buffer
+        .set_timestamp(TimeVal::seconds(frame_id as i64))
+        .queue_with_handles(handles, &bytes_used)
+    {
+        Ok(()) => (),
+        Err(e) => {
+            error!("Error while queueing buffer: {}", e);
+            return v4l2r_error::V4L2R_ERROR_DEVICE.into();
+        }
+    };
+
+    v4l2_buffer_id as c_int
+}
+
+fn v4l2r_encoder_encode_mmap_safe(
+    encoder: &mut v4l2r_encoder,
+    frame_id: i32,
+    planes: &[v4l2r_encoder_plane],
+) -> c_int {
+    let encoder_mmap = match &mut encoder.encoder {
+        EncoderInstance::Mmap(encoder) => encoder,
+        EncoderInstance::DmaBuf(_) => {
+            error!("Encoder has not been created for MMAP input");
+            return v4l2r_error::V4L2R_ERROR_INVALID_STATE.into();
+        }
+    };
+
+    let plane_fmt = &encoder.input_format.plane_fmt;
+    if planes.len() != plane_fmt.len() {
+        error!(
+            "Frame has {} planes, but the input format requires {}",
+            planes.len(),
+            plane_fmt.len()
+        );
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+    }
+
+    let mut v4l2_buffer = match encoder_mmap.get_buffer() {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            error!("Error obtaining V4L2 buffer: {}", e);
+            return v4l2r_error::V4L2R_ERROR_DEVICE.into();
+        }
+    };
+    let v4l2_buffer_id = v4l2_buffer.index();
+
+    for (i, plane) in planes.iter().enumerate() {
+        let mut mapping = match v4l2_buffer.get_plane_mapping(i) {
+            Some(mapping) => mapping,
+            None => {
+                error!("Cannot map plane {} of V4L2 buffer {}", i, v4l2_buffer_id);
+                return v4l2r_error::V4L2R_ERROR_DEVICE.into();
+            }
+        };
+        let mapping = mapping.as_mut();
+        if plane.size > mapping.len() {
+            error!(
+                "Plane {} is {} bytes large, but the V4L2 buffer can only hold {}",
+                i,
+                plane.size,
+                mapping.len()
+            );
+            return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+        }
+        // Safe because the caller guarantees that `data` points to at least
+        // `size` bytes.
+        let data = unsafe { slice::from_raw_parts(plane.data, plane.size) };
+        mapping[..plane.size].copy_from_slice(data);
+    }
+
+    let bytes_used = planes.iter().map(|plane| plane.size).collect::<Vec<_>>();
+    match v4l2_buffer
+        .set_timestamp(TimeVal::seconds(frame_id as i64))
+        .queue(&bytes_used)
+    {
+        Ok(()) => (),
+        Err(e) => {
+            error!("Error while queueing buffer: {}", e);
+            return v4l2r_error::V4L2R_ERROR_DEVICE.into();
+        }
+    };
+
+    v4l2_buffer_id as c_int
+}
+
+/// Create a new encoder producing a given encoded format.
+///
+/// * `path` is the path to the V4L2 device that will be used for encoding.
+/// * `input_format_fourcc` is the FOURCC code of the raw frames we will
+///   encode, e.g. "NV12".
+/// * `width` and `height` are the dimensions of the frames to encode.
+/// * `output_format_fourcc` is the FOURCC code of the encoded format to
+///   produce, e.g. "H264" or "VP80".
+/// * `num_input_buffers` is the number of V4L2 buffers to use for input
+///   frames.
+/// * `input_memory` is the memory type input frames will be passed with. It
+///   determines whether [`v4l2r_encoder_encode_mmap`] or
+///   [`v4l2r_encoder_encode_dmabuf`] must be used to submit frames.
+/// * `num_output_buffers` is the number of V4L2 buffers to use for encoded
+///   data.
+/// * `input_done_cb` is a pointer to a callback function to be called whenever
+///   an input frame is done being processed. This callback is guaranteed to be
+///   invoked during calls to [`v4l2r_encoder_encode_dmabuf`] or
+///   [`v4l2r_encoder_encode_mmap`], i.e. it will always be called in the
+///   current thread.
+/// * `event_cb` is a pointer to a function to be called for handling the
+///   various events produced by the encoder. See [`v4l2r_encoder_event`] for
+///   more details on events. This callback is guaranteed to be called from a
+///   separate, unique thread, therefore the events can be assumed to be
+///   sequential (i.e. two events cannot be produced at the same time from two
+///   different threads).
+/// * `cb_data` is a pointer that will always be passed as the first parameter
+///   of the `input_done_cb` and `events_cb`.
+/// * `error` is a pointer that receives 0 if the encoder has been created
+///   successfully, or one of the codes of `v4l2r_error` otherwise. It can be
+///   NULL if the client is not interested in the reason of a failure.
+///
+/// The actual layout of input frames is decided by the encoder, and can be
+/// obtained using [`v4l2r_encoder_get_input_format`].
+///
+/// Returns NULL if the encoder could not be created.
+///
+/// # Safety
+/// The passed `path` must be NULL or a valid, zero-terminated C string
+/// containining the path to the device. Expect a crash if passing an invalid
+/// string. `error` must be NULL or point to valid memory that can receive a
+/// `c_int`.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_new(
+    path: *const c_char,
+    input_format_fourcc: u32,
+    width: u32,
+    height: u32,
+    output_format_fourcc: u32,
+    num_input_buffers: usize,
+    input_memory: v4l2r_memory_type,
+    num_output_buffers: usize,
+    input_done_cb: v4l2r_encoder_input_done_cb,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+    error: *mut c_int,
+) -> *mut v4l2r_encoder {
+    let res = arg_ref(path, "path")
+        .and_then(|path| {
+            CStr::from_ptr(path).to_str().map_err(log_error(
+                v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT,
+                "Invalid device path",
+            ))
+        })
+        .and_then(|rstr| {
+            v4l2r_encoder_new_safe(
+                Path::new(rstr),
+                input_format_fourcc,
+                width,
+                height,
+                output_format_fourcc,
+                num_input_buffers,
+                input_memory,
+                num_output_buffers,
+                input_done_cb,
+                event_cb,
+                cb_data,
+            )
+        });
+
+    match res {
+        Ok(encoder) => {
+            info!("Encoder {:p}: successfully started", encoder.as_ref());
+            set_error(error, v4l2r_error::V4L2R_SUCCESS);
+            Box::into_raw(encoder)
+        }
+        Err(code) => {
+            set_error(error, code);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Stop and destroy an encoder.
+///
+/// Stop `encoder` and destroy it. This function DOES take ownership of
+/// `encoder`, which must absolutely not be used after this call. Frames that
+/// have not been encoded yet are discarded, call [`v4l2r_encoder_drain`]
+/// beforehand to make sure all frames are encoded.
+///
+/// It is guaranteed that none of the callbacks passed to [`v4l2r_encoder_new`]
+/// will be called after this function has returned.
+///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occurred while stopping the encoder. The encoder is destroyed in both
+/// cases.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// `v4l2r_encoder_new`. Passing an invalid pointer will cause a crash.
+/// `encoder` must not be used again after this function is called.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_destroy(encoder: *mut v4l2r_encoder) -> c_int {
+    info!("Encoder {:p}: destroying", encoder);
+
+    if encoder.is_null() {
+        warn!("Trying to destroy a NULL encoder");
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+    }
+
+    let encoder = Box::from_raw(encoder);
+    let res = match encoder.encoder {
+        EncoderInstance::Mmap(encoder) => encoder.stop().map(|_| ()),
+        EncoderInstance::DmaBuf(encoder) => encoder.stop().map(|_| ()),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
+        Err(e) => {
+            error!("Error while stopping encoder: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
+    }
+}
+
+/// Obtain the input format (i.e. the format set on the *OUTPUT* queue).
+///
+/// Obtain the input format for `encoder` and write it into `format`. The
+/// client must use it to find out the number of planes of input frames, and
+/// their expected layout.
+///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occured, in which case `format` is not overwritten.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder instance. `format` must
+/// point to valid memory that can receive a `v4l2_format`
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_get_input_format(
+    encoder: *const v4l2r_encoder,
+    format: *mut bindings::v4l2_format,
+) -> c_int {
+    let encoder = match arg_ref(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+    let format = match arg_mut(format, "format") {
+        Ok(format) => format,
+        Err(e) => return e.into(),
+    };
+
+    *format = match bindings::v4l2_format::try_from((
+        QueueType::VideoOutputMplane,
+        &encoder.input_format,
+    )) {
+        Ok(format) => format,
+        Err(e) => {
+            error!("Error while converting input format: {}", e);
+            return v4l2r_error::V4L2R_ERROR_UNKNOWN.into();
+        }
+    };
+
+    v4l2r_error::V4L2R_SUCCESS.into()
+}
+
+/// Encode the frame whose planes are referenced by the DMABUF FDs of `frame`.
+///
+/// This function can only be used if the encoder has been created with
+/// `V4L2R_MEMORY_DMABUF`. The encoder does NOT take ownership
+/// of the FDs and won't close them. Each FD must be backed by enough memory for
+/// the matching plane of the input format returned by
+/// [`v4l2r_encoder_get_input_format`]. The `id` member of `frame` is ignored.
+///
+/// `frame_id` is the identifier of this frame. The encoded data produced from
+/// it will carry this identifier in its timestamp.
+///
+/// This function may block until an input buffer becomes available, and may
+/// invoke the *input done callback* for frames that have been processed in
+/// the meantime.
+///
+/// The value returned is the index of the V4L2 buffer `frame` has been queued
+/// with. It can be used to know when `frame` is done being encoded as a
+/// `v4l2_buffer` of the same index will be passed as argument to the *input
+/// done callback* when this is the case.
+///
+/// In case of error, one of the codes of `v4l2r_error` is returned.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+/// Failure to provide valid FDs will return in an ioctl error (but no crash).
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_encode_dmabuf(
+    encoder: *mut v4l2r_encoder,
+    frame_id: i32,
+    frame: v4l2r_video_frame,
+) -> c_int {
+    debug!("Encoder {:p}: encoding frame id {}", encoder, frame_id);
+    let encoder = match arg_mut(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+
+    v4l2r_encoder_encode_dmabuf_safe(encoder, frame_id, &frame)
+}
+
+/// Encode the frame whose planes are described by `planes`.
+///
+/// This function can only be used if the encoder has been created with
+/// `V4L2R_MEMORY_MMAP`. The data of the `num_planes` planes is
+/// copied into a buffer allocated by the encoder, so the client can reuse it as
+/// soon as this function returns. `num_planes` must match the number of planes
+/// of the input format returned by [`v4l2r_encoder_get_input_format`].
+///
+/// `frame_id`, the return value and the blocking behavior are the same as for
+/// [`v4l2r_encoder_encode_dmabuf`].
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+/// `planes` must point to `num_planes` valid planes, each of which pointing
+/// to at least `size` bytes of data.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_encode_mmap(
+    encoder: *mut v4l2r_encoder,
+    frame_id: i32,
+    planes: *const v4l2r_encoder_plane,
+    num_planes: usize,
+) -> c_int {
+    debug!("Encoder {:p}: encoding frame id {}", encoder, frame_id);
+    let encoder = match arg_mut(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+    let planes = if num_planes == 0 {
+        &[]
+    } else if planes.is_null() {
+        error!("planes must not be NULL");
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+    } else {
+        slice::from_raw_parts(planes, num_planes)
+    };
+
+    v4l2r_encoder_encode_mmap_safe(encoder, frame_id, planes)
+}
+
+/// Set the target bitrate of `encoder`, in bits per second.
+///
+/// The new bitrate applies to the frames submitted after this call.
+///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occured.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_set_bitrate(
+    encoder: *mut v4l2r_encoder,
+    bitrate: u32,
+) -> c_int {
+    debug!("Encoder {:p}: setting bitrate to {}", encoder, bitrate);
+    let encoder = match arg_ref(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+
+    let res = match &encoder.encoder {
+        EncoderInstance::Mmap(encoder) => encoder.set_bitrate(bitrate),
+        EncoderInstance::DmaBuf(encoder) => encoder.set_bitrate(bitrate),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
+        Err(e) => {
+            error!("Error while setting bitrate: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
+    }
+}
+
+/// Request the next frame submitted to `encoder` to be encoded as a key frame.
+///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occured.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_force_keyframe(encoder: *mut v4l2r_encoder) -> c_int {
+    debug!("Encoder {:p}: forcing key frame", encoder);
+    let encoder = match arg_ref(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+
+    let res = match &encoder.encoder {
+        EncoderInstance::Mmap(encoder) => encoder.force_key_frame(),
+        EncoderInstance::DmaBuf(encoder) => encoder.force_key_frame(),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
+        Err(e) => {
+            error!("Error while forcing key frame: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
+    }
+}
+
+/// Wait until all the frames submitted to `encoder` are encoded.
+///
+/// This function blocks until the `FrameEncoded` events for all the frames
+/// submitted so far have been sent. The encoder can be used again afterwards.
+///
+/// Returns 0 in case of success, or one of the codes of `v4l2r_error` if an
+/// error occured.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+/// This function must not be called from the events callback, as it would
+/// deadlock.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_drain(encoder: *mut v4l2r_encoder) -> c_int {
+    debug!("Encoder {:p}: draining", encoder);
+    let encoder = match arg_ref(encoder, "encoder") {
+        Ok(encoder) => encoder,
+        Err(e) => return e.into(),
+    };
+
+    let res = match &encoder.encoder {
+        EncoderInstance::Mmap(encoder) => encoder.drain(),
+        EncoderInstance::DmaBuf(encoder) => encoder.drain(),
+    };
+    match res {
+        Ok(()) => v4l2r_error::V4L2R_SUCCESS.into(),
+        Err(e) => {
+            error!("Error while draining encoder: {}", e);
+            v4l2r_error::V4L2R_ERROR_DEVICE.into()
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Error codes returned by the entry points of the C API.
+//!
+//! All the functions of the C API that can fail return either a non-negative
+//! value in case of success, or one of the negative codes of [`v4l2r_error`].
+//! Errors that do not occur during a call, but on the threads of the library,
+//! are reported with an event carrying a [`v4l2r_error_event`].
+//!
+//! NULL pointers passed where an object or a buffer is expected are rejected
+//! with `V4L2R_ERROR_INVALID_ARGUMENT`.
+#![allow(non_camel_case_types)]
+
+use log::error;
+use std::{
+    fmt::Display,
+    os::raw::{c_char, c_int},
+};
+
+use crate::to_cstring;
+
+/// Error codes of the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_error {
+    /// The operation succeeded.
+    V4L2R_SUCCESS = 0,
+    /// An error not covered by any other code occurred.
+    V4L2R_ERROR_UNKNOWN = -1,
+    /// An argument passed by the client is invalid.
+    V4L2R_ERROR_INVALID_ARGUMENT = -2,
+    /// The operation is not possible in the current state of the object, e.g.
+    /// because it has been created for another memory type.
+    V4L2R_ERROR_INVALID_STATE = -3,
+    /// The requested format is not supported by the device.
+    V4L2R_ERROR_UNSUPPORTED_FORMAT = -4,
+    /// The device could not be opened, or one of its operations failed.
+    V4L2R_ERROR_DEVICE = -5,
+    /// The operation cannot be performed right now and should be tried again
+    /// later.
+    V4L2R_ERROR_TRY_AGAIN = -6,
+}
+
+impl From<v4l2r_error> for c_int {
+    fn from(error: v4l2r_error) -> Self {
+        error as c_int
+    }
+}
+
+/// Returns a closure logging an error with `context` and turning it into
+/// `code`, to be used with `Result::map_err`.
+pub(crate) fn log_error<E: Display>(
+    code: v4l2r_error,
+    context: &'static str,
+) -> impl FnOnce(E) -> v4l2r_error {
+    move |e| {
+        error!("{}: {:#}", context, e);
+        code
+    }
+}
+
+/// Returns a reference to the `name` argument pointed to by `ptr`, or
+/// `V4L2R_ERROR_INVALID_ARGUMENT` if it is NULL.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a valid `T` for the lifetime `'a`.
+pub(crate) unsafe fn arg_ref<'a, T>(
+    ptr: *const T,
+    name: &'static str,
+) -> Result<&'a T, v4l2r_error> {
+    ptr.as_ref().ok_or_else(|| {
+        error!("{} must not be NULL", name);
+        v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT
+    })
+}
+
+/// Mutable version of [`arg_ref`].
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to a valid `T` that is not accessed through
+/// any other pointer for the lifetime `'a`.
+pub(crate) unsafe fn arg_mut<'a, T>(
+    ptr: *mut T,
+    name: &'static str,
+) -> Result<&'a mut T, v4l2r_error> {
+    ptr.as_mut().ok_or_else(|| {
+        error!("{} must not be NULL", name);
+        v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT
+    })
+}
+
+/// Write `code` into `error` if it is not NULL.
+///
+/// # Safety
+///
+/// `error` must be NULL or point to valid memory that can receive a `c_int`.
+pub(crate) unsafe fn set_error(error: *mut c_int, code: v4l2r_error) {
+    if let Some(error) = error.as_mut() {
+        *error = code.into();
+    }
+}
+
+/// Error that occurred outside of a call to the C API.
+#[repr(C)]
+pub struct v4l2r_error_event {
+    /// Error code.
+    code: v4l2r_error,
+    /// Zero-terminated description of the error. Only valid for the duration of
+    /// the callback, the client must copy it if it needs to keep it.
+    message: *const c_char,
+}
+
+impl v4l2r_error_event {
+    /// Invoke `f` with an event describing `error`.
+    pub(crate) fn with<E: Display, F: FnOnce(v4l2r_error_event)>(
+        code: v4l2r_error,
+        error: E,
+        f: F,
+    ) {
+        let message = to_cstring(format!("{:#}", error));
+
+        f(v4l2r_error_event {
+            code,
+            message: message.as_ptr(),
+        })
+    }
+}
@@ -2,29 +2,50 @@
 //!
 //! This crate provides a C API that can be used by client programs to make use
 //! of the features exported by this crate. For now it strictly focuses on
-//! stateful decoders.
+//! stateful decoders and encoders.
 
 use log::debug;
+use std::ffi::CString;
 
 pub mod decoder;
+pub mod encoder;
+pub mod error;
+pub mod logger;
 pub mod memory;
 
+// A void pointer that can be sent across threads. This is usually not allowed
+// by Rust, but is necessary for us to call back into the client.
+pub(crate) struct SendablePtr<T>(pub(crate) *mut T);
+impl<T> Clone for SendablePtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for SendablePtr<T> {}
+unsafe impl<T> Send for SendablePtr<T> {}
+unsafe impl<T> Sync for SendablePtr<T> {}
+
+/// Convert `s` into a C string, dropping the NUL characters it may contain.
+pub(crate) fn to_cstring(s: String) -> CString {
+    CString::new(s).unwrap_or_else(|e| {
+        let mut bytes = e.into_vec();
+        bytes.retain(|&b| b != 0);
+        // Cannot fail as we just removed all the NUL characters.
+        CString::new(bytes).unwrap()
+    })
+}
+
 static INIT: std::sync::Once = std::sync::Once::new();
 
 /// Initialize the V4L2R library. This only sets up the proper hooks for
 /// logging, so although it is not a hard requirement to call this function,
 /// failure to do so will result in no logs being printed.
+///
+/// Log messages are printed using the logger of the platform, unless
+/// [`logger::v4l2r_set_log_cb`] is used to route them to the client.
 #[no_mangle]
 pub extern "C" fn v4l2r_init() {
-    INIT.call_once(|| {
-        #[cfg(feature = "env_logger")]
-        env_logger::builder().format_timestamp(None).init();
-
-        #[cfg(feature = "android")]
-        android_logger::init_once(
-            android_logger::Config::default().with_min_level(log::Level::Trace),
-        );
-    });
+    INIT.call_once(logger::init);
 
     debug!("v4l2r initialized");
 }
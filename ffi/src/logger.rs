@@ -0,0 +1,253 @@
+//! Routing of the log messages of the library.
+//!
+//! By default, messages are printed using the logger of the platform (e.g.
+//! `env_logger` on Linux or the Android logger). Clients can route them into
+//! their own logging system instead using [`v4l2r_set_log_cb`].
+#![allow(non_camel_case_types)]
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::Cell,
+    os::raw::{c_char, c_void},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock, RwLock,
+    },
+};
+
+use crate::{to_cstring, v4l2r_init, SendablePtr};
+
+/// Severity of a log message.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_log_level {
+    V4L2R_LOG_LEVEL_ERROR = 1,
+    V4L2R_LOG_LEVEL_WARN = 2,
+    V4L2R_LOG_LEVEL_INFO = 3,
+    V4L2R_LOG_LEVEL_DEBUG = 4,
+    V4L2R_LOG_LEVEL_TRACE = 5,
+}
+
+impl From<Level> for v4l2r_log_level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => v4l2r_log_level::V4L2R_LOG_LEVEL_ERROR,
+            Level::Warn => v4l2r_log_level::V4L2R_LOG_LEVEL_WARN,
+            Level::Info => v4l2r_log_level::V4L2R_LOG_LEVEL_INFO,
+            Level::Debug => v4l2r_log_level::V4L2R_LOG_LEVEL_DEBUG,
+            Level::Trace => v4l2r_log_level::V4L2R_LOG_LEVEL_TRACE,
+        }
+    }
+}
+
+impl From<v4l2r_log_level> for LevelFilter {
+    fn from(level: v4l2r_log_level) -> Self {
+        match level {
+            v4l2r_log_level::V4L2R_LOG_LEVEL_ERROR => LevelFilter::Error,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_WARN => LevelFilter::Warn,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_INFO => LevelFilter::Info,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_DEBUG => LevelFilter::Debug,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_TRACE => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Log callback.
+///
+/// The first argument is the `cb_data` pointer given to [`v4l2r_set_log_cb`].
+/// The second one is the severity of the message. The third and fourth ones
+/// are zero-terminated strings containing respectively the Rust module the
+/// message originates from and the message itself. They are only valid for the
+/// duration of the callback.
+///
+/// This callback can be invoked from any thread, including concurrently from
+/// several threads.
+pub type v4l2r_log_cb = extern "C" fn(*mut c_void, v4l2r_log_level, *const c_char, *const c_char);
+
+#[derive(Clone, Copy)]
+struct ClientLogCb {
+    cb: v4l2r_log_cb,
+    cb_data: SendablePtr<c_void>,
+    max_level: LevelFilter,
+}
+
+/// Logger forwarding messages to the client callback if one is set, or to the
+/// platform logger otherwise.
+struct FfiLogger {
+    platform: Option<Box<dyn Log>>,
+    platform_max_level: LevelFilter,
+    client: RwLock<Option<ClientLogCb>>,
+    // Whether we are the logger of the program. Otherwise we must not touch
+    // the maximum level, which belongs to the installed logger.
+    installed: AtomicBool,
+}
+
+impl FfiLogger {
+    #[allow(unreachable_code)]
+    fn new() -> Self {
+        #[cfg(feature = "env_logger")]
+        {
+            let logger = env_logger::builder().format_timestamp(None).build();
+            return FfiLogger {
+                platform_max_level: logger.filter(),
+                platform: Some(Box::new(logger)),
+                client: RwLock::new(None),
+                installed: AtomicBool::new(false),
+            };
+        }
+
+        #[cfg(feature = "android")]
+        {
+            return FfiLogger {
+                platform: Some(Box::new(android_logger::AndroidLogger::new(
+                    android_logger::Config::default().with_min_level(Level::Trace),
+                ))),
+                platform_max_level: LevelFilter::Trace,
+                client: RwLock::new(None),
+                installed: AtomicBool::new(false),
+            };
+        }
+
+        FfiLogger {
+            platform: None,
+            platform_max_level: LevelFilter::Off,
+            client: RwLock::new(None),
+            installed: AtomicBool::new(false),
+        }
+    }
+
+    fn client(&self) -> Option<ClientLogCb> {
+        *self.client.read().unwrap()
+    }
+
+    /// Set the client callback and update the maximum level of the messages
+    /// to process accordingly.
+    ///
+    /// This waits for the invocations of the previous callback in progress on
+    /// other threads to return. It does nothing if called from the callback.
+    fn set_client(&self, client: Option<ClientLogCb>) {
+        if IN_CLIENT_CB.with(Cell::get) {
+            // We are holding the lock further up the stack.
+            return;
+        }
+
+        let mut current = self.client.write().unwrap();
+        if self.installed.load(Ordering::SeqCst) {
+            log::set_max_level(match &client {
+                Some(client) => client.max_level,
+                None => self.platform_max_level,
+            });
+        }
+        *current = client;
+    }
+}
+
+impl Log for FfiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if IN_CLIENT_CB.with(Cell::get) {
+            return false;
+        }
+
+        match self.client() {
+            Some(client) => metadata.level() <= client.max_level,
+            None => self
+                .platform
+                .as_ref()
+                .is_some_and(|platform| platform.enabled(metadata)),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        // Messages logged by the client callback itself are dropped, as we
+        // would otherwise recurse into it.
+        if IN_CLIENT_CB.with(Cell::get) {
+            return;
+        }
+
+        // Keep the lock while calling the client, so the callback and its data
+        // cannot be replaced while in use.
+        let client = self.client.read().unwrap();
+        match &*client {
+            Some(client) => {
+                if record.level() > client.max_level {
+                    return;
+                }
+                let target = to_cstring(record.target().to_string());
+                let message = to_cstring(record.args().to_string());
+                IN_CLIENT_CB.with(|in_cb| in_cb.set(true));
+                (client.cb)(
+                    client.cb_data.0,
+                    record.level().into(),
+                    target.as_ptr(),
+                    message.as_ptr(),
+                );
+                IN_CLIENT_CB.with(|in_cb| in_cb.set(false));
+            }
+            None => {
+                if let Some(platform) = &self.platform {
+                    platform.log(record);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(platform) = &self.platform {
+            platform.flush();
+        }
+    }
+}
+
+static LOGGER: OnceLock<FfiLogger> = OnceLock::new();
+
+thread_local! {
+    /// Whether the client callback is being invoked on this thread.
+    static IN_CLIENT_CB: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Install our logger. Does nothing if another logger has already been
+/// installed by the program the library is linked into.
+pub(crate) fn init() {
+    let logger = LOGGER.get_or_init(FfiLogger::new);
+    if log::set_logger(logger).is_ok() {
+        logger.installed.store(true, Ordering::SeqCst);
+        logger.set_client(logger.client());
+    }
+}
+
+/// Route the log messages of the library to `cb` instead of the platform
+/// logger.
+///
+/// Only the messages with a severity of `max_level` or higher are passed to
+/// `cb`. `cb_data` is passed as the first argument of every invocation of
+/// `cb`. Passing NULL as `cb` routes the messages to the platform logger
+/// again.
+///
+/// This function also initializes the library like [`v4l2r_init`], so it can
+/// be called instead of it. It can be called again at any time to change the
+/// callback or the maximum severity, except from the callback itself where it
+/// does nothing. When it returns, the previous callback is not running on any
+/// thread and will not be invoked anymore. Messages logged while the callback
+/// is running on the same thread are dropped.
+///
+/// # Safety
+///
+/// `cb_data` must remain valid for as long as `cb` can be invoked, i.e. until
+/// this function is called again.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_set_log_cb(
+    // Spelled out because cbindgen cannot turn an `Option` of a type alias
+    // into a nullable function pointer.
+    cb: Option<extern "C" fn(*mut c_void, v4l2r_log_level, *const c_char, *const c_char)>,
+    cb_data: *mut c_void,
+    max_level: v4l2r_log_level,
+) {
+    v4l2r_init();
+
+    let logger = LOGGER.get_or_init(FfiLogger::new);
+    logger.set_client(cb.map(|cb| ClientLogCb {
+        cb,
+        cb_data: SendablePtr(cb_data),
+        max_level: max_level.into(),
+    }));
+}
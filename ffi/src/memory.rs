@@ -1,6 +1,6 @@
 #![allow(non_camel_case_types)]
 
-use log::{error, trace};
+use log::{error, trace, warn};
 use std::{
     collections::VecDeque,
     os::{
@@ -26,6 +26,8 @@ use v4l2r::{
     memory::{BufferHandles, DmaBufHandle, DmaBufSource, MemoryType, PrimitiveBufferHandles},
 };
 
+use crate::error::{arg_ref, v4l2r_error};
+
 /// The simplest type used to represent a DMABUF fd. It does not take ownership
 /// of the FD at any time and does not close it ; thus the using code is
 /// responsible for managing the given FD's lifetime.
@@ -50,7 +52,7 @@ impl DmaBufFd {
 }
 
 impl AsFd for DmaBufFd {
-    fn as_fd(&self) -> BorrowedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
         unsafe { BorrowedFd::borrow_raw(self.fd) }
     }
 }
@@ -67,6 +69,18 @@ impl DmaBufSource for DmaBufFd {
     }
 }
 
+/// Memory type of the frames exchanged between the client and an encoder or
+/// decoder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_memory_type {
+    /// Frames are allocated by the library and accessed by the client through
+    /// CPU mappings.
+    V4L2R_MEMORY_MMAP,
+    /// Frames are allocated by the client and passed as DMABUF FDs.
+    V4L2R_MEMORY_DMABUF,
+}
+
 /// A struct representing a set of buffers to which decoded frames will be
 /// output.
 #[derive(Debug, Default)]
@@ -197,8 +211,8 @@ impl HandlesProvider for v4l2r_video_frame_provider {
 /// will remain untouched by the decoder until the client passes it to this
 /// function again.
 ///
-/// Returns `true` upon success, `false` if the provided frame had an invalid
-/// index or the decoder thread could not be awakened.
+/// Returns 0 upon success, or `V4L2R_ERROR_INVALID_ARGUMENT` if the provided
+/// frame had an invalid index.
 ///
 /// This function can safely be called from any thread.
 ///
@@ -211,14 +225,16 @@ impl HandlesProvider for v4l2r_video_frame_provider {
 pub unsafe extern "C" fn v4l2r_video_frame_provider_queue_frame(
     provider: *const v4l2r_video_frame_provider,
     frame: v4l2r_video_frame,
-) -> bool {
+) -> c_int {
     trace!("Queueing output frame: {:?}", frame);
-    assert!(!provider.is_null());
-    let provider = &*provider;
+    let provider = match arg_ref(provider, "provider") {
+        Ok(provider) => provider,
+        Err(e) => return e.into(),
+    };
 
     if frame.id >= bindings::VIDEO_MAX_FRAME {
         error!("Invalid frame id {}, aborting queue.", frame.id);
-        return false;
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
     }
 
     let mut provider = provider.d.lock().unwrap();
@@ -226,11 +242,14 @@ pub unsafe extern "C" fn v4l2r_video_frame_provider_queue_frame(
     if let Some(waker) = provider.waker.take() {
         waker.wake_by_ref();
     }
-    true
+    v4l2r_error::V4L2R_SUCCESS.into()
 }
 
 /// Delete a video frame provider.
 ///
+/// Returns 0 upon success, or `V4L2R_ERROR_INVALID_ARGUMENT` if `provider` is
+/// NULL.
+///
 /// # Safety
 ///
 /// `provider` must be a provider previously passed through the
@@ -245,9 +264,13 @@ pub unsafe extern "C" fn v4l2r_video_frame_provider_queue_frame(
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_video_frame_provider_drop(
     provider: *const v4l2r_video_frame_provider,
-) {
+) -> c_int {
     trace!("Destroying video frame provider: {:p}", provider);
-    assert!(!provider.is_null());
+    if provider.is_null() {
+        warn!("Trying to destroy a NULL video frame provider");
+        return v4l2r_error::V4L2R_ERROR_INVALID_ARGUMENT.into();
+    }
 
     Arc::from_raw(provider);
+    v4l2r_error::V4L2R_SUCCESS.into()
 }
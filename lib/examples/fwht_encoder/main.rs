@@ -1,30 +1,101 @@
 use utils::framegen::FrameGenerator;
 
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::{self, BufReader, Write};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{cell::RefCell, collections::VecDeque, time::Instant};
 
 use v4l2r::{
+    decoder::format::ivf::IvfHeader,
     device::{
         poller::PollError,
         queue::{
-            direction::Capture,
-            dqbuf::DqBuffer,
             generic::{GenericBufferHandles, GenericQBuffer, GenericSupportedMemoryType},
             handles_provider::MmapProvider,
             qbuf::OutputQueueable,
         },
     },
-    encoder::*,
+    encoder::{
+        format::{annexb::AnnexBWriter, ivf::IvfSink, mp4::FragmentedMp4Writer, StreamSink},
+        *,
+    },
+    image::{
+        y4m::Y4mReader,
+        yuv::{ChromaFormat, RawYuvReader, YuvLayout},
+        ImageViewMut,
+    },
     memory::{MmapHandle, UserPtrHandle},
-    Format,
+    Format, PixelFormat, Rect,
 };
 
 use anyhow::ensure;
 use clap::{App, Arg};
+use nix::sys::time::{TimeVal, TimeValLike};
+
+/// Frame rate used to compute the timestamps of the frames, unless the input file specifies one.
+const FRAME_RATE: u32 = 30;
+
+/// Source of the frames to encode.
+enum FrameSource {
+    /// Generated RGB24 test pattern.
+    Generator(FrameGenerator),
+    Y4m(Y4mReader<BufReader<File>>),
+    RawYuv(RawYuvReader<BufReader<File>>),
+}
+
+impl FrameSource {
+    /// Returns the chroma format and size of the frames of YUV files.
+    fn layout(&self) -> Option<YuvLayout> {
+        match self {
+            FrameSource::Generator(_) => None,
+            FrameSource::Y4m(reader) => Some(reader.header().layout()),
+            FrameSource::RawYuv(reader) => Some(*reader.layout()),
+        }
+    }
+
+    /// Write the next frame into `planes`, which are laid out according to `format`. Returns
+    /// `false` once the end of the input has been reached.
+    fn next_frame(&mut self, format: &Format, planes: &mut [Vec<u8>]) -> bool {
+        let layout = match self {
+            FrameSource::Generator(frame_gen) => {
+                frame_gen
+                    .next_frame(&mut planes[0])
+                    .expect("Failed to generate frame");
+                return true;
+            }
+            // Unwrap is safe since files always have a layout.
+            _ => self.layout().unwrap(),
+        };
+
+        // The format may be larger than the frames if the encoder requires some alignment.
+        let visible_rect = Rect::new(0, 0, layout.width as u32, layout.height as u32);
+        let mut image = ImageViewMut::new(format, Some(&visible_rect), planes)
+            .expect("Failed to create view of the input frame");
+        match self {
+            FrameSource::Generator(_) => unreachable!(),
+            FrameSource::Y4m(reader) => reader
+                .read_frame_into(&mut image)
+                .expect("Failed to read input frame"),
+            FrameSource::RawYuv(reader) => reader
+                .read_frame_into(&mut image)
+                .expect("Failed to read input frame"),
+        }
+    }
+
+    /// Returns the number of bytes used in each plane of a frame of `format`.
+    fn bytes_used(&self, format: &Format) -> Vec<usize> {
+        match self {
+            FrameSource::Generator(frame_gen) => vec![frame_gen.frame_size()],
+            _ => format
+                .plane_fmt
+                .iter()
+                .map(|plane| plane.sizeimage as usize)
+                .collect(),
+        }
+    }
+}
 
 fn main() {
     env_logger::init();
@@ -49,6 +120,24 @@ fn main() {
                 .default_value("640x480")
                 .help("Size of the frames to encode (e.g. \"640x480\")"),
         )
+        .arg(
+            Arg::with_name("input_file")
+                .long("input")
+                .required(false)
+                .takes_value(true)
+                .help(
+                    "Encode the frames of a Y4M file, or of a raw YUV 4:2:0 file of size \
+                     frame_size, instead of a generated pattern",
+                ),
+        )
+        .arg(
+            Arg::with_name("codec")
+                .long("codec")
+                .required(false)
+                .takes_value(true)
+                .default_value("fwht")
+                .help("Codec to encode into (fwht, h264, hevc, vp8 or vp9)"),
+        )
         .arg(
             Arg::with_name("output_file")
                 .long("save")
@@ -56,6 +145,14 @@ fn main() {
                 .takes_value(true)
                 .help("Save the encoded stream to a file"),
         )
+        .arg(
+            Arg::with_name("container")
+                .long("container")
+                .required(false)
+                .takes_value(true)
+                .default_value("raw")
+                .help("Format of the saved stream (raw, annexb, ivf or mp4)"),
+        )
         .arg(
             Arg::with_name("output_mem")
                 .long("output_mem")
@@ -74,7 +171,7 @@ fn main() {
         Err(e) => panic!("Invalid value for stop_after: {}", e),
     };
 
-    let frame_size = matches
+    let mut frame_size = matches
         .value_of("frame_size")
         .map(|s| {
             const ERROR_MSG: &str = "Invalid parameter for frame_size";
@@ -89,7 +186,34 @@ fn main() {
         })
         .unwrap();
 
-    let mut output_file = matches
+    let input = matches.value_of("input_file").map(|path| {
+        let file = BufReader::new(File::open(path).expect("Input file not found"));
+        if path.ends_with(".y4m") {
+            FrameSource::Y4m(Y4mReader::new(file).expect("Invalid Y4M file"))
+        } else {
+            let layout = YuvLayout::new(ChromaFormat::Yuv420, frame_size.0, frame_size.1);
+            FrameSource::RawYuv(RawYuvReader::new(file, layout).expect("Invalid frame size"))
+        }
+    });
+    let input_layout = input.as_ref().and_then(FrameSource::layout);
+    if let Some(layout) = input_layout {
+        frame_size = (layout.width, layout.height);
+    }
+    let frame_rate = match &input {
+        Some(FrameSource::Y4m(reader)) => reader.header().frame_rate,
+        _ => (FRAME_RATE, 1),
+    };
+
+    let codec = match matches.value_of("codec") {
+        Some("fwht") => PixelFormat::from_fourcc(b"FWHT"),
+        Some("h264") => PixelFormat::H264,
+        Some("hevc") => PixelFormat::HEVC,
+        Some("vp8") => PixelFormat::VP8,
+        Some("vp9") => PixelFormat::VP9,
+        _ => panic!("Invalid value for codec"),
+    };
+
+    let output_file = matches
         .value_of("output_file")
         .map(|s| File::create(s).expect("Invalid output file specified."));
 
@@ -113,30 +237,58 @@ fn main() {
     let encoder = Encoder::open(Path::new(&device_path))
         .expect("Failed to open device")
         .set_capture_format(|f| {
-            let format: Format = f.set_pixelformat(b"FWHT").apply()?;
+            let format: Format = f
+                .set_pixelformat(codec)
+                .set_size(frame_size.0, frame_size.1)
+                .apply()?;
 
             ensure!(
-                format.pixelformat == b"FWHT".into(),
-                "FWHT format not supported"
+                format.pixelformat == codec,
+                "{} format not supported",
+                codec
             );
 
             Ok(())
         })
         .expect("Failed to set capture format")
         .set_output_format(|f| {
+            let pixelformat = match input_layout {
+                Some(layout) => layout.chroma.pixel_format(),
+                None => PixelFormat::RGB24,
+            };
             let format: Format = f
-                .set_pixelformat(b"RGB3")
+                .set_pixelformat(pixelformat)
                 .set_size(frame_size.0, frame_size.1)
                 .apply()?;
 
-            ensure!(
-                format.pixelformat == b"RGB3".into(),
-                "RGB3 format not supported"
-            );
-            ensure!(
-                format.width as usize == frame_size.0 && format.height as usize == frame_size.1,
-                "Output frame resolution not supported"
-            );
+            match input_layout {
+                // Input files can be copied into any format with the same chroma subsampling,
+                // and frames larger than the input.
+                Some(layout) => {
+                    ensure!(
+                        ChromaFormat::from_pixel_format(format.pixelformat) == Some(layout.chroma),
+                        "No format compatible with {:?} supported",
+                        layout.chroma
+                    );
+                    ensure!(
+                        format.width as usize >= frame_size.0
+                            && format.height as usize >= frame_size.1,
+                        "Output frame resolution not supported"
+                    );
+                }
+                None => {
+                    ensure!(
+                        format.pixelformat == pixelformat,
+                        "{} format not supported",
+                        pixelformat
+                    );
+                    ensure!(
+                        format.width as usize == frame_size.0
+                            && format.height as usize == frame_size.1,
+                        "Output frame resolution not supported"
+                    );
+                }
+            }
 
             Ok(())
         })
@@ -157,22 +309,65 @@ fn main() {
         output_format.width, output_format.height, output_format.plane_fmt[0].bytesperline
     );
 
-    let mut frame_gen = FrameGenerator::new(
-        output_format.width as usize,
-        output_format.height as usize,
-        output_format.plane_fmt[0].bytesperline as usize,
-    )
-    .expect("Failed to create frame generator");
+    // Either write the encoded frames as-is, or through a sink for the selected container.
+    let (mut raw_output, sink) = match (output_file, matches.value_of("container")) {
+        (None, _) => (None, None),
+        (Some(file), Some("raw")) => (Some(file), None),
+        (Some(file), Some("annexb")) => (
+            None,
+            Some(Box::new(AnnexBWriter::new(file)) as Box<dyn StreamSink + Send>),
+        ),
+        (Some(file), Some("ivf")) => {
+            let header = IvfHeader {
+                fourcc: codec,
+                width: output_format.width as u16,
+                height: output_format.height as u16,
+                timebase: (frame_rate.1, frame_rate.0),
+                num_frames: 0,
+            };
+            let sink = IvfSink::new(file, header).expect("Failed to create IVF sink");
+            (None, Some(Box::new(sink) as Box<dyn StreamSink + Send>))
+        }
+        (Some(file), Some("mp4")) => {
+            let sink = FragmentedMp4Writer::new(
+                file,
+                codec,
+                output_format.width as u16,
+                output_format.height as u16,
+            )
+            .expect("Failed to create MP4 muxer");
+            (None, Some(Box::new(sink) as Box<dyn StreamSink + Send>))
+        }
+        _ => panic!("Invalid value for container"),
+    };
+    let sink = Arc::new(Mutex::new(sink));
+    let sink_writer = Arc::clone(&sink);
+
+    let mut source = input.unwrap_or_else(|| {
+        FrameSource::Generator(
+            FrameGenerator::new(
+                output_format.width as usize,
+                output_format.height as usize,
+                output_format.plane_fmt[0].bytesperline as usize,
+            )
+            .expect("Failed to create frame generator"),
+        )
+    });
+    let bytes_used = source.bytes_used(&output_format);
+    // Frames are read or generated here before being copied into the OUTPUT buffers.
+    let mut frame: Vec<Vec<u8>> = output_format
+        .plane_fmt
+        .iter()
+        .map(|plane| vec![0u8; plane.sizeimage as usize])
+        .collect();
 
     const NUM_BUFFERS: usize = 2;
 
     let free_buffers: Option<VecDeque<_>> = match output_mem {
         GenericSupportedMemoryType::Mmap | GenericSupportedMemoryType::DmaBuf => None,
-        GenericSupportedMemoryType::UserPtr => Some(
-            std::iter::repeat(vec![0u8; output_format.plane_fmt[0].sizeimage as usize])
-                .take(NUM_BUFFERS)
-                .collect(),
-        ),
+        GenericSupportedMemoryType::UserPtr => {
+            Some(std::iter::repeat_n(frame.clone(), NUM_BUFFERS).collect())
+        }
     };
     let free_buffers = RefCell::new(free_buffers);
 
@@ -196,12 +391,12 @@ fn main() {
             // We have nothing to do for MMAP buffers.
             GenericBufferHandles::Mmap(_) => {}
             // For user-allocated memory, return the buffer to the free list.
-            GenericBufferHandles::User(mut u) => {
+            GenericBufferHandles::User(u) => {
                 free_buffers
                     .borrow_mut()
                     .as_mut()
                     .unwrap()
-                    .push_back(u.remove(0).0);
+                    .push_back(u.into_iter().map(|handle| handle.0).collect());
             }
             GenericBufferHandles::DmaBuf(d) => {
                 dmabufs.borrow_mut().as_mut().unwrap().push_back(d);
@@ -214,8 +409,8 @@ fn main() {
     let poll_count_reader = Arc::new(AtomicUsize::new(0));
     let poll_count_writer = Arc::clone(&poll_count_reader);
     let mut frame_counter = 0usize;
-    let output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
-        let bytes_used = cap_dqbuf.data.get_first_plane().bytesused() as usize;
+    let output_ready_cb = move |frame: EncodedFrame<Vec<MmapHandle>>| {
+        let bytes_used = frame.payload_size();
         // Ignore zero-sized buffers.
         if bytes_used == 0 {
             return;
@@ -228,8 +423,8 @@ fn main() {
         let ppf = poll_count_reader.load(Ordering::SeqCst) as f32 / frame_counter as f32;
         print!(
             "\rEncoded buffer {:#5}, index: {:#2}), bytes used:{:#6} total encoded size:{:#8} fps: {:#5.2} ppf: {:#4.2}" ,
-            cap_dqbuf.data.sequence(),
-            cap_dqbuf.data.index(),
+            frame.buffer().data.sequence(),
+            frame.buffer().data.index(),
             bytes_used,
             total_size,
             fps,
@@ -237,14 +432,16 @@ fn main() {
         );
         io::stdout().flush().unwrap();
 
-        if let Some(ref mut output) = output_file {
-            let mapping = cap_dqbuf
-                .get_plane_mapping(0)
-                .expect("Failed to map capture buffer");
+        if let Some(ref mut output) = raw_output {
+            let mapping = frame.payload().expect("Failed to map capture buffer");
             output
                 .write_all(mapping.as_ref())
                 .expect("Error while writing output data");
         }
+        if let Some(sink) = sink_writer.lock().unwrap().as_mut() {
+            sink.write_encoded_frame(&frame)
+                .expect("Error while writing output data");
+        }
     };
 
     let mut encoder = encoder
@@ -256,6 +453,7 @@ fn main() {
         .start(input_done_cb, output_ready_cb)
         .expect("Failed to start encoder");
 
+    let mut frame_index = 0i64;
     while !lets_quit.load(Ordering::SeqCst) {
         if let Some(max_cpt) = &mut stop_after {
             if *max_cpt == 0 {
@@ -264,26 +462,30 @@ fn main() {
             *max_cpt -= 1;
         }
 
+        if !source.next_frame(&output_format, &mut frame) {
+            break;
+        }
+
         let v4l2_buffer = match encoder.get_buffer() {
             Ok(buffer) => buffer,
             // If we got interrupted while waiting for a buffer, just exit normally.
-            Err(GetBufferError::PollError(PollError::EPollWait(e)))
-                if e == nix::errno::Errno::EINTR =>
-            {
-                break
-            }
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => break,
             Err(e) => panic!("{}", e),
         };
-        let bytes_used = frame_gen.frame_size();
+        let timestamp = TimeVal::microseconds(
+            frame_index * 1_000_000 * frame_rate.1 as i64 / frame_rate.0 as i64,
+        );
+        frame_index += 1;
         match v4l2_buffer {
-            GenericQBuffer::Mmap(buf) => {
-                let mut mapping = buf
-                    .get_plane_mapping(0)
-                    .expect("Failed to get MMAP mapping");
-                frame_gen
-                    .next_frame(&mut mapping)
-                    .expect("Failed to generate frame");
-                buf.queue(&[bytes_used])
+            GenericQBuffer::Mmap(mut buf) => {
+                for (i, plane) in frame.iter().enumerate() {
+                    let mut mapping = buf
+                        .get_plane_mapping(i)
+                        .expect("Failed to get MMAP mapping");
+                    mapping.as_mut()[..plane.len()].copy_from_slice(plane);
+                }
+                buf.set_timestamp(timestamp)
+                    .queue(&bytes_used)
                     .expect("Failed to queue input frame");
             }
             GenericQBuffer::User(buf) => {
@@ -293,14 +495,16 @@ fn main() {
                     .unwrap()
                     .pop_front()
                     .expect("No backing buffer to bind");
-                frame_gen
-                    .next_frame(&mut buffer)
-                    .expect("Failed to generate frame");
-                buf.queue_with_handles(
-                    GenericBufferHandles::from(vec![UserPtrHandle::from(buffer)]),
-                    &[bytes_used],
-                )
-                .expect("Failed to queue input frame");
+                for (dst, src) in buffer.iter_mut().zip(&frame) {
+                    dst.copy_from_slice(src);
+                }
+                let handles = buffer
+                    .into_iter()
+                    .map(UserPtrHandle::from)
+                    .collect::<Vec<_>>();
+                buf.set_timestamp(timestamp)
+                    .queue_with_handles(GenericBufferHandles::from(handles), &bytes_used)
+                    .expect("Failed to queue input frame");
             }
             GenericQBuffer::DmaBuf(buf) => {
                 let buffer = dmabufs
@@ -309,11 +513,12 @@ fn main() {
                     .unwrap()
                     .pop_front()
                     .expect("No backing dmabuf to bind");
-                let mut mapping = buffer[0].map().unwrap();
-                frame_gen
-                    .next_frame(&mut mapping)
-                    .expect("Failed to generate frame");
-                buf.queue_with_handles(GenericBufferHandles::from(buffer), &[bytes_used])
+                for (handle, plane) in buffer.iter().zip(&frame) {
+                    let mut mapping = handle.map().unwrap();
+                    mapping.as_mut()[..plane.len()].copy_from_slice(plane);
+                }
+                buf.set_timestamp(timestamp)
+                    .queue_with_handles(GenericBufferHandles::from(buffer), &bytes_used)
                     .expect("Failed to queue input frame");
             }
         }
@@ -321,6 +526,10 @@ fn main() {
 
     encoder.stop().unwrap();
 
+    if let Some(sink) = sink.lock().unwrap().as_mut() {
+        sink.finish().expect("Error while writing output data");
+    }
+
     // Insert new line since we were overwriting the same one
     println!();
 
@@ -0,0 +1,151 @@
+//! Decode a FWHT stream, e.g. produced by `fwht_encoder`, using the software implementation of
+//! the codec instead of vicodec.
+use std::{fs::File, io::BufReader, io::Write};
+
+use anyhow::{anyhow, Context};
+use clap::{App, Arg};
+use utils::fwht::{default_pixelformat, is_compatible, FwhtDecoder};
+use v4l2r::{
+    decoder::format::fwht::FwhtFrameParser,
+    image::{
+        y4m::{Y4mHeader, Y4mWriter},
+        ImageView, ImageViewMut,
+    },
+    Format,
+};
+
+/// Destination of the decoded frames.
+enum OutputFile {
+    Raw(File),
+    /// Y4M file. The writer is created when the format of the stream is known.
+    Y4m(Option<File>, Option<Y4mWriter<File>>),
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let matches = App::new("FWHT software decoder")
+        .arg(
+            Arg::with_name("stream")
+                .required(true)
+                .help("Path to the FWHT stream to decode"),
+        )
+        .arg(
+            Arg::with_name("num_frames")
+                .long("stop_after")
+                .takes_value(true)
+                .help("Stop after decoding a given number of frames"),
+        )
+        .arg(
+            Arg::with_name("output_file")
+                .long("save")
+                .required(false)
+                .takes_value(true)
+                .help(
+                    "Save the decoded frames to a file, as Y4M if its name ends with .y4m, or \
+                     as raw frames otherwise",
+                ),
+        )
+        .get_matches();
+
+    let stream_path = matches
+        .value_of("stream")
+        .expect("Stream argument not specified");
+    let stop_after = matches
+        .value_of("num_frames")
+        .map(|v| v.parse::<usize>())
+        .transpose()
+        .context("Invalid value for stop_after")?;
+    let mut output_file = matches
+        .value_of("output_file")
+        .map(|path| {
+            let file = File::create(path).context("Cannot create output file")?;
+            Ok::<_, anyhow::Error>(if path.ends_with(".y4m") {
+                OutputFile::Y4m(Some(file), None)
+            } else {
+                OutputFile::Raw(file)
+            })
+        })
+        .transpose()?;
+
+    let stream = BufReader::new(File::open(stream_path).context("Cannot open stream")?);
+    let parser = FwhtFrameParser::new(stream).ok_or_else(|| anyhow!("Not a FWHT stream"))?;
+    let mut decoder = FwhtDecoder::new();
+    let mut frame_format: Option<Format> = None;
+    let mut planes: Vec<Vec<u8>> = Vec::new();
+
+    for (i, frame) in parser.take(stop_after.unwrap_or(usize::MAX)).enumerate() {
+        let header = decoder
+            .decode(&frame)
+            .with_context(|| format!("Failed to decode frame {}", i))?;
+
+        // (Re)allocate the decoded frame if the stream has changed.
+        let format = match &frame_format {
+            Some(format)
+                if (format.width, format.height) == (header.width, header.height)
+                    && is_compatible(format.pixelformat, &header) =>
+            {
+                format.clone()
+            }
+            _ => {
+                let pixelformat = default_pixelformat(&header)
+                    .ok_or_else(|| anyhow!("Unsupported frame layout {:?}", header.flags))?;
+                let format = Format {
+                    width: header.width,
+                    height: header.height,
+                    pixelformat,
+                    plane_fmt: pixelformat
+                        .info()
+                        .and_then(|info| info.plane_layouts(header.width, header.height, 1))
+                        .ok_or_else(|| anyhow!("Cannot compute layout of {}", pixelformat))?,
+                    ..Default::default()
+                };
+                println!(
+                    "Decoding {}x{} frames as {}",
+                    format.width, format.height, format.pixelformat
+                );
+                planes = format
+                    .plane_fmt
+                    .iter()
+                    .map(|p| vec![0u8; p.sizeimage as usize])
+                    .collect();
+                frame_format = Some(format.clone());
+                format
+            }
+        };
+
+        let mut image = ImageViewMut::new(&format, None, &mut planes)?;
+        decoder.copy_to_image(&mut image)?;
+        println!(
+            "Frame {}: {} bytes, {}",
+            i,
+            frame.len(),
+            if header.is_i_frame() { "I" } else { "P" }
+        );
+
+        match &mut output_file {
+            None => (),
+            Some(OutputFile::Raw(file)) => {
+                for plane in &planes {
+                    file.write_all(plane)?;
+                }
+            }
+            Some(OutputFile::Y4m(file, writer)) => {
+                if writer.is_none() {
+                    let header = Y4mHeader::from_format(&format, (30, 1))?;
+                    *writer = Some(Y4mWriter::new(file.take().unwrap(), header)?);
+                }
+                writer
+                    .as_mut()
+                    .unwrap()
+                    .write_frame(&ImageView::new(&format, None, &planes)?)?;
+            }
+        }
+    }
+
+    if let Some(OutputFile::Y4m(_, Some(writer))) = &mut output_file {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
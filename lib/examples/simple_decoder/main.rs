@@ -6,21 +6,31 @@ use std::{
     path::Path,
     sync::atomic::AtomicBool,
     sync::atomic::{AtomicUsize, Ordering},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
-use anyhow::ensure;
-use nix::sys::time::{TimeVal, TimeValLike};
 use v4l2r::{
     decoder::{format::fwht::FwhtFrameParser, FormatChangedReply},
-    device::queue::{handles_provider::MmapProvider, FormatBuilder},
+    device::queue::handles_provider::MmapProvider,
+    image::{
+        y4m::{Y4mHeader, Y4mWriter},
+        ImageView,
+    },
     memory::{MemoryType, MmapHandle},
-    PlaneLayout,
 };
 use v4l2r::{
     decoder::{
-        format::{h264::H264FrameSplitter, StreamSplitter},
+        format::{
+            h264::H264FrameSplitter,
+            hevc::HevcFrameSplitter,
+            ivf::IvfReader,
+            mkv::MkvReader,
+            mp4::Mp4Reader,
+            probe::{probe, Container, StreamInfo},
+            StreamSplitter,
+        },
         stateful::GetBufferError,
+        CaptureFormatChange,
     },
     PixelFormat,
 };
@@ -34,10 +44,28 @@ use v4l2r::{
 };
 
 use clap::{App, Arg};
+use nix::sys::time::{TimeVal, TimeValLike};
+use utils::conversion;
+
+/// Destination of the decoded frames.
+enum OutputFile {
+    /// Frames converted to RGB24 if possible, or as they are otherwise.
+    Raw(File),
+    /// Y4M file. Its header depends on the CAPTURE format, so the writer is only created when
+    /// the first frame is decoded.
+    Y4m {
+        file: Option<File>,
+        writer: Option<Y4mWriter<File>>,
+    },
+}
 
 enum Codec {
     Fwht,
     H264,
+    Hevc,
+    Ivf,
+    Mkv,
+    Mp4,
 }
 
 fn main() {
@@ -59,15 +87,21 @@ fn main() {
                 .long("input_format")
                 .required(false)
                 .takes_value(true)
-                .default_value("fwht")
-                .help("Format of the encoded stream (fwht or h264)"),
+                .default_value("auto")
+                .help(
+                    "Format of the encoded stream (auto, fwht, h264, hevc, ivf, mkv or mp4). \
+                     With auto, the format is detected from the content of the stream",
+                ),
         )
         .arg(
             Arg::with_name("output_file")
                 .long("save")
                 .required(false)
                 .takes_value(true)
-                .help("Save the decoded RGB frames to a file"),
+                .help(
+                    "Save the decoded frames to a file, as Y4M if its name ends with .y4m, or \
+                     converted to RGB24 if possible otherwise",
+                ),
         )
         .get_matches();
 
@@ -81,16 +115,98 @@ fn main() {
         .value_of("input_format")
         .expect("Input format not specified")
     {
-        "fwht" => Codec::Fwht,
-        "h264" => Codec::H264,
+        "auto" => None,
+        "fwht" => Some(Codec::Fwht),
+        "h264" => Some(Codec::H264),
+        "hevc" => Some(Codec::Hevc),
+        "ivf" => Some(Codec::Ivf),
+        "mkv" | "webm" => Some(Codec::Mkv),
+        "mp4" => Some(Codec::Mp4),
         _ => panic!("Invalid input format specified"),
     };
 
-    let stream = BufReader::new(File::open(stream_path).expect("Compressed stream not found"));
+    let mut stream = BufReader::new(File::open(stream_path).expect("Compressed stream not found"));
 
-    let mut output_file: Option<File> = matches
-        .value_of("output_file")
-        .map(|path| File::create(path).expect("Invalid output file specified."));
+    // Probe the stream even if its format is given, to size the buffers after its resolution.
+    let probed = probe(&mut stream);
+    match &probed {
+        Ok(info) => println!("Probed stream: {:?}", info),
+        Err(e) => println!("Failed to probe stream: {}", e),
+    }
+    let codec = codec.unwrap_or_else(|| match &probed {
+        Ok(info) => match (info.container, info.codec) {
+            (Container::Elementary, PixelFormat::FWHT) => Codec::Fwht,
+            (Container::Elementary, PixelFormat::H264) => Codec::H264,
+            (Container::Elementary, PixelFormat::HEVC) => Codec::Hevc,
+            (Container::Elementary, codec) => panic!("Unsupported elementary {} stream", codec),
+            (Container::Ivf, _) => Codec::Ivf,
+            (Container::Matroska, _) => Codec::Mkv,
+            (Container::Mp4, _) => Codec::Mp4,
+        },
+        Err(e) => panic!("Cannot detect the format of {}: {}", stream_path, e),
+    });
+
+    // The codec of streams in a container is given by the container's header.
+    let mut container_fourcc = None;
+    let mut parser = match codec {
+        Codec::Fwht => Box::new(
+            FwhtFrameParser::new(stream)
+                .unwrap_or_else(|| panic!("No FWHT stream detected in {}", stream_path)),
+        ) as Box<dyn StreamSplitter>,
+        Codec::H264 => Box::new(
+            H264FrameSplitter::new(stream)
+                .unwrap_or_else(|| panic!("No H.264 stream detected in {}", stream_path)),
+        ) as Box<dyn StreamSplitter>,
+        Codec::Hevc => Box::new(
+            HevcFrameSplitter::new(stream)
+                .unwrap_or_else(|| panic!("No HEVC stream detected in {}", stream_path)),
+        ) as Box<dyn StreamSplitter>,
+        Codec::Ivf => {
+            let reader = IvfReader::new(stream)
+                .unwrap_or_else(|e| panic!("Invalid IVF file {}: {}", stream_path, e));
+            let header = reader.header();
+            println!(
+                "IVF stream: {} {}x{}, {} frames",
+                header.fourcc, header.width, header.height, header.num_frames
+            );
+            container_fourcc = Some(header.fourcc);
+            Box::new(reader) as Box<dyn StreamSplitter>
+        }
+        Codec::Mkv => {
+            let reader = MkvReader::new(stream)
+                .unwrap_or_else(|e| panic!("Invalid Matroska file {}: {}", stream_path, e));
+            let track = reader.track();
+            println!(
+                "Matroska stream: track {} ({}) {}x{}",
+                track.number, track.codec_id, track.width, track.height
+            );
+            container_fourcc = track.fourcc;
+            Box::new(reader) as Box<dyn StreamSplitter>
+        }
+        Codec::Mp4 => {
+            let reader = Mp4Reader::new(stream)
+                .unwrap_or_else(|e| panic!("Invalid MP4 file {}: {}", stream_path, e));
+            let track = reader.track();
+            println!(
+                "MP4 stream: {} {}x{}, {} frames",
+                track.fourcc, track.width, track.height, track.num_samples
+            );
+            container_fourcc = Some(track.fourcc);
+            Box::new(reader) as Box<dyn StreamSplitter>
+        }
+    };
+
+    let mut output_file: Option<OutputFile> = matches.value_of("output_file").map(|path| {
+        let file = File::create(path).expect("Invalid output file specified.");
+        if path.ends_with(".y4m") {
+            OutputFile::Y4m {
+                file: Some(file),
+                writer: None,
+            }
+        } else {
+            OutputFile::Raw(file)
+        }
+    });
 
     let lets_quit = Arc::new(AtomicBool::new(false));
     // Setup the Ctrl+c handler.
@@ -104,11 +220,16 @@ fn main() {
 
     const NUM_OUTPUT_BUFFERS: usize = 4;
 
+    // CAPTURE format and visible rectangle, used to convert the decoded frames before saving them.
+    let capture_format_writer: Arc<Mutex<Option<(Format, Rect)>>> = Default::default();
+    let capture_format_reader = Arc::clone(&capture_format_writer);
+
     let poll_count_reader = Arc::new(AtomicUsize::new(0));
     let poll_count_writer = Arc::clone(&poll_count_reader);
     let start_time = std::time::Instant::now();
     let mut frame_counter = 0usize;
-    let mut output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
+    let mut output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>,
+                                    bitstream_id: Option<usize>| {
         let bytes_used = cap_dqbuf.data.get_first_plane().bytesused() as usize;
         // Ignore zero-sized buffers.
         if bytes_used == 0 {
@@ -119,9 +240,13 @@ fn main() {
         frame_counter += 1;
         let fps = frame_counter as f32 / elapsed.as_millis() as f32 * 1000.0;
         let ppf = poll_count_reader.load(Ordering::SeqCst) as f32 / frame_counter as f32;
+        let timestamp = cap_dqbuf.data.timestamp();
+        let timestamp = TimeVal::new(timestamp.tv_sec, timestamp.tv_usec);
         print!(
-            "\rDecoded buffer {:#5}, index: {:#2}), bytes used:{:#6} fps: {:#5.2} ppf: {:#4.2}",
+            "\rDecoded buffer {:#5}, bitstream id: {:>5}, timestamp: {:>8}us, index: {:#2}), bytes used:{:#6} fps: {:#5.2} ppf: {:#4.2}",
             cap_dqbuf.data.sequence(),
+            bitstream_id.map_or_else(|| "?".to_string(), |id| id.to_string()),
+            timestamp.num_microseconds(),
             cap_dqbuf.data.index(),
             bytes_used,
             fps,
@@ -130,31 +255,88 @@ fn main() {
         io::stdout().flush().unwrap();
 
         if let Some(ref mut output) = output_file {
-            for i in 0..cap_dqbuf.data.num_planes() {
-                let mapping = cap_dqbuf
-                    .get_plane_mapping(i)
-                    .expect("Failed to map capture buffer plane");
-                output
-                    .write_all(&mapping)
-                    .expect("Error while writing output data");
+            let mappings = (0..cap_dqbuf.data.num_planes())
+                .map(|i| {
+                    cap_dqbuf
+                        .get_plane_mapping(i)
+                        .expect("Failed to map capture buffer plane")
+                })
+                .collect::<Vec<_>>();
+            let (format, visible_rect) = capture_format_reader
+                .lock()
+                .unwrap()
+                .clone()
+                .expect("CAPTURE format not set");
+
+            match output {
+                OutputFile::Raw(file) => {
+                    match conversion::to_rgb24(&format, Some(&visible_rect), &mappings) {
+                        Ok((_, rgb_frame)) => file
+                            .write_all(&rgb_frame)
+                            .expect("Error while writing output data"),
+                        // Save the raw frame if we cannot convert it.
+                        Err(_) => {
+                            for mapping in &mappings {
+                                file.write_all(mapping)
+                                    .expect("Error while writing output data");
+                            }
+                        }
+                    }
+                }
+                OutputFile::Y4m { file, writer } => {
+                    let writer = writer.get_or_insert_with(|| {
+                        let visible_format = Format {
+                            width: visible_rect.width,
+                            height: visible_rect.height,
+                            ..format.clone()
+                        };
+                        // The frame rate of the stream is unknown, so use a common one.
+                        let header = Y4mHeader::from_format(&visible_format, (30, 1))
+                            .expect("Decoded format cannot be saved as Y4M");
+                        Y4mWriter::new(file.take().unwrap(), header)
+                            .expect("Error while writing output data")
+                    });
+                    let image = ImageView::new(&format, Some(&visible_rect), &mappings)
+                        .expect("Failed to create view of the decoded frame");
+                    writer
+                        .write_frame(&image)
+                        .expect("Error while writing output data");
+                }
             }
         }
     };
-    let decoder_event_cb = move |event: DecoderEvent<MmapProvider>| match event {
-        DecoderEvent::FrameDecoded(dqbuf) => output_ready_cb(dqbuf),
+    let decoder_event_cb = move |event: DecoderEvent<MmapProvider, usize>| match event {
+        DecoderEvent::FrameDecoded { buffer, metadata } => output_ready_cb(buffer, metadata),
         DecoderEvent::EndOfStream => (),
+        DecoderEvent::FrameError { metadata, .. } => {
+            eprintln!(
+                "\nError while decoding frame with bitstream id {:?}",
+                metadata
+            )
+        }
+        DecoderEvent::FrameDropped(bitstream_id) => {
+            eprintln!("\nFrame with bitstream id {} dropped", bitstream_id)
+        }
+        DecoderEvent::FatalError(e) => eprintln!("\nDecoder error: {}", e),
     };
-    let set_capture_format_cb = move |f: FormatBuilder,
+    let set_capture_format_cb = move |change: CaptureFormatChange,
                                       visible_rect: Rect,
                                       min_num_buffers: usize|
           -> anyhow::Result<FormatChangedReply<MmapProvider>> {
         // Let's keep the pixel format that the decoder found convenient.
-        let format = f.format();
+        let format = change.format();
 
         println!(
-            "New CAPTURE format: {:?} (visible rect: {})",
-            format, visible_rect
+            "New CAPTURE format: {:?} (visible rect: {}, {})",
+            format,
+            visible_rect,
+            if change.needs_reallocation() {
+                "reallocating buffers"
+            } else {
+                "reusing buffers"
+            }
         );
+        *capture_format_writer.lock().unwrap() = Some((format.clone(), visible_rect));
 
         Ok(FormatChangedReply {
             provider: MmapProvider::new(format),
@@ -165,32 +347,38 @@ fn main() {
         })
     };
 
-    let mut decoder = Decoder::open(Path::new(device_path))
-        .expect("Failed to open device")
-        .set_output_format(|f| {
-            let pixel_format: PixelFormat = match codec {
-                Codec::Fwht => b"FWHT".into(),
-                Codec::H264 => b"H264".into(),
-            };
-            let format: Format = f
-                .set_pixelformat(pixel_format)
-                // 1 MB per decoding unit should be enough for most streams.
-                .set_planes_layout(vec![PlaneLayout {
-                    sizeimage: 1024 * 1024,
-                    ..Default::default()
-                }])
-                .apply()?;
-
-            ensure!(
-                format.pixelformat == pixel_format,
-                format!("{} format not supported by device", pixel_format)
-            );
+    let (container, pixel_format) = match codec {
+        Codec::Fwht => (Container::Elementary, PixelFormat::FWHT),
+        Codec::H264 => (Container::Elementary, PixelFormat::H264),
+        Codec::Hevc => (Container::Elementary, PixelFormat::HEVC),
+        Codec::Ivf => (Container::Ivf, container_fourcc.unwrap()),
+        Codec::Mkv => (Container::Matroska, container_fourcc.unwrap()),
+        Codec::Mp4 => (Container::Mp4, container_fourcc.unwrap()),
+    };
+    // Only trust the probe if it agrees with the format we are going to decode.
+    let stream_info = probed
+        .ok()
+        .filter(|info| info.container == container && info.codec == pixel_format)
+        .unwrap_or_else(|| StreamInfo::new(container, pixel_format));
 
-            println!("Tentative OUTPUT format: {:?}", format);
+    let decoder = Decoder::open(Path::new(device_path)).expect("Failed to open device");
+    // Refuse unsupported streams before allocating anything.
+    if !decoder.supports_output_format(pixel_format) {
+        panic!("{} format not supported by device", pixel_format);
+    }
+    let decoder = decoder
+        .set_output_format_for_stream(&stream_info)
+        .expect("Failed to set output format");
+    if stream_info.coded_size.is_some() {
+        println!(
+            "Expected CAPTURE format: {:?}",
+            decoder
+                .get_capture_format()
+                .expect("Failed to get capture format")
+        );
+    }
 
-            Ok(())
-        })
-        .expect("Failed to set output format")
+    let mut decoder = decoder
         .allocate_output_buffers::<Vec<MmapHandle>>(NUM_OUTPUT_BUFFERS)
         .expect("Failed to allocate output buffers")
         .set_poll_counter(poll_count_writer)
@@ -199,29 +387,26 @@ fn main() {
 
     println!("Allocated {} buffers", decoder.num_output_buffers());
 
-    let parser = match codec {
-        Codec::Fwht => Box::new(
-            FwhtFrameParser::new(stream)
-                .unwrap_or_else(|| panic!("No FWHT stream detected in {}", stream_path)),
-        ) as Box<dyn StreamSplitter>,
-        Codec::H264 => Box::new(
-            H264FrameSplitter::new(stream)
-                .unwrap_or_else(|| panic!("No H.264 stream detected in {}", stream_path)),
-        ) as Box<dyn StreamSplitter>,
-    };
-
-    'mainloop: for (bitstream_id, frame) in parser.enumerate() {
+    let chunks = std::iter::from_fn(|| parser.next_timestamped());
+    'mainloop: for (bitstream_id, chunk) in chunks.enumerate() {
         // Ctrl-c ?
         if lets_quit.load(Ordering::SeqCst) {
             break;
         }
 
-        let v4l2_buffer = match decoder.get_buffer() {
+        // Frames read from a container keep their presentation timestamp, which the decoder
+        // copies into the decoded frame. Otherwise the timestamp is used to return the bitstream
+        // id with the decoded frame.
+        let timestamp = match chunk.timestamp_us {
+            Some(timestamp_us) => TimeVal::microseconds(timestamp_us),
+            None => decoder.add_frame_metadata(bitstream_id),
+        };
+        let frame = chunk.data;
+
+        let mut v4l2_buffer = match decoder.get_buffer() {
             Ok(buffer) => buffer,
             // If we got interrupted while waiting for a buffer, just exit normally.
-            Err(GetBufferError::PollError(PollError::EPollWait(e)))
-                if e == nix::errno::Errno::EINTR =>
-            {
+            Err(GetBufferError::PollError(PollError::EPollWait(nix::errno::Errno::EINTR))) => {
                 break 'mainloop
             }
             Err(e) => panic!("{}", e),
@@ -233,10 +418,8 @@ fn main() {
         mapping.as_mut()[0..frame.len()].copy_from_slice(&frame);
         drop(mapping);
 
-        // TODO setting the timestamp should not be necessary. This is a requirement of the crosvm
-        // video device.
         v4l2_buffer
-            .set_timestamp(TimeVal::seconds(bitstream_id as i64))
+            .set_timestamp(timestamp)
             .queue(&[frame.len()])
             .expect("Failed to queue input frame");
     }
@@ -6,8 +6,8 @@ use std::time::Instant;
 use utils::framegen::FrameGenerator;
 
 use qbuf::{get_free::GetFreeCaptureBuffer, get_indexed::GetOutputBufferByIndex};
+use v4l2r::device::queue::*;
 use v4l2r::{device::queue::qbuf::OutputQueueable, memory::MemoryType, Format};
-use v4l2r::{device::queue::*, memory::MmapHandle};
 use v4l2r::{
     device::{
         queue::generic::{GenericBufferHandles, GenericQBuffer, GenericSupportedMemoryType},
@@ -133,7 +133,7 @@ pub fn run<F: FnMut(&[u8])>(
         .expect("Failed to allocate output buffers");
 
     let capture_queue = capture_queue
-        .request_buffers::<Vec<MmapHandle>>(2)
+        .request_mapped_buffers(2)
         .expect("Failed to allocate output buffers");
     println!(
         "Using {} output and {} capture buffers.",
@@ -188,7 +188,7 @@ pub fn run<F: FnMut(&[u8])>(
             .expect("Failed to obtain output buffer");
 
         match output_buffer {
-            GenericQBuffer::Mmap(buf) => {
+            GenericQBuffer::Mmap(mut buf) => {
                 let mut mapping = buf
                     .get_plane_mapping(0)
                     .expect("Failed to get MMAP mapping");
@@ -1,24 +1,41 @@
 //! High-level interface for a V4L2 video decoder. Currently only supports the
 //! [stateful interface](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html).
 use crate::{
-    device::queue::{
-        direction::{Capture, Output},
-        dqbuf::DqBuffer,
-        handles_provider::HandlesProvider,
-        CanceledBuffer, FormatBuilder,
+    device::{
+        queue::{
+            direction::{Capture, Output},
+            dqbuf::DqBuffer,
+            handles_provider::HandlesProvider,
+            CanceledBuffer, FormatBuilder,
+        },
+        FatalError,
     },
+    ioctl::BufferFlags,
     memory::BufferHandles,
-    Rect,
+    Format, Rect,
 };
+use nix::sys::time::TimeVal;
 
 pub mod format;
 pub mod stateful;
 
+#[allow(clippy::large_enum_variant)]
 pub enum CompletedInputBuffer<OP: BufferHandles> {
     Dequeued(DqBuffer<Output, OP>),
     Canceled(CanceledBuffer<OP>),
 }
 
+impl<OP: BufferHandles> CompletedInputBuffer<OP> {
+    /// Whether the driver reported an error while processing this buffer. This is not fatal, and
+    /// the decoder keeps processing the next buffers.
+    pub fn has_error(&self) -> bool {
+        match self {
+            CompletedInputBuffer::Dequeued(buf) => buf.data.flags().contains(BufferFlags::ERROR),
+            CompletedInputBuffer::Canceled(_) => false,
+        }
+    }
+}
+
 pub trait InputDoneCallback<OP: BufferHandles>: Fn(CompletedInputBuffer<OP>) {}
 impl<OP, F> InputDoneCallback<OP> for F
 where
@@ -27,33 +44,63 @@ where
 {
 }
 
-// TODO: add errors?
-pub enum DecoderEvent<P: HandlesProvider> {
+/// Events emitted by the decoder.
+///
+/// `M` is the type of the metadata the client associates with each frame to decode using
+/// [`Decoder::add_frame_metadata`](stateful::Decoder::add_frame_metadata).
+#[allow(clippy::large_enum_variant)]
+pub enum DecoderEvent<P: HandlesProvider, M = ()> {
     /// Emitted when a frame is decoded.
     ///
-    /// The parameter is the dequeued buffer, containing the plane handles of
+    /// `buffer` is the dequeued buffer, containing the plane handles of
     /// the decoded frame as well as its V4L2 parameters such as flags. The
     /// flags remain untouched, but the client should not take action on some
     /// of them: for instance, when the `V4L2_BUF_FLAG_LAST` is set, the proper
     /// corresponding event (resolution change or end of stream) will be
     /// signaled appropriately.
-    FrameDecoded(DqBuffer<Capture, P::HandleType>),
+    ///
+    /// `metadata` is the metadata associated with the input frame this frame was decoded from, if
+    /// any.
+    FrameDecoded {
+        buffer: DqBuffer<Capture, P::HandleType>,
+        metadata: Option<M>,
+    },
     /// Emitted when a previously requested `drain` request completes.
     ///
     /// When this event is emitted, the client knows that all the frames
     /// corresponding to all the input buffers queued before the `drain` request
     /// have been emitted.
     EndOfStream,
+    /// Emitted instead of `FrameDecoded` when the driver flagged a decoded frame as erroneous.
+    ///
+    /// `timestamp` is the one of the input buffer the frame was decoded from. The content of
+    /// `buffer` may be corrupted, but it can be recycled like any decoded frame. This error is
+    /// not fatal and decoding continues.
+    FrameError {
+        timestamp: TimeVal,
+        buffer: DqBuffer<Capture, P::HandleType>,
+        metadata: Option<M>,
+    },
+    /// Emitted with the metadata of an input frame that will never produce a decoded frame,
+    /// either because the decoder skipped it, or because it has been canceled by a flush or stop.
+    ///
+    /// Skipped frames are reported right before the `EndOfStream` event of the drain sequence
+    /// they were queued before.
+    FrameDropped(M),
+    /// Emitted when an unrecoverable error occurs. Apart from `FrameDropped` events for the
+    /// pending frames when the decoder is stopped, this is the last event emitted by the decoder,
+    /// which must then be stopped.
+    FatalError(FatalError),
 }
 
-pub trait DecoderEventCallback<P: HandlesProvider>:
-    FnMut(DecoderEvent<P>) + Send + 'static
+pub trait DecoderEventCallback<P: HandlesProvider, M = ()>:
+    FnMut(DecoderEvent<P, M>) + Send + 'static
 {
 }
-impl<P, F> DecoderEventCallback<P> for F
+impl<P, M, F> DecoderEventCallback<P, M> for F
 where
     P: HandlesProvider,
-    F: FnMut(DecoderEvent<P>) + Send + 'static,
+    F: FnMut(DecoderEvent<P, M>) + Send + 'static,
 {
 }
 
@@ -63,13 +110,43 @@ pub struct FormatChangedReply<P: HandlesProvider> {
     pub num_buffers: usize,
 }
 
+/// Describes how a new CAPTURE format will be handled, as passed to the [`FormatChangedCallback`].
+pub enum CaptureFormatChange<'a> {
+    /// The CAPTURE buffers need to be reallocated. The builder can be used to adjust the format
+    /// before the new buffers are allocated following the [`FormatChangedReply`].
+    Reallocate(FormatBuilder<'a>),
+    /// The new format fits into the currently allocated CAPTURE buffers, which will be kept. The
+    /// format cannot be adjusted.
+    ///
+    /// The buffers are only reallocated if the reply requests a different memory type or more
+    /// buffers than are currently allocated.
+    Reuse(Format),
+}
+
+impl<'a> CaptureFormatChange<'a> {
+    /// Returns the new CAPTURE format.
+    pub fn format(&self) -> &Format {
+        match self {
+            CaptureFormatChange::Reallocate(builder) => builder.format(),
+            CaptureFormatChange::Reuse(format) => format,
+        }
+    }
+
+    /// Returns `true` if the CAPTURE buffers will be reallocated.
+    pub fn needs_reallocation(&self) -> bool {
+        matches!(self, CaptureFormatChange::Reallocate(_))
+    }
+}
+
 pub trait FormatChangedCallback<P: HandlesProvider>:
-    Fn(FormatBuilder, Rect, usize) -> anyhow::Result<FormatChangedReply<P>> + Send + 'static
+    Fn(CaptureFormatChange, Rect, usize) -> anyhow::Result<FormatChangedReply<P>> + Send + 'static
 {
 }
 impl<P, F> FormatChangedCallback<P> for F
 where
     P: HandlesProvider,
-    F: Fn(FormatBuilder, Rect, usize) -> anyhow::Result<FormatChangedReply<P>> + Send + 'static,
+    F: Fn(CaptureFormatChange, Rect, usize) -> anyhow::Result<FormatChangedReply<P>>
+        + Send
+        + 'static,
 {
 }
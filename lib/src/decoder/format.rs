@@ -1,19 +1,57 @@
+pub(crate) mod bitreader;
 pub mod fwht;
 pub mod h264;
+pub mod hevc;
+pub mod ivf;
+pub mod mkv;
+pub mod mp4;
+pub mod nal;
+pub mod probe;
 
 use log::error;
-use std::io;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+
+/// A chunk of encoded data along with its presentation timestamp.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampedChunk {
+    pub data: Vec<u8>,
+    /// Presentation timestamp of the chunk in microseconds, if the stream provides one.
+    pub timestamp_us: Option<i64>,
+}
 
 /// Trait for classes able to iterate an encoded stream over chunks of decodable units (typically
 /// frames).
-pub trait StreamSplitter: Iterator<Item = Vec<u8>> {}
+pub trait StreamSplitter: Iterator<Item = Vec<u8>> {
+    /// Returns the next chunk of the stream along with its presentation timestamp.
+    ///
+    /// Elementary streams carry no timing information, so the default implementation returns
+    /// chunks without timestamp. Containers override it to return the timestamps they store.
+    fn next_timestamped(&mut self) -> Option<TimestampedChunk> {
+        self.next().map(|data| TimestampedChunk {
+            data,
+            timestamp_us: None,
+        })
+    }
+}
+
+/// Converts `timestamp`, expressed in units of `num / den` seconds, into microseconds.
+///
+/// Returns `None` if `den` is zero or if the result does not fit into an `i64`.
+fn timestamp_to_us(timestamp: i64, num: u32, den: u32) -> Option<i64> {
+    if den == 0 {
+        return None;
+    }
+
+    i64::try_from(timestamp as i128 * num as i128 * 1_000_000 / den as i128).ok()
+}
 
 /// Splits a stream at each encounter of a given pattern. Useful to extract decodable units (or
 /// frames from an encoded stream.
 struct PatternSplitter<S: io::Read> {
     /// The pattern to split at.
     pattern: Vec<u8>,
-    stream: io::Bytes<S>,
+    stream: io::Bytes<io::BufReader<S>>,
 }
 
 impl<S: io::Read> PatternSplitter<S> {
@@ -22,7 +60,7 @@ impl<S: io::Read> PatternSplitter<S> {
     /// `stream` must start with `pattern`, otherwise the input is considered invalid and `None` is
     /// returned.
     fn new(pattern: impl Into<Vec<u8>>, stream: S) -> Option<Self> {
-        let mut stream = stream.bytes();
+        let mut stream = io::BufReader::new(stream).bytes();
         let pattern = pattern.into();
 
         // The stream must begin by our header, or it is invalid.
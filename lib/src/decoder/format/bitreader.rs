@@ -0,0 +1,174 @@
+//! Bit-level reading of codec headers.
+use std::convert::TryInto;
+
+/// Reads a bitstream MSB first.
+pub(crate) struct BitReader {
+    data: Vec<u8>,
+    /// Position of the next bit to read.
+    pos: usize,
+}
+
+impl BitReader {
+    pub(crate) fn new(data: &[u8]) -> Self {
+        BitReader {
+            data: data.to_vec(),
+            pos: 0,
+        }
+    }
+
+    /// Create a reader for `nal_content`, removing its emulation prevention bytes.
+    pub(crate) fn from_nal(nal_content: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal_content.len());
+        let mut zeros = 0;
+        for &b in nal_content {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+
+        BitReader { data, pos: 0 }
+    }
+
+    /// Read up to 32 bits.
+    pub(crate) fn read_bits(&mut self, num_bits: usize) -> Option<u32> {
+        let mut value = 0u64;
+        for _ in 0..num_bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u64;
+            self.pos += 1;
+        }
+
+        value.try_into().ok()
+    }
+
+    pub(crate) fn read_bit(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    pub(crate) fn skip_bits(&mut self, num_bits: usize) -> Option<()> {
+        if self.pos + num_bits > self.data.len() * 8 {
+            return None;
+        }
+        self.pos += num_bits;
+
+        Some(())
+    }
+
+    /// Read an unsigned Exp-Golomb coded value.
+    pub(crate) fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+
+        ((1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)? as u64)
+            .try_into()
+            .ok()
+    }
+
+    /// Read a signed Exp-Golomb coded value.
+    pub(crate) fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        let value = if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        };
+
+        Some(value as i32)
+    }
+
+    /// Read an AV1 variable length unsigned value.
+    pub(crate) fn read_uvlc(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+        }
+        if leading_zeros >= 32 {
+            return Some(u32::MAX);
+        }
+
+        Some(self.read_bits(leading_zeros)? + ((1u64 << leading_zeros) - 1) as u32)
+    }
+}
+
+/// Helpers for building the headers parsed by [`BitReader`] in tests.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Writes bits MSB first.
+    #[derive(Default)]
+    pub(crate) struct BitWriter {
+        data: Vec<u8>,
+        num_bits: usize,
+    }
+
+    impl BitWriter {
+        pub(crate) fn write_bits(&mut self, value: u64, num_bits: usize) {
+            for i in (0..num_bits).rev() {
+                if self.num_bits.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                *self.data.last_mut().unwrap() |=
+                    (((value >> i) & 1) as u8) << (7 - self.num_bits % 8);
+                self.num_bits += 1;
+            }
+        }
+
+        pub(crate) fn write_ue(&mut self, value: u32) {
+            let value = value as u64 + 1;
+            let len = 64 - value.leading_zeros() as usize;
+            self.write_bits(0, len - 1);
+            self.write_bits(value, len);
+        }
+
+        /// Finish the data with the RBSP trailing bits.
+        pub(crate) fn into_rbsp(mut self) -> Vec<u8> {
+            self.write_bits(1, 1);
+            self.data
+        }
+    }
+
+    /// Insert emulation prevention bytes into `rbsp`.
+    pub(crate) fn escape(rbsp: Vec<u8>) -> Vec<u8> {
+        let mut escaped = Vec::new();
+        let mut zeros = 0;
+        for b in rbsp {
+            if zeros >= 2 && b <= 3 {
+                escaped.push(3);
+                zeros = 0;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            escaped.push(b);
+        }
+
+        escaped
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        let mut writer = BitWriter::default();
+        writer.write_bits(0, 24);
+        writer.write_ue(0);
+        writer.write_ue(41);
+        // -3 is coded as 6.
+        writer.write_ue(6);
+        let nal = escape(writer.into_rbsp());
+        assert_eq!(&nal[..4], &[0, 0, 3, 0]);
+
+        let mut reader = BitReader::from_nal(&nal);
+        assert_eq!(reader.read_bits(24), Some(0));
+        assert_eq!(reader.read_ue(), Some(0));
+        assert_eq!(reader.read_ue(), Some(41));
+        assert_eq!(reader.read_se(), Some(-3));
+        assert_eq!(reader.read_bit(), Some(true));
+        assert_eq!(reader.skip_bits(8), None);
+    }
+}
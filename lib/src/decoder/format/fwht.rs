@@ -1,7 +1,148 @@
 use super::{PatternSplitter, StreamSplitter};
+use bitflags::bitflags;
+use std::convert::TryInto;
 use std::io;
 
-static FRAME_HEADER: [u8; 8] = [0x4f, 0x4f, 0x4f, 0x4f, 0xff, 0xff, 0xff, 0xff];
+pub(super) static FRAME_HEADER: [u8; 8] = [0x4f, 0x4f, 0x4f, 0x4f, 0xff, 0xff, 0xff, 0xff];
+
+/// Latest version of the FWHT bitstream, as produced by vicodec.
+pub const FWHT_VERSION: u32 = 3;
+
+bitflags! {
+    /// Flags of the `flags` field of a FWHT frame header.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FwhtFlags: u32 {
+        const IS_INTERLACED = 1 << 0;
+        const IS_BOTTOM_FIRST = 1 << 1;
+        const IS_ALTERNATE = 1 << 2;
+        const IS_BOTTOM_FIELD = 1 << 3;
+        const LUMA_IS_UNCOMPRESSED = 1 << 4;
+        const CB_IS_UNCOMPRESSED = 1 << 5;
+        const CR_IS_UNCOMPRESSED = 1 << 6;
+        const CHROMA_FULL_HEIGHT = 1 << 7;
+        const CHROMA_FULL_WIDTH = 1 << 8;
+        const ALPHA_IS_UNCOMPRESSED = 1 << 9;
+        const I_FRAME = 1 << 10;
+        /// Number of components of the frame, minus one.
+        const COMPONENTS_NUM_MASK = 0x7 << 16;
+        /// Pixel encoding of the frame, see `FwhtPixelEncoding`.
+        const PIXENC_MASK = 0x3 << 19;
+    }
+}
+
+/// Pixel encoding of the components of a FWHT frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FwhtPixelEncoding {
+    Yuv = 1,
+    Rgb = 2,
+    Hsv = 3,
+}
+
+/// Header preceding each compressed FWHT frame (`struct fwht_cframe_hdr`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FwhtHeader {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub flags: FwhtFlags,
+    pub colorspace: u32,
+    pub xfer_func: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+    /// Size in bytes of the compressed data following the header.
+    pub size: u32,
+}
+
+impl FwhtHeader {
+    /// Size of the header in bytes.
+    pub const SIZE: usize = 44;
+
+    /// Parse the header at the start of `frame`. Returns `None` if `frame` does not start with
+    /// a FWHT header.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if !frame.starts_with(&FRAME_HEADER) {
+            return None;
+        }
+        let read_u32 = |offset: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(
+                frame.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+
+        Some(FwhtHeader {
+            version: read_u32(8)?,
+            width: read_u32(12)?,
+            height: read_u32(16)?,
+            flags: FwhtFlags::from_bits_retain(read_u32(20)?),
+            colorspace: read_u32(24)?,
+            xfer_func: read_u32(28)?,
+            ycbcr_enc: read_u32(32)?,
+            quantization: read_u32(36)?,
+            size: read_u32(40)?,
+        })
+    }
+
+    /// Returns the serialized form of this header.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&FRAME_HEADER);
+        for (i, field) in [
+            self.version,
+            self.width,
+            self.height,
+            self.flags.bits(),
+            self.colorspace,
+            self.xfer_func,
+            self.ycbcr_enc,
+            self.quantization,
+            self.size,
+        ]
+        .iter()
+        .enumerate()
+        {
+            bytes[8 + i * 4..12 + i * 4].copy_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Number of components (luma, chroma and alpha) of the frame. Version 1 frames always have
+    /// 3 components.
+    pub fn components_num(&self) -> usize {
+        if self.version < 2 {
+            3
+        } else {
+            1 + ((self.flags & FwhtFlags::COMPONENTS_NUM_MASK).bits() >> 16) as usize
+        }
+    }
+
+    /// Pixel encoding of the frame. Version 1 frames are always YUV.
+    pub fn pixel_encoding(&self) -> Option<FwhtPixelEncoding> {
+        if self.version < 2 {
+            return Some(FwhtPixelEncoding::Yuv);
+        }
+        match (self.flags & FwhtFlags::PIXENC_MASK).bits() >> 19 {
+            1 => Some(FwhtPixelEncoding::Yuv),
+            2 => Some(FwhtPixelEncoding::Rgb),
+            3 => Some(FwhtPixelEncoding::Hsv),
+            _ => None,
+        }
+    }
+
+    /// Horizontal and vertical subsampling factors of the chroma components.
+    pub fn chroma_subsampling(&self) -> (usize, usize) {
+        let div = |flag| if self.flags.contains(flag) { 1 } else { 2 };
+        (
+            div(FwhtFlags::CHROMA_FULL_WIDTH),
+            div(FwhtFlags::CHROMA_FULL_HEIGHT),
+        )
+    }
+
+    /// Whether the frame can be decoded without a reference frame. Only reliable for version 3
+    /// and later.
+    pub fn is_i_frame(&self) -> bool {
+        self.flags.contains(FwhtFlags::I_FRAME)
+    }
+}
 
 /// Iterator that returns exactly one frame worth of data from a FWHT stream.
 pub struct FwhtFrameParser<S: io::Read>(PatternSplitter<S>);
@@ -23,3 +164,34 @@ impl<S: io::Read> Iterator for FwhtFrameParser<S> {
 }
 
 impl<S: io::Read> StreamSplitter for FwhtFrameParser<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = FwhtHeader {
+            version: FWHT_VERSION,
+            width: 640,
+            height: 480,
+            flags: FwhtFlags::I_FRAME
+                | FwhtFlags::CHROMA_FULL_WIDTH
+                | FwhtFlags::from_bits_retain((2 << 16) | (1 << 19)),
+            colorspace: 8,
+            xfer_func: 1,
+            ycbcr_enc: 1,
+            quantization: 2,
+            size: 1234,
+        };
+        let bytes = header.to_bytes();
+
+        assert!(bytes.starts_with(&FRAME_HEADER));
+        assert_eq!(FwhtHeader::parse(&bytes), Some(header));
+        assert_eq!(header.components_num(), 3);
+        assert_eq!(header.pixel_encoding(), Some(FwhtPixelEncoding::Yuv));
+        assert_eq!(header.chroma_subsampling(), (1, 2));
+        assert!(header.is_i_frame());
+        assert_eq!(FwhtHeader::parse(&bytes[..40]), None);
+    }
+}
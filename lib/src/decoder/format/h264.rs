@@ -1,44 +1,231 @@
-use super::{PatternSplitter, StreamSplitter};
+use super::{
+    nal::{AccessUnit, AccessUnitRules, AccessUnitSplitter, NalClass},
+    StreamSplitter,
+};
 use std::io;
 
-static H264_START_CODE: [u8; 4] = [0x0, 0x0, 0x0, 0x1];
+/// Profiles which SPS includes the chroma format and bit depths.
+pub(crate) const SPS_CHROMA_PROFILES: [u8; 13] =
+    [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
 
-/// Splits a H.264 annex B stream into chunks that are all guaranteed to contain a full frame
-/// worth of data.
+/// Type of a H.264 NAL unit, as defined in table 7-1 of the specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264NalType {
+    Slice,
+    SliceDataPartitionA,
+    SliceDataPartitionB,
+    SliceDataPartitionC,
+    SliceIdr,
+    Sei,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    EndOfSequence,
+    EndOfStream,
+    FillerData,
+    SpsExtension,
+    PrefixNal,
+    SubsetSps,
+    DepthParameterSet,
+    AuxiliarySlice,
+    SliceExtension,
+    SliceExtensionDepth,
+    /// Reserved or unspecified NAL unit type.
+    Other(u8),
+}
+
+impl From<u8> for H264NalType {
+    fn from(nal_unit_type: u8) -> Self {
+        match nal_unit_type {
+            1 => H264NalType::Slice,
+            2 => H264NalType::SliceDataPartitionA,
+            3 => H264NalType::SliceDataPartitionB,
+            4 => H264NalType::SliceDataPartitionC,
+            5 => H264NalType::SliceIdr,
+            6 => H264NalType::Sei,
+            7 => H264NalType::Sps,
+            8 => H264NalType::Pps,
+            9 => H264NalType::AccessUnitDelimiter,
+            10 => H264NalType::EndOfSequence,
+            11 => H264NalType::EndOfStream,
+            12 => H264NalType::FillerData,
+            13 => H264NalType::SpsExtension,
+            14 => H264NalType::PrefixNal,
+            15 => H264NalType::SubsetSps,
+            16 => H264NalType::DepthParameterSet,
+            19 => H264NalType::AuxiliarySlice,
+            20 => H264NalType::SliceExtension,
+            21 => H264NalType::SliceExtensionDepth,
+            t => H264NalType::Other(t),
+        }
+    }
+}
+
+impl H264NalType {
+    /// Whether this NAL unit contains the slice data of a primary coded picture.
+    pub fn is_vcl(self) -> bool {
+        matches!(
+            self,
+            H264NalType::Slice
+                | H264NalType::SliceDataPartitionA
+                | H264NalType::SliceDataPartitionB
+                | H264NalType::SliceDataPartitionC
+                | H264NalType::SliceIdr
+        )
+    }
+}
+
+/// Access unit delimitation rules of section 7.4.1.2.3 of the H.264 specification.
+struct H264Rules;
+
+impl AccessUnitRules for H264Rules {
+    type NalType = H264NalType;
+
+    fn nal_type(payload: &[u8]) -> H264NalType {
+        H264NalType::from(payload[0] & 0x1f)
+    }
+
+    fn classify(payload: &[u8]) -> NalClass {
+        match Self::nal_type(payload) {
+            // `first_mb_in_slice` is the first syntax element after the NAL unit header. It is
+            // coded as ue(v), so its value is 0 if and only if its first bit is set. Emulation
+            // prevention cannot apply to this byte since the header is never zero.
+            H264NalType::Slice | H264NalType::SliceDataPartitionA | H264NalType::SliceIdr => {
+                NalClass::Vcl {
+                    first_in_picture: payload.get(1).is_some_and(|b| b & 0x80 != 0),
+                }
+            }
+            H264NalType::SliceDataPartitionB | H264NalType::SliceDataPartitionC => NalClass::Vcl {
+                first_in_picture: false,
+            },
+            H264NalType::Sei
+            | H264NalType::Sps
+            | H264NalType::Pps
+            | H264NalType::AccessUnitDelimiter
+            | H264NalType::PrefixNal
+            | H264NalType::SubsetSps
+            | H264NalType::DepthParameterSet
+            | H264NalType::Other(17..=18) => NalClass::StartsAccessUnit,
+            H264NalType::EndOfSequence | H264NalType::EndOfStream => NalClass::EndsAccessUnit,
+            _ => NalClass::Other,
+        }
+    }
+}
+
+/// Splits a H.264 Annex-B stream into access units, i.e. chunks containing exactly one picture
+/// along with the parameter sets and SEI messages that precede it.
 ///
-/// This is a pretty naive implementation that is only guaranteed to work with our examples.
-pub struct H264FrameSplitter<S: io::Read>(PatternSplitter<S>);
+/// The stream is read incrementally. Pictures with arbitrary slice order are not supported.
+pub struct H264FrameSplitter<S: io::Read>(AccessUnitSplitter<S, H264Rules>);
 
 impl<S: io::Read> H264FrameSplitter<S> {
+    /// Create a new splitter for `stream`, which must start with a start code.
     pub fn new(stream: S) -> Option<Self> {
-        Some(Self(PatternSplitter::new(
-            H264_START_CODE.to_vec(),
-            stream,
-        )?))
+        Some(Self(AccessUnitSplitter::new(stream)?))
     }
 
-    fn contains_frame(data: &[u8]) -> bool {
-        data[4..].windows(4).any(|window| {
-            window[0..3] == [0x0, 0x0, 0x1] && (window[3] & 0x1f == 0x5 || window[3] & 0x1f == 0x1)
-        })
+    /// Returns the next access unit in the stream, along with the types of its NAL units.
+    pub fn next_access_unit(&mut self) -> Option<AccessUnit<H264NalType>> {
+        self.0.next_access_unit()
     }
 }
 
 impl<S: io::Read> Iterator for H264FrameSplitter<S> {
     type Item = Vec<u8>;
 
-    /// Returns the next frame in the stream, header included.
+    /// Returns the next access unit in the stream, start codes included.
     fn next(&mut self) -> Option<Self::Item> {
-        let mut next_slice = self.0.next()?;
-        while !Self::contains_frame(&next_slice) {
-            match self.0.next() {
-                None => return Some(next_slice),
-                Some(data) => next_slice.extend(data),
-            }
-        }
-
-        Some(next_slice)
+        self.next_access_unit().map(|access_unit| access_unit.data)
     }
 }
 
 impl<S: io::Read> StreamSplitter for H264FrameSplitter<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUD: [u8; 5] = [0, 0, 0, 1, 0x09];
+    const SPS: [u8; 5] = [0, 0, 0, 1, 0x67];
+    const PPS: [u8; 5] = [0, 0, 0, 1, 0x68];
+    const SEI: [u8; 5] = [0, 0, 0, 1, 0x06];
+    // IDR and non-IDR slices, with `first_mb_in_slice` set to 0 or not.
+    const IDR_FIRST: [u8; 6] = [0, 0, 1, 0x65, 0x88, 0x84];
+    const IDR_NEXT: [u8; 6] = [0, 0, 1, 0x65, 0x40, 0x84];
+    const SLICE_FIRST: [u8; 6] = [0, 0, 1, 0x41, 0x9a, 0x21];
+    const SLICE_NEXT: [u8; 6] = [0, 0, 1, 0x41, 0x20, 0x21];
+    const EOS: [u8; 4] = [0, 0, 1, 0x0a];
+
+    fn split(units: &[&[u8]]) -> Vec<AccessUnit<H264NalType>> {
+        let stream = units.concat();
+        let mut splitter = H264FrameSplitter::new(stream.as_slice()).unwrap();
+        std::iter::from_fn(|| splitter.next_access_unit()).collect()
+    }
+
+    #[test]
+    fn test_multi_slice_without_aud() {
+        let units = split(&[
+            &SPS,
+            &PPS,
+            &SEI,
+            &IDR_FIRST,
+            &IDR_NEXT,
+            &SLICE_FIRST,
+            &SLICE_NEXT,
+            &SLICE_NEXT,
+        ]);
+
+        assert_eq!(units.len(), 2);
+        assert_eq!(
+            units[0].nal_types,
+            vec![
+                H264NalType::Sps,
+                H264NalType::Pps,
+                H264NalType::Sei,
+                H264NalType::SliceIdr,
+                H264NalType::SliceIdr
+            ]
+        );
+        assert_eq!(
+            units[0].data,
+            [&SPS[..], &PPS, &SEI, &IDR_FIRST, &IDR_NEXT].concat()
+        );
+        assert_eq!(
+            units[1].nal_types,
+            vec![H264NalType::Slice, H264NalType::Slice, H264NalType::Slice]
+        );
+    }
+
+    #[test]
+    fn test_sei_before_slice() {
+        let units = split(&[&AUD, &IDR_FIRST, &SEI, &SLICE_FIRST, &AUD, &SLICE_FIRST]);
+
+        assert_eq!(
+            units
+                .iter()
+                .map(|unit| unit.nal_types.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![H264NalType::AccessUnitDelimiter, H264NalType::SliceIdr],
+                vec![H264NalType::Sei, H264NalType::Slice],
+                vec![H264NalType::AccessUnitDelimiter, H264NalType::Slice],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_end_of_sequence() {
+        let units = split(&[&SLICE_FIRST, &EOS, &SLICE_NEXT]);
+
+        assert_eq!(
+            units
+                .iter()
+                .map(|unit| unit.nal_types.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![H264NalType::Slice, H264NalType::EndOfSequence],
+                vec![H264NalType::Slice],
+            ]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use super::{
+    bitreader::BitReader,
+    nal::{AccessUnit, AccessUnitRules, AccessUnitSplitter, NalClass},
+    StreamSplitter,
+};
+use std::io;
+
+/// Read the `profile_tier_level` structure of a VPS or SPS with `max_sub_layers_minus1`
+/// sub-layers.
+///
+/// Returns the 12 bytes of the general profile, tier and level. The sub-layers information is
+/// skipped.
+pub(crate) fn read_profile_tier_level(
+    reader: &mut BitReader,
+    max_sub_layers_minus1: usize,
+) -> Option<[u8; 12]> {
+    let mut general_profile_tier_level = [0u8; 12];
+    for b in general_profile_tier_level.iter_mut() {
+        *b = reader.read_bits(8)? as u8;
+    }
+
+    let mut sub_layer_flags = Vec::with_capacity(max_sub_layers_minus1);
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = reader.read_bit()?;
+        let level_present = reader.read_bit()?;
+        sub_layer_flags.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        // reserved_zero_2bits
+        reader.skip_bits(2 * (8 - max_sub_layers_minus1))?;
+    }
+    for (profile_present, level_present) in sub_layer_flags {
+        if profile_present {
+            reader.skip_bits(88)?;
+        }
+        if level_present {
+            reader.skip_bits(8)?;
+        }
+    }
+
+    Some(general_profile_tier_level)
+}
+
+/// Type of a HEVC NAL unit, as defined in table 7-1 of the specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HevcNalType {
+    TrailN,
+    TrailR,
+    TsaN,
+    TsaR,
+    StsaN,
+    StsaR,
+    RadlN,
+    RadlR,
+    RaslN,
+    RaslR,
+    BlaWLp,
+    BlaWRadl,
+    BlaNLp,
+    IdrWRadl,
+    IdrNLp,
+    CraNut,
+    Vps,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    EndOfSequence,
+    EndOfBitstream,
+    FillerData,
+    PrefixSei,
+    SuffixSei,
+    /// Reserved or unspecified NAL unit type.
+    Other(u8),
+}
+
+impl From<u8> for HevcNalType {
+    fn from(nal_unit_type: u8) -> Self {
+        match nal_unit_type {
+            0 => HevcNalType::TrailN,
+            1 => HevcNalType::TrailR,
+            2 => HevcNalType::TsaN,
+            3 => HevcNalType::TsaR,
+            4 => HevcNalType::StsaN,
+            5 => HevcNalType::StsaR,
+            6 => HevcNalType::RadlN,
+            7 => HevcNalType::RadlR,
+            8 => HevcNalType::RaslN,
+            9 => HevcNalType::RaslR,
+            16 => HevcNalType::BlaWLp,
+            17 => HevcNalType::BlaWRadl,
+            18 => HevcNalType::BlaNLp,
+            19 => HevcNalType::IdrWRadl,
+            20 => HevcNalType::IdrNLp,
+            21 => HevcNalType::CraNut,
+            32 => HevcNalType::Vps,
+            33 => HevcNalType::Sps,
+            34 => HevcNalType::Pps,
+            35 => HevcNalType::AccessUnitDelimiter,
+            36 => HevcNalType::EndOfSequence,
+            37 => HevcNalType::EndOfBitstream,
+            38 => HevcNalType::FillerData,
+            39 => HevcNalType::PrefixSei,
+            40 => HevcNalType::SuffixSei,
+            t => HevcNalType::Other(t),
+        }
+    }
+}
+
+impl HevcNalType {
+    /// Whether this NAL unit contains a slice segment.
+    pub fn is_vcl(self) -> bool {
+        match self {
+            HevcNalType::Other(t) => t < 32,
+            HevcNalType::TrailN
+            | HevcNalType::TrailR
+            | HevcNalType::TsaN
+            | HevcNalType::TsaR
+            | HevcNalType::StsaN
+            | HevcNalType::StsaR
+            | HevcNalType::RadlN
+            | HevcNalType::RadlR
+            | HevcNalType::RaslN
+            | HevcNalType::RaslR => true,
+            t => t.is_irap(),
+        }
+    }
+
+    /// Whether this NAL unit contains a slice segment of an intra random access point picture,
+    /// i.e. a picture decoding can start from.
+    pub fn is_irap(self) -> bool {
+        matches!(
+            self,
+            HevcNalType::BlaWLp
+                | HevcNalType::BlaWRadl
+                | HevcNalType::BlaNLp
+                | HevcNalType::IdrWRadl
+                | HevcNalType::IdrNLp
+                | HevcNalType::CraNut
+                | HevcNalType::Other(22..=23)
+        )
+    }
+}
+
+/// Access unit delimitation rules of section 7.4.2.4.4 of the HEVC specification.
+struct HevcRules;
+
+impl AccessUnitRules for HevcRules {
+    type NalType = HevcNalType;
+
+    fn nal_type(payload: &[u8]) -> HevcNalType {
+        HevcNalType::from((payload[0] >> 1) & 0x3f)
+    }
+
+    fn classify(payload: &[u8]) -> NalClass {
+        let nal_unit_type = (payload[0] >> 1) & 0x3f;
+        let nuh_layer_id = payload
+            .get(1)
+            .map_or(0, |b| ((payload[0] & 0x1) << 5) | (b >> 3));
+
+        match nal_unit_type {
+            // `first_slice_segment_in_pic_flag` is the first bit after the 2 bytes NAL unit
+            // header. Emulation prevention cannot apply to this byte since the second byte of the
+            // header is never zero.
+            0..=31 => NalClass::Vcl {
+                first_in_picture: nuh_layer_id == 0
+                    && payload.get(2).is_some_and(|b| b & 0x80 != 0),
+            },
+            32..=35 | 39 | 41..=44 | 48..=55 => NalClass::StartsAccessUnit,
+            36 | 37 => NalClass::EndsAccessUnit,
+            _ => NalClass::Other,
+        }
+    }
+}
+
+/// Splits a HEVC Annex-B stream into access units, i.e. chunks containing exactly one picture
+/// along with the parameter sets and SEI messages that surround it.
+///
+/// The stream is read incrementally.
+pub struct HevcFrameSplitter<S: io::Read>(AccessUnitSplitter<S, HevcRules>);
+
+impl<S: io::Read> HevcFrameSplitter<S> {
+    /// Create a new splitter for `stream`, which must start with a start code.
+    pub fn new(stream: S) -> Option<Self> {
+        Some(Self(AccessUnitSplitter::new(stream)?))
+    }
+
+    /// Returns the next access unit in the stream, along with the types of its NAL units.
+    pub fn next_access_unit(&mut self) -> Option<AccessUnit<HevcNalType>> {
+        self.0.next_access_unit()
+    }
+}
+
+impl<S: io::Read> Iterator for HevcFrameSplitter<S> {
+    type Item = Vec<u8>;
+
+    /// Returns the next access unit in the stream, start codes included.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_access_unit().map(|access_unit| access_unit.data)
+    }
+}
+
+impl<S: io::Read> StreamSplitter for HevcFrameSplitter<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VPS: [u8; 6] = [0, 0, 0, 1, 0x40, 0x01];
+    const SPS: [u8; 6] = [0, 0, 0, 1, 0x42, 0x01];
+    const PPS: [u8; 6] = [0, 0, 0, 1, 0x44, 0x01];
+    const AUD: [u8; 7] = [0, 0, 0, 1, 0x46, 0x01, 0x50];
+    const PREFIX_SEI: [u8; 6] = [0, 0, 1, 0x4e, 0x01, 0x05];
+    const SUFFIX_SEI: [u8; 6] = [0, 0, 1, 0x50, 0x01, 0x05];
+    // IDR and trailing slice segments, with `first_slice_segment_in_pic_flag` set or not.
+    const IDR_FIRST: [u8; 6] = [0, 0, 1, 0x26, 0x01, 0xaf];
+    const IDR_NEXT: [u8; 6] = [0, 0, 1, 0x26, 0x01, 0x2f];
+    const TRAIL_FIRST: [u8; 6] = [0, 0, 1, 0x02, 0x01, 0xd0];
+    const TRAIL_NEXT: [u8; 6] = [0, 0, 1, 0x02, 0x01, 0x50];
+    const EOS: [u8; 5] = [0, 0, 1, 0x48, 0x01];
+
+    fn split(units: &[&[u8]]) -> Vec<Vec<HevcNalType>> {
+        let stream = units.concat();
+        let mut splitter = HevcFrameSplitter::new(stream.as_slice()).unwrap();
+        std::iter::from_fn(|| splitter.next_access_unit())
+            .map(|unit| unit.nal_types)
+            .collect()
+    }
+
+    #[test]
+    fn test_multi_slice() {
+        assert_eq!(
+            split(&[
+                &VPS,
+                &SPS,
+                &PPS,
+                &PREFIX_SEI,
+                &IDR_FIRST,
+                &IDR_NEXT,
+                &SUFFIX_SEI,
+                &TRAIL_FIRST,
+                &TRAIL_NEXT,
+                &AUD,
+                &TRAIL_FIRST,
+            ]),
+            vec![
+                vec![
+                    HevcNalType::Vps,
+                    HevcNalType::Sps,
+                    HevcNalType::Pps,
+                    HevcNalType::PrefixSei,
+                    HevcNalType::IdrWRadl,
+                    HevcNalType::IdrWRadl,
+                    HevcNalType::SuffixSei,
+                ],
+                vec![HevcNalType::TrailR, HevcNalType::TrailR],
+                vec![HevcNalType::AccessUnitDelimiter, HevcNalType::TrailR],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_end_of_sequence() {
+        assert_eq!(
+            split(&[&TRAIL_FIRST, &EOS, &TRAIL_NEXT]),
+            vec![
+                vec![HevcNalType::TrailR, HevcNalType::EndOfSequence],
+                vec![HevcNalType::TrailR],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nal_type_properties() {
+        assert!(HevcNalType::TrailN.is_vcl());
+        assert!(HevcNalType::CraNut.is_vcl());
+        assert!(HevcNalType::Other(24).is_vcl());
+        assert!(!HevcNalType::Vps.is_vcl());
+        assert!(!HevcNalType::Other(41).is_vcl());
+        assert!(HevcNalType::IdrNLp.is_irap());
+        assert!(!HevcNalType::RaslN.is_irap());
+    }
+}
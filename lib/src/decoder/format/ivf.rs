@@ -0,0 +1,391 @@
+//! Reader and writer for the IVF container, commonly used to store VP8, VP9 and AV1 streams.
+//!
+//! An IVF file starts with a 32 bytes header describing the stream, followed by the frames, each
+//! preceded by a 12 bytes header containing its size and presentation timestamp. All values are
+//! little-endian.
+use super::{timestamp_to_us, StreamSplitter, TimestampedChunk};
+use crate::PixelFormat;
+use log::error;
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, SeekFrom},
+};
+use thiserror::Error;
+
+const SIGNATURE: [u8; 4] = *b"DKIF";
+const VERSION: u16 = 0;
+const HEADER_SIZE: u16 = 32;
+const FRAME_HEADER_SIZE: usize = 12;
+/// Offset of the number of frames in the file header.
+const NUM_FRAMES_OFFSET: u64 = 24;
+/// Frames larger than this are considered corrupted rather than allocated.
+const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Codecs that can be stored into an IVF container, identified by the fourcc of their header.
+/// VP8 and VP9 use the same fourcc as their V4L2 pixel format.
+pub const SUPPORTED_FOURCCS: [PixelFormat; 3] = [
+    PixelFormat::VP8,
+    PixelFormat::VP9,
+    PixelFormat::from_fourcc(b"AV01"),
+];
+
+#[derive(Debug, Error)]
+pub enum IvfError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid IVF signature")]
+    InvalidSignature,
+    #[error("unsupported IVF version {0}")]
+    UnsupportedVersion(u16),
+    #[error("invalid IVF header size {0}")]
+    InvalidHeaderSize(u16),
+    #[error("unsupported codec {0}")]
+    UnsupportedFourcc(PixelFormat),
+    #[error("invalid dimensions {0}x{1}")]
+    InvalidDimensions(u16, u16),
+    #[error("invalid time base {0}/{1}")]
+    InvalidTimebase(u32, u32),
+    #[error("frame of {0} bytes is too large")]
+    FrameTooLarge(u32),
+    #[error("stream ends in the middle of a frame")]
+    TruncatedFrame,
+}
+
+/// Description of the stream contained in an IVF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IvfHeader {
+    /// Codec of the stream.
+    pub fourcc: PixelFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Unit of the frames timestamps, in seconds, as a `(numerator, denominator)` fraction.
+    pub timebase: (u32, u32),
+    /// Number of frames in the file. Some writers leave this to 0.
+    pub num_frames: u32,
+}
+
+impl IvfHeader {
+    fn parse(data: &[u8; HEADER_SIZE as usize]) -> Result<Self, IvfError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+
+        if data[0..4] != SIGNATURE {
+            return Err(IvfError::InvalidSignature);
+        }
+        match u16_at(4) {
+            VERSION => (),
+            version => return Err(IvfError::UnsupportedVersion(version)),
+        }
+        match u16_at(6) {
+            HEADER_SIZE => (),
+            size => return Err(IvfError::InvalidHeaderSize(size)),
+        }
+
+        let header = IvfHeader {
+            fourcc: PixelFormat::from_fourcc(&[data[8], data[9], data[10], data[11]]),
+            width: u16_at(12),
+            height: u16_at(14),
+            // The denominator comes first.
+            timebase: (u32_at(20), u32_at(16)),
+            num_frames: u32_at(24),
+        };
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<(), IvfError> {
+        if !SUPPORTED_FOURCCS.contains(&self.fourcc) {
+            return Err(IvfError::UnsupportedFourcc(self.fourcc));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(IvfError::InvalidDimensions(self.width, self.height));
+        }
+        if self.timebase.0 == 0 || self.timebase.1 == 0 {
+            return Err(IvfError::InvalidTimebase(self.timebase.0, self.timebase.1));
+        }
+
+        Ok(())
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE as usize] {
+        let mut data = [0u8; HEADER_SIZE as usize];
+        data[0..4].copy_from_slice(&SIGNATURE);
+        data[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        data[6..8].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        data[8..12].copy_from_slice(&u32::from(self.fourcc).to_le_bytes());
+        data[12..14].copy_from_slice(&self.width.to_le_bytes());
+        data[14..16].copy_from_slice(&self.height.to_le_bytes());
+        data[16..20].copy_from_slice(&self.timebase.1.to_le_bytes());
+        data[20..24].copy_from_slice(&self.timebase.0.to_le_bytes());
+        data[24..28].copy_from_slice(&self.num_frames.to_le_bytes());
+
+        data
+    }
+}
+
+/// A frame read from an IVF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IvfFrame {
+    /// Presentation timestamp of the frame, in units of the header's time base.
+    pub pts: u64,
+    pub data: Vec<u8>,
+}
+
+/// Splits an IVF file into its frames.
+///
+/// Iterating over the reader returns the frames data only. Use [`IvfReader::next_frame`] or
+/// [`StreamSplitter::next_timestamped`] to also obtain their presentation timestamp.
+pub struct IvfReader<S: io::Read> {
+    stream: S,
+    header: IvfHeader,
+}
+
+impl<S: io::Read> IvfReader<S> {
+    /// Create a new reader for `stream`, after reading and validating its header.
+    pub fn new(mut stream: S) -> Result<Self, IvfError> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        stream.read_exact(&mut header)?;
+        let header = IvfHeader::parse(&header)?;
+
+        Ok(IvfReader { stream, header })
+    }
+
+    pub fn header(&self) -> &IvfHeader {
+        &self.header
+    }
+
+    /// Returns the next frame of the stream, or `None` if the end of the stream is reached.
+    pub fn next_frame(&mut self) -> Result<Option<IvfFrame>, IvfError> {
+        let mut frame_header = [0u8; FRAME_HEADER_SIZE];
+        // A stream ending right before a frame header is not truncated.
+        match self.stream.read(&mut frame_header[0..1])? {
+            0 => return Ok(None),
+            _ => self
+                .stream
+                .read_exact(&mut frame_header[1..])
+                .map_err(truncated)?,
+        }
+
+        let size = u32::from_le_bytes(frame_header[0..4].try_into().unwrap());
+        let pts = u64::from_le_bytes(frame_header[4..12].try_into().unwrap());
+        if size > MAX_FRAME_SIZE {
+            return Err(IvfError::FrameTooLarge(size));
+        }
+
+        let mut data = vec![0u8; size as usize];
+        self.stream.read_exact(&mut data).map_err(truncated)?;
+
+        Ok(Some(IvfFrame { pts, data }))
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+fn truncated(e: io::Error) -> IvfError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => IvfError::TruncatedFrame,
+        _ => IvfError::Io(e),
+    }
+}
+
+impl<S: io::Read> Iterator for IvfReader<S> {
+    type Item = Vec<u8>;
+
+    /// Returns the data of the next frame in the stream.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_frame() {
+            Ok(frame) => frame.map(|frame| frame.data),
+            Err(e) => {
+                error!("Error while reading IVF stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<S: io::Read> StreamSplitter for IvfReader<S> {
+    fn next_timestamped(&mut self) -> Option<TimestampedChunk> {
+        let (num, den) = self.header.timebase;
+        match self.next_frame() {
+            Ok(frame) => frame.map(|frame| TimestampedChunk {
+                timestamp_us: i64::try_from(frame.pts)
+                    .ok()
+                    .and_then(|pts| timestamp_to_us(pts, num, den)),
+                data: frame.data,
+            }),
+            Err(e) => {
+                error!("Error while reading IVF stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Writes frames into an IVF file.
+pub struct IvfWriter<W: io::Write> {
+    writer: W,
+    num_frames: u32,
+}
+
+impl<W: io::Write> IvfWriter<W> {
+    /// Create a new writer, and write the file header to `writer`.
+    ///
+    /// The `num_frames` member of `header` is ignored. Use [`IvfWriter::finish`] to update it
+    /// once all the frames are written if `writer` is seekable.
+    pub fn new(mut writer: W, header: IvfHeader) -> Result<Self, IvfError> {
+        header.validate()?;
+        writer.write_all(
+            &IvfHeader {
+                num_frames: 0,
+                ..header
+            }
+            .to_bytes(),
+        )?;
+
+        Ok(IvfWriter {
+            writer,
+            num_frames: 0,
+        })
+    }
+
+    /// Write a frame of `data` with presentation timestamp `pts`, in units of the header's time
+    /// base.
+    pub fn write_frame(&mut self, data: &[u8], pts: u64) -> Result<(), IvfError> {
+        let size = u32::try_from(data.len()).unwrap_or(u32::MAX);
+        if size > MAX_FRAME_SIZE {
+            return Err(IvfError::FrameTooLarge(size));
+        }
+
+        self.writer.write_all(&size.to_le_bytes())?;
+        self.writer.write_all(&pts.to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.num_frames += 1;
+
+        Ok(())
+    }
+
+    /// Returns the number of frames written so far.
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<(), IvfError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the underlying writer. The number of frames in the header is left to 0.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: io::Write + io::Seek> IvfWriter<W> {
+    /// Update the number of frames in the header and return the underlying writer, positioned at
+    /// the end of the file.
+    pub fn finish(mut self) -> Result<W, IvfError> {
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(NUM_FRAMES_OFFSET))?;
+        self.writer.write_all(&self.num_frames.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const HEADER: IvfHeader = IvfHeader {
+        fourcc: PixelFormat::VP9,
+        width: 352,
+        height: 288,
+        timebase: (1, 30),
+        num_frames: 0,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = IvfWriter::new(Cursor::new(Vec::new()), HEADER).unwrap();
+        writer.write_frame(&[1, 2, 3], 0).unwrap();
+        writer.write_frame(&[4, 5], 1).unwrap();
+        let file = writer.finish().unwrap().into_inner();
+        assert_eq!(file.len(), 32 + 12 + 3 + 12 + 2);
+
+        let mut reader = IvfReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(
+            reader.header(),
+            &IvfHeader {
+                num_frames: 2,
+                ..HEADER
+            }
+        );
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(IvfFrame {
+                pts: 0,
+                data: vec![1, 2, 3]
+            })
+        );
+        assert_eq!(
+            reader.next_timestamped(),
+            Some(TimestampedChunk {
+                data: vec![4, 5],
+                timestamp_us: Some(33_333),
+            })
+        );
+        assert_eq!(reader.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut header = HEADER.to_bytes();
+        header[0] = b'X';
+        assert!(matches!(
+            IvfReader::new(Cursor::new(header)),
+            Err(IvfError::InvalidSignature)
+        ));
+
+        let header = IvfHeader {
+            fourcc: PixelFormat::H264,
+            ..HEADER
+        };
+        assert!(matches!(
+            IvfReader::new(Cursor::new(header.to_bytes())),
+            Err(IvfError::UnsupportedFourcc(_))
+        ));
+
+        let header = IvfHeader { width: 0, ..HEADER };
+        assert!(matches!(
+            IvfReader::new(Cursor::new(header.to_bytes())),
+            Err(IvfError::InvalidDimensions(0, 288))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut file = HEADER.to_bytes().to_vec();
+        file.extend_from_slice(&10u32.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+
+        let mut reader = IvfReader::new(Cursor::new(file)).unwrap();
+        assert!(matches!(reader.next_frame(), Err(IvfError::TruncatedFrame)));
+    }
+}
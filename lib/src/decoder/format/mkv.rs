@@ -0,0 +1,821 @@
+//! Streaming demuxer for the Matroska and WebM containers.
+//!
+//! The file is read sequentially and never needs to be seekable. Elements are identified by
+//! their EBML ID: the `Segment` and `Cluster` elements are entered as soon as they are met, which
+//! allows them to have an unknown size as produced by live recorders, while all the elements we
+//! have no use for are skipped.
+//!
+//! Frames of the selected video track are returned in storage order, i.e. decoding order. AV1
+//! configuration OBUs found in the track's `CodecPrivate` are prepended to the first frame.
+use super::{StreamSplitter, TimestampedChunk};
+use crate::PixelFormat;
+use log::error;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{self, Read},
+};
+use thiserror::Error;
+
+const EBML_HEADER: u32 = 0x1a45_dfa3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_a966;
+const TIMESTAMP_SCALE: u32 = 0x2a_d7b1;
+const TRACKS: u32 = 0x1654_ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const CLUSTER: u32 = 0x1f43_b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const BLOCK_GROUP: u32 = 0xa0;
+const BLOCK: u32 = 0xa1;
+const REFERENCE_BLOCK: u32 = 0xfb;
+
+/// Value of the `TrackType` element for video tracks.
+const TRACK_TYPE_VIDEO: u64 = 1;
+/// Timestamps are in milliseconds unless the file specifies otherwise.
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+/// Elements larger than this are considered corrupted rather than read into memory.
+const MAX_ELEMENT_SIZE: u64 = 64 * 1024 * 1024;
+/// Size of the fixed part of the `CodecPrivate` of AV1 tracks, which precedes the configuration
+/// OBUs.
+const AV1_CONFIG_HEADER_SIZE: usize = 4;
+
+/// Matroska codec IDs we can demux, along with the pixel format of their stream. AV1 uses the same
+/// fourcc as in IVF files.
+const SUPPORTED_CODECS: [(&str, PixelFormat); 3] = [
+    ("V_VP8", PixelFormat::VP8),
+    ("V_VP9", PixelFormat::VP9),
+    ("V_AV1", PixelFormat::from_fourcc(b"AV01")),
+];
+
+#[derive(Debug, Error)]
+pub enum MkvError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("not an EBML file")]
+    NotEbml,
+    #[error("unsupported document type {0}")]
+    UnsupportedDocType(String),
+    #[error("invalid variable-size integer")]
+    InvalidVint,
+    #[error("invalid or truncated element {0:#x}")]
+    InvalidElement(u32),
+    #[error("element {0:#x} of {1} bytes is too large")]
+    ElementTooLarge(u32, u64),
+    #[error("element {0:#x} has an unknown size")]
+    UnknownSize(u32),
+    #[error("stream ends in the middle of an element")]
+    TruncatedElement,
+    #[error("no Tracks element found")]
+    MissingTracks,
+    #[error("no supported video track found")]
+    NoVideoTrack,
+    #[error("track {0} does not exist or is not a supported video track")]
+    UnsupportedTrack(u64),
+    #[error("invalid lacing in block")]
+    InvalidLacing,
+    #[error("block timestamp does not fit in 64 bits")]
+    TimestampOverflow,
+}
+
+/// Returns the length of a variable-size integer starting with `first`, which cannot be larger
+/// than `max_len`.
+fn vint_len(first: u8, max_len: usize) -> Result<usize, MkvError> {
+    match first.leading_zeros() as usize + 1 {
+        len if len <= max_len => Ok(len),
+        _ => Err(MkvError::InvalidVint),
+    }
+}
+
+/// Decode an element ID. IDs keep their length marker.
+fn decode_id(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |id, b| (id << 8) | *b as u32)
+}
+
+/// Decode a variable-size integer, removing its length marker. Returns `None` if all the bits of
+/// its value are set, which means "unknown" for element sizes.
+fn decode_vint(bytes: &[u8]) -> Option<u64> {
+    let len = bytes.len();
+    let value = bytes[1..]
+        .iter()
+        .fold(bytes[0] as u64 & (0xff >> len), |value, b| {
+            (value << 8) | *b as u64
+        });
+
+    if value == (1 << (7 * len)) - 1 {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Read a variable-size integer of at most `max_len` bytes from the start of `data`.
+fn read_vint<'a>(data: &mut &'a [u8], max_len: usize) -> Result<&'a [u8], MkvError> {
+    let len = vint_len(*data.first().ok_or(MkvError::InvalidVint)?, max_len)?;
+    if len > data.len() {
+        return Err(MkvError::InvalidVint);
+    }
+    let (vint, rest) = data.split_at(len);
+    *data = rest;
+
+    Ok(vint)
+}
+
+/// Iterates over the elements contained in `data`, returning their ID and content.
+struct Elements<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Elements<'a> {
+    fn read_element(&mut self) -> Result<(u32, &'a [u8]), MkvError> {
+        let id = decode_id(read_vint(&mut self.data, 4)?);
+        let size = decode_vint(read_vint(&mut self.data, 8)?).ok_or(MkvError::UnknownSize(id))?;
+        if size > self.data.len() as u64 {
+            return Err(MkvError::InvalidElement(id));
+        }
+        let (content, rest) = self.data.split_at(size as usize);
+        self.data = rest;
+
+        Ok((id, content))
+    }
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Result<(u32, &'a [u8]), MkvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let res = self.read_element();
+        if res.is_err() {
+            self.data = &[];
+        }
+
+        Some(res)
+    }
+}
+
+fn elements(data: &[u8]) -> Elements<'_> {
+    Elements { data }
+}
+
+/// Decode the content of an unsigned integer element.
+fn parse_uint(id: u32, data: &[u8]) -> Result<u64, MkvError> {
+    if data.len() > 8 {
+        return Err(MkvError::InvalidElement(id));
+    }
+
+    Ok(data.iter().fold(0, |value, b| (value << 8) | *b as u64))
+}
+
+/// A track of a Matroska file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MkvTrack {
+    pub number: u64,
+    /// Type of the track, as defined by the Matroska `TrackType` element. Video tracks are of
+    /// type 1.
+    pub track_type: u64,
+    /// Matroska codec ID of the track, e.g. `V_VP9`.
+    pub codec_id: String,
+    pub codec_private: Vec<u8>,
+    /// Pixel format of the stream if this is a video track which codec is supported.
+    pub fourcc: Option<PixelFormat>,
+    pub width: u64,
+    pub height: u64,
+}
+
+impl MkvTrack {
+    fn parse(data: &[u8]) -> Result<Self, MkvError> {
+        let mut track = MkvTrack {
+            number: 0,
+            track_type: 0,
+            codec_id: String::new(),
+            codec_private: Vec::new(),
+            fourcc: None,
+            width: 0,
+            height: 0,
+        };
+
+        for element in elements(data) {
+            match element? {
+                (TRACK_NUMBER, data) => track.number = parse_uint(TRACK_NUMBER, data)?,
+                (TRACK_TYPE, data) => track.track_type = parse_uint(TRACK_TYPE, data)?,
+                (CODEC_ID, data) => {
+                    // Strings may be padded with zero bytes.
+                    track.codec_id = String::from_utf8_lossy(data)
+                        .trim_end_matches('\0')
+                        .to_string()
+                }
+                (CODEC_PRIVATE, data) => track.codec_private = data.to_vec(),
+                (VIDEO, data) => {
+                    for element in elements(data) {
+                        match element? {
+                            (PIXEL_WIDTH, data) => track.width = parse_uint(PIXEL_WIDTH, data)?,
+                            (PIXEL_HEIGHT, data) => track.height = parse_uint(PIXEL_HEIGHT, data)?,
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if track.track_type == TRACK_TYPE_VIDEO {
+            track.fourcc = SUPPORTED_CODECS
+                .iter()
+                .find(|(codec_id, _)| *codec_id == track.codec_id)
+                .map(|(_, fourcc)| *fourcc);
+        }
+
+        Ok(track)
+    }
+
+    /// Returns the data to prepend to the first frame of the track for the decoder to be
+    /// configured.
+    fn codec_config(&self) -> Option<Vec<u8>> {
+        match self.codec_id.as_str() {
+            "V_AV1" if self.codec_private.len() > AV1_CONFIG_HEADER_SIZE => {
+                Some(self.codec_private[AV1_CONFIG_HEADER_SIZE..].to_vec())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A frame read from a Matroska file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MkvFrame {
+    /// Presentation timestamp of the frame, in nanoseconds. All the frames of a laced block share
+    /// the timestamp of the block.
+    pub timestamp_ns: i64,
+    /// Whether decoding can start from this frame.
+    pub is_keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Content of a `Block` or `SimpleBlock` element.
+struct Block<'a> {
+    track_number: u64,
+    /// Timestamp of the block, relative to the one of its cluster.
+    timestamp: i16,
+    flags: u8,
+    frames: Vec<&'a [u8]>,
+}
+
+impl<'a> Block<'a> {
+    fn parse(id: u32, mut data: &'a [u8]) -> Result<Self, MkvError> {
+        let track_number =
+            decode_vint(read_vint(&mut data, 8)?).ok_or(MkvError::InvalidElement(id))?;
+        if data.len() < 3 {
+            return Err(MkvError::InvalidElement(id));
+        }
+        let timestamp = i16::from_be_bytes([data[0], data[1]]);
+        let flags = data[2];
+        data = &data[3..];
+
+        let frames = match (flags >> 1) & 0x3 {
+            0 => vec![data],
+            lacing => Self::split_laced_frames(lacing, data)?,
+        };
+
+        Ok(Block {
+            track_number,
+            timestamp,
+            flags,
+            frames,
+        })
+    }
+
+    /// Split the content of a block using the Xiph (1), fixed-size (2) or EBML (3) `lacing`.
+    fn split_laced_frames(lacing: u8, mut data: &[u8]) -> Result<Vec<&[u8]>, MkvError> {
+        let num_frames = *data.first().ok_or(MkvError::InvalidLacing)? as usize + 1;
+        data = &data[1..];
+
+        // Sizes of all the frames but the last one, which takes the rest of the block.
+        let mut sizes = Vec::with_capacity(num_frames);
+        match lacing {
+            1 => {
+                for _ in 0..num_frames - 1 {
+                    let mut size = 0;
+                    loop {
+                        let (b, rest) = data.split_first().ok_or(MkvError::InvalidLacing)?;
+                        data = rest;
+                        size += *b as usize;
+                        if *b != 0xff {
+                            break;
+                        }
+                    }
+                    sizes.push(size);
+                }
+            }
+            2 => {
+                if !data.len().is_multiple_of(num_frames) {
+                    return Err(MkvError::InvalidLacing);
+                }
+                sizes.resize(num_frames - 1, data.len() / num_frames);
+            }
+            _ => {
+                let vint = |data: &mut &[u8]| {
+                    let vint = read_vint(data, 8).map_err(|_| MkvError::InvalidLacing)?;
+                    decode_vint(vint)
+                        .map(|value| (value, vint.len()))
+                        .ok_or(MkvError::InvalidLacing)
+                };
+                if num_frames > 1 {
+                    let (mut size, _) = vint(&mut data)?;
+                    sizes.push(size as usize);
+                    // Following sizes are coded as signed differences with the previous one.
+                    for _ in 0..num_frames - 2 {
+                        let (value, len) = vint(&mut data)?;
+                        let bias = (1i64 << (7 * len - 1)) - 1;
+                        let new_size = size as i64 + value as i64 - bias;
+                        if new_size < 0 {
+                            return Err(MkvError::InvalidLacing);
+                        }
+                        size = new_size as u64;
+                        sizes.push(size as usize);
+                    }
+                }
+            }
+        }
+
+        let mut frames = Vec::with_capacity(num_frames);
+        for size in sizes {
+            if size > data.len() {
+                return Err(MkvError::InvalidLacing);
+            }
+            let (frame, rest) = data.split_at(size);
+            frames.push(frame);
+            data = rest;
+        }
+        frames.push(data);
+
+        Ok(frames)
+    }
+}
+
+/// Reads the frames of a video track of a Matroska or WebM file.
+///
+/// The first supported video track is selected by default. Iterating over the reader returns the
+/// frames data only. Use [`MkvReader::next_frame`] to also obtain their timestamp.
+pub struct MkvReader<S: io::Read> {
+    stream: S,
+    tracks: Vec<MkvTrack>,
+    /// Index of the selected track in `tracks`.
+    selected_track: usize,
+    /// Data to prepend to the next frame.
+    codec_config: Option<Vec<u8>>,
+    /// Duration of a timestamp unit, in nanoseconds.
+    timestamp_scale: u64,
+    /// Timestamp of the current cluster.
+    cluster_timestamp: u64,
+    /// Frames read from the last block and not returned yet.
+    pending_frames: VecDeque<MkvFrame>,
+}
+
+impl<S: io::Read> MkvReader<S> {
+    /// Create a new reader for `stream`, reading until the track descriptions are found.
+    pub fn new(stream: S) -> Result<Self, MkvError> {
+        let mut reader = MkvReader {
+            stream,
+            tracks: Vec::new(),
+            selected_track: 0,
+            codec_config: None,
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            cluster_timestamp: 0,
+            pending_frames: VecDeque::new(),
+        };
+
+        match reader.read_element_header()? {
+            Some((EBML_HEADER, Some(size))) => {
+                let header = reader.read_element(EBML_HEADER, size)?;
+                for element in elements(&header) {
+                    if let (DOC_TYPE, data) = element? {
+                        let doc_type = String::from_utf8_lossy(data)
+                            .trim_end_matches('\0')
+                            .to_string();
+                        if doc_type != "webm" && doc_type != "matroska" {
+                            return Err(MkvError::UnsupportedDocType(doc_type));
+                        }
+                    }
+                }
+            }
+            _ => return Err(MkvError::NotEbml),
+        }
+
+        while reader.tracks.is_empty() {
+            if !reader.process_element()? {
+                return Err(MkvError::MissingTracks);
+            }
+        }
+
+        reader.selected_track = reader
+            .tracks
+            .iter()
+            .position(|track| track.fourcc.is_some())
+            .ok_or(MkvError::NoVideoTrack)?;
+        reader.codec_config = reader.track().codec_config();
+
+        Ok(reader)
+    }
+
+    /// Returns all the tracks of the file.
+    pub fn tracks(&self) -> &[MkvTrack] {
+        &self.tracks
+    }
+
+    /// Returns the track which frames are returned.
+    pub fn track(&self) -> &MkvTrack {
+        &self.tracks[self.selected_track]
+    }
+
+    /// Select the video track which frames should be returned from now on.
+    pub fn select_track(&mut self, number: u64) -> Result<(), MkvError> {
+        self.selected_track = self
+            .tracks
+            .iter()
+            .position(|track| track.number == number && track.fourcc.is_some())
+            .ok_or(MkvError::UnsupportedTrack(number))?;
+        self.codec_config = self.track().codec_config();
+        self.pending_frames.clear();
+
+        Ok(())
+    }
+
+    /// Returns the next frame of the selected track, or `None` if the end of the stream is
+    /// reached.
+    pub fn next_frame(&mut self) -> Result<Option<MkvFrame>, MkvError> {
+        loop {
+            if let Some(mut frame) = self.pending_frames.pop_front() {
+                if let Some(mut codec_config) = self.codec_config.take() {
+                    codec_config.extend_from_slice(&frame.data);
+                    frame.data = codec_config;
+                }
+                return Ok(Some(frame));
+            }
+
+            if !self.process_element()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Read an element ID and size. Returns `None` if the end of the stream is reached, and a
+    /// `None` size if the element has an unknown size.
+    #[allow(clippy::type_complexity)]
+    fn read_element_header(&mut self) -> Result<Option<(u32, Option<u64>)>, MkvError> {
+        let mut first = [0u8];
+        if self.stream.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let id = self.read_vint_rest(first[0], 4)?;
+        let id = decode_id(&id);
+
+        self.stream.read_exact(&mut first).map_err(truncated)?;
+        let size = self.read_vint_rest(first[0], 8)?;
+
+        Ok(Some((id, decode_vint(&size))))
+    }
+
+    /// Read the remaining bytes of the variable-size integer starting with `first`.
+    fn read_vint_rest(&mut self, first: u8, max_len: usize) -> Result<Vec<u8>, MkvError> {
+        let mut vint = vec![0u8; vint_len(first, max_len)?];
+        vint[0] = first;
+        self.stream.read_exact(&mut vint[1..]).map_err(truncated)?;
+
+        Ok(vint)
+    }
+
+    fn read_element(&mut self, id: u32, size: u64) -> Result<Vec<u8>, MkvError> {
+        if size > MAX_ELEMENT_SIZE {
+            return Err(MkvError::ElementTooLarge(id, size));
+        }
+        let mut data = vec![0u8; size as usize];
+        self.stream.read_exact(&mut data).map_err(truncated)?;
+
+        Ok(data)
+    }
+
+    /// Read the next element of the stream and update our state according to it. Returns `false`
+    /// if the end of the stream is reached.
+    fn process_element(&mut self) -> Result<bool, MkvError> {
+        let (id, size) = match self.read_element_header()? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+
+        // The content of these elements is processed as if they were top-level elements, so their
+        // size does not matter.
+        if id == SEGMENT || id == CLUSTER {
+            return Ok(true);
+        }
+        let size = size.ok_or(MkvError::UnknownSize(id))?;
+
+        match id {
+            INFO => {
+                for element in elements(&self.read_element(id, size)?) {
+                    if let (TIMESTAMP_SCALE, data) = element? {
+                        self.timestamp_scale = parse_uint(TIMESTAMP_SCALE, data)?;
+                    }
+                }
+            }
+            TRACKS => {
+                for element in elements(&self.read_element(id, size)?) {
+                    if let (TRACK_ENTRY, data) = element? {
+                        self.tracks.push(MkvTrack::parse(data)?);
+                    }
+                }
+            }
+            TIMESTAMP => self.cluster_timestamp = parse_uint(id, &self.read_element(id, size)?)?,
+            SIMPLE_BLOCK => {
+                let data = self.read_element(id, size)?;
+                let block = Block::parse(id, &data)?;
+                let is_keyframe = block.flags & 0x80 != 0;
+                self.queue_frames(block, is_keyframe)?;
+            }
+            BLOCK_GROUP => {
+                let data = self.read_element(id, size)?;
+                let mut block = None;
+                // Blocks referencing no other block are keyframes.
+                let mut is_keyframe = true;
+                for element in elements(&data) {
+                    match element? {
+                        (BLOCK, data) => block = Some(Block::parse(BLOCK, data)?),
+                        (REFERENCE_BLOCK, _) => is_keyframe = false,
+                        _ => (),
+                    }
+                }
+                if let Some(block) = block {
+                    self.queue_frames(block, is_keyframe)?;
+                }
+            }
+            _ => {
+                io::copy(&mut self.stream.by_ref().take(size), &mut io::sink())?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Queue the frames of `block` if it belongs to the selected track.
+    fn queue_frames(&mut self, block: Block, is_keyframe: bool) -> Result<(), MkvError> {
+        match self.tracks.get(self.selected_track) {
+            Some(track) if track.number == block.track_number => (),
+            _ => return Ok(()),
+        }
+
+        let timestamp_ns = i64::try_from(self.cluster_timestamp)
+            .ok()
+            .and_then(|ts| ts.checked_add(block.timestamp as i64))
+            .zip(i64::try_from(self.timestamp_scale).ok())
+            .and_then(|(ts, scale)| ts.checked_mul(scale))
+            .ok_or(MkvError::TimestampOverflow)?;
+        self.pending_frames
+            .extend(block.frames.into_iter().map(|frame| MkvFrame {
+                timestamp_ns,
+                is_keyframe,
+                data: frame.to_vec(),
+            }));
+
+        Ok(())
+    }
+}
+
+fn truncated(e: io::Error) -> MkvError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => MkvError::TruncatedElement,
+        _ => MkvError::Io(e),
+    }
+}
+
+impl<S: io::Read> Iterator for MkvReader<S> {
+    type Item = Vec<u8>;
+
+    /// Returns the data of the next frame of the selected track.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_frame() {
+            Ok(frame) => frame.map(|frame| frame.data),
+            Err(e) => {
+                error!("Error while reading Matroska stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<S: io::Read> StreamSplitter for MkvReader<S> {
+    fn next_timestamped(&mut self) -> Option<TimestampedChunk> {
+        match self.next_frame() {
+            Ok(frame) => frame.map(|frame| TimestampedChunk {
+                timestamp_us: Some(frame.timestamp_ns / 1000),
+                data: frame.data,
+            }),
+            Err(e) => {
+                error!("Error while reading Matroska stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode an element, with its size coded on 8 bytes.
+    fn element(id: u32, content: &[&[u8]]) -> Vec<u8> {
+        let content = content.concat();
+        let id = id.to_be_bytes();
+        let id = &id[id.iter().position(|b| *b != 0).unwrap()..];
+        let mut size = (content.len() as u64).to_be_bytes();
+        size[0] = 0x01;
+
+        [id, &size, &content].concat()
+    }
+
+    /// Encode an element of unknown size.
+    fn unknown_size_element(id: u32) -> Vec<u8> {
+        [&id.to_be_bytes()[..], &[0xff]].concat()
+    }
+
+    fn webm_file() -> Vec<u8> {
+        let ebml_header = element(EBML_HEADER, &[&element(DOC_TYPE, &[b"webm"])]);
+        let info = element(INFO, &[&element(TIMESTAMP_SCALE, &[&[0x0f, 0x42, 0x40]])]);
+        let tracks = element(
+            TRACKS,
+            &[
+                &element(
+                    TRACK_ENTRY,
+                    &[
+                        &element(TRACK_NUMBER, &[&[1]]),
+                        &element(TRACK_TYPE, &[&[2]]),
+                        &element(CODEC_ID, &[b"A_OPUS"]),
+                    ],
+                ),
+                &element(
+                    TRACK_ENTRY,
+                    &[
+                        &element(TRACK_NUMBER, &[&[2]]),
+                        &element(TRACK_TYPE, &[&[1]]),
+                        &element(CODEC_ID, &[b"V_AV1"]),
+                        &element(CODEC_PRIVATE, &[&[0x81, 0, 0, 0, 0x0a, 0x01, 0x00]]),
+                        &element(
+                            VIDEO,
+                            &[
+                                &element(PIXEL_WIDTH, &[&[0x01, 0x40]]),
+                                &element(PIXEL_HEIGHT, &[&[0xf0]]),
+                            ],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        let cluster = [
+            unknown_size_element(CLUSTER),
+            element(TIMESTAMP, &[&[100]]),
+            // Keyframe of the video track.
+            element(SIMPLE_BLOCK, &[&[0x82, 0, 0, 0x80, 1, 2]]),
+            // Audio frame.
+            element(SIMPLE_BLOCK, &[&[0x81, 0, 0, 0x80, 9, 9]]),
+            element(
+                BLOCK_GROUP,
+                &[
+                    &element(BLOCK, &[&[0x82, 0, 10, 0, 3]]),
+                    &element(REFERENCE_BLOCK, &[&[0xf6]]),
+                ],
+            ),
+            // Two frames of 2 and 1 bytes with Xiph lacing.
+            element(SIMPLE_BLOCK, &[&[0x82, 0, 20, 0x02, 1, 2, 4, 5, 6]]),
+        ]
+        .concat();
+
+        [
+            ebml_header,
+            unknown_size_element(SEGMENT),
+            element(0xec, &[&[0; 4]]),
+            info,
+            tracks,
+            cluster,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_webm_frames() {
+        let file = webm_file();
+        let mut reader = MkvReader::new(file.as_slice()).unwrap();
+
+        assert_eq!(reader.tracks().len(), 2);
+        assert_eq!(reader.track().number, 2);
+        assert_eq!(
+            reader.track().fourcc,
+            Some(PixelFormat::from_fourcc(b"AV01"))
+        );
+        assert_eq!((reader.track().width, reader.track().height), (320, 240));
+
+        // The configuration OBUs are prepended to the first frame.
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(MkvFrame {
+                timestamp_ns: 100_000_000,
+                is_keyframe: true,
+                data: vec![0x0a, 0x01, 0x00, 1, 2],
+            })
+        );
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(MkvFrame {
+                timestamp_ns: 110_000_000,
+                is_keyframe: false,
+                data: vec![3],
+            })
+        );
+        assert_eq!(reader.next(), Some(vec![4, 5]));
+        assert_eq!(
+            reader.next_timestamped(),
+            Some(TimestampedChunk {
+                data: vec![6],
+                timestamp_us: Some(120_000),
+            })
+        );
+        assert_eq!(reader.next_frame().unwrap(), None);
+
+        assert!(matches!(
+            reader.select_track(1),
+            Err(MkvError::UnsupportedTrack(1))
+        ));
+    }
+
+    #[test]
+    fn test_timestamp_overflow() {
+        let mut file = webm_file();
+        // Replace the timestamp of the cluster with one that overflows once scaled.
+        let timestamp = element(TIMESTAMP, &[&[100]]);
+        let pos = file
+            .windows(timestamp.len())
+            .position(|w| w == timestamp.as_slice())
+            .unwrap();
+        file.splice(
+            pos..pos + timestamp.len(),
+            element(TIMESTAMP, &[&[0x7f, 0xff, 0xff, 0xff, 0xff, 0xff]]),
+        );
+
+        let mut reader = MkvReader::new(file.as_slice()).unwrap();
+        assert!(matches!(
+            reader.next_frame(),
+            Err(MkvError::TimestampOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_lacing() {
+        // Fixed-size lacing.
+        assert_eq!(
+            Block::split_laced_frames(2, &[2, 1, 2, 3, 4, 5, 6]).unwrap(),
+            vec![&[1, 2][..], &[3, 4], &[5, 6]]
+        );
+        // EBML lacing: sizes 3, then 3 - 2 = 1.
+        assert_eq!(
+            Block::split_laced_frames(3, &[2, 0x83, 0xbd, 1, 2, 3, 4, 5, 6]).unwrap(),
+            vec![&[1, 2, 3][..], &[4], &[5, 6]]
+        );
+        // Xiph lacing with a frame of 255 bytes.
+        let mut data = vec![1, 0xff, 0];
+        data.extend_from_slice(&[7; 256]);
+        let frames = Block::split_laced_frames(1, &data).unwrap();
+        assert_eq!((frames[0].len(), frames[1].len()), (255, 1));
+
+        assert!(matches!(
+            Block::split_laced_frames(2, &[1, 1, 2, 3]),
+            Err(MkvError::InvalidLacing)
+        ));
+    }
+
+    #[test]
+    fn test_not_matroska() {
+        let file = element(EBML_HEADER, &[&element(DOC_TYPE, &[b"other"])]);
+        assert!(matches!(
+            MkvReader::new(file.as_slice()),
+            Err(MkvError::UnsupportedDocType(_))
+        ));
+        assert!(matches!(
+            MkvReader::new(element(0xec, &[&[0]]).as_slice()),
+            Err(MkvError::NotEbml)
+        ));
+    }
+}
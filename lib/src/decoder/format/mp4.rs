@@ -0,0 +1,906 @@
+//! Minimal demuxer for the ISO base media file format (MP4).
+//!
+//! Only the first video track of a non-fragmented file is read. Its samples are located using the
+//! sample tables of the `moov` box and returned in decoding order. H.264 and HEVC samples are
+//! converted from their length-prefixed form into Annex-B, with the parameter sets of the codec
+//! configuration record prepended to each sync sample, so they can be fed to a stateful decoder
+//! as-is. VP9 and AV1 samples are returned unchanged.
+use super::{timestamp_to_us, StreamSplitter, TimestampedChunk};
+use crate::PixelFormat;
+use log::error;
+use std::{
+    convert::TryInto,
+    io::{self, SeekFrom},
+};
+use thiserror::Error;
+
+/// The `moov` box is read into memory at once. Larger boxes are considered corrupted.
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+/// Samples larger than this are considered corrupted rather than allocated.
+const MAX_SAMPLE_SIZE: u32 = 64 * 1024 * 1024;
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Codecs that can be extracted from an MP4 file. AV1 uses the same fourcc as in IVF files.
+pub const SUPPORTED_FOURCCS: [PixelFormat; 4] = [
+    PixelFormat::H264,
+    PixelFormat::HEVC,
+    PixelFormat::VP9,
+    PixelFormat::from_fourcc(b"AV01"),
+];
+
+#[derive(Debug, Error)]
+pub enum Mp4Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("no {0} box found")]
+    MissingBox(String),
+    #[error("invalid or truncated {0} box")]
+    InvalidBox(String),
+    #[error("moov box of {0} bytes is too large")]
+    MoovTooLarge(u64),
+    #[error("fragmented MP4 files are not supported")]
+    Fragmented,
+    #[error("no video track found")]
+    NoVideoTrack,
+    #[error("unsupported sample entry {0}")]
+    UnsupportedSampleEntry(String),
+    #[error("invalid NAL unit length size {0}")]
+    InvalidNalLengthSize(u8),
+    #[error("sample of {0} bytes is too large")]
+    SampleTooLarge(u32),
+    #[error("sample {0} is not a sequence of length-prefixed NAL units")]
+    InvalidSample(usize),
+    #[error("stream ends in the middle of sample {0}")]
+    TruncatedSample(usize),
+}
+
+fn box_name(box_type: &[u8; 4]) -> String {
+    String::from_utf8_lossy(box_type).into_owned()
+}
+
+/// Reads the content of a box, failing with [`Mp4Error::InvalidBox`] if it is too short.
+struct BoxReader<'a> {
+    box_type: [u8; 4],
+    data: &'a [u8],
+}
+
+impl<'a> BoxReader<'a> {
+    fn new(box_type: &[u8; 4], data: &'a [u8]) -> Self {
+        BoxReader {
+            box_type: *box_type,
+            data,
+        }
+    }
+
+    fn invalid(&self) -> Mp4Error {
+        Mp4Error::InvalidBox(box_name(&self.box_type))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Mp4Error> {
+        if len > self.data.len() {
+            return Err(self.invalid());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Mp4Error> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Mp4Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Mp4Error> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Mp4Error> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Mp4Error> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Skip the version and flags of a full box and return the version.
+    fn full_box_version(&mut self) -> Result<u8, Mp4Error> {
+        let version = self.u8()?;
+        self.skip(3)?;
+
+        Ok(version)
+    }
+
+    /// Read the number of entries of a table which entries are `entry_size` bytes each, making
+    /// sure the box is large enough to contain them all before anything gets allocated.
+    fn entry_count(&mut self, entry_size: usize) -> Result<usize, Mp4Error> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(entry_size) > self.data.len() {
+            return Err(self.invalid());
+        }
+
+        Ok(count)
+    }
+}
+
+/// Iterates over the boxes contained in `data`, returning their type and content.
+struct Boxes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Result<([u8; 4], &'a [u8]), Mp4Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut reader = BoxReader::new(b"box ", self.data);
+        let res = (|| {
+            let size = reader.u32()? as u64;
+            let box_type: [u8; 4] = reader.bytes(4)?.try_into().unwrap();
+            let (header_size, size) = match size {
+                0 => (8, self.data.len() as u64),
+                1 => (16, reader.u64()?),
+                size => (8, size),
+            };
+            if size < header_size || size > self.data.len() as u64 {
+                return Err(Mp4Error::InvalidBox(box_name(&box_type)));
+            }
+            let (content, rest) = self.data.split_at(size as usize);
+
+            Ok((box_type, &content[header_size as usize..], rest))
+        })();
+
+        match res {
+            Ok((box_type, content, rest)) => {
+                self.data = rest;
+                Some(Ok((box_type, content)))
+            }
+            Err(e) => {
+                self.data = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn boxes(data: &[u8]) -> Boxes<'_> {
+    Boxes { data }
+}
+
+/// Returns the content of the first box of type `box_type` in `data`.
+fn find_box<'a>(data: &'a [u8], box_type: &[u8; 4]) -> Result<&'a [u8], Mp4Error> {
+    for b in boxes(data) {
+        let (t, content) = b?;
+        if &t == box_type {
+            return Ok(content);
+        }
+    }
+
+    Err(Mp4Error::MissingBox(box_name(box_type)))
+}
+
+/// Description of the video track read from an MP4 file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mp4Track {
+    /// Codec of the track.
+    pub fourcc: PixelFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Number of timestamp units per second.
+    pub timescale: u32,
+    pub num_samples: usize,
+}
+
+/// A sample read from an MP4 file, i.e. a single frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mp4Sample {
+    /// Decoding timestamp, in units of the track's time scale.
+    pub dts: u64,
+    /// Presentation timestamp, in units of the track's time scale. It can be negative if the
+    /// composition offsets of the file are.
+    pub pts: i64,
+    /// Whether decoding can start from this sample.
+    pub is_sync: bool,
+    pub data: Vec<u8>,
+}
+
+/// Location and timing of a sample, as given by the sample tables.
+struct SampleInfo {
+    offset: u64,
+    size: u32,
+    dts: u64,
+    pts: i64,
+    is_sync: bool,
+}
+
+/// Codec configuration required to convert length-prefixed samples into Annex-B.
+struct NalConfig {
+    /// Size in bytes of the length of each NAL unit in a sample.
+    length_size: u8,
+    /// Parameter sets of the configuration record, in Annex-B form.
+    parameter_sets: Vec<u8>,
+}
+
+impl NalConfig {
+    fn new(length_size: u8, nals: Vec<&[u8]>) -> Result<Self, Mp4Error> {
+        if !matches!(length_size, 1 | 2 | 4) {
+            return Err(Mp4Error::InvalidNalLengthSize(length_size));
+        }
+
+        let mut parameter_sets = Vec::new();
+        for nal in nals {
+            parameter_sets.extend_from_slice(&START_CODE);
+            parameter_sets.extend_from_slice(nal);
+        }
+
+        Ok(NalConfig {
+            length_size,
+            parameter_sets,
+        })
+    }
+
+    /// Parse an `avcC` box (AVCDecoderConfigurationRecord).
+    fn parse_avcc(data: &[u8]) -> Result<Self, Mp4Error> {
+        let mut reader = BoxReader::new(b"avcC", data);
+        reader.skip(4)?;
+        let length_size = (reader.u8()? & 0x3) + 1;
+        let mut nals = Vec::new();
+        // SPSs, then PPSs.
+        for count_mask in [0x1f, 0xff] {
+            for _ in 0..(reader.u8()? & count_mask) {
+                let len = reader.u16()? as usize;
+                nals.push(reader.bytes(len)?);
+            }
+        }
+
+        Self::new(length_size, nals)
+    }
+
+    /// Parse a `hvcC` box (HEVCDecoderConfigurationRecord).
+    fn parse_hvcc(data: &[u8]) -> Result<Self, Mp4Error> {
+        let mut reader = BoxReader::new(b"hvcC", data);
+        reader.skip(21)?;
+        let length_size = (reader.u8()? & 0x3) + 1;
+        let mut nals = Vec::new();
+        for _ in 0..reader.u8()? {
+            // Type of the NAL units of the array.
+            reader.skip(1)?;
+            for _ in 0..reader.u16()? {
+                let len = reader.u16()? as usize;
+                nals.push(reader.bytes(len)?);
+            }
+        }
+
+        Self::new(length_size, nals)
+    }
+
+    /// Convert the length-prefixed NAL units of `sample` into Annex-B, with the parameter sets
+    /// prepended if `is_sync` is set.
+    fn to_annex_b(&self, sample: &[u8], is_sync: bool) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(sample.len() + self.parameter_sets.len());
+        if is_sync {
+            data.extend_from_slice(&self.parameter_sets);
+        }
+
+        let length_size = self.length_size as usize;
+        let mut rest = sample;
+        while !rest.is_empty() {
+            if rest.len() < length_size {
+                return None;
+            }
+            let (len, tail) = rest.split_at(length_size);
+            let len = len.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            if len > tail.len() {
+                return None;
+            }
+            let (nal, tail) = tail.split_at(len);
+            data.extend_from_slice(&START_CODE);
+            data.extend_from_slice(nal);
+            rest = tail;
+        }
+
+        Some(data)
+    }
+}
+
+/// Parse the `stsd` box and return the track's codec, dimensions and NAL configuration.
+fn parse_stsd(data: &[u8]) -> Result<(PixelFormat, u16, u16, Option<NalConfig>), Mp4Error> {
+    let mut reader = BoxReader::new(b"stsd", data);
+    reader.full_box_version()?;
+    reader.u32()?;
+    let (entry_type, entry) = boxes(reader.data)
+        .next()
+        .unwrap_or_else(|| Err(Mp4Error::MissingBox("sample entry".into())))?;
+
+    // VisualSampleEntry fields preceding the dimensions, then the ones following them.
+    let mut reader = BoxReader::new(&entry_type, entry);
+    reader.skip(24)?;
+    let width = reader.u16()?;
+    let height = reader.u16()?;
+    reader.skip(50)?;
+    let children = reader.data;
+
+    let (fourcc, nal_config) = match &entry_type {
+        b"avc1" | b"avc3" => (
+            PixelFormat::H264,
+            Some(NalConfig::parse_avcc(find_box(children, b"avcC")?)?),
+        ),
+        b"hvc1" | b"hev1" => (
+            PixelFormat::HEVC,
+            Some(NalConfig::parse_hvcc(find_box(children, b"hvcC")?)?),
+        ),
+        b"vp09" => (PixelFormat::VP9, None),
+        b"av01" => (PixelFormat::from_fourcc(b"AV01"), None),
+        t => return Err(Mp4Error::UnsupportedSampleEntry(box_name(t))),
+    };
+
+    Ok((fourcc, width, height, nal_config))
+}
+
+/// Build the list of samples of a track from the boxes of its `stbl` box. `file_size` is the size
+/// of the whole file, which all the samples must fit in.
+fn parse_sample_table(stbl: &[u8], file_size: u64) -> Result<Vec<SampleInfo>, Mp4Error> {
+    // Sample sizes.
+    let sizes = match find_box(stbl, b"stsz") {
+        Ok(stsz) => {
+            let mut reader = BoxReader::new(b"stsz", stsz);
+            reader.full_box_version()?;
+            match reader.u32()? {
+                0 => (0..reader.entry_count(4)?)
+                    .map(|_| reader.u32())
+                    .collect::<Result<Vec<_>, _>>()?,
+                // The table is omitted if all samples have the same size, so make sure they can
+                // all fit in the file before allocating anything.
+                size => {
+                    let count = reader.u32()?;
+                    if (count as u64).saturating_mul(size as u64) > file_size {
+                        return Err(reader.invalid());
+                    }
+                    vec![size; count as usize]
+                }
+            }
+        }
+        Err(_) => {
+            let mut reader = BoxReader::new(b"stz2", find_box(stbl, b"stz2")?);
+            reader.full_box_version()?;
+            reader.skip(3)?;
+            let field_size = reader.u8()?;
+            if !matches!(field_size, 4 | 8 | 16) {
+                return Err(reader.invalid());
+            }
+            let count = reader.entry_count(field_size as usize / 8)?;
+            match field_size {
+                4 => reader
+                    .bytes(count.div_ceil(2))?
+                    .iter()
+                    .flat_map(|b| [(b >> 4) as u32, (b & 0xf) as u32])
+                    .take(count)
+                    .collect(),
+                8 => reader.bytes(count)?.iter().map(|b| *b as u32).collect(),
+                _ => (0..count)
+                    .map(|_| reader.u16().map(u32::from))
+                    .collect::<Result<Vec<_>, _>>()?,
+            }
+        }
+    };
+
+    // Chunk offsets.
+    let (chunk_offsets, chunk_offsets_box) = match find_box(stbl, b"stco") {
+        Ok(stco) => {
+            let mut reader = BoxReader::new(b"stco", stco);
+            reader.full_box_version()?;
+            let offsets = (0..reader.entry_count(4)?)
+                .map(|_| reader.u32().map(u64::from))
+                .collect::<Result<Vec<_>, _>>()?;
+            (offsets, b"stco")
+        }
+        Err(_) => {
+            let mut reader = BoxReader::new(b"co64", find_box(stbl, b"co64")?);
+            reader.full_box_version()?;
+            let offsets = (0..reader.entry_count(8)?)
+                .map(|_| reader.u64())
+                .collect::<Result<Vec<_>, _>>()?;
+            (offsets, b"co64")
+        }
+    };
+
+    // Sample to chunk mapping, as (first_chunk, samples_per_chunk) runs.
+    let mut reader = BoxReader::new(b"stsc", find_box(stbl, b"stsc")?);
+    reader.full_box_version()?;
+    let chunk_runs = (0..reader.entry_count(12)?)
+        .map(|_| {
+            let first_chunk = reader.u32()?;
+            let samples_per_chunk = reader.u32()?;
+            reader.u32()?;
+            Ok((first_chunk, samples_per_chunk))
+        })
+        .collect::<Result<Vec<_>, Mp4Error>>()?;
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut sizes_iter = sizes.iter();
+    'runs: for (i, &(first_chunk, samples_per_chunk)) in chunk_runs.iter().enumerate() {
+        let last_chunk = match chunk_runs.get(i + 1) {
+            Some(&(next_first_chunk, _)) => next_first_chunk,
+            None => chunk_offsets.len() as u32 + 1,
+        };
+        for chunk in first_chunk..last_chunk {
+            let mut offset = *chunk_offsets
+                .get((chunk as usize).wrapping_sub(1))
+                .ok_or_else(|| reader.invalid())?;
+            for _ in 0..samples_per_chunk {
+                let size = match sizes_iter.next() {
+                    Some(size) => *size,
+                    None => break 'runs,
+                };
+                samples.push(SampleInfo {
+                    offset,
+                    size,
+                    dts: 0,
+                    pts: 0,
+                    is_sync: true,
+                });
+                offset = offset
+                    .checked_add(size as u64)
+                    .ok_or_else(|| Mp4Error::InvalidBox(box_name(chunk_offsets_box)))?;
+            }
+        }
+    }
+
+    // Decoding timestamps.
+    let mut reader = BoxReader::new(b"stts", find_box(stbl, b"stts")?);
+    reader.full_box_version()?;
+    let mut samples_iter = samples.iter_mut();
+    let mut dts = 0u64;
+    for _ in 0..reader.entry_count(8)? {
+        let count = reader.u32()?;
+        let delta = reader.u32()?;
+        for sample in samples_iter.by_ref().take(count as usize) {
+            sample.dts = dts;
+            sample.pts = dts as i64;
+            dts += delta as u64;
+        }
+    }
+
+    // Composition offsets, only present if the presentation order differs from decoding order.
+    if let Ok(ctts) = find_box(stbl, b"ctts") {
+        let mut reader = BoxReader::new(b"ctts", ctts);
+        let version = reader.full_box_version()?;
+        let mut samples_iter = samples.iter_mut();
+        for _ in 0..reader.entry_count(8)? {
+            let count = reader.u32()?;
+            let offset = match version {
+                0 => reader.u32()? as i64,
+                _ => reader.u32()? as i32 as i64,
+            };
+            for sample in samples_iter.by_ref().take(count as usize) {
+                sample.pts = (sample.dts as i64)
+                    .checked_add(offset)
+                    .ok_or_else(|| reader.invalid())?;
+            }
+        }
+    }
+
+    // Sync samples. All samples are sync samples if the box is absent.
+    if let Ok(stss) = find_box(stbl, b"stss") {
+        let mut reader = BoxReader::new(b"stss", stss);
+        reader.full_box_version()?;
+        samples.iter_mut().for_each(|sample| sample.is_sync = false);
+        for _ in 0..reader.entry_count(4)? {
+            let sample_number = reader.u32()? as usize;
+            if let Some(sample) = samples.get_mut(sample_number.wrapping_sub(1)) {
+                sample.is_sync = true;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Reads the samples of the first video track of an MP4 file.
+///
+/// Iterating over the reader returns the samples data only. Use [`Mp4Reader::next_sample`] to
+/// also obtain their timestamps.
+pub struct Mp4Reader<S: io::Read + io::Seek> {
+    stream: S,
+    track: Mp4Track,
+    nal_config: Option<NalConfig>,
+    samples: Vec<SampleInfo>,
+    next_sample: usize,
+}
+
+impl<S: io::Read + io::Seek> Mp4Reader<S> {
+    /// Create a new reader for `stream`, after locating and parsing its `moov` box.
+    pub fn new(mut stream: S) -> Result<Self, Mp4Error> {
+        let moov = Self::read_moov(&mut stream)?;
+
+        if find_box(&moov, b"mvex").is_ok() {
+            return Err(Mp4Error::Fragmented);
+        }
+
+        for b in boxes(&moov) {
+            let (box_type, trak) = b?;
+            if &box_type != b"trak" {
+                continue;
+            }
+
+            let mdia = find_box(trak, b"mdia")?;
+            let mut hdlr = BoxReader::new(b"hdlr", find_box(mdia, b"hdlr")?);
+            hdlr.skip(8)?;
+            if hdlr.bytes(4)? != b"vide" {
+                continue;
+            }
+
+            let mut mdhd = BoxReader::new(b"mdhd", find_box(mdia, b"mdhd")?);
+            let timescale = match mdhd.full_box_version()? {
+                1 => {
+                    mdhd.skip(16)?;
+                    mdhd.u32()?
+                }
+                _ => {
+                    mdhd.skip(8)?;
+                    mdhd.u32()?
+                }
+            };
+
+            let stbl = find_box(find_box(mdia, b"minf")?, b"stbl")?;
+            let (fourcc, width, height, nal_config) = parse_stsd(find_box(stbl, b"stsd")?)?;
+            let file_size = stream.seek(SeekFrom::End(0))?;
+            let samples = parse_sample_table(stbl, file_size)?;
+
+            return Ok(Mp4Reader {
+                stream,
+                track: Mp4Track {
+                    fourcc,
+                    width,
+                    height,
+                    timescale,
+                    num_samples: samples.len(),
+                },
+                nal_config,
+                samples,
+                next_sample: 0,
+            });
+        }
+
+        Err(Mp4Error::NoVideoTrack)
+    }
+
+    /// Skip the top-level boxes of `stream` until the `moov` box is found, and return its content.
+    fn read_moov(stream: &mut S) -> Result<Vec<u8>, Mp4Error> {
+        let missing_moov = || Mp4Error::MissingBox("moov".into());
+        let mut pos = 0u64;
+
+        loop {
+            let mut header = [0u8; 8];
+            match stream.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(missing_moov()),
+                Err(e) => return Err(e.into()),
+            }
+            let box_type: [u8; 4] = header[4..8].try_into().unwrap();
+            let (header_size, size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+                1 => {
+                    let mut size = [0u8; 8];
+                    stream.read_exact(&mut size)?;
+                    (16, u64::from_be_bytes(size))
+                }
+                // Box extending to the end of the file.
+                0 if &box_type != b"moov" => return Err(missing_moov()),
+                0 => {
+                    let end = stream.seek(SeekFrom::End(0))?;
+                    stream.seek(SeekFrom::Start(pos + 8))?;
+                    (8, end - pos)
+                }
+                size => (8, size as u64),
+            };
+            if size < header_size {
+                return Err(Mp4Error::InvalidBox(box_name(&box_type)));
+            }
+
+            if &box_type == b"moov" {
+                let len = size - header_size;
+                if len > MAX_MOOV_SIZE {
+                    return Err(Mp4Error::MoovTooLarge(len));
+                }
+                let mut moov = vec![0u8; len as usize];
+                stream.read_exact(&mut moov)?;
+                return Ok(moov);
+            }
+
+            pos = pos.checked_add(size).ok_or_else(missing_moov)?;
+            stream.seek(SeekFrom::Start(pos))?;
+        }
+    }
+
+    pub fn track(&self) -> &Mp4Track {
+        &self.track
+    }
+
+    /// Returns the next sample of the track in decoding order, or `None` if all the samples have
+    /// been read.
+    pub fn next_sample(&mut self) -> Result<Option<Mp4Sample>, Mp4Error> {
+        let index = self.next_sample;
+        let info = match self.samples.get(index) {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        self.next_sample += 1;
+
+        if info.size > MAX_SAMPLE_SIZE {
+            return Err(Mp4Error::SampleTooLarge(info.size));
+        }
+        let mut data = vec![0u8; info.size as usize];
+        self.stream.seek(SeekFrom::Start(info.offset))?;
+        self.stream
+            .read_exact(&mut data)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Mp4Error::TruncatedSample(index),
+                _ => Mp4Error::Io(e),
+            })?;
+
+        if let Some(nal_config) = &self.nal_config {
+            data = nal_config
+                .to_annex_b(&data, info.is_sync)
+                .ok_or(Mp4Error::InvalidSample(index))?;
+        }
+
+        Ok(Some(Mp4Sample {
+            dts: info.dts,
+            pts: info.pts,
+            is_sync: info.is_sync,
+            data,
+        }))
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: io::Read + io::Seek> Iterator for Mp4Reader<S> {
+    type Item = Vec<u8>;
+
+    /// Returns the data of the next sample in the track.
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_sample() {
+            Ok(sample) => sample.map(|sample| sample.data),
+            Err(e) => {
+                error!("Error while reading MP4 stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<S: io::Read + io::Seek> StreamSplitter for Mp4Reader<S> {
+    fn next_timestamped(&mut self) -> Option<TimestampedChunk> {
+        let timescale = self.track.timescale;
+        match self.next_sample() {
+            Ok(sample) => sample.map(|sample| TimestampedChunk {
+                timestamp_us: timestamp_to_us(sample.pts, 1, timescale),
+                data: sample.data,
+            }),
+            Err(e) => {
+                error!("Error while reading MP4 stream: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(box_type: &[u8; 4], content: &[&[u8]]) -> Vec<u8> {
+        let content = content.concat();
+        [
+            &(content.len() as u32 + 8).to_be_bytes()[..],
+            box_type,
+            &content,
+        ]
+        .concat()
+    }
+
+    fn full_box(box_type: &[u8; 4], content: &[&[u8]]) -> Vec<u8> {
+        mp4_box(box_type, &[&[0, 0, 0, 0], &content.concat()])
+    }
+
+    /// Build a file with a single H.264 track made of `samples`, stored in two chunks after the
+    /// `moov` box.
+    fn h264_file(samples: &[&[u8]]) -> Vec<u8> {
+        let avcc = mp4_box(
+            b"avcC",
+            &[
+                &[
+                    1, 0x64, 0, 0x1f, 0xff, 0xe1, 0, 2, 0x67, 0x64, 1, 0, 1, 0x68,
+                ],
+                &[],
+            ],
+        );
+        let avc1 = mp4_box(
+            b"avc1",
+            &[
+                &[0; 24],
+                &320u16.to_be_bytes(),
+                &240u16.to_be_bytes(),
+                &[0; 50],
+                &avcc,
+            ],
+        );
+        let sizes = samples
+            .iter()
+            .flat_map(|s| (s.len() as u32).to_be_bytes())
+            .collect::<Vec<_>>();
+        let stbl = |chunk_offsets: &[u32]| {
+            mp4_box(
+                b"stbl",
+                &[
+                    &full_box(b"stsd", &[&1u32.to_be_bytes(), &avc1]),
+                    // All samples last 1000 units.
+                    &full_box(
+                        b"stts",
+                        &[
+                            &[0, 0, 0, 1],
+                            &(samples.len() as u32).to_be_bytes(),
+                            &[0, 0, 3, 0xe8],
+                        ],
+                    ),
+                    // Second sample is presented 2000 units later.
+                    &full_box(
+                        b"ctts",
+                        &[&[
+                            0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 7, 0xd0,
+                        ]],
+                    ),
+                    &full_box(b"stss", &[&[0, 0, 0, 1, 0, 0, 0, 1]]),
+                    // Two samples in the first chunk, the rest in the second.
+                    &full_box(
+                        b"stsc",
+                        &[
+                            &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],
+                            &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1],
+                        ],
+                    ),
+                    &full_box(
+                        b"stsz",
+                        &[&[0, 0, 0, 0], &(samples.len() as u32).to_be_bytes(), &sizes],
+                    ),
+                    &full_box(
+                        b"stco",
+                        &[
+                            &[0, 0, 0, 2],
+                            &chunk_offsets[0].to_be_bytes(),
+                            &chunk_offsets[1].to_be_bytes(),
+                        ],
+                    ),
+                ],
+            )
+        };
+        let moov = |chunk_offsets: &[u32]| {
+            let mdia = mp4_box(
+                b"mdia",
+                &[
+                    &full_box(b"mdhd", &[&[0; 8], &90000u32.to_be_bytes(), &[0; 8]]),
+                    &full_box(b"hdlr", &[&[0; 4], b"vide", &[0; 13]]),
+                    &mp4_box(b"minf", &[&stbl(chunk_offsets)]),
+                ],
+            );
+            mp4_box(b"moov", &[&mp4_box(b"trak", &[&mdia])])
+        };
+
+        let ftyp = mp4_box(b"ftyp", &[b"isom", &[0; 4]]);
+        // The size of the moov box does not depend on the chunk offsets.
+        let mdat_start = (ftyp.len() + moov(&[0, 0]).len() + 8) as u32;
+        let first_chunk_len = (samples[0].len() + samples[1].len()) as u32;
+        let moov = moov(&[mdat_start, mdat_start + first_chunk_len]);
+
+        [ftyp, moov, mp4_box(b"mdat", samples)].concat()
+    }
+
+    #[test]
+    fn test_h264_track() {
+        let file = h264_file(&[
+            &[0, 0, 0, 2, 0x65, 0x88, 0, 0, 0, 1, 0x06],
+            &[0, 0, 0, 2, 0x41, 0x9a],
+            &[0, 0, 0, 2, 0x41, 0x9b],
+        ]);
+        let mut reader = Mp4Reader::new(Cursor::new(file)).unwrap();
+
+        assert_eq!(
+            reader.track(),
+            &Mp4Track {
+                fourcc: PixelFormat::H264,
+                width: 320,
+                height: 240,
+                timescale: 90000,
+                num_samples: 3,
+            }
+        );
+        assert_eq!(
+            reader.next_sample().unwrap(),
+            Some(Mp4Sample {
+                dts: 0,
+                pts: 0,
+                is_sync: true,
+                data: vec![
+                    0, 0, 0, 1, 0x67, 0x64, 0, 0, 0, 1, 0x68, 0, 0, 0, 1, 0x65, 0x88, 0, 0, 0, 1,
+                    0x06
+                ],
+            })
+        );
+        assert_eq!(
+            reader.next_sample().unwrap(),
+            Some(Mp4Sample {
+                dts: 1000,
+                pts: 3000,
+                is_sync: false,
+                data: vec![0, 0, 0, 1, 0x41, 0x9a],
+            })
+        );
+        assert_eq!(reader.next(), Some(vec![0, 0, 0, 1, 0x41, 0x9b]));
+        assert_eq!(reader.next_sample().unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_sample() {
+        let file = h264_file(&[
+            &[0, 0, 0, 2, 0x65],
+            &[0, 0, 0, 1, 0x41],
+            &[0, 0, 0, 1, 0x41],
+        ]);
+        let mut reader = Mp4Reader::new(Cursor::new(file)).unwrap();
+
+        assert!(matches!(
+            reader.next_sample(),
+            Err(Mp4Error::InvalidSample(0))
+        ));
+        assert_eq!(reader.next(), Some(vec![0, 0, 0, 1, 0x41]));
+    }
+
+    #[test]
+    fn test_oversized_sample_table() {
+        // A single fixed-size stsz box claiming 2^32 - 1 samples.
+        let stbl = full_box(b"stsz", &[&[0, 0, 0, 1], &[0xff; 4]]);
+        assert!(matches!(
+            parse_sample_table(&stbl, 1 << 20),
+            Err(Mp4Error::InvalidBox(b)) if b == "stsz"
+        ));
+
+        // Two samples in a chunk located at the very end of the 64-bit address space.
+        let stbl = [
+            full_box(
+                b"stsz",
+                &[&[0, 0, 0, 0], &[0, 0, 0, 2], &[0, 0, 0, 2, 0, 0, 0, 2]],
+            ),
+            full_box(b"co64", &[&[0, 0, 0, 1], &(u64::MAX - 1).to_be_bytes()]),
+            full_box(
+                b"stsc",
+                &[&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1]],
+            ),
+        ]
+        .concat();
+        assert!(matches!(
+            parse_sample_table(&stbl, 1 << 20),
+            Err(Mp4Error::InvalidBox(b)) if b == "co64"
+        ));
+    }
+
+    #[test]
+    fn test_missing_moov() {
+        let file = [mp4_box(b"ftyp", &[b"isom"]), mp4_box(b"mdat", &[&[0; 16]])].concat();
+
+        assert!(matches!(
+            Mp4Reader::new(Cursor::new(file)),
+            Err(Mp4Error::MissingBox(_))
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+//! Support for Annex-B byte streams, used by H.264 and HEVC, in which NAL units are separated by
+//! start codes.
+use log::error;
+use std::io::{self, Read};
+
+/// A NAL unit read from an Annex-B stream.
+pub struct Nal {
+    /// The NAL unit, including its start code and any leading zero byte.
+    data: Vec<u8>,
+    /// Size of the start code and leading zero bytes.
+    start_code_len: usize,
+}
+
+impl Nal {
+    /// Returns the NAL unit as found in the stream, start code included.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the content of the NAL unit, starting with its header.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.start_code_len..]
+    }
+}
+
+/// Reads the NAL units of an Annex-B stream one by one, without buffering more than the NAL unit
+/// being read.
+pub struct NalReader<S: io::Read> {
+    stream: io::Bytes<io::BufReader<S>>,
+    /// Start code of the next NAL unit, already read from the stream. Empty once the end of the
+    /// stream is reached.
+    next_start_code: Vec<u8>,
+}
+
+impl<S: io::Read> NalReader<S> {
+    /// Create a new reader for `stream`.
+    ///
+    /// `stream` must start with a start code, optionally preceded by zero bytes, otherwise the
+    /// input is considered invalid and `None` is returned.
+    pub fn new(stream: S) -> Option<Self> {
+        let mut stream = io::BufReader::new(stream).bytes();
+        let mut start_code = Vec::new();
+
+        loop {
+            match stream.next() {
+                Some(Ok(0)) => start_code.push(0),
+                Some(Ok(1)) if start_code.len() >= 2 => {
+                    start_code.push(1);
+                    break;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(NalReader {
+            stream,
+            next_start_code: start_code,
+        })
+    }
+}
+
+impl<S: io::Read> Iterator for NalReader<S> {
+    type Item = Nal;
+
+    /// Returns the next NAL unit in the stream.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next_start_code.is_empty() {
+                return None;
+            }
+
+            let mut data = std::mem::take(&mut self.next_start_code);
+            let start_code_len = data.len();
+            // Number of consecutive zero bytes at the end of `data`.
+            let mut zeros = 0;
+
+            loop {
+                match self.stream.next() {
+                    // Found the next start code. The zero bytes preceding it belong to it.
+                    Some(Ok(1)) if zeros >= 2 => {
+                        self.next_start_code = data.split_off(data.len() - zeros);
+                        self.next_start_code.push(1);
+                        break;
+                    }
+                    Some(Ok(b)) => {
+                        data.push(b);
+                        zeros = if b == 0 { zeros + 1 } else { 0 };
+                    }
+                    // End of stream.
+                    None => break,
+                    Some(Err(e)) => {
+                        error!("Error while reading stream: {}", e);
+                        return None;
+                    }
+                }
+            }
+
+            // Skip empty NAL units.
+            if data.len() > start_code_len {
+                return Some(Nal {
+                    data,
+                    start_code_len,
+                });
+            }
+        }
+    }
+}
+
+/// An access unit, i.e. the NAL units making up a single picture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessUnit<T> {
+    /// The NAL units of the access unit, start codes included.
+    pub data: Vec<u8>,
+    /// The types of the NAL units of the access unit, in stream order.
+    pub nal_types: Vec<T>,
+}
+
+/// Role of a NAL unit in the delimitation of access units.
+pub(super) enum NalClass {
+    /// VCL NAL unit. `first_in_picture` is set if this is the first slice of a new picture.
+    Vcl { first_in_picture: bool },
+    /// Non-VCL NAL unit that starts a new access unit if it follows a VCL NAL unit.
+    StartsAccessUnit,
+    /// NAL unit that terminates the current access unit.
+    EndsAccessUnit,
+    /// NAL unit that belongs to the current access unit.
+    Other,
+}
+
+/// Codec-specific knowledge required to delimit access units.
+pub(super) trait AccessUnitRules {
+    type NalType;
+
+    /// Returns the type of the NAL unit which content starts with `payload`.
+    fn nal_type(payload: &[u8]) -> Self::NalType;
+    /// Returns the role of the NAL unit which content starts with `payload`.
+    fn classify(payload: &[u8]) -> NalClass;
+}
+
+/// Groups the NAL units of an Annex-B stream into access units, according to the rules of `R`.
+pub(super) struct AccessUnitSplitter<S: io::Read, R: AccessUnitRules> {
+    nals: NalReader<S>,
+    /// First NAL unit of the next access unit, already read from the stream.
+    pending: Option<Nal>,
+    _rules: std::marker::PhantomData<R>,
+}
+
+impl<S: io::Read, R: AccessUnitRules> AccessUnitSplitter<S, R> {
+    pub(super) fn new(stream: S) -> Option<Self> {
+        Some(AccessUnitSplitter {
+            nals: NalReader::new(stream)?,
+            pending: None,
+            _rules: std::marker::PhantomData,
+        })
+    }
+
+    /// Returns the next access unit of the stream.
+    pub(super) fn next_access_unit(&mut self) -> Option<AccessUnit<R::NalType>> {
+        let mut access_unit = AccessUnit {
+            data: Vec::new(),
+            nal_types: Vec::new(),
+        };
+        let mut has_vcl = false;
+        let mut ended = false;
+
+        while let Some(nal) = self.pending.take().or_else(|| self.nals.next()) {
+            let payload = nal.payload();
+            let class = R::classify(payload);
+
+            let starts_new_unit = !access_unit.nal_types.is_empty()
+                && (ended
+                    || match class {
+                        NalClass::Vcl { first_in_picture } => has_vcl && first_in_picture,
+                        NalClass::StartsAccessUnit => has_vcl,
+                        NalClass::EndsAccessUnit | NalClass::Other => false,
+                    });
+            if starts_new_unit {
+                self.pending = Some(nal);
+                break;
+            }
+
+            match class {
+                NalClass::Vcl { .. } => has_vcl = true,
+                NalClass::EndsAccessUnit => ended = true,
+                NalClass::StartsAccessUnit | NalClass::Other => (),
+            }
+            access_unit.nal_types.push(R::nal_type(payload));
+            access_unit.data.extend_from_slice(nal.data());
+        }
+
+        if access_unit.nal_types.is_empty() {
+            None
+        } else {
+            Some(access_unit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nal_reader() {
+        let stream: &[u8] = &[
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 0, 0, 0, 1, 0, 0, 1, 0x65, 0, 3, 0,
+        ];
+        let nals = NalReader::new(stream)
+            .unwrap()
+            .map(|nal| (nal.data().to_vec(), nal.payload().to_vec()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            nals,
+            vec![
+                (vec![0, 0, 0, 1, 0x67, 1, 2], vec![0x67, 1, 2]),
+                (vec![0, 0, 1, 0x68], vec![0x68]),
+                (vec![0, 0, 1, 0x65, 0, 3, 0], vec![0x65, 0, 3, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nal_reader_invalid_start() {
+        assert!(NalReader::new(&[0u8, 1, 0x67][..]).is_none());
+        assert!(NalReader::new(&[0x67u8, 0, 0, 1][..]).is_none());
+    }
+}
@@ -0,0 +1,818 @@
+//! Detection of the format of encoded streams.
+//!
+//! [`probe`] looks at the beginning of a stream to identify its container and codec, and parses
+//! the sequence-level headers of the codec to obtain the profile, bit depth and coded size of the
+//! stream. This is enough to configure the OUTPUT queue of a decoder, and to check that it
+//! supports the stream, before decoding starts.
+//!
+//! Detected formats are IVF, MP4 and Matroska/WebM files, FWHT streams, and H.264 and HEVC
+//! Annex-B byte streams. Codec headers are parsed for H.264, HEVC, VP8, VP9, AV1 and FWHT.
+use super::{
+    bitreader::BitReader,
+    fwht::{FwhtHeader, FRAME_HEADER as FWHT_FRAME_HEADER},
+    h264,
+    hevc::read_profile_tier_level,
+    ivf::{IvfError, IvfReader},
+    mkv::{MkvError, MkvReader},
+    mp4::{Mp4Error, Mp4Reader},
+    nal::NalReader,
+};
+use crate::PixelFormat;
+use std::{
+    convert::TryInto,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+use thiserror::Error;
+
+/// Number of bytes read from the start of the stream to detect elementary streams.
+const PROBE_SIZE: u64 = 256 * 1024;
+
+/// Minimum size of the OUTPUT buffers returned by [`StreamInfo::output_buffer_size`].
+const MIN_OUTPUT_BUFFER_SIZE: u32 = 1024 * 1024;
+
+const IVF_MAGIC: &[u8] = b"DKIF";
+const EBML_MAGIC: &[u8] = &[0x1a, 0x45, 0xdf, 0xa3];
+/// Box types that can start an MP4 file.
+const MP4_FIRST_BOXES: [&[u8]; 4] = [b"ftyp", b"moov", b"mdat", b"free"];
+
+/// Fourcc of AV1 streams, as returned by the container readers.
+const AV1: PixelFormat = PixelFormat::from_fourcc(b"AV01");
+
+/// Container a stream is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// Bare codec stream, e.g. an Annex-B byte stream.
+    Elementary,
+    Ivf,
+    Mp4,
+    Matroska,
+}
+
+/// Properties of a stream obtained by probing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub container: Container,
+    /// Pixel format to use on the OUTPUT queue of the decoder.
+    pub codec: PixelFormat,
+    /// Codec-specific profile: `profile_idc` for H.264, `general_profile_idc` for HEVC, version
+    /// for VP8 and FWHT, profile for VP9 and `seq_profile` for AV1.
+    pub profile: Option<u32>,
+    /// Bit depth of the luma samples.
+    pub bit_depth: Option<u32>,
+    /// Size of the decoded frames, including the padding required by the codec (e.g. the
+    /// macroblock alignment of H.264). Falls back to the size given by the container if the
+    /// codec headers could not be parsed.
+    pub coded_size: Option<(u32, u32)>,
+}
+
+impl StreamInfo {
+    /// Information about a stream of codec `codec`, which codec headers are unknown.
+    pub fn new(container: Container, codec: PixelFormat) -> Self {
+        StreamInfo {
+            container,
+            codec,
+            profile: None,
+            bit_depth: None,
+            coded_size: None,
+        }
+    }
+
+    /// Returns a size for the OUTPUT buffers that should be large enough for any frame of the
+    /// stream, i.e. half the size of a decoded 4:2:0 frame, and at least 1 MB.
+    pub fn output_buffer_size(&self) -> u32 {
+        let frame_size = self.coded_size.map_or(0, |(width, height)| {
+            width.saturating_mul(height).saturating_mul(3) / 4
+        });
+
+        std::cmp::max(frame_size, MIN_OUTPUT_BUFFER_SIZE)
+    }
+
+    /// Information about a stream of codec `codec` in `container`, completed with the codec
+    /// headers found in `frame`, if any. `container_size` is used as the coded size if these
+    /// headers cannot be parsed.
+    fn from_frame(
+        container: Container,
+        codec: PixelFormat,
+        frame: Option<&[u8]>,
+        container_size: Option<(u32, u32)>,
+    ) -> Self {
+        let header = frame.and_then(|frame| parse_sequence_header(codec, frame));
+
+        StreamInfo {
+            container,
+            codec,
+            profile: header.map(|h| h.profile),
+            bit_depth: header.map(|h| h.bit_depth),
+            coded_size: header.map(|h| h.coded_size).or(container_size),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("IVF error: {0}")]
+    Ivf(#[from] IvfError),
+    #[error("MP4 error: {0}")]
+    Mp4(#[from] Mp4Error),
+    #[error("Matroska error: {0}")]
+    Mkv(#[from] MkvError),
+    #[error("Matroska track uses an unsupported codec")]
+    UnsupportedTrack,
+    #[error("unknown stream format")]
+    UnknownFormat,
+}
+
+/// Identify the format of `stream` and parse its codec headers.
+///
+/// `stream` is rewound to its initial position before returning, so it can then be passed to
+/// the reader or splitter for its format.
+pub fn probe<S: Read + Seek>(stream: &mut S) -> Result<StreamInfo, ProbeError> {
+    let start = stream.stream_position()?;
+    let info = probe_from(stream, start);
+    stream.seek(SeekFrom::Start(start))?;
+
+    info
+}
+
+fn probe_from<S: Read + Seek>(stream: &mut S, start: u64) -> Result<StreamInfo, ProbeError> {
+    let mut prefix = Vec::new();
+    stream.by_ref().take(PROBE_SIZE).read_to_end(&mut prefix)?;
+    stream.seek(SeekFrom::Start(start))?;
+
+    if prefix.starts_with(IVF_MAGIC) {
+        let mut reader = IvfReader::new(stream)?;
+        let header = *reader.header();
+        let frame = reader.next_frame()?;
+
+        Ok(StreamInfo::from_frame(
+            Container::Ivf,
+            header.fourcc,
+            frame.as_ref().map(|f| f.data.as_slice()),
+            Some((header.width as u32, header.height as u32)),
+        ))
+    } else if prefix.starts_with(EBML_MAGIC) {
+        let mut reader = MkvReader::new(stream)?;
+        let track = reader.track();
+        let codec = track.fourcc.ok_or(ProbeError::UnsupportedTrack)?;
+        let size = Some((track.width as u32, track.height as u32));
+        let frame = reader.next_frame()?;
+
+        Ok(StreamInfo::from_frame(
+            Container::Matroska,
+            codec,
+            frame.as_ref().map(|f| f.data.as_slice()),
+            size,
+        ))
+    } else if prefix
+        .get(4..8)
+        .is_some_and(|box_type| MP4_FIRST_BOXES.contains(&box_type))
+    {
+        let mut reader = Mp4Reader::new(stream)?;
+        let track = reader.track();
+        let (codec, size) = (
+            track.fourcc,
+            Some((track.width as u32, track.height as u32)),
+        );
+        let sample = reader.next_sample()?;
+
+        Ok(StreamInfo::from_frame(
+            Container::Mp4,
+            codec,
+            sample.as_ref().map(|s| s.data.as_slice()),
+            size,
+        ))
+    } else if prefix.starts_with(&FWHT_FRAME_HEADER) {
+        Ok(StreamInfo::from_frame(
+            Container::Elementary,
+            PixelFormat::FWHT,
+            Some(&prefix),
+            None,
+        ))
+    } else {
+        probe_annex_b(&prefix).ok_or(ProbeError::UnknownFormat)
+    }
+}
+
+/// Look for a H.264 or HEVC sequence parameter set in the Annex-B byte stream `data`.
+fn probe_annex_b(data: &[u8]) -> Option<StreamInfo> {
+    let (codec, header) = NalReader::new(Cursor::new(data))?.find_map(|nal| {
+        let payload = nal.payload();
+        if is_hevc_sps(payload) {
+            parse_hevc_sps(payload).map(|header| (PixelFormat::HEVC, header))
+        } else if is_h264_sps(payload) {
+            parse_h264_sps(payload).map(|header| (PixelFormat::H264, header))
+        } else {
+            None
+        }
+    })?;
+
+    Some(StreamInfo {
+        container: Container::Elementary,
+        codec,
+        profile: Some(header.profile),
+        bit_depth: Some(header.bit_depth),
+        coded_size: Some(header.coded_size),
+    })
+}
+
+/// Sequence-level properties of a stream, as found in its codec headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SequenceHeader {
+    profile: u32,
+    bit_depth: u32,
+    coded_size: (u32, u32),
+}
+
+/// Parse the codec headers found at the start of `frame`, the first frame of a stream of codec
+/// `codec`.
+fn parse_sequence_header(codec: PixelFormat, frame: &[u8]) -> Option<SequenceHeader> {
+    match codec {
+        PixelFormat::H264 => NalReader::new(Cursor::new(frame))?
+            .find(|nal| is_h264_sps(nal.payload()))
+            .and_then(|nal| parse_h264_sps(nal.payload())),
+        PixelFormat::HEVC => NalReader::new(Cursor::new(frame))?
+            .find(|nal| is_hevc_sps(nal.payload()))
+            .and_then(|nal| parse_hevc_sps(nal.payload())),
+        PixelFormat::VP8 => parse_vp8_frame_header(frame),
+        PixelFormat::VP9 => parse_vp9_frame_header(frame),
+        AV1 => parse_av1_obus(frame),
+        PixelFormat::FWHT => parse_fwht_frame_header(frame),
+        _ => None,
+    }
+}
+
+fn is_h264_sps(nal: &[u8]) -> bool {
+    // forbidden_zero_bit must be 0, nal_ref_idc can be anything, and the type of SPS is 7.
+    nal.first().is_some_and(|header| header & 0x9f == 7)
+}
+
+fn is_hevc_sps(nal: &[u8]) -> bool {
+    // forbidden_zero_bit must be 0, the type of SPS is 33 and nuh_temporal_id_plus1 cannot be 0.
+    matches!(nal, [first, second, ..] if first & 0x80 == 0 && (first >> 1) == 33 && second & 0x7 != 0)
+}
+
+/// Skip a `scaling_list` of `size` coefficients of a H.264 SPS.
+fn skip_h264_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last_scale, mut next_scale) = (8, 8);
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = reader.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+
+    Some(())
+}
+
+/// Parse a H.264 SPS NAL unit, header included.
+fn parse_h264_sps(nal: &[u8]) -> Option<SequenceHeader> {
+    let mut reader = BitReader::from_nal(nal.get(1..)?);
+    let profile_idc = reader.read_bits(8)?;
+    // constraint_set flags and level_idc.
+    reader.skip_bits(16)?;
+    // seq_parameter_set_id
+    reader.read_ue()?;
+
+    let mut bit_depth = 8;
+    if h264::SPS_CHROMA_PROFILES.contains(&(profile_idc as u8)) {
+        let chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc == 3 {
+            // separate_colour_plane_flag
+            reader.skip_bits(1)?;
+        }
+        bit_depth = reader.read_ue()?.checked_add(8)?;
+        // bit_depth_chroma_minus8
+        reader.read_ue()?;
+        // qpprime_y_zero_transform_bypass_flag
+        reader.skip_bits(1)?;
+        // seq_scaling_matrix_present_flag
+        if reader.read_bit()? {
+            let num_lists = if chroma_format_idc != 3 { 8 } else { 12 };
+            for i in 0..num_lists {
+                if reader.read_bit()? {
+                    skip_h264_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    // log2_max_frame_num_minus4
+    reader.read_ue()?;
+    match reader.read_ue()? {
+        // log2_max_pic_order_cnt_lsb_minus4
+        0 => {
+            reader.read_ue()?;
+        }
+        1 => {
+            // delta_pic_order_always_zero_flag
+            reader.skip_bits(1)?;
+            // offset_for_non_ref_pic and offset_for_top_to_bottom_field
+            reader.read_se()?;
+            reader.read_se()?;
+            let num_ref_frames_in_pic_order_cnt_cycle = reader.read_ue()?;
+            if num_ref_frames_in_pic_order_cnt_cycle > 255 {
+                return None;
+            }
+            for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+                reader.read_se()?;
+            }
+        }
+        _ => (),
+    }
+    // max_num_ref_frames and gaps_in_frame_num_value_allowed_flag
+    reader.read_ue()?;
+    reader.skip_bits(1)?;
+
+    let width_in_mbs = reader.read_ue()?.checked_add(1)?;
+    let height_in_map_units = reader.read_ue()?.checked_add(1)?;
+    let frame_mbs_only_flag = reader.read_bit()?;
+    let height_in_mbs = height_in_map_units.checked_mul(if frame_mbs_only_flag { 1 } else { 2 })?;
+
+    Some(SequenceHeader {
+        profile: profile_idc,
+        bit_depth,
+        coded_size: (
+            width_in_mbs.checked_mul(16)?,
+            height_in_mbs.checked_mul(16)?,
+        ),
+    })
+}
+
+/// Parse a HEVC SPS NAL unit, header included.
+fn parse_hevc_sps(nal: &[u8]) -> Option<SequenceHeader> {
+    let mut reader = BitReader::from_nal(nal.get(2..)?);
+    // sps_video_parameter_set_id
+    reader.skip_bits(4)?;
+    let max_sub_layers_minus1 = reader.read_bits(3)? as usize;
+    // sps_temporal_id_nesting_flag
+    reader.skip_bits(1)?;
+
+    // general_profile_space and general_tier_flag precede the profile.
+    let general_profile_idc =
+        (read_profile_tier_level(&mut reader, max_sub_layers_minus1)?[0] & 0x1f) as u32;
+
+    // sps_seq_parameter_set_id
+    reader.read_ue()?;
+    if reader.read_ue()? == 3 {
+        // separate_colour_plane_flag
+        reader.skip_bits(1)?;
+    }
+    let width = reader.read_ue()?;
+    let height = reader.read_ue()?;
+    // conformance_window_flag
+    if reader.read_bit()? {
+        for _ in 0..4 {
+            reader.read_ue()?;
+        }
+    }
+    let bit_depth = reader.read_ue()?.checked_add(8)?;
+
+    Some(SequenceHeader {
+        profile: general_profile_idc,
+        bit_depth,
+        coded_size: (width, height),
+    })
+}
+
+/// Parse the frame tag and, for keyframes, the frame size of a VP8 frame.
+fn parse_vp8_frame_header(frame: &[u8]) -> Option<SequenceHeader> {
+    let tag = frame.get(0..3)?;
+    // Only keyframes carry the frame size.
+    if tag[0] & 1 != 0 || frame.get(3..6)? != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let size = |offset: usize| -> Option<u32> {
+        let bytes = frame.get(offset..offset + 2)?;
+        Some((u16::from_le_bytes([bytes[0], bytes[1]]) & 0x3fff) as u32)
+    };
+
+    Some(SequenceHeader {
+        profile: ((tag[0] >> 1) & 0x7) as u32,
+        bit_depth: 8,
+        coded_size: (size(6)?, size(8)?),
+    })
+}
+
+/// Parse the uncompressed header of a VP9 keyframe.
+fn parse_vp9_frame_header(frame: &[u8]) -> Option<SequenceHeader> {
+    const VP9_SYNC_CODE: u32 = 0x49_83_42;
+    const CS_RGB: u32 = 7;
+
+    let mut reader = BitReader::new(frame);
+    // frame_marker
+    if reader.read_bits(2)? != 2 {
+        return None;
+    }
+    let profile_low_bit = reader.read_bits(1)?;
+    let profile = (reader.read_bits(1)? << 1) | profile_low_bit;
+    if profile == 3 {
+        // reserved_zero
+        reader.skip_bits(1)?;
+    }
+    // Only keyframes carry the color config and frame size, so reject shown existing frames
+    // and inter frames.
+    if reader.read_bit()? || reader.read_bit()? {
+        return None;
+    }
+    // show_frame and error_resilient_mode
+    reader.skip_bits(2)?;
+    if reader.read_bits(24)? != VP9_SYNC_CODE {
+        return None;
+    }
+
+    let bit_depth = match profile {
+        0 | 1 => 8,
+        _ if reader.read_bit()? => 12,
+        _ => 10,
+    };
+    let color_space = reader.read_bits(3)?;
+    if color_space != CS_RGB {
+        // color_range
+        reader.skip_bits(1)?;
+        if profile == 1 || profile == 3 {
+            // subsampling_x, subsampling_y and reserved_zero
+            reader.skip_bits(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        // reserved_zero
+        reader.skip_bits(1)?;
+    }
+    let width = reader.read_bits(16)? + 1;
+    let height = reader.read_bits(16)? + 1;
+
+    Some(SequenceHeader {
+        profile,
+        bit_depth,
+        coded_size: (width, height),
+    })
+}
+
+/// Read a little-endian base 128 value. Returns the value and the number of bytes it takes.
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value.try_into().ok()?, i + 1));
+        }
+    }
+
+    None
+}
+
+/// Look for a sequence header among the OBUs of `data`, and parse it.
+fn parse_av1_obus(mut data: &[u8]) -> Option<SequenceHeader> {
+    const OBU_SEQUENCE_HEADER: u8 = 1;
+
+    while let Some(header) = data.first() {
+        let obu_type = (header >> 3) & 0xf;
+        let has_extension = header & 0x4 != 0;
+        let has_size_field = header & 0x2 != 0;
+        let mut pos = if has_extension { 2 } else { 1 };
+        let size = if has_size_field {
+            let (size, len) = read_leb128(data.get(pos..)?)?;
+            pos += len;
+            size
+        } else {
+            data.len().checked_sub(pos)?
+        };
+        let end = pos.checked_add(size)?;
+        let payload = data.get(pos..end)?;
+
+        if obu_type == OBU_SEQUENCE_HEADER {
+            return parse_av1_sequence_header(payload);
+        }
+        data = &data[end..];
+    }
+
+    None
+}
+
+/// Parse the payload of an AV1 sequence header OBU, up to its color config.
+fn parse_av1_sequence_header(payload: &[u8]) -> Option<SequenceHeader> {
+    let mut reader = BitReader::new(payload);
+    let seq_profile = reader.read_bits(3)?;
+    // still_picture
+    reader.skip_bits(1)?;
+    let reduced_still_picture_header = reader.read_bit()?;
+
+    if reduced_still_picture_header {
+        // seq_level_idx[0]
+        reader.skip_bits(5)?;
+    } else {
+        let mut decoder_model_info_present = false;
+        let mut buffer_delay_length = 0;
+        // timing_info_present_flag
+        if reader.read_bit()? {
+            // num_units_in_display_tick and time_scale
+            reader.skip_bits(64)?;
+            // equal_picture_interval
+            if reader.read_bit()? {
+                reader.read_uvlc()?;
+            }
+            decoder_model_info_present = reader.read_bit()?;
+            if decoder_model_info_present {
+                buffer_delay_length = reader.read_bits(5)? as usize + 1;
+                // num_units_in_decoding_tick, buffer_removal_time_length_minus_1 and
+                // frame_presentation_time_length_minus_1
+                reader.skip_bits(32 + 5 + 5)?;
+            }
+        }
+        let initial_display_delay_present = reader.read_bit()?;
+        let operating_points_cnt = reader.read_bits(5)? + 1;
+        for _ in 0..operating_points_cnt {
+            // operating_point_idc
+            reader.skip_bits(12)?;
+            let seq_level_idx = reader.read_bits(5)?;
+            if seq_level_idx > 7 {
+                // seq_tier
+                reader.skip_bits(1)?;
+            }
+            // decoder_model_present_for_this_op
+            if decoder_model_info_present && reader.read_bit()? {
+                // decoder_buffer_delay, encoder_buffer_delay and low_delay_mode_flag
+                reader.skip_bits(2 * buffer_delay_length + 1)?;
+            }
+            // initial_display_delay_present_for_this_op
+            if initial_display_delay_present && reader.read_bit()? {
+                reader.skip_bits(4)?;
+            }
+        }
+    }
+
+    let frame_width_bits = reader.read_bits(4)? as usize + 1;
+    let frame_height_bits = reader.read_bits(4)? as usize + 1;
+    let width = reader.read_bits(frame_width_bits)?.checked_add(1)?;
+    let height = reader.read_bits(frame_height_bits)?.checked_add(1)?;
+
+    // frame_id_numbers_present_flag
+    if !reduced_still_picture_header && reader.read_bit()? {
+        // delta_frame_id_length_minus_2 and additional_frame_id_length_minus_1
+        reader.skip_bits(4 + 3)?;
+    }
+    // use_128x128_superblock, enable_filter_intra and enable_intra_edge_filter
+    reader.skip_bits(3)?;
+    if !reduced_still_picture_header {
+        // enable_interintra_compound, enable_masked_compound, enable_warped_motion and
+        // enable_dual_filter
+        reader.skip_bits(4)?;
+        let enable_order_hint = reader.read_bit()?;
+        if enable_order_hint {
+            // enable_jnt_comp and enable_ref_frame_mvs
+            reader.skip_bits(2)?;
+        }
+        // seq_choose_screen_content_tools
+        let seq_force_screen_content_tools = if reader.read_bit()? {
+            2
+        } else {
+            reader.read_bits(1)?
+        };
+        // seq_choose_integer_mv and seq_force_integer_mv
+        if seq_force_screen_content_tools > 0 && !reader.read_bit()? {
+            reader.skip_bits(1)?;
+        }
+        if enable_order_hint {
+            // order_hint_bits_minus_1
+            reader.skip_bits(3)?;
+        }
+    }
+    // enable_superres, enable_cdef and enable_restoration
+    reader.skip_bits(3)?;
+
+    // color_config
+    let high_bitdepth = reader.read_bit()?;
+    let bit_depth = match (seq_profile, high_bitdepth) {
+        (_, false) => 8,
+        (2, true) if reader.read_bit()? => 12,
+        (_, true) => 10,
+    };
+
+    Some(SequenceHeader {
+        profile: seq_profile,
+        bit_depth,
+        coded_size: (width, height),
+    })
+}
+
+/// Parse the header of a FWHT frame.
+fn parse_fwht_frame_header(frame: &[u8]) -> Option<SequenceHeader> {
+    let header = FwhtHeader::parse(frame)?;
+
+    Some(SequenceHeader {
+        profile: header.version,
+        bit_depth: 8,
+        coded_size: (header.width, header.height),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::format::bitreader::tests::{escape, BitWriter};
+    use crate::decoder::format::fwht::FwhtFlags;
+    use crate::decoder::format::ivf::{IvfHeader, IvfWriter};
+
+    #[test]
+    fn test_probe_h264() {
+        // High 10 profile SPS for a 1920x1080 stream, preceded by an AUD.
+        let mut sps = BitWriter::default();
+        sps.write_bits(0x6e, 8);
+        sps.write_bits(0, 8);
+        sps.write_bits(40, 8);
+        sps.write_ue(0);
+        // chroma_format_idc, bit depths, qpprime_y_zero_transform_bypass_flag and
+        // seq_scaling_matrix_present_flag.
+        sps.write_ue(1);
+        sps.write_ue(2);
+        sps.write_ue(2);
+        sps.write_bits(0b01, 2);
+        // Only the first scaling list is present, and a delta_scale of -8 ends it.
+        sps.write_bits(1, 1);
+        sps.write_ue(16);
+        sps.write_bits(0, 7);
+        // log2_max_frame_num_minus4, pic_order_cnt_type 2, max_num_ref_frames, gaps flag.
+        sps.write_ue(0);
+        sps.write_ue(2);
+        sps.write_ue(4);
+        sps.write_bits(0, 1);
+        sps.write_ue(119);
+        sps.write_ue(67);
+        sps.write_bits(1, 1);
+
+        let mut stream = vec![0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x67];
+        stream.extend(escape(sps.into_rbsp()));
+        stream.extend([0, 0, 1, 0x65, 0x88]);
+        let mut stream = Cursor::new(stream);
+
+        let info = probe(&mut stream).unwrap();
+        assert_eq!(
+            info,
+            StreamInfo {
+                container: Container::Elementary,
+                codec: PixelFormat::H264,
+                profile: Some(110),
+                bit_depth: Some(10),
+                coded_size: Some((1920, 1088)),
+            }
+        );
+        assert_eq!(stream.position(), 0);
+        assert_eq!(info.output_buffer_size(), 1920 * 1088 * 3 / 4);
+    }
+
+    #[test]
+    fn test_probe_hevc() {
+        // Main 10 SPS for a 3840x2160 stream with two sub-layers.
+        let mut sps = BitWriter::default();
+        sps.write_bits(0, 4);
+        sps.write_bits(1, 3);
+        sps.write_bits(1, 1);
+        sps.write_bits(2, 8);
+        sps.write_bits(0x2000_0000, 32);
+        sps.write_bits(0x9000_0000_0000, 48);
+        sps.write_bits(153, 8);
+        // Sub-layer with its level only, followed by the reserved bits.
+        sps.write_bits(0b01, 2);
+        sps.write_bits(0, 14);
+        sps.write_bits(150, 8);
+        sps.write_ue(0);
+        sps.write_ue(1);
+        sps.write_ue(3840);
+        sps.write_ue(2160);
+        sps.write_bits(0, 1);
+        sps.write_ue(2);
+        sps.write_ue(2);
+
+        let mut stream = vec![0, 0, 0, 1, 0x40, 0x01, 0x0c, 0, 0, 1, 0x42, 0x01];
+        stream.extend(escape(sps.into_rbsp()));
+
+        assert_eq!(
+            probe(&mut Cursor::new(stream)).unwrap(),
+            StreamInfo {
+                container: Container::Elementary,
+                codec: PixelFormat::HEVC,
+                profile: Some(2),
+                bit_depth: Some(10),
+                coded_size: Some((3840, 2160)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_probe_vp9_ivf() {
+        // Uncompressed header of a 10-bit profile 2 keyframe.
+        let mut frame = BitWriter::default();
+        // frame_marker, profile, show_existing_frame, frame_type, show_frame and
+        // error_resilient_mode.
+        frame.write_bits(0b10, 2);
+        frame.write_bits(0b01, 2);
+        frame.write_bits(0b0010, 4);
+        frame.write_bits(0x49_83_42, 24);
+        // ten_or_twelve_bit, color_space, color_range.
+        frame.write_bits(0, 1);
+        frame.write_bits(2, 3);
+        frame.write_bits(0, 1);
+        frame.write_bits(351, 16);
+        frame.write_bits(287, 16);
+
+        let header = IvfHeader {
+            fourcc: PixelFormat::VP9,
+            width: 320,
+            height: 240,
+            timebase: (1, 30),
+            num_frames: 1,
+        };
+        let mut writer = IvfWriter::new(Vec::new(), header).unwrap();
+        writer.write_frame(&frame.into_rbsp(), 0).unwrap();
+
+        assert_eq!(
+            probe(&mut Cursor::new(writer.into_inner())).unwrap(),
+            StreamInfo {
+                container: Container::Ivf,
+                codec: PixelFormat::VP9,
+                profile: Some(2),
+                bit_depth: Some(10),
+                coded_size: Some((352, 288)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_av1_sequence_header() {
+        let mut seq = BitWriter::default();
+        // Main profile, no reduced header, no timing info, one operating point of level 5.1.
+        seq.write_bits(0, 3);
+        seq.write_bits(0, 1);
+        seq.write_bits(0, 1);
+        seq.write_bits(0, 1);
+        seq.write_bits(0, 1);
+        seq.write_bits(0, 5);
+        seq.write_bits(0, 12);
+        seq.write_bits(13, 5);
+        seq.write_bits(0, 1);
+        // Frame size on 12 bits.
+        seq.write_bits(11, 4);
+        seq.write_bits(11, 4);
+        seq.write_bits(1919, 12);
+        seq.write_bits(1079, 12);
+        // No frame ids, then the tools flags with order hints and screen content tools forced
+        // off.
+        seq.write_bits(0, 1);
+        seq.write_bits(0b011, 3);
+        seq.write_bits(0b0000, 4);
+        seq.write_bits(1, 1);
+        seq.write_bits(0b11, 2);
+        seq.write_bits(0, 1);
+        seq.write_bits(0, 1);
+        seq.write_bits(6, 3);
+        seq.write_bits(0b011, 3);
+        // high_bitdepth
+        seq.write_bits(1, 1);
+        let payload = seq.into_rbsp();
+
+        // Temporal delimiter followed by the sequence header.
+        let mut obus = vec![0x12, 0x00, 0x0a, payload.len() as u8];
+        obus.extend(&payload);
+
+        assert_eq!(
+            parse_sequence_header(AV1, &obus),
+            Some(SequenceHeader {
+                profile: 0,
+                bit_depth: 10,
+                coded_size: (1920, 1080),
+            })
+        );
+    }
+
+    #[test]
+    fn test_probe_fwht_and_unknown() {
+        let stream = FwhtHeader {
+            version: 3,
+            width: 640,
+            height: 480,
+            flags: FwhtFlags::I_FRAME,
+            colorspace: 0,
+            xfer_func: 0,
+            ycbcr_enc: 0,
+            quantization: 0,
+            size: 0,
+        }
+        .to_bytes()
+        .to_vec();
+
+        let info = probe(&mut Cursor::new(stream)).unwrap();
+        assert_eq!(info.codec, PixelFormat::FWHT);
+        assert_eq!(info.profile, Some(3));
+        assert_eq!(info.coded_size, Some((640, 480)));
+        assert_eq!(info.output_buffer_size(), MIN_OUTPUT_BUFFER_SIZE);
+
+        assert!(matches!(
+            probe(&mut Cursor::new(b"not a video stream".to_vec())),
+            Err(ProbeError::UnknownFormat)
+        ));
+    }
+}
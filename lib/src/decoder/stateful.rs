@@ -1,4 +1,5 @@
 mod capture_thread;
+pub mod sync;
 
 use crate::{
     bindings,
@@ -22,17 +23,22 @@ use crate::{
         V4l2Buffer,
     },
     memory::{BufferHandles, PrimitiveBufferHandles},
+    metadata::FrameMetadataMap,
+    PixelFormat, PlaneLayout,
 };
 
 use capture_thread::CaptureThread;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use std::{
     convert::TryFrom,
     io,
+    ops::ControlFlow,
+    os::unix::io::{AsRawFd, RawFd},
     path::Path,
-    sync::{atomic::AtomicUsize, mpsc, Arc},
+    sync::{atomic::AtomicUsize, mpsc, Arc, Mutex},
     task::Wake,
     thread::JoinHandle,
+    time::Duration,
 };
 use thiserror::Error;
 
@@ -62,6 +68,16 @@ pub enum DecoderOpenError {
     NotAStatefulDecoder,
 }
 
+#[derive(Debug, Error)]
+pub enum SetStreamFormatError {
+    #[error("codec {0} is not supported by the decoder")]
+    UnsupportedCodec(PixelFormat),
+    #[error("error while getting the OUTPUT format")]
+    GFmtError(#[from] ioctl::GFmtError),
+    #[error("error while setting the OUTPUT format")]
+    SFmtError(#[from] ioctl::SFmtError),
+}
+
 impl Decoder<AwaitingOutputFormat> {
     pub fn open(path: &Path) -> Result<Self, DecoderOpenError> {
         let config = DeviceConfig::new().non_blocking_dqbuf();
@@ -117,6 +133,55 @@ impl Decoder<AwaitingOutputFormat> {
             },
         })
     }
+
+    /// Returns whether the decoder can decode streams of `codec`.
+    pub fn supports_output_format(&self, codec: PixelFormat) -> bool {
+        self.state
+            .output_queue
+            .format_iter()
+            .any(|fmt| fmt.pixelformat == codec)
+    }
+
+    /// Set the OUTPUT format for decoding the stream described by `info`, typically obtained
+    /// with [`format::probe::probe`].
+    ///
+    /// If the coded size of the stream is known, it is set on the OUTPUT queue so the driver can
+    /// propagate it to the CAPTURE format before the first resolution change event, and the
+    /// OUTPUT buffers are sized after it. An `UnsupportedCodec` error is returned before
+    /// anything is changed if the decoder does not support the codec of the stream.
+    pub fn set_output_format_for_stream(
+        mut self,
+        info: &format::probe::StreamInfo,
+    ) -> Result<Decoder<AwaitingOutputBuffers>, SetStreamFormatError> {
+        if !self.supports_output_format(info.codec) {
+            return Err(SetStreamFormatError::UnsupportedCodec(info.codec));
+        }
+
+        let mut builder = self
+            .state
+            .output_queue
+            .change_format()?
+            .set_pixelformat(info.codec)
+            .set_planes_layout(vec![PlaneLayout {
+                sizeimage: info.output_buffer_size(),
+                ..Default::default()
+            }]);
+        if let Some((width, height)) = info.coded_size {
+            builder = builder.set_size(width as usize, height as usize);
+        }
+        let format: Format = builder.apply()?;
+        if format.pixelformat != info.codec {
+            return Err(SetStreamFormatError::UnsupportedCodec(info.codec));
+        }
+
+        Ok(Decoder {
+            device: self.device,
+            state: AwaitingOutputBuffers {
+                output_queue: self.state.output_queue,
+                capture_queue: self.state.capture_queue,
+            },
+        })
+    }
 }
 
 pub struct AwaitingOutputBuffers {
@@ -126,11 +191,29 @@ pub struct AwaitingOutputBuffers {
 impl DecoderState for AwaitingOutputBuffers {}
 
 impl Decoder<AwaitingOutputBuffers> {
+    /// Returns the CAPTURE format derived by the driver from the OUTPUT format. If the coded size
+    /// of the stream has been set, it can be used to estimate the size of the CAPTURE buffers
+    /// before decoding starts. The actual format is only known after the first resolution change
+    /// event.
+    pub fn get_capture_format(&self) -> Result<Format, ioctl::GFmtError> {
+        self.state.capture_queue.get_format()
+    }
+
+    /// Returns the minimum number of OUTPUT buffers required by the driver for the current
+    /// format, or `None` if the driver does not report it.
+    pub fn get_min_num_output_buffers(&self) -> Result<Option<usize>, ioctl::GCtrlError> {
+        self.state.output_queue.get_min_num_buffers()
+    }
+
+    /// Allocates `num_buffers` OUTPUT buffers of `memory_type`. A `TooFewBuffers` error is
+    /// returned if `num_buffers` is lower than the minimum required by the driver.
     pub fn allocate_output_buffers_generic<OP: BufferHandles>(
         self,
         memory_type: OP::SupportedMemoryType,
         num_buffers: usize,
     ) -> Result<Decoder<ReadyToDecode<OP>>, RequestBuffersError> {
+        self.state.output_queue.check_num_buffers(num_buffers)?;
+
         Ok(Decoder {
             device: self.device,
             state: ReadyToDecode {
@@ -182,20 +265,78 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
     }
 
     #[allow(clippy::type_complexity)]
-    pub fn start<P, InputDoneCb, DecoderEventCb, FormatChangedCb>(
+    pub fn start<P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>(
+        self,
+        input_done_cb: InputDoneCb,
+        decoder_event_cb: DecoderEventCb,
+        set_capture_format_cb: FormatChangedCb,
+    ) -> Result<
+        Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>,
+        StartDecoderError,
+    >
+    where
+        P: HandlesProvider,
+        InputDoneCb: InputDoneCallback<OP>,
+        DecoderEventCb: DecoderEventCallback<P, M>,
+        FormatChangedCb: FormatChangedCallback<P>,
+        M: Send + 'static,
+        for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
+            GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
+    {
+        self.start_with(input_done_cb, decoder_event_cb, set_capture_format_cb, true)
+    }
+
+    /// Start decoding without spawning a capture thread.
+    ///
+    /// The client is then responsible for driving the decoder by calling
+    /// [`Decoder::process_events`], typically when the file descriptor returned by
+    /// [`Decoder::event_fd`] becomes readable in its own event loop. All the callbacks are
+    /// invoked from within [`Decoder::process_events`], or from the methods that need to wait
+    /// for the decoder, such as [`Decoder::drain`] or [`Decoder::flush`].
+    #[allow(clippy::type_complexity)]
+    pub fn start_external<P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>(
+        self,
+        input_done_cb: InputDoneCb,
+        decoder_event_cb: DecoderEventCb,
+        set_capture_format_cb: FormatChangedCb,
+    ) -> Result<
+        Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>,
+        StartDecoderError,
+    >
+    where
+        P: HandlesProvider,
+        InputDoneCb: InputDoneCallback<OP>,
+        DecoderEventCb: DecoderEventCallback<P, M>,
+        FormatChangedCb: FormatChangedCallback<P>,
+        M: Send + 'static,
+        for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
+            GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
+    {
+        self.start_with(
+            input_done_cb,
+            decoder_event_cb,
+            set_capture_format_cb,
+            false,
+        )
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn start_with<P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>(
         self,
         input_done_cb: InputDoneCb,
         decoder_event_cb: DecoderEventCb,
         set_capture_format_cb: FormatChangedCb,
+        spawn_thread: bool,
     ) -> Result<
-        Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>,
+        Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>,
         StartDecoderError,
     >
     where
         P: HandlesProvider,
         InputDoneCb: InputDoneCallback<OP>,
-        DecoderEventCb: DecoderEventCallback<P>,
+        DecoderEventCb: DecoderEventCallback<P, M>,
         FormatChangedCb: FormatChangedCallback<P>,
+        M: Send + 'static,
         for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
             GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
     {
@@ -214,10 +355,12 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
 
         let (command_sender, command_receiver) = mpsc::channel::<DecoderCommand>();
         let (response_sender, response_receiver) = mpsc::channel::<CaptureThreadResponse>();
+        let frame_metadata = Arc::new(Mutex::new(FrameMetadataMap::new()));
 
         let mut decoder_thread = CaptureThread::new(
             &self.device,
             self.state.capture_queue,
+            Arc::clone(&frame_metadata),
             decoder_event_cb,
             set_capture_format_cb,
             command_receiver,
@@ -232,10 +375,19 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
             decoder_thread.poller.set_poll_counter(Arc::clone(counter));
         }
 
-        let handle = std::thread::Builder::new()
-            .name("V4L2 Decoder".into())
-            .spawn(move || decoder_thread.run())
-            .map_err(StartDecoderError::CannotStartCaptureThread)?;
+        let capture_thread = if spawn_thread {
+            CaptureThreadHandle::Spawned(
+                std::thread::Builder::new()
+                    .name("V4L2 Decoder".into())
+                    .spawn(move || decoder_thread.run())
+                    .map_err(StartDecoderError::CannotStartCaptureThread)?,
+            )
+        } else {
+            CaptureThreadHandle::External {
+                event_fd: decoder_thread.poller.as_raw_fd(),
+                capture_thread: Mutex::new(Some(decoder_thread)),
+            }
+        };
 
         self.state.output_queue.stream_on()?;
 
@@ -248,7 +400,8 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
                 command_waker,
                 command_sender,
                 response_receiver,
-                handle,
+                capture_thread,
+                frame_metadata,
             },
         })
     }
@@ -267,12 +420,30 @@ enum CaptureThreadResponse {
     FlushDone(anyhow::Result<()>),
 }
 
-pub struct Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
+/// How the event loop of the capture thread is run.
+#[allow(clippy::large_enum_variant)]
+enum CaptureThreadHandle<P, DecoderEventCb, FormatChangedCb, M>
+where
+    P: HandlesProvider,
+    DecoderEventCb: DecoderEventCallback<P, M>,
+    FormatChangedCb: FormatChangedCallback<P>,
+{
+    /// The event loop runs on its own thread.
+    Spawned(JoinHandle<CaptureThread<P, DecoderEventCb, FormatChangedCb, M>>),
+    /// The event loop is run by the client through [`Decoder::process_events`]. The capture
+    /// thread is only taken out of its `Option` while it is processing events.
+    External {
+        event_fd: RawFd,
+        capture_thread: Mutex<Option<CaptureThread<P, DecoderEventCb, FormatChangedCb, M>>>,
+    },
+}
+
+pub struct Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M = ()>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
 {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
@@ -283,15 +454,17 @@ where
     command_sender: mpsc::Sender<DecoderCommand>,
     response_receiver: mpsc::Receiver<CaptureThreadResponse>,
 
-    handle: JoinHandle<CaptureThread<P, DecoderEventCb, FormatChangedCb>>,
+    capture_thread: CaptureThreadHandle<P, DecoderEventCb, FormatChangedCb, M>,
+
+    frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
 }
-impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb> DecoderState
-    for Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
+impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M> DecoderState
+    for Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
 {
 }
@@ -322,6 +495,8 @@ pub enum DrainError {
     RecvError(#[from] mpsc::RecvError),
     #[error("error while draining on the capture thread")]
     CaptureThreadError(anyhow::Error),
+    #[error("the capture thread has stopped after a fatal error")]
+    FatalError,
 }
 
 #[derive(Debug, Error)]
@@ -334,6 +509,8 @@ pub enum FlushError {
     RecvError(#[from] mpsc::RecvError),
     #[error("error while flushing on the capture thread")]
     CaptureThreadError(anyhow::Error),
+    #[error("the capture thread has stopped after a fatal error")]
+    FatalError,
     #[error("error while starting the OUTPUT queue")]
     StreamonError(#[from] ioctl::StreamOnError),
 }
@@ -342,15 +519,36 @@ pub enum FlushError {
 type CanceledBuffers<OP: BufferHandles> =
     Vec<<Queue<Output, BuffersAllocated<OP>> as Stream>::Canceled>;
 
-impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
-    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
+#[derive(Debug, Error)]
+pub enum ProcessEventsError {
+    #[error("the decoder has not been started with `start_external`")]
+    NotExternallyDriven,
+    #[error("error while dequeueing OUTPUT buffer")]
+    DequeueError(#[from] DqBufError<V4l2Buffer>),
+}
+
+impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>
+    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
+    for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
+        GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
 {
+    /// Associate `metadata` with the next frame to decode.
+    ///
+    /// Returns the timestamp to set on the OUTPUT buffer containing the frame, e.g. using
+    /// [`QBuffer::set_timestamp`](crate::device::queue::qbuf::QBuffer::set_timestamp). The
+    /// metadata is then returned with the decoded frame, or with a [`DecoderEvent::FrameDropped`]
+    /// event if the frame is never decoded. Timestamps set by the client and timestamps obtained
+    /// from this method should not be mixed.
+    pub fn add_frame_metadata(&self, metadata: M) -> TimeVal {
+        self.state.frame_metadata.lock().unwrap().insert(metadata)
+    }
+
     pub fn num_output_buffers(&self) -> usize {
         self.state.output_queue.num_buffers()
     }
@@ -388,14 +586,116 @@ where
         debug!("Stop requested");
         self.send_command(DecoderCommand::Stop)?;
 
-        match self.state.handle.join() {
-            Ok(_) => (),
-            Err(_) => return Err(StopError::Join),
+        match self.state.capture_thread {
+            CaptureThreadHandle::Spawned(handle) => match handle.join() {
+                Ok(_) => (),
+                Err(_) => return Err(StopError::Join),
+            },
+            CaptureThreadHandle::External { capture_thread, .. } => {
+                if let Some(mut capture_thread) = capture_thread.into_inner().unwrap() {
+                    // Run the event loop until the stop command is processed.
+                    loop {
+                        match capture_thread.process_events(None, false) {
+                            ControlFlow::Continue(thread) => capture_thread = thread,
+                            ControlFlow::Break(thread) => {
+                                thread.finish();
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         Ok(self.state.output_queue.stream_off()?)
     }
 
+    /// Returns the file descriptor to watch for readability in the client's event loop if the
+    /// decoder has been started with [`Decoder::start_external`], or `None` otherwise.
+    ///
+    /// [`Decoder::process_events`] should be called every time it becomes readable.
+    pub fn event_fd(&self) -> Option<RawFd> {
+        match &self.state.capture_thread {
+            CaptureThreadHandle::Spawned(_) => None,
+            CaptureThreadHandle::External { event_fd, .. } => Some(*event_fd),
+        }
+    }
+
+    /// Process the pending events of a decoder started with [`Decoder::start_external`], without
+    /// blocking.
+    ///
+    /// This dequeues completed input buffers and decoded frames, requeues the CAPTURE buffers
+    /// returned by the client, processes resolution changes and pending commands, and invokes the
+    /// corresponding callbacks. It must not be called from these callbacks.
+    pub fn process_events(&self) -> Result<(), ProcessEventsError> {
+        match &self.state.capture_thread {
+            CaptureThreadHandle::Spawned(_) => Err(ProcessEventsError::NotExternallyDriven),
+            CaptureThreadHandle::External { capture_thread, .. } => {
+                self.dequeue_output_buffers()?;
+                let watch_output = self.state.output_queue.num_queued_buffers() > 0;
+                Self::run_capture_thread(capture_thread, Some(Duration::ZERO), watch_output);
+                Ok(())
+            }
+        }
+    }
+
+    /// Run one iteration of the event loop of an externally-driven capture thread, waiting up to
+    /// `timeout` for events, or for an OUTPUT buffer to complete if `watch_output` is `true`.
+    /// Returns `false` if the capture thread has stopped.
+    fn run_capture_thread(
+        capture_thread: &Mutex<Option<CaptureThread<P, DecoderEventCb, FormatChangedCb, M>>>,
+        timeout: Option<Duration>,
+        watch_output: bool,
+    ) -> bool {
+        let mut capture_thread = capture_thread.lock().unwrap();
+        let (thread, running) = match capture_thread.take() {
+            Some(thread) => match thread.process_events(timeout, watch_output) {
+                ControlFlow::Continue(thread) => (thread, true),
+                ControlFlow::Break(thread) => (thread, false),
+            },
+            None => return false,
+        };
+        *capture_thread = Some(thread);
+
+        running
+    }
+
+    /// Returns whether the event loop of the capture thread has stopped after a fatal error.
+    fn is_thread_finished(&self) -> bool {
+        match &self.state.capture_thread {
+            CaptureThreadHandle::Spawned(handle) => handle.is_finished(),
+            CaptureThreadHandle::External { capture_thread, .. } => {
+                match capture_thread.lock().unwrap().as_ref() {
+                    Some(thread) => thread.fatal_error_reported(),
+                    None => true,
+                }
+            }
+        }
+    }
+
+    /// Wait for the capture thread to reply to a command. An externally-driven capture thread is
+    /// run until it does.
+    fn recv_response(&self) -> Result<CaptureThreadResponse, mpsc::RecvError> {
+        match &self.state.capture_thread {
+            CaptureThreadHandle::Spawned(_) => self.state.response_receiver.recv(),
+            CaptureThreadHandle::External { capture_thread, .. } => loop {
+                match self.state.response_receiver.try_recv() {
+                    Ok(response) => return Ok(response),
+                    Err(mpsc::TryRecvError::Empty) => {
+                        if let Err(e) = self.dequeue_output_buffers() {
+                            warn!("Error while dequeuing OUTPUT buffers: {}", e);
+                        }
+                        let watch_output = self.state.output_queue.num_queued_buffers() > 0;
+                        if !Self::run_capture_thread(capture_thread, None, watch_output) {
+                            return Err(mpsc::RecvError);
+                        }
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => return Err(mpsc::RecvError),
+                }
+            },
+        }
+    }
+
     /// Drain the decoder, i.e. make sure all its pending work is processed.
     ///
     /// The `blocking` parameters decides whether this method is permitted to
@@ -419,9 +719,13 @@ where
     /// pending jobs, see the [`Decoder::flush`] method.
     pub fn drain(&self, blocking: bool) -> Result<bool, DrainError> {
         debug!("Drain requested");
+        // The capture thread exits after a fatal error and would never reply.
+        if self.is_thread_finished() {
+            return Err(DrainError::FatalError);
+        }
         self.send_command(DecoderCommand::Drain(blocking))?;
 
-        match self.state.response_receiver.recv()? {
+        match self.recv_response()? {
             CaptureThreadResponse::DrainDone(response) => match response {
                 Ok(completed) => Ok(completed),
                 Err(e) => {
@@ -449,10 +753,15 @@ where
     /// If a [`Decoder::drain`] operation was in progress, it is also canceled.
     ///
     /// This function is blocking. When is returns, the frame decoded callback
-    /// has been called for all pre-flush frames, and the decoder can accept new
+    /// has been called for all pre-flush frames, the metadata of the canceled frames has been
+    /// returned through [`DecoderEvent::FrameDropped`] events, and the decoder can accept new
     /// content to decode.
     pub fn flush(&self) -> Result<(), FlushError> {
         debug!("Flush requested");
+        // The capture thread exits after a fatal error and would never reply.
+        if self.is_thread_finished() {
+            return Err(FlushError::FatalError);
+        }
         let canceled_buffers = self.state.output_queue.stream_off()?;
 
         // Request the decoder to flush itself.
@@ -465,7 +774,7 @@ where
 
         // Wait for the decoder thread to signal it is done with our request.
         // TODO add timeout?
-        match self.state.response_receiver.recv()? {
+        match self.recv_response()? {
             CaptureThreadResponse::FlushDone(response) => match response {
                 Ok(()) => (),
                 Err(e) => {
@@ -491,6 +800,46 @@ where
         Ok(())
     }
 
+    // Make this thread sleep until at least one OUTPUT buffer is ready to be
+    // obtained through [`Decoder::try_get_buffer()`].
+    fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError> {
+        // An externally-driven decoder only makes progress while its capture thread runs, so run
+        // it until an OUTPUT buffer completes instead of waiting on the OUTPUT queue alone.
+        if let CaptureThreadHandle::External { capture_thread, .. } = &self.state.capture_thread {
+            let output_queue = &self.state.output_queue;
+            loop {
+                self.dequeue_output_buffers()?;
+                if output_queue.num_queued_buffers() < output_queue.num_buffers() {
+                    return Ok(());
+                }
+                if !Self::run_capture_thread(capture_thread, None, true) {
+                    return Err(GetBufferError::FatalError);
+                }
+            }
+        }
+
+        for event in self.state.output_poller.poll(None)? {
+            match event {
+                PollEvent::Device(DeviceEvent::OutputReady) => {
+                    self.dequeue_output_buffers()?;
+                }
+                _ => panic!("Unexpected return from OUTPUT queue poll!"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>
+    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>
+where
+    OP: BufferHandles,
+    P: HandlesProvider,
+    InputDoneCb: InputDoneCallback<OP>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
+    FormatChangedCb: FormatChangedCallback<P>,
+{
     /// Attempts to dequeue and release output buffers that the driver is done with.
     fn dequeue_output_buffers(&self) -> Result<(), DqBufError<V4l2Buffer>> {
         let output_queue = &self.state.output_queue;
@@ -501,39 +850,25 @@ where
                     (self.state.input_done_cb)(CompletedInputBuffer::Dequeued(buf));
                 }
                 Err(ioctl::DqBufError::NotReady) => break,
-                // TODO buffers with the error flag set should not result in
-                // a fatal error!
+                // Buffers with the error flag set are dequeued successfully and
+                // reported through `CompletedInputBuffer::has_error`, so this is
+                // an actual device error.
                 Err(e) => return Err(e),
             }
         }
 
         Ok(())
     }
-
-    // Make this thread sleep until at least one OUTPUT buffer is ready to be
-    // obtained through [`Decoder::try_get_buffer()`].
-    fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError> {
-        for event in self.state.output_poller.poll(None)? {
-            match event {
-                PollEvent::Device(DeviceEvent::OutputReady) => {
-                    self.dequeue_output_buffers()?;
-                }
-                _ => panic!("Unexpected return from OUTPUT queue poll!"),
-            }
-        }
-
-        Ok(())
-    }
 }
 
-impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb> OutputQueueableProvider<'a, OP>
-    for Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
+impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M> OutputQueueableProvider<'a, OP>
+    for Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>
 where
     Queue<Output, BuffersAllocated<OP>>: OutputQueueableProvider<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
 {
     type Queueable =
@@ -548,18 +883,20 @@ pub enum GetBufferError {
     PollError(#[from] PollError),
     #[error("error while obtaining buffer")]
     GetFreeBufferError(#[from] GetFreeBufferError),
+    #[error("the capture thread has stopped after a fatal error")]
+    FatalError,
 }
 
 /// Let the decoder provide the buffers from the OUTPUT queue.
-impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
+impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>
     GetFreeOutputBuffer<'a, OP, GetBufferError>
-    for Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
+    for Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>
 where
     Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
 {
     /// Returns a V4L2 buffer to be filled with a frame to decode if one
@@ -575,15 +912,17 @@ where
 
 // If [`GetFreeBuffer`] is implemented, we can also provide a blocking `get_buffer`
 // method.
-impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
-    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
+impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>
+    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb, M>>
 where
     Self: GetFreeOutputBuffer<'a, OP, GetBufferError>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: InputDoneCallback<OP>,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
+    for<'b> Queue<Capture, BuffersAllocated<P::HandleType>>:
+        GetFreeCaptureBuffer<'b, P::HandleType> + GetCaptureBufferByIndex<'b, P::HandleType>,
 {
     /// Returns the number of currently queued encoded buffers.
     pub fn num_queued_buffers(&self) -> usize {
@@ -594,7 +933,9 @@ where
     /// one to be available if needed.
     ///
     /// Contrary to [`Decoder::try_get_free_buffer()`], this method will wait for a buffer
-    /// to be available if needed.
+    /// to be available if needed. If the decoder has been started with
+    /// [`Decoder::start_external`], its event loop is run while waiting and the decoder callbacks
+    /// may be invoked from this method.
     pub fn get_buffer(
         &'a mut self,
     ) -> Result<<Self as OutputQueueableProvider<'a, OP>>::Queueable, GetBufferError> {
@@ -1,10 +1,10 @@
 use crate::{
     decoder::{
         stateful::{CaptureThreadResponse, DecoderCommand, DecoderEvent, DrainError},
-        DecoderEventCallback, FormatChangedCallback, FormatChangedReply,
+        CaptureFormatChange, DecoderEventCallback, FormatChangedCallback, FormatChangedReply,
     },
     device::{
-        poller::{DeviceEvent, PollEvent, Poller, Waker},
+        poller::{DeviceEvent, PollError, PollEvent, Poller, Waker},
         queue::{
             self,
             direction::Capture,
@@ -13,20 +13,26 @@ use crate::{
                 get_free::GetFreeCaptureBuffer, get_indexed::GetCaptureBufferByIndex,
                 CaptureQueueable,
             },
-            BuffersAllocated, Queue, QueueInit,
+            BuffersAllocated, Queue, QueueInit, QueueState,
         },
-        AllocatedQueue, Device, Stream, TryDequeue,
+        AllocatedQueue, Device, FatalError, Stream, TryDequeue,
     },
     ioctl::{self, SelectionTarget},
+    memory::BufferHandles,
+    metadata::{FrameMark, FrameMetadataMap},
+    Format, Rect,
 };
 
 use std::{
     io,
-    sync::{mpsc, Arc},
+    ops::ControlFlow,
+    sync::{mpsc, Arc, Mutex},
     task::Wake,
+    time::Duration,
 };
 
 use log::{debug, error, trace, warn};
+use nix::{errno::Errno, sys::time::TimeVal};
 use thiserror::Error;
 
 /// Check if `device` has a dynamic resolution change event pending.
@@ -36,7 +42,7 @@ use thiserror::Error;
 /// detected. This consumes all the event, meaning that if this method
 /// returned `true` once it will return `false` until a new resolution
 /// change happens in the stream.
-fn is_drc_event_pending(device: &Device) -> Result<bool, ioctl::DqEventError> {
+pub(super) fn is_drc_event_pending(device: &Device) -> Result<bool, ioctl::DqEventError> {
     let mut drc_pending = false;
 
     loop {
@@ -69,15 +75,20 @@ enum CaptureQueue<P: HandlesProvider> {
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         provider: P,
         cap_buffer_waker: Arc<Waker>,
+        /// Format the CAPTURE buffers have last been set up for.
+        format: Format,
         // TODO not super elegant...
         blocking_drain_in_progress: bool,
     },
+    /// The CAPTURE queue has been lost after failing to change its format. This only happens
+    /// after a fatal error has been reported.
+    Lost,
 }
 
-pub(super) struct CaptureThread<P, DecoderEventCb, FormatChangedCb>
+pub(super) struct CaptureThread<P, DecoderEventCb, FormatChangedCb, M>
 where
     P: HandlesProvider,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
 {
     device: Arc<Device>,
@@ -94,6 +105,14 @@ where
     // Sender we use to send status messages after receiving commands from the
     // main thread.
     response_sender: mpsc::Sender<CaptureThreadResponse>,
+    // Set when an unrecoverable error has been reported to the client.
+    fatal_error_reported: bool,
+
+    // Metadata of the frames being decoded, shared with the main thread.
+    frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
+    // Frames registered before this mark are dropped if they have not been decoded by the end of
+    // the pending drain.
+    drain_mark: Option<FrameMark>,
 }
 
 #[derive(Debug, Error)]
@@ -108,6 +127,8 @@ enum UpdateCaptureError {
     FreeBuffers(#[from] ioctl::ReqbufsError),
     #[error("error while obtaining CAPTURE format: {0}")]
     GFmt(#[from] ioctl::GFmtError),
+    #[error("error while querying the minimum number of CAPTURE buffers: {0}")]
+    MinBuffers(#[from] ioctl::GCtrlError),
     #[error("error while obtaining selection target from CAPTURE queue: {0}")]
     GSelection(#[from] ioctl::GSelectionError),
     #[error("error while running the CAPTURE format callback: {0}")]
@@ -118,25 +139,55 @@ enum UpdateCaptureError {
     AddWaker(io::Error),
     #[error("error while streaming CAPTURE queue: {0}")]
     StreamOn(#[from] ioctl::StreamOnError),
+    #[error("CAPTURE queue lost after a previous error")]
+    QueueLost,
 }
 
-const CAPTURE_READY: u32 = 1;
-const COMMAND_WAITING: u32 = 2;
+/// Stream `capture_queue` off and back on, dropping its queued buffers.
+fn restart_capture_queue<P: BufferHandles>(
+    capture_queue: &Queue<Capture, BuffersAllocated<P>>,
+) -> Result<(), FatalError> {
+    capture_queue.stream_off()?;
+    capture_queue.stream_on()?;
 
-#[derive(Debug, Error)]
-enum ProcessEventsError {
-    #[error("error while dequeueing event")]
-    DqEvent(#[from] ioctl::DqEventError),
-    #[error("error while requesting buffers")]
-    RequestBuffers(#[from] queue::RequestBuffersError),
-    #[error("error while updating CAPTURE format")]
-    UpdateCapture(#[from] UpdateCaptureError),
+    Ok(())
 }
 
-impl<P, DecoderEventCb, FormatChangedCb> CaptureThread<P, DecoderEventCb, FormatChangedCb>
+/// Returns the visible rectangle of the frames produced by `capture_queue`.
+fn get_visible_rect<S: QueueState>(
+    capture_queue: &Queue<Capture, S>,
+) -> Result<Rect, ioctl::GSelectionError> {
+    let visible_rect = capture_queue.get_selection(SelectionTarget::Compose)?;
+    debug!(
+        "Visible rectangle: ({}, {}), {}x{}",
+        visible_rect.left, visible_rect.top, visible_rect.width, visible_rect.height
+    );
+
+    Ok(visible_rect)
+}
+
+/// Default number of CAPTURE buffers to request if the driver does not report its minimum.
+pub(super) const DEFAULT_MIN_CAPTURE_BUFFERS: usize = 4;
+
+/// Returns the minimum number of buffers required by `capture_queue` for the current format.
+fn get_min_num_buffers<S: QueueState>(
+    capture_queue: &Queue<Capture, S>,
+) -> Result<usize, ioctl::GCtrlError> {
+    let min_num_buffers = capture_queue
+        .get_min_num_buffers()?
+        .unwrap_or(DEFAULT_MIN_CAPTURE_BUFFERS);
+    debug!("Stream requires {} capture buffers", min_num_buffers);
+
+    Ok(min_num_buffers)
+}
+
+const CAPTURE_READY: u32 = 1;
+const COMMAND_WAITING: u32 = 2;
+
+impl<P, DecoderEventCb, FormatChangedCb, M> CaptureThread<P, DecoderEventCb, FormatChangedCb, M>
 where
     P: HandlesProvider,
-    DecoderEventCb: DecoderEventCallback<P>,
+    DecoderEventCb: DecoderEventCallback<P, M>,
     FormatChangedCb: FormatChangedCallback<P>,
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
         GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
@@ -144,6 +195,7 @@ where
     pub(super) fn new(
         device: &Arc<Device>,
         capture_queue: Queue<Capture, QueueInit>,
+        frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
         event_cb: DecoderEventCb,
         set_capture_format_cb: FormatChangedCb,
         command_receiver: mpsc::Receiver<DecoderCommand>,
@@ -165,6 +217,9 @@ where
             command_waker,
             command_receiver,
             response_sender,
+            fatal_error_reported: false,
+            frame_metadata,
+            drain_mark: None,
         };
 
         Ok(decoder_thread)
@@ -173,7 +228,34 @@ where
     fn send_response(&self, response: CaptureThreadResponse) {
         trace!("Sending response: {:?}", response);
 
-        self.response_sender.send(response).unwrap();
+        if let Err(e) = self.response_sender.send(response) {
+            error!("Error while sending response: {}", e);
+        }
+    }
+
+    /// Report `error` to the client. The main loop will exit after this.
+    fn report_fatal_error(&mut self, error: FatalError) {
+        error!("Fatal error, exiting capture thread: {}", error);
+        (self.event_cb)(DecoderEvent::FatalError(error));
+        self.fatal_error_reported = true;
+        // We won't reply to commands anymore, so disconnect the response channel to make sure
+        // the client does not wait forever for a reply to a command sent in the meantime.
+        self.response_sender = mpsc::channel().0;
+    }
+
+    /// Returns whether the event loop has stopped after a fatal error.
+    pub(super) fn fatal_error_reported(&self) -> bool {
+        self.fatal_error_reported
+    }
+
+    /// Report the frames which metadata is in `dropped` as dropped to the client.
+    fn report_dropped_frames(&mut self, dropped: Vec<M>) {
+        if !dropped.is_empty() {
+            debug!("{} frames dropped", dropped.len());
+        }
+        for metadata in dropped {
+            (self.event_cb)(DecoderEvent::FrameDropped(metadata));
+        }
     }
 
     fn drain(&mut self, blocking: bool) {
@@ -181,7 +263,7 @@ where
         let response = match &mut self.capture_queue {
             // We cannot initiate the flush sequence before receiving the initial
             // resolution.
-            CaptureQueue::AwaitingResolution { .. } => {
+            CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => {
                 Some(CaptureThreadResponse::DrainDone(Err(DrainError::TryAgain)))
             }
             CaptureQueue::Decoding {
@@ -190,7 +272,13 @@ where
             } => {
                 // We can receive the LAST buffer, send the STOP command
                 // and exit the loop once the buffer with the LAST tag is received.
-                ioctl::decoder_cmd::<_, ()>(&*self.device, ioctl::DecoderCommand::Stop).unwrap();
+                if let Err(e) =
+                    ioctl::decoder_cmd::<_, ()>(&*self.device, ioctl::DecoderCommand::Stop)
+                {
+                    self.report_fatal_error(e.into());
+                    return;
+                }
+                self.drain_mark = Some(self.frame_metadata.lock().unwrap().mark());
                 if blocking {
                     // If we are blocking, we will send the answer when the drain
                     // is completed.
@@ -211,7 +299,7 @@ where
     fn flush(&mut self) {
         trace!("Processing flush command");
         match &mut self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => {}
+            CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => {}
             CaptureQueue::Decoding {
                 capture_queue,
                 blocking_drain_in_progress,
@@ -220,12 +308,19 @@ where
                 // Stream the capture queue off and back on, dropping any queued
                 // buffer, and making the decoder ready to work again if it was
                 // halted.
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
+                if let Err(e) = restart_capture_queue(capture_queue) {
+                    self.report_fatal_error(e);
+                    return;
+                }
                 *blocking_drain_in_progress = false;
             }
         }
 
+        // All the frames queued so far are gone.
+        self.drain_mark = None;
+        let dropped = self.frame_metadata.lock().unwrap().take_all();
+        self.report_dropped_frames(dropped);
+
         self.send_response(CaptureThreadResponse::FlushDone(Ok(())));
         self.enqueue_capture_buffers()
     }
@@ -234,7 +329,7 @@ where
         trace!("Queueing available CAPTURE buffers");
         let (capture_queue, provider, cap_buffer_waker) = match &mut self.capture_queue {
             // Capture queue is not set up yet, no buffers to queue.
-            CaptureQueue::AwaitingResolution { .. } => return,
+            CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => return,
             CaptureQueue::Decoding {
                 capture_queue,
                 provider,
@@ -275,81 +370,147 @@ where
     fn process_v4l2_event(mut self) -> Self {
         trace!("Processing V4L2 event");
         match self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => {
-                if is_drc_event_pending(&self.device).unwrap() {
-                    self = self.update_capture_format().unwrap()
-                }
-            }
-            CaptureQueue::Decoding { .. } => unreachable!(),
+            CaptureQueue::AwaitingResolution { .. } => match is_drc_event_pending(&self.device) {
+                Ok(true) => self.change_capture_format(),
+                Ok(false) => (),
+                Err(e) => self.report_fatal_error(e.into()),
+            },
+            CaptureQueue::Decoding { .. } | CaptureQueue::Lost => unreachable!(),
         }
 
         self
     }
 
-    fn update_capture_format(mut self) -> Result<Self, UpdateCaptureError> {
-        debug!("Updating CAPTURE format");
-        // First reset the capture queue to the `Init` state if needed.
-        let mut capture_queue = match self.capture_queue {
-            // Initial resolution
-            CaptureQueue::AwaitingResolution { capture_queue } => {
-                // Stop listening to V4L2 events. We will check them when we get
-                // a buffer with the LAST flag.
-                self.poller
-                    .disable_event(DeviceEvent::V4L2Event)
-                    .map_err(Into::<io::Error>::into)
-                    .map_err(UpdateCaptureError::PollerEvents)?;
-                // Listen to CAPTURE buffers being ready to dequeue, as we will
-                // be streaming soon.
-                self.poller
-                    .enable_event(DeviceEvent::CaptureReady)
-                    .map_err(Into::<io::Error>::into)
-                    .map_err(UpdateCaptureError::PollerEvents)?;
-                capture_queue
-            }
-            // Dynamic resolution change
-            CaptureQueue::Decoding { capture_queue, .. } => {
-                // Remove the waker for the previous buffers pool, as we will
-                // get a new set of buffers.
-                self.poller
-                    .remove_waker(CAPTURE_READY)
-                    .map_err(UpdateCaptureError::RemoveWaker)?;
-                // Deallocate the queue and return it to the `Init` state. Good
-                // as new!
-                capture_queue.stream_off()?;
-                capture_queue.free_buffers()?.queue
-            }
-        };
+    /// Update the CAPTURE format after a resolution change, reporting a fatal error if this fails.
+    fn change_capture_format(&mut self) {
+        if let Err(e) = self.update_capture_format() {
+            self.report_fatal_error(FatalError::CaptureFormatChange(e.into()));
+        }
+    }
 
-        // Now get the parameters of the new format and build our new CAPTURE
-        // queue.
+    /// Update the CAPTURE queue to the new format. The CAPTURE queue is lost if this fails.
+    fn update_capture_format(&mut self) -> Result<(), UpdateCaptureError> {
+        debug!("Updating CAPTURE format");
 
-        // TODO use the proper control to get the right value.
-        let min_num_buffers = 4usize;
-        debug!("Stream requires {} capture buffers", min_num_buffers);
+        // First reset the capture queue to the `Init` state if needed, and
+        // let the client adjust the new format and give us the handles
+        // provider.
+        let (capture_queue, reply) =
+            match std::mem::replace(&mut self.capture_queue, CaptureQueue::Lost) {
+                // Initial resolution
+                CaptureQueue::AwaitingResolution { mut capture_queue } => {
+                    // Stop listening to V4L2 events. We will check them when we get
+                    // a buffer with the LAST flag.
+                    self.poller
+                        .disable_event(DeviceEvent::V4L2Event)
+                        .map_err(Into::<io::Error>::into)
+                        .map_err(UpdateCaptureError::PollerEvents)?;
+                    // Listen to CAPTURE buffers being ready to dequeue, as we will
+                    // be streaming soon.
+                    self.poller
+                        .enable_event(DeviceEvent::CaptureReady)
+                        .map_err(Into::<io::Error>::into)
+                        .map_err(UpdateCaptureError::PollerEvents)?;
+
+                    let min_num_buffers = get_min_num_buffers(&capture_queue)?;
+                    let visible_rect = get_visible_rect(&capture_queue)?;
+                    let reply = (self.set_capture_format_cb)(
+                        CaptureFormatChange::Reallocate(capture_queue.change_format()?),
+                        visible_rect,
+                        min_num_buffers,
+                    )?;
+
+                    (capture_queue, reply)
+                }
+                // Dynamic resolution change
+                CaptureQueue::Decoding {
+                    capture_queue,
+                    cap_buffer_waker,
+                    format,
+                    ..
+                } => {
+                    capture_queue.stream_off()?;
+
+                    let new_format: Format = capture_queue.get_format()?;
+                    let min_num_buffers = get_min_num_buffers(&capture_queue)?;
+                    let visible_rect = get_visible_rect(&capture_queue)?;
+
+                    // Keep the current buffers if the new format fits into them.
+                    // Only a streamoff/streamon cycle is needed in that case.
+                    let reply = if new_format.pixelformat == format.pixelformat
+                        && capture_queue.num_buffers() >= min_num_buffers
+                        && capture_queue.can_hold_format(&new_format)
+                    {
+                        debug!("New format fits into current CAPTURE buffers");
+                        let reply = (self.set_capture_format_cb)(
+                            CaptureFormatChange::Reuse(new_format.clone()),
+                            visible_rect,
+                            min_num_buffers,
+                        )?;
+
+                        queue::check_min_num_buffers(reply.num_buffers, min_num_buffers)?;
+
+                        if reply.mem_type.into() == capture_queue.memory_type().into()
+                            && reply.num_buffers <= capture_queue.num_buffers()
+                        {
+                            // Signal the waker so we immediately enqueue buffers
+                            // using the new provider.
+                            cap_buffer_waker.wake_by_ref();
+                            capture_queue.stream_on()?;
+
+                            self.capture_queue = CaptureQueue::Decoding {
+                                capture_queue,
+                                provider: reply.provider,
+                                cap_buffer_waker,
+                                format: new_format,
+                                blocking_drain_in_progress: false,
+                            };
+                            return Ok(());
+                        }
 
-        let visible_rect = capture_queue.get_selection(SelectionTarget::Compose)?;
-        debug!(
-            "Visible rectangle: ({}, {}), {}x{}",
-            visible_rect.left, visible_rect.top, visible_rect.width, visible_rect.height
-        );
+                        debug!("Client reply requires new CAPTURE buffers");
+                        Some(reply)
+                    } else {
+                        None
+                    };
+
+                    // Remove the waker for the previous buffers pool, as we will
+                    // get a new set of buffers.
+                    self.poller
+                        .remove_waker(CAPTURE_READY)
+                        .map_err(UpdateCaptureError::RemoveWaker)?;
+                    // Deallocate the queue and return it to the `Init` state. Good
+                    // as new!
+                    let mut capture_queue = capture_queue.free_buffers()?.queue;
+
+                    let reply = match reply {
+                        Some(reply) => reply,
+                        None => (self.set_capture_format_cb)(
+                            CaptureFormatChange::Reallocate(capture_queue.change_format()?),
+                            visible_rect,
+                            min_num_buffers,
+                        )?,
+                    };
+
+                    (capture_queue, reply)
+                }
+                CaptureQueue::Lost => return Err(UpdateCaptureError::QueueLost),
+            };
 
-        // Let the client adjust the new format and give us the handles provider.
         let FormatChangedReply {
             provider,
             mem_type,
             num_buffers,
-        } = (self.set_capture_format_cb)(
-            capture_queue.change_format()?,
-            visible_rect,
-            min_num_buffers,
-        )?;
+        } = reply;
 
         debug!("Client requires {} capture buffers", num_buffers);
+        capture_queue.check_num_buffers(num_buffers)?;
 
         // Allocate the new CAPTURE buffers and get ourselves a new waker for
         // returning buffers.
         let capture_queue =
             capture_queue.request_buffers_generic::<P::HandleType>(mem_type, num_buffers as u32)?;
+        let format = capture_queue.get_format()?;
         let cap_buffer_waker = self
             .poller
             .add_waker(CAPTURE_READY)
@@ -360,15 +521,15 @@ where
         cap_buffer_waker.wake_by_ref();
         capture_queue.stream_on()?;
 
-        Ok(Self {
-            capture_queue: CaptureQueue::Decoding {
-                capture_queue,
-                provider,
-                cap_buffer_waker,
-                blocking_drain_in_progress: false,
-            },
-            ..self
-        })
+        self.capture_queue = CaptureQueue::Decoding {
+            capture_queue,
+            provider,
+            cap_buffer_waker,
+            format,
+            blocking_drain_in_progress: false,
+        };
+
+        Ok(())
     }
 
     /// Attempt to dequeue and process a single CAPTURE buffer.
@@ -386,7 +547,7 @@ where
         trace!("Dequeueing decoded CAPTURE buffers");
         let (capture_queue, cap_buffer_waker, blocking_drain_in_progress) =
             match &mut self.capture_queue {
-                CaptureQueue::AwaitingResolution { .. } => unreachable!(),
+                CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => unreachable!(),
                 CaptureQueue::Decoding {
                     capture_queue,
                     cap_buffer_waker,
@@ -397,16 +558,21 @@ where
 
         let mut cap_buf = match capture_queue.try_dequeue() {
             Ok(cap_buf) => cap_buf,
-            Err(e) => {
+            Err(e @ (ioctl::DqBufError::NotReady | ioctl::DqBufError::Eos)) => {
                 warn!(
                     "Expected a CAPTURE buffer but none available, possible driver bug: {}",
                     e
                 );
                 return self;
             }
+            Err(e) => {
+                self.report_fatal_error(e.into());
+                return self;
+            }
         };
 
         let is_last = cap_buf.data.is_last();
+        let has_error = cap_buf.data.flags().contains(ioctl::BufferFlags::ERROR);
 
         // Add a drop callback to the dequeued buffer so we
         // re-queue it as soon as it is dropped.
@@ -416,14 +582,40 @@ where
             cap_waker.wake();
         });
 
+        let timestamp = cap_buf.data.timestamp();
+        let timestamp = TimeVal::new(timestamp.tv_sec, timestamp.tv_usec);
+        let metadata = self.frame_metadata.lock().unwrap().take(&timestamp);
+
         // Pass buffers to the client
-        (self.event_cb)(DecoderEvent::FrameDecoded(cap_buf));
+        if has_error {
+            warn!(
+                "CAPTURE buffer {} has the error flag set",
+                cap_buf.data.index()
+            );
+            (self.event_cb)(DecoderEvent::FrameError {
+                timestamp,
+                buffer: cap_buf,
+                metadata,
+            });
+        } else {
+            (self.event_cb)(DecoderEvent::FrameDecoded {
+                buffer: cap_buf,
+                metadata,
+            });
+        }
 
         if is_last {
             debug!("CAPTURE buffer marked with LAST flag");
-            if is_drc_event_pending(&self.device).unwrap() {
+            let drc_pending = match is_drc_event_pending(&self.device) {
+                Ok(drc_pending) => drc_pending,
+                Err(e) => {
+                    self.report_fatal_error(e.into());
+                    return self;
+                }
+            };
+            if drc_pending {
                 debug!("DRC event pending, updating CAPTURE format");
-                self = self.update_capture_format().unwrap()
+                self.change_capture_format();
             }
             // No DRC event pending, this is the end of the stream.
             // We need to stop and restart the CAPTURE queue, otherwise
@@ -435,12 +627,19 @@ where
                 // instead, but with vicodec the CAPTURE queue reports
                 // as ready in subsequent polls() and DQBUF returns
                 // -EPIPE...
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
+                if let Err(e) = restart_capture_queue(capture_queue) {
+                    self.report_fatal_error(e);
+                    return self;
+                }
+                let blocking_drain_done = std::mem::take(blocking_drain_in_progress);
+                // Frames queued before the drain that have not been decoded by now never will.
+                if let Some(mark) = self.drain_mark.take() {
+                    let dropped = self.frame_metadata.lock().unwrap().take_before(mark);
+                    self.report_dropped_frames(dropped);
+                }
                 (self.event_cb)(DecoderEvent::EndOfStream);
-                if *blocking_drain_in_progress {
+                if blocking_drain_done {
                     debug!("Signaling end of blocking drain");
-                    *blocking_drain_in_progress = false;
                     self.send_response(CaptureThreadResponse::DrainDone(Ok(true)));
                 }
             }
@@ -450,89 +649,144 @@ where
     }
 
     pub(super) fn run(mut self) -> Self {
-        'mainloop: loop {
-            if let CaptureQueue::Decoding { capture_queue, .. } = &self.capture_queue {
-                match capture_queue.num_queued_buffers() {
-                    // If there are no buffers on the CAPTURE queue, poll() will return
-                    // immediately with EPOLLERR and we would loop indefinitely.
-                    // Prevent this by temporarily disabling polling the CAPTURE queue
-                    // in such cases.
-                    0 => {
-                        self.poller
-                            .disable_event(DeviceEvent::CaptureReady)
-                            .unwrap();
-                    }
-                    // If device polling was disabled and we have buffers queued, we
-                    // can reenable it as poll will now wait for a CAPTURE buffer to
-                    // be ready for dequeue.
-                    _ => {
-                        self.poller.enable_event(DeviceEvent::CaptureReady).unwrap();
-                    }
-                }
+        loop {
+            match self.process_events(None, false) {
+                ControlFlow::Continue(thread) => self = thread,
+                ControlFlow::Break(thread) => break thread.finish(),
             }
+        }
+    }
 
-            trace!("Polling...");
-            let events = match self.poller.poll(None) {
-                Ok(events) => events,
-                Err(e) => {
-                    error!("Polling failure, exiting capture thread: {}", e);
-                    break 'mainloop;
-                }
+    /// Wait up to `timeout` for events and process them.
+    ///
+    /// If `watch_output` is `true`, completed OUTPUT buffers also interrupt the wait. They are left
+    /// for the caller to dequeue, and it must only be set while OUTPUT buffers are queued, as
+    /// poll() would otherwise return immediately with EPOLLERR when the CAPTURE queue is empty.
+    ///
+    /// Returns `ControlFlow::Break` if the capture thread should stop, either because it has been
+    /// requested to or because of a fatal error.
+    pub(super) fn process_events(
+        mut self,
+        timeout: Option<Duration>,
+        watch_output: bool,
+    ) -> ControlFlow<Self, Self> {
+        let res = if watch_output {
+            self.poller.enable_event(DeviceEvent::OutputReady)
+        } else {
+            self.poller.disable_event(DeviceEvent::OutputReady)
+        };
+        if let Err(e) = res {
+            self.report_fatal_error(FatalError::Poller(e.into()));
+        }
+
+        if let CaptureQueue::Decoding { capture_queue, .. } = &self.capture_queue {
+            let res = match capture_queue.num_queued_buffers() {
+                // If there are no buffers on the CAPTURE queue, poll() will return
+                // immediately with EPOLLERR and we would loop indefinitely.
+                // Prevent this by temporarily disabling polling the CAPTURE queue
+                // in such cases.
+                0 => self.poller.disable_event(DeviceEvent::CaptureReady),
+                // If device polling was disabled and we have buffers queued, we
+                // can reenable it as poll will now wait for a CAPTURE buffer to
+                // be ready for dequeue.
+                _ => self.poller.enable_event(DeviceEvent::CaptureReady),
             };
-            for event in events {
-                self = match event {
-                    PollEvent::Device(DeviceEvent::V4L2Event) => self.process_v4l2_event(),
-                    PollEvent::Device(DeviceEvent::CaptureReady) => self.dequeue_capture_buffer(),
-                    PollEvent::Waker(CAPTURE_READY) => {
-                        self.enqueue_capture_buffers();
-                        self
-                    }
-                    PollEvent::Waker(COMMAND_WAITING) => {
-                        loop {
-                            let command =
-                                match self.command_receiver.recv_timeout(Default::default()) {
-                                    Ok(command) => command,
-                                    Err(mpsc::RecvTimeoutError::Timeout) => break,
-                                    Err(e) => {
-                                        error!("Error while reading decoder command: {}", e);
-                                        break;
-                                    }
-                                };
-                            match command {
-                                DecoderCommand::Drain(blocking) => self.drain(blocking),
-                                DecoderCommand::Flush => self.flush(),
-                                DecoderCommand::Stop => {
-                                    trace!("Processing stop command");
-                                    break 'mainloop;
-                                }
+            if let Err(e) = res {
+                self.report_fatal_error(FatalError::Poller(e.into()));
+            }
+        }
+
+        if self.fatal_error_reported {
+            return ControlFlow::Break(self);
+        }
+
+        trace!("Polling...");
+        let events = match self.poller.poll(timeout) {
+            Ok(events) => events,
+            // A signal interrupted us, just try again.
+            Err(PollError::EPollWait(Errno::EINTR)) => return ControlFlow::Continue(self),
+            Err(e) => {
+                self.report_fatal_error(e.into());
+                return ControlFlow::Break(self);
+            }
+        };
+        for event in events {
+            self = match event {
+                PollEvent::Device(DeviceEvent::V4L2Event) => self.process_v4l2_event(),
+                PollEvent::Device(DeviceEvent::CaptureReady) => self.dequeue_capture_buffer(),
+                PollEvent::Device(DeviceEvent::OutputReady) if watch_output => self,
+                PollEvent::Waker(CAPTURE_READY) => {
+                    self.enqueue_capture_buffers();
+                    self
+                }
+                PollEvent::Waker(COMMAND_WAITING) => {
+                    loop {
+                        let command = match self.command_receiver.recv_timeout(Default::default()) {
+                            Ok(command) => command,
+                            Err(mpsc::RecvTimeoutError::Timeout) => break,
+                            Err(e) => {
+                                error!("Error while reading decoder command: {}", e);
+                                break;
+                            }
+                        };
+                        match command {
+                            DecoderCommand::Drain(blocking) => self.drain(blocking),
+                            DecoderCommand::Flush => self.flush(),
+                            DecoderCommand::Stop => {
+                                trace!("Processing stop command");
+                                return ControlFlow::Break(self);
                             }
                         }
-                        self
                     }
-                    _ => panic!("Unexpected event!"),
+                    self
                 }
+                _ => panic!("Unexpected event!"),
             }
         }
 
-        // Return the decoder to the awaiting resolution state.
-        match self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => self,
-            CaptureQueue::Decoding { capture_queue, .. } => Self {
-                capture_queue: CaptureQueue::AwaitingResolution {
-                    capture_queue: {
-                        capture_queue.stream_off().unwrap();
-                        capture_queue.free_buffers().unwrap().queue
-                    },
-                },
-                poller: {
-                    let mut poller = self.poller;
-                    poller.disable_event(DeviceEvent::CaptureReady).unwrap();
-                    poller.enable_event(DeviceEvent::V4L2Event).unwrap();
-                    poller.remove_waker(CAPTURE_READY).unwrap();
-                    poller
-                },
-                ..self
-            },
+        ControlFlow::Continue(self)
+    }
+
+    /// Returns the decoder to the awaiting resolution state once it has stopped, and reports the
+    /// frames still pending as dropped.
+    pub(super) fn finish(mut self) -> Self {
+        self.drain_mark = None;
+        let dropped = self.frame_metadata.lock().unwrap().take_all();
+        self.report_dropped_frames(dropped);
+
+        if let Err(e) = self.release_capture_queue() {
+            self.report_fatal_error(e);
         }
+
+        self
+    }
+
+    /// Stop the CAPTURE queue and free its buffers, so it can be set up again for the next
+    /// resolution. The CAPTURE queue is lost if this fails.
+    fn release_capture_queue(&mut self) -> Result<(), FatalError> {
+        let capture_queue = match std::mem::replace(&mut self.capture_queue, CaptureQueue::Lost) {
+            CaptureQueue::Decoding { capture_queue, .. } => capture_queue,
+            capture_queue => {
+                self.capture_queue = capture_queue;
+                return Ok(());
+            }
+        };
+
+        capture_queue.stream_off()?;
+        self.capture_queue = CaptureQueue::AwaitingResolution {
+            capture_queue: capture_queue.free_buffers()?.queue,
+        };
+
+        self.poller
+            .disable_event(DeviceEvent::CaptureReady)
+            .map_err(|e| FatalError::Poller(e.into()))?;
+        self.poller
+            .enable_event(DeviceEvent::V4L2Event)
+            .map_err(|e| FatalError::Poller(e.into()))?;
+        self.poller
+            .remove_waker(CAPTURE_READY)
+            .map_err(FatalError::Poller)?;
+
+        Ok(())
     }
 }
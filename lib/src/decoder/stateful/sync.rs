@@ -0,0 +1,531 @@
+//! Synchronous, pull-based interface to a stateful decoder.
+//!
+//! Contrary to [`Decoding`](super::Decoding), a [`SyncDecoder`] does not spawn any thread and does
+//! not use callbacks: compressed chunks are submitted using [`SyncDecoder::decode`], and the
+//! resulting frames, format changes and end of stream are pulled as [`DecoderOutput`] items from
+//! the current thread. Both queues use `MMAP` buffers.
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use nix::sys::time::{TimeVal, TimeValLike};
+//! # use v4l2r::decoder::stateful::{sync::DecoderOutput, Decoder};
+//! # use v4l2r::memory::MmapHandle;
+//! #
+//! # let chunks: Vec<Vec<u8>> = vec![];
+//! let mut decoder = Decoder::open(Path::new("/dev/video0"))
+//!     .unwrap()
+//!     .set_output_format(|f| {
+//!         f.set_pixelformat(b"FWHT").apply::<v4l2r::Format>()?;
+//!         Ok(())
+//!     })
+//!     .unwrap()
+//!     .allocate_output_buffers::<Vec<MmapHandle>>(4)
+//!     .unwrap()
+//!     .start_sync()
+//!     .unwrap();
+//!
+//! let mut handle_output = |output| match output {
+//!     DecoderOutput::FormatChanged { format, .. } => println!("New format: {:?}", format),
+//!     DecoderOutput::Frame(frame) => println!("Frame {}", frame.data.index()),
+//!     DecoderOutput::FrameError(frame) => println!("Corrupted frame {}", frame.data.index()),
+//!     DecoderOutput::EndOfStream => println!("End of stream"),
+//! };
+//!
+//! for (i, chunk) in chunks.iter().enumerate() {
+//!     decoder.decode(chunk, TimeVal::seconds(i as i64)).unwrap();
+//!     for output in decoder.frames() {
+//!         handle_output(output.unwrap());
+//!     }
+//! }
+//!
+//! for output in decoder.drain().unwrap() {
+//!     handle_output(output.unwrap());
+//! }
+//! ```
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use log::{debug, trace, warn};
+use nix::{errno::Errno, sys::time::TimeVal};
+use thiserror::Error;
+
+use super::{
+    capture_thread::{is_drc_event_pending, DEFAULT_MIN_CAPTURE_BUFFERS},
+    Decoder, ReadyToDecode, StartDecoderError,
+};
+use crate::{
+    device::{
+        poller::{DeviceEvent, PollError, PollEvent, Poller},
+        queue::{
+            direction::{Capture, Output},
+            dqbuf::DqBuffer,
+            qbuf::get_free::{GetFreeBufferError, GetFreeCaptureBuffer, GetFreeOutputBuffer},
+            BuffersAllocated, Queue, QueueInit, RequestBuffersError,
+        },
+        AllocatedQueue, Device, Stream, TryDequeue,
+    },
+    ioctl::{self, subscribe_event, DqBufError, SelectionTarget, V4l2Buffer},
+    memory::MmapHandle,
+    Format, Rect,
+};
+
+/// Type of the decoded frames produced by a [`SyncDecoder`].
+pub type DecodedFrame = DqBuffer<Capture, Vec<MmapHandle>>;
+
+/// Items produced by a [`SyncDecoder`].
+pub enum DecoderOutput {
+    /// The CAPTURE buffers have been (re)allocated for a new format. All the frames that follow
+    /// use this format.
+    FormatChanged {
+        format: Format,
+        visible_rect: Rect,
+        num_buffers: usize,
+    },
+    /// A decoded frame. Its CAPTURE buffer is returned to the decoder when it is dropped.
+    Frame(DecodedFrame),
+    /// A frame the driver flagged as erroneous. Its content may be corrupted. Decoding continues.
+    FrameError(DecodedFrame),
+    /// All the frames corresponding to the input submitted before [`SyncDecoder::drain`] have
+    /// been produced. The decoder can accept new input after this.
+    EndOfStream,
+}
+
+#[derive(Debug, Error)]
+pub enum SyncDecoderError {
+    #[error("input of {0} bytes does not fit into OUTPUT buffers of {1} bytes")]
+    InputTooLarge(usize, usize),
+    #[error("all CAPTURE buffers are held by the client, pull and drop some frames first")]
+    FramesPending,
+    #[error("cannot drain now: CAPTURE format not yet determined")]
+    TryAgain,
+    #[error("error while polling the device: {0}")]
+    Poll(#[from] PollError),
+    #[error("error while enabling poller event: {0}")]
+    PollerEvent(nix::Error),
+    #[error("cannot map OUTPUT buffer")]
+    MapOutputBuffer,
+    #[error("error while obtaining a free buffer: {0}")]
+    GetFreeBuffer(#[from] GetFreeBufferError),
+    #[error("error while queueing buffer: {0}")]
+    QueueBuffer(#[from] ioctl::QBufError<()>),
+    #[error("error while dequeueing buffer: {0}")]
+    DequeueBuffer(#[from] DqBufError<V4l2Buffer>),
+    #[error("error while sending decoder command: {0}")]
+    DecoderCmd(#[from] ioctl::DecoderCmdError),
+    #[error("error while dequeueing V4L2 event: {0}")]
+    DqEvent(#[from] ioctl::DqEventError),
+    #[error("error while querying the minimum number of CAPTURE buffers: {0}")]
+    MinBuffers(#[from] ioctl::GCtrlError),
+    #[error("error while obtaining CAPTURE format: {0}")]
+    GFmt(#[from] ioctl::GFmtError),
+    #[error("error while obtaining selection target from CAPTURE queue: {0}")]
+    GSelection(#[from] ioctl::GSelectionError),
+    #[error("error while freeing CAPTURE buffers: {0}")]
+    FreeBuffers(#[from] ioctl::ReqbufsError),
+    #[error("error while requesting CAPTURE buffers: {0}")]
+    RequestBuffers(#[from] RequestBuffersError),
+    #[error("error while streaming CAPTURE queue on: {0}")]
+    StreamOn(#[from] ioctl::StreamOnError),
+    #[error("error while streaming CAPTURE queue off: {0}")]
+    StreamOff(#[from] ioctl::StreamOffError),
+    #[error("CAPTURE queue lost after a failed format change")]
+    CaptureQueueLost,
+}
+
+/// Number of CAPTURE buffers allocated on top of the minimum required by the driver by default,
+/// so the client can hold some decoded frames without stalling the decoder.
+pub const DEFAULT_EXTRA_CAPTURE_BUFFERS: usize = 2;
+
+enum CaptureQueue {
+    AwaitingResolution(Queue<Capture, QueueInit>),
+    Decoding(Queue<Capture, BuffersAllocated<Vec<MmapHandle>>>),
+}
+
+impl Decoder<ReadyToDecode<Vec<MmapHandle>>> {
+    /// Start decoding without spawning a capture thread, returning a [`SyncDecoder`] that
+    /// must be driven from the current thread.
+    pub fn start_sync(self) -> Result<SyncDecoder, StartDecoderError> {
+        // We are interested in all resolution change events for the current input (normally 0).
+        subscribe_event(
+            &*self.device,
+            ioctl::EventType::SourceChange(0),
+            ioctl::SubscribeEventFlags::empty(),
+        )?;
+
+        let mut poller =
+            Poller::new(Arc::clone(&self.device)).map_err(StartDecoderError::CannotCreatePoller)?;
+        poller
+            .enable_event(DeviceEvent::V4L2Event)
+            .map_err(StartDecoderError::CannotEnableEvent)?;
+        if let Some(counter) = self.state.poll_wakeups_counter {
+            poller.set_poll_counter(counter);
+        }
+
+        self.state.output_queue.stream_on()?;
+
+        Ok(SyncDecoder {
+            device: self.device,
+            output_queue: self.state.output_queue,
+            capture_queue: Some(CaptureQueue::AwaitingResolution(self.state.capture_queue)),
+            poller,
+            pending: VecDeque::new(),
+            extra_capture_buffers: DEFAULT_EXTRA_CAPTURE_BUFFERS,
+        })
+    }
+}
+
+/// Stateful decoder driven synchronously from the thread that owns it.
+///
+/// See the [module documentation](self) for an example.
+pub struct SyncDecoder {
+    device: Arc<Device>,
+    output_queue: Queue<Output, BuffersAllocated<Vec<MmapHandle>>>,
+    /// Only `None` while the CAPTURE buffers are being reallocated.
+    capture_queue: Option<CaptureQueue>,
+    poller: Poller,
+    /// Outputs that have been dequeued but not pulled by the client yet.
+    pending: VecDeque<DecoderOutput>,
+    /// Number of CAPTURE buffers to allocate on top of the driver's minimum.
+    extra_capture_buffers: usize,
+}
+
+impl SyncDecoder {
+    pub fn num_output_buffers(&self) -> usize {
+        self.output_queue.num_buffers()
+    }
+
+    /// Set the number of CAPTURE buffers to allocate on top of the minimum required by the driver,
+    /// i.e. the number of decoded frames the client can hold without stalling the decoder. Takes
+    /// effect at the next format change. Defaults to [`DEFAULT_EXTRA_CAPTURE_BUFFERS`].
+    pub fn set_extra_capture_buffers(&mut self, num_buffers: usize) {
+        self.extra_capture_buffers = num_buffers;
+    }
+
+    /// Copies `data` into a free OUTPUT buffer and queues it for decoding with `timestamp`.
+    ///
+    /// If all the OUTPUT buffers are queued, this method blocks until one is released by the
+    /// driver. The frames produced in the meantime are kept until they are pulled using
+    /// [`SyncDecoder::frames`]. If all the CAPTURE buffers are waiting to be pulled, or are held
+    /// by the client, `FramesPending` is returned and `data` can be submitted again after
+    /// pulling and dropping some frames.
+    pub fn decode(&mut self, data: &[u8], timestamp: TimeVal) -> Result<(), SyncDecoderError> {
+        loop {
+            self.dequeue_output_buffers()?;
+            if self.output_queue.num_free_buffers() > 0 {
+                break;
+            }
+
+            self.enqueue_capture_buffers()?;
+            if self.is_capture_starved() {
+                return Err(SyncDecoderError::FramesPending);
+            }
+            self.process_events(None)?;
+        }
+
+        let mut buffer = self.output_queue.try_get_free_buffer()?;
+        {
+            let mut mapping = buffer
+                .get_plane_mapping(0)
+                .ok_or(SyncDecoderError::MapOutputBuffer)?;
+            if data.len() > mapping.len() {
+                return Err(SyncDecoderError::InputTooLarge(data.len(), mapping.len()));
+            }
+            mapping[..data.len()].copy_from_slice(data);
+        }
+        buffer.set_timestamp(timestamp).queue(&[data.len()])?;
+
+        // Pick up whatever is ready without blocking.
+        self.process_events(Some(Duration::ZERO))
+    }
+
+    /// Returns an iterator over the outputs that are ready, without blocking.
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames { decoder: self }
+    }
+
+    /// Starts draining the decoder, and returns a blocking iterator over the remaining outputs
+    /// which ends after [`DecoderOutput::EndOfStream`].
+    ///
+    /// If the CAPTURE format cannot be determined from the input submitted so far, `TryAgain` is
+    /// returned.
+    pub fn drain(&mut self) -> Result<Drain<'_>, SyncDecoderError> {
+        debug!("Drain requested");
+
+        // The decoder cannot be drained before the CAPTURE queue is set up. Give it a chance to
+        // find the format from the input that is still queued.
+        while matches!(
+            self.capture_queue,
+            Some(CaptureQueue::AwaitingResolution(_))
+        ) {
+            self.dequeue_output_buffers()?;
+            if self.output_queue.num_queued_buffers() == 0 {
+                // One last chance for the event to be processed.
+                self.process_events(Some(Duration::ZERO))?;
+                if matches!(
+                    self.capture_queue,
+                    Some(CaptureQueue::AwaitingResolution(_))
+                ) {
+                    return Err(SyncDecoderError::TryAgain);
+                }
+                break;
+            }
+            self.process_events(Some(Duration::from_millis(10)))?;
+        }
+
+        ioctl::decoder_cmd::<_, ()>(&*self.device, ioctl::DecoderCommand::Stop)?;
+
+        Ok(Drain {
+            decoder: self,
+            done: false,
+        })
+    }
+
+    /// Returns the next pending output, processing the device events that occur within `timeout`
+    /// if there is none.
+    fn next_output(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DecoderOutput>, SyncDecoderError> {
+        if let Some(output) = self.pending.pop_front() {
+            return Ok(Some(output));
+        }
+
+        self.process_events(timeout)?;
+
+        Ok(self.pending.pop_front())
+    }
+
+    /// Returns `true` if no CAPTURE buffer is queued, meaning that decoding cannot progress until
+    /// the client returns some.
+    fn is_capture_starved(&self) -> bool {
+        match &self.capture_queue {
+            Some(CaptureQueue::Decoding(capture_queue)) => capture_queue.num_queued_buffers() == 0,
+            _ => false,
+        }
+    }
+
+    /// Wait up to `timeout` for events on the device and process them.
+    fn process_events(&mut self, timeout: Option<Duration>) -> Result<(), SyncDecoderError> {
+        self.dequeue_output_buffers()?;
+        self.enqueue_capture_buffers()?;
+
+        // The OUTPUT queue signals as ready as long as one of its buffers is free, so only
+        // listen to it when we are waiting for one.
+        let output_full =
+            self.output_queue.num_free_buffers() == 0 && self.output_queue.num_queued_buffers() > 0;
+        if output_full {
+            self.poller.enable_event(DeviceEvent::OutputReady)
+        } else {
+            self.poller.disable_event(DeviceEvent::OutputReady)
+        }
+        .map_err(SyncDecoderError::PollerEvent)?;
+
+        trace!("Polling...");
+        let events = match self.poller.poll(timeout) {
+            Ok(events) => events,
+            // A signal interrupted us, the caller will try again.
+            Err(PollError::EPollWait(Errno::EINTR)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        for event in events {
+            match event {
+                PollEvent::Device(DeviceEvent::OutputReady) => self.dequeue_output_buffers()?,
+                PollEvent::Device(DeviceEvent::CaptureReady) => self.dequeue_capture_buffer()?,
+                PollEvent::Device(DeviceEvent::V4L2Event) => self.process_v4l2_event()?,
+                PollEvent::Waker(id) => warn!("Unexpected waker {} signaled", id),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release the OUTPUT buffers that the driver is done with.
+    fn dequeue_output_buffers(&mut self) -> Result<(), SyncDecoderError> {
+        while self.output_queue.num_queued_buffers() > 0 {
+            match self.output_queue.try_dequeue() {
+                // Dropping the buffer makes it available again.
+                Ok(_) => (),
+                Err(ioctl::DqBufError::NotReady) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queue all the free CAPTURE buffers, and only poll the CAPTURE queue if at least one
+    /// buffer is queued, as poll() would otherwise return immediately.
+    fn enqueue_capture_buffers(&mut self) -> Result<(), SyncDecoderError> {
+        let capture_queue = match &self.capture_queue {
+            Some(CaptureQueue::Decoding(capture_queue)) => capture_queue,
+            _ => return Ok(()),
+        };
+
+        while let Ok(buffer) = capture_queue.try_get_free_buffer() {
+            buffer.queue()?;
+        }
+
+        if capture_queue.num_queued_buffers() > 0 {
+            self.poller.enable_event(DeviceEvent::CaptureReady)
+        } else {
+            self.poller.disable_event(DeviceEvent::CaptureReady)
+        }
+        .map_err(SyncDecoderError::PollerEvent)
+    }
+
+    fn process_v4l2_event(&mut self) -> Result<(), SyncDecoderError> {
+        trace!("Processing V4L2 event");
+        if matches!(
+            self.capture_queue,
+            Some(CaptureQueue::AwaitingResolution(_))
+        ) && is_drc_event_pending(&self.device)?
+        {
+            self.update_capture_format()?;
+        }
+
+        Ok(())
+    }
+
+    /// Dequeue a single CAPTURE buffer and add the corresponding outputs to the pending list.
+    fn dequeue_capture_buffer(&mut self) -> Result<(), SyncDecoderError> {
+        let capture_queue = match &self.capture_queue {
+            Some(CaptureQueue::Decoding(capture_queue)) => capture_queue,
+            _ => return Ok(()),
+        };
+
+        let cap_buf = match capture_queue.try_dequeue() {
+            Ok(cap_buf) => cap_buf,
+            Err(e @ (ioctl::DqBufError::NotReady | ioctl::DqBufError::Eos)) => {
+                warn!(
+                    "Expected a CAPTURE buffer but none available, possible driver bug: {}",
+                    e
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let is_last = cap_buf.data.is_last();
+        let is_empty = cap_buf.data.get_first_plane().bytesused() == 0;
+
+        if cap_buf.data.flags().contains(ioctl::BufferFlags::ERROR) {
+            warn!(
+                "CAPTURE buffer {} has the error flag set",
+                cap_buf.data.index()
+            );
+            self.pending.push_back(DecoderOutput::FrameError(cap_buf));
+        } else if !(is_last && is_empty) {
+            self.pending.push_back(DecoderOutput::Frame(cap_buf));
+        }
+
+        if is_last {
+            debug!("CAPTURE buffer marked with LAST flag");
+            if is_drc_event_pending(&self.device)? {
+                debug!("DRC event pending, updating CAPTURE format");
+                self.update_capture_format()?;
+            } else {
+                debug!("No DRC event pending, restarting capture queue");
+                // Restart the CAPTURE queue so the decoder can accept new input.
+                capture_queue.stream_off()?;
+                capture_queue.stream_on()?;
+                self.pending.push_back(DecoderOutput::EndOfStream);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Allocate CAPTURE buffers for the current format, freeing the previous ones if needed.
+    fn update_capture_format(&mut self) -> Result<(), SyncDecoderError> {
+        debug!("Updating CAPTURE format");
+        let capture_queue = match self.capture_queue.take() {
+            Some(CaptureQueue::AwaitingResolution(capture_queue)) => {
+                // Stop listening to V4L2 events. We will check them when we get
+                // a buffer with the LAST flag.
+                self.poller
+                    .disable_event(DeviceEvent::V4L2Event)
+                    .map_err(SyncDecoderError::PollerEvent)?;
+                capture_queue
+            }
+            Some(CaptureQueue::Decoding(capture_queue)) => {
+                capture_queue.stream_off()?;
+                capture_queue.free_buffers()?.queue
+            }
+            // A previous format change failed after taking the queue.
+            None => return Err(SyncDecoderError::CaptureQueueLost),
+        };
+
+        let num_buffers = capture_queue
+            .get_min_num_buffers()?
+            .unwrap_or(DEFAULT_MIN_CAPTURE_BUFFERS)
+            + self.extra_capture_buffers;
+        let format: Format = capture_queue.get_format()?;
+        let visible_rect = capture_queue.get_selection(SelectionTarget::Compose)?;
+        debug!(
+            "New CAPTURE format: {:?}, visible rectangle: {}, {} buffers",
+            format, visible_rect, num_buffers
+        );
+
+        let capture_queue = capture_queue.request_buffers::<Vec<MmapHandle>>(num_buffers as u32)?;
+        capture_queue.stream_on()?;
+        self.capture_queue = Some(CaptureQueue::Decoding(capture_queue));
+
+        self.pending.push_back(DecoderOutput::FormatChanged {
+            format,
+            visible_rect,
+            num_buffers,
+        });
+
+        self.enqueue_capture_buffers()
+    }
+}
+
+/// Non-blocking iterator over the outputs of a [`SyncDecoder`], returned by
+/// [`SyncDecoder::frames`].
+pub struct Frames<'a> {
+    decoder: &'a mut SyncDecoder,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Result<DecoderOutput, SyncDecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.next_output(Some(Duration::ZERO)).transpose()
+    }
+}
+
+/// Blocking iterator over the remaining outputs of a draining [`SyncDecoder`], returned by
+/// [`SyncDecoder::drain`].
+pub struct Drain<'a> {
+    decoder: &'a mut SyncDecoder,
+    done: bool,
+}
+
+impl Iterator for Drain<'_> {
+    type Item = Result<DecoderOutput, SyncDecoderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self.decoder.pending.is_empty() {
+                if let Err(e) = self.decoder.enqueue_capture_buffers() {
+                    return Some(Err(e));
+                }
+                // Waiting would never end if the client holds all the CAPTURE buffers.
+                if self.decoder.is_capture_starved() {
+                    return Some(Err(SyncDecoderError::FramesPending));
+                }
+            }
+
+            match self.decoder.next_output(None) {
+                Ok(Some(output)) => {
+                    self.done = matches!(output, DecoderOutput::EndOfStream);
+                    return Some(Ok(output));
+                }
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        None
+    }
+}
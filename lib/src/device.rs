@@ -65,6 +65,33 @@ pub enum DeviceOpenError {
     QueryCapError(#[from] ioctl::QueryCapError),
 }
 
+/// Unrecoverable error met while processing a stream, e.g. because the device has been
+/// disconnected. Once it has been reported no more frames are produced, and the decoder or encoder
+/// can only be stopped.
+#[derive(Debug, Error)]
+pub enum FatalError {
+    #[error("error while polling the device: {0}")]
+    Poll(#[from] poller::PollError),
+    #[error("error while dequeuing a buffer: {0}")]
+    Dequeue(#[from] ioctl::DqBufError<ioctl::V4l2Buffer>),
+    #[error("error while queueing a buffer: {0}")]
+    Queue(#[from] ioctl::QBufError<()>),
+    #[error("error while dequeuing an event: {0}")]
+    DequeueEvent(#[from] ioctl::DqEventError),
+    #[error("error while sending a decoder command: {0}")]
+    DecoderCommand(#[from] ioctl::DecoderCmdError),
+    #[error("error while starting a queue: {0}")]
+    StreamOn(#[from] ioctl::StreamOnError),
+    #[error("error while stopping a queue: {0}")]
+    StreamOff(#[from] ioctl::StreamOffError),
+    #[error("error while freeing buffers: {0}")]
+    FreeBuffers(#[from] ioctl::ReqbufsError),
+    #[error("error while changing the CAPTURE format: {0}")]
+    CaptureFormatChange(anyhow::Error),
+    #[error("error while setting up the poller: {0}")]
+    Poller(std::io::Error),
+}
+
 impl Device {
     fn new(fd: File) -> Result<Self, ioctl::QueryCapError> {
         Ok(Device {
@@ -99,7 +126,7 @@ impl Device {
 }
 
 impl AsFd for Device {
-    fn as_fd(&self) -> BorrowedFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
         self.fd.as_fd()
     }
 }
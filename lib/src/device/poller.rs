@@ -10,6 +10,7 @@ use std::{
     fs::File,
     io::{self, Read, Write},
     mem,
+    os::unix::io::{AsRawFd, RawFd},
     sync::atomic::{AtomicUsize, Ordering},
     sync::Arc,
     task::Wake,
@@ -171,6 +172,15 @@ pub enum PollError {
     V4L2Device,
 }
 
+/// The epoll file descriptor of a `Poller` becomes readable whenever one of its enabled events or
+/// wakers is signaled. This allows registering it into another event loop, and calling
+/// `poll` with a zero timeout once it is readable.
+impl AsRawFd for Poller {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.0.as_raw_fd()
+    }
+}
+
 impl Poller {
     pub fn new(device: Arc<Device>) -> nix::Result<Self> {
         let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
@@ -17,7 +17,7 @@ use crate::{
     },
     PlaneLayout, Rect,
 };
-use crate::{Format, PixelFormat, QueueType};
+use crate::{Format, PixelFormat, QueueDirection, QueueType};
 use buffer::*;
 use direction::*;
 use dqbuf::*;
@@ -111,12 +111,12 @@ where
     /// Returns a `FormatBuilder` which is set to the currently active format
     /// and can be modified and eventually applied. The `FormatBuilder` holds
     /// a mutable reference to this `Queue`.
-    pub fn change_format(&mut self) -> Result<FormatBuilder, GFmtError> {
+    pub fn change_format(&mut self) -> Result<FormatBuilder<'_>, GFmtError> {
         FormatBuilder::new(&mut self.inner)
     }
 
     /// Returns an iterator over all the formats currently supported by this queue.
-    pub fn format_iter(&self) -> ioctl::FormatIterator<QueueBase> {
+    pub fn format_iter(&self) -> ioctl::FormatIterator<'_, QueueBase> {
         ioctl::FormatIterator::new(&self.inner, self.inner.type_)
     }
 
@@ -129,6 +129,49 @@ where
 
         ioctl::g_selection(&self.inner, selection, target)
     }
+
+    /// Returns the minimum number of buffers the driver requires on this queue in order to
+    /// operate, as reported by the `MIN_BUFFERS_FOR_CAPTURE` or `MIN_BUFFERS_FOR_OUTPUT`
+    /// control. `None` is returned if the driver does not expose that control.
+    ///
+    /// The value may depend on the current format, so it should be queried after the format is
+    /// set.
+    pub fn get_min_num_buffers(&self) -> Result<Option<usize>, ioctl::GCtrlError> {
+        let id = match self.get_type().direction() {
+            QueueDirection::Capture => bindings::V4L2_CID_MIN_BUFFERS_FOR_CAPTURE,
+            QueueDirection::Output => bindings::V4L2_CID_MIN_BUFFERS_FOR_OUTPUT,
+        };
+
+        match ioctl::g_ctrl(&self.inner, id) {
+            Ok(min_num_buffers) => Ok(Some(min_num_buffers.max(0) as usize)),
+            Err(ioctl::GCtrlError::Invalid) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks that `count` buffers are enough for the driver to operate this queue, and returns
+    /// a `TooFewBuffers` error if they are not.
+    ///
+    /// Drivers may otherwise accept to allocate less buffers than they need, and then stall
+    /// waiting for more buffers to be queued.
+    pub fn check_num_buffers(&self, count: usize) -> Result<(), RequestBuffersError> {
+        match self.get_min_num_buffers()? {
+            Some(minimum) => check_min_num_buffers(count, minimum),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns a `TooFewBuffers` error if `requested` is lower than `minimum`.
+pub(crate) fn check_min_num_buffers(
+    requested: usize,
+    minimum: usize,
+) -> Result<(), RequestBuffersError> {
+    if requested < minimum {
+        Err(RequestBuffersError::TooFewBuffers { requested, minimum })
+    } else {
+        Ok(())
+    }
 }
 
 /// Builder for a V4L2 format. This takes a mutable reference on the queue, so
@@ -208,6 +251,12 @@ pub enum RequestBuffersError {
     ReqbufsError(#[from] ioctl::ReqbufsError),
     #[error("error while querying buffer")]
     QueryBufferError(#[from] ioctl::QueryBufError<QueryBuffer>),
+    #[error("error while mapping buffer")]
+    MmapError(#[from] ioctl::MmapError),
+    #[error("error while querying the minimum number of buffers")]
+    MinBuffersError(#[from] ioctl::GCtrlError),
+    #[error("{requested} buffers requested, but the driver requires at least {minimum}")]
+    TooFewBuffers { requested: usize, minimum: usize },
 }
 
 impl<D: Direction> Queue<D, QueueInit> {
@@ -303,6 +352,19 @@ impl<D: Direction> Queue<D, QueueInit> {
     ) -> Result<Queue<D, BuffersAllocated<P>>, RequestBuffersError> {
         self.request_buffers_generic(P::MEMORY_TYPE, count)
     }
+
+    /// Allocate `count` MMAP buffers for this queue and map all their planes right away, so
+    /// `get_plane_mapping` never needs to create a mapping while streaming. The mappings are kept
+    /// until the buffers are freed.
+    pub fn request_mapped_buffers(
+        self,
+        count: u32,
+    ) -> Result<Queue<D, BuffersAllocated<Vec<MmapHandle>>>, RequestBuffersError> {
+        let queue = self.request_buffers::<Vec<MmapHandle>>(count)?;
+        queue.map_buffers()?;
+
+        Ok(queue)
+    }
 }
 
 impl Queue<Output, QueueInit> {
@@ -353,6 +415,28 @@ pub struct BuffersAllocated<P: BufferHandles> {
 impl<P: BufferHandles> QueueState for BuffersAllocated<P> {}
 
 impl<D: Direction, P: BufferHandles> Queue<D, BuffersAllocated<P>> {
+    /// Returns the memory type the buffers of this queue have been allocated with.
+    pub fn memory_type(&self) -> P::SupportedMemoryType {
+        self.state.memory_type
+    }
+
+    /// Returns whether the currently allocated buffers can hold frames of `format`, i.e. whether
+    /// `format` can be used without reallocating them.
+    ///
+    /// The number of planes of `format` must match the one of the buffers, and each plane must be
+    /// at least as large as the `sizeimage` requested by `format`. For `MMAP` buffers, the size of
+    /// the planes allocated by the driver is used. Other memory types do not own their memory, so
+    /// the size of the memory (e.g. the DMABUF) last queued with each buffer is used instead, and
+    /// buffers that have never been queued cannot hold any format.
+    pub fn can_hold_format(&self, format: &Format) -> bool {
+        let is_mmap = self.state.memory_type.into() == MemoryType::Mmap;
+
+        self.state
+            .buffer_info
+            .iter()
+            .all(|buffer_info| buffer_info.can_hold_format(format, is_mmap))
+    }
+
     /// Return all the currently queued buffers as CanceledBuffers. This can
     /// be called after a explicit or implicit streamoff to inform the client
     /// of which buffers have been canceled and return their handles.
@@ -419,6 +503,26 @@ impl<D: Direction, P: BufferHandles> Queue<D, BuffersAllocated<P>> {
     }
 }
 
+impl<D: Direction> Queue<D, BuffersAllocated<Vec<MmapHandle>>> {
+    /// Map all the planes of all the buffers of this queue that are not mapped yet.
+    ///
+    /// Mappings are otherwise created lazily the first time `get_plane_mapping` is called on a
+    /// buffer. In both cases they are kept until the buffers are freed.
+    pub fn map_buffers(&self) -> Result<(), ioctl::MmapError> {
+        let device = self.inner.device.as_ref();
+
+        for buffer_info in &self.state.buffer_info {
+            buffer_info.map_planes(|plane_info| {
+                ioctl::mmap(device, plane_info.mem_offset, plane_info.length)
+            })?;
+        }
+
+        debug!("Mapped all buffers of {} queue", self.get_type());
+
+        Ok(())
+    }
+}
+
 impl<'a, D: Direction, P: BufferHandles + 'a> AllocatedQueue<'a, D>
     for Queue<D, BuffersAllocated<P>>
 {
@@ -436,7 +540,13 @@ impl<'a, D: Direction, P: BufferHandles + 'a> AllocatedQueue<'a, D>
 
     fn free_buffers(self) -> Result<FreeBuffersResult<D, Self>, ioctl::ReqbufsError> {
         let type_ = self.inner.type_;
-        ioctl::reqbufs(&self.inner, type_, self.state.memory_type.into(), 0)?;
+
+        // Release our mappings before the buffers so the driver can free their memory right away.
+        for buffer_info in &self.state.buffer_info {
+            buffer_info.unmap_planes();
+        }
+
+        ioctl::reqbufs::<()>(&self.inner, type_, self.state.memory_type.into(), 0)?;
 
         debug!("Freed all buffers on {} queue", type_);
 
@@ -686,3 +796,22 @@ impl<P: BufferHandles> Drop for BufferStateFuse<P> {
         self.trigger();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_min_num_buffers() {
+        assert!(check_min_num_buffers(4, 4).is_ok());
+        assert!(check_min_num_buffers(8, 4).is_ok());
+        assert!(check_min_num_buffers(0, 0).is_ok());
+        assert!(matches!(
+            check_min_num_buffers(3, 4),
+            Err(RequestBuffersError::TooFewBuffers {
+                requested: 3,
+                minimum: 4
+            })
+        ));
+    }
+}
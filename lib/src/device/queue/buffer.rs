@@ -1,9 +1,15 @@
 use super::BufferHandles;
-use crate::ioctl;
+use crate::ioctl::{self, PlaneMapping, QueryBufPlane};
+use crate::Format;
 
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 /// Represents the current state of an allocated buffer.
@@ -51,6 +57,12 @@ pub(super) struct BufferInfo<P: BufferHandles> {
     pub(super) features: ioctl::QueryBuffer,
     /// Current state of the buffer.
     state: Mutex<BufferState<P>>,
+    /// CPU mappings of the planes of the buffer, created upon first access and kept until the
+    /// buffer is freed.
+    mappings: Mutex<Vec<Option<Arc<SharedPlaneMapping>>>>,
+    /// Size of the memory backing each plane the last time the buffer was queued, or `None` if it
+    /// has never been queued.
+    queued_plane_lengths: Mutex<Option<Vec<u32>>>,
     /// Link to the queue's buffer stats, so we can update them as the buffer state changes.
     stats: Arc<BufferStats>,
 }
@@ -66,6 +78,8 @@ impl<P: BufferHandles> BufferInfo<P> {
         stats.num_free.fetch_add(1, Ordering::Relaxed);
         Self {
             state: Mutex::new(BufferState::Free),
+            mappings: Mutex::new(vec![None; features.planes.len()]),
+            queued_plane_lengths: Mutex::new(None),
             features,
             stats: Arc::clone(&stats),
         }
@@ -98,11 +112,248 @@ impl<P: BufferHandles> BufferInfo<P> {
 
         res
     }
+
+    /// Returns the size of the memory backing each plane the last time the buffer was queued.
+    pub(super) fn queued_plane_lengths(&self) -> Option<Vec<u32>> {
+        self.queued_plane_lengths.lock().unwrap().clone()
+    }
+
+    pub(super) fn set_queued_plane_lengths(&self, lengths: Vec<u32>) {
+        *self.queued_plane_lengths.lock().unwrap() = Some(lengths);
+    }
+
+    /// Returns whether the planes of this buffer are large enough for `format`. `is_mmap` tells
+    /// whether the buffer uses memory allocated by the driver, otherwise the size of the memory
+    /// last queued with it is used.
+    pub(super) fn can_hold_format(&self, format: &Format, is_mmap: bool) -> bool {
+        let plane_lengths = if is_mmap {
+            self.features
+                .planes
+                .iter()
+                .map(|plane| plane.length)
+                .collect()
+        } else {
+            match self.queued_plane_lengths() {
+                Some(plane_lengths) => plane_lengths,
+                None => return false,
+            }
+        };
+
+        plane_lengths.len() == format.plane_fmt.len()
+            && plane_lengths
+                .iter()
+                .zip(format.plane_fmt.iter())
+                .all(|(length, layout)| *length >= layout.sizeimage)
+    }
+
+    /// Returns the mapping for plane `plane` of this buffer, using `map` to create it if it
+    /// has not been mapped yet. The mapping is then kept until the buffer is freed, so
+    /// subsequent calls are cheap.
+    pub(super) fn get_plane_mapping<F>(
+        &self,
+        plane: usize,
+        map: F,
+    ) -> Option<Arc<SharedPlaneMapping>>
+    where
+        F: FnOnce(&QueryBufPlane) -> Option<PlaneMapping>,
+    {
+        let plane_info = self.features.planes.get(plane)?;
+        let mut mappings = self.mappings.lock().unwrap();
+        let mapping = mappings.get_mut(plane)?;
+
+        if mapping.is_none() {
+            *mapping = Some(Arc::new(SharedPlaneMapping::new(map(plane_info)?)));
+        }
+
+        mapping.clone()
+    }
+
+    /// Create the mappings of all the planes of this buffer that are not mapped yet using `map`.
+    pub(super) fn map_planes<E, F>(&self, mut map: F) -> Result<(), E>
+    where
+        F: FnMut(&QueryBufPlane) -> Result<PlaneMapping, E>,
+    {
+        let mut mappings = self.mappings.lock().unwrap();
+
+        for (mapping, plane_info) in mappings.iter_mut().zip(self.features.planes.iter()) {
+            if mapping.is_none() {
+                *mapping = Some(Arc::new(SharedPlaneMapping::new(map(plane_info)?)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release the mappings of this buffer. Views that are still alive keep their mapping valid
+    /// until they are dropped.
+    pub(super) fn unmap_planes(&self) {
+        self.mappings
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|mapping| *mapping = None);
+    }
+}
+
+/// CPU mapping of a buffer plane that can be shared by several views.
+///
+/// The views never access the memory through the `PlaneMapping`, which only exists to keep the
+/// memory mapped. Instead they use the base pointer and length that are taken from it when it
+/// is created, while we still have exclusive access to it. This allows writable views to
+/// legitimately write into the mapping even though it is shared.
+pub(super) struct SharedPlaneMapping {
+    base: NonNull<u8>,
+    len: usize,
+    _mapping: PlaneMapping,
+}
+
+// Safe because the mapping is not tied to any thread, and the queue guarantees that a writable
+// view of a plane cannot coexist with any other view of it.
+unsafe impl Send for SharedPlaneMapping {}
+unsafe impl Sync for SharedPlaneMapping {}
+
+impl SharedPlaneMapping {
+    fn new(mapping: PlaneMapping) -> Self {
+        let len = mapping.data.len();
+        let base = NonNull::from(&mut *mapping.data).cast::<u8>();
+
+        Self {
+            base,
+            len,
+            _mapping: mapping,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the `start..end` range of the mapping.
+    ///
+    /// # Safety
+    ///
+    /// `start..end` must be within the mapping, and no mutable slice of that range may exist for
+    /// the lifetime of the returned slice.
+    unsafe fn slice(&self, start: usize, end: usize) -> &[u8] {
+        std::slice::from_raw_parts(self.base.as_ptr().add(start), end - start)
+    }
+
+    /// Returns the whole mapping as a mutable slice.
+    ///
+    /// # Safety
+    ///
+    /// No other slice of the mapping may exist for the lifetime of the returned slice.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice_mut(&self) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.base.as_ptr(), self.len)
+    }
+}
+
+/// Read-only view into the CPU mapping of a buffer plane.
+///
+/// The view borrows the buffer it has been obtained from, which guarantees that the buffer
+/// cannot be queued again (and thus accessed by the device) while the view is alive.
+pub struct PlaneMappingRef<'a> {
+    mapping: Arc<SharedPlaneMapping>,
+    start: usize,
+    end: usize,
+    _b: PhantomData<&'a ()>,
+}
+
+impl<'a> PlaneMappingRef<'a> {
+    /// Create a view of `mapping` limited to the `start..end` range. The range is clamped to
+    /// the size of the mapping.
+    pub(super) fn new(mapping: Arc<SharedPlaneMapping>, start: usize, end: usize) -> Self {
+        let end = std::cmp::min(end, mapping.len());
+        let start = std::cmp::min(start, end);
+
+        Self {
+            mapping,
+            start,
+            end,
+            _b: PhantomData,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl<'a> Deref for PlaneMappingRef<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because the range has been clamped to the mapping, and no writable view of the
+        // plane can exist while the buffer is borrowed by this view.
+        unsafe { self.mapping.slice(self.start, self.end) }
+    }
+}
+
+impl<'a> AsRef<[u8]> for PlaneMappingRef<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Writable view into the CPU mapping of a buffer plane.
+///
+/// The view mutably borrows the buffer it has been obtained from, which guarantees that the
+/// buffer cannot be queued while the view is alive, and that no other view of the same plane
+/// exists at the same time.
+pub struct PlaneMappingMut<'a> {
+    mapping: Arc<SharedPlaneMapping>,
+    _b: PhantomData<&'a mut ()>,
+}
+
+impl<'a> PlaneMappingMut<'a> {
+    pub(super) fn new(mapping: Arc<SharedPlaneMapping>) -> Self {
+        Self {
+            mapping,
+            _b: PhantomData,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.mapping.len()
+    }
+}
+
+impl<'a> Deref for PlaneMappingMut<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because this view is the only one of the plane, and `deref_mut` requires a
+        // mutable borrow of it.
+        unsafe { self.mapping.slice(0, self.mapping.len()) }
+    }
+}
+
+impl<'a> DerefMut for PlaneMappingMut<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safe because writable views can only be obtained from a mutable reference to a buffer
+        // that is exclusively owned by the client, hence no other view of this plane can exist
+        // for as long as this one is alive.
+        unsafe { self.mapping.slice_mut() }
+    }
+}
+
+impl<'a> AsRef<[u8]> for PlaneMappingMut<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'a> AsMut<[u8]> for PlaneMappingMut<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::memory::MmapHandle;
+    use crate::PlaneLayout;
 
     use super::*;
 
@@ -178,4 +429,122 @@ mod tests {
         assert_eq!(buffer_stats.num_free(), NUM_BUFFERS);
         assert_eq!(buffer_stats.num_queued(), 0);
     }
+    /// Returns an unlinked temporary file of `len` bytes that can be mapped like a V4L2 buffer.
+    fn mappable_file(name: &str, len: u64) -> std::fs::File {
+        let path = std::env::temp_dir().join(format!("v4l2r-{}-{}", name, std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(len).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_plane_mapping_views() {
+        let file = mappable_file("plane-mapping", 16);
+        let mapping = Arc::new(SharedPlaneMapping::new(ioctl::mmap(&file, 0, 16).unwrap()));
+
+        let mut view = PlaneMappingMut::new(Arc::clone(&mapping));
+        assert_eq!(view.size(), 16);
+        view.iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = i as u8);
+        drop(view);
+
+        let view = PlaneMappingRef::new(Arc::clone(&mapping), 4, 8);
+        assert_eq!(*view, [4, 5, 6, 7]);
+        // Ranges are clamped to the size of the mapping.
+        let view = PlaneMappingRef::new(Arc::clone(&mapping), 12, 32);
+        assert_eq!(*view, [12, 13, 14, 15]);
+        let view = PlaneMappingRef::new(Arc::clone(&mapping), 20, 32);
+        assert_eq!(view.size(), 0);
+        let view = PlaneMappingRef::new(mapping, 8, 4);
+        assert_eq!(view.size(), 0);
+    }
+    #[test]
+    fn test_can_hold_format() {
+        let format = |sizes: &[u32]| Format {
+            plane_fmt: sizes
+                .iter()
+                .map(|&sizeimage| PlaneLayout {
+                    bytesperline: 0,
+                    sizeimage,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let querybuf = ioctl::QueryBuffer {
+            index: 0,
+            flags: ioctl::BufferFlags::empty(),
+            planes: [100, 50]
+                .iter()
+                .map(|&length| QueryBufPlane {
+                    mem_offset: 0,
+                    length,
+                })
+                .collect(),
+        };
+        let buffer: BufferInfo<Vec<MmapHandle>> =
+            BufferInfo::new(querybuf, Arc::new(BufferStats::new()));
+
+        // MMAP buffers are checked against the size of the planes allocated by the driver.
+        assert!(buffer.can_hold_format(&format(&[100, 50]), true));
+        assert!(!buffer.can_hold_format(&format(&[100, 51]), true));
+        assert!(!buffer.can_hold_format(&format(&[100]), true));
+
+        // Other buffers are checked against the size of the memory last queued with them.
+        assert!(!buffer.can_hold_format(&format(&[10, 10]), false));
+        buffer.set_queued_plane_lengths(vec![200, 20]);
+        assert!(buffer.can_hold_format(&format(&[200, 20]), false));
+        assert!(!buffer.can_hold_format(&format(&[100, 50]), false));
+    }
+    #[test]
+    fn test_map_planes() {
+        let file = mappable_file("map-planes", 16);
+        let querybuf = ioctl::QueryBuffer {
+            index: 0,
+            flags: ioctl::BufferFlags::empty(),
+            planes: [16, 8]
+                .iter()
+                .map(|&length| QueryBufPlane {
+                    mem_offset: 0,
+                    length,
+                })
+                .collect(),
+        };
+        let buffer: BufferInfo<Vec<MmapHandle>> =
+            BufferInfo::new(querybuf, Arc::new(BufferStats::new()));
+        let num_mapped = std::cell::Cell::new(0);
+        let map = |plane: &QueryBufPlane| {
+            num_mapped.set(num_mapped.get() + 1);
+            ioctl::mmap(&file, plane.mem_offset, plane.length)
+        };
+
+        // All planes are mapped once, and mapping errors are propagated.
+        assert!(buffer
+            .map_planes(|_| Err(ioctl::MmapError::ZeroLength))
+            .is_err());
+        buffer.map_planes(map).unwrap();
+        buffer.map_planes(map).unwrap();
+        assert_eq!(num_mapped.get(), 2);
+
+        // Planes mapped in advance are returned without creating a new mapping.
+        let mapping = buffer.get_plane_mapping(1, |_| None).unwrap();
+        let view = PlaneMappingRef::new(Arc::clone(&mapping), 0, 8);
+        assert_eq!(view.size(), 8);
+
+        // Unmapping drops the buffer's mappings, but not the ones still in use.
+        buffer.unmap_planes();
+        assert_eq!(view.size(), 8);
+        assert!(buffer.get_plane_mapping(1, |_| None).is_none());
+        assert!(buffer
+            .get_plane_mapping(0, |plane| map(plane).ok())
+            .is_some());
+        assert_eq!(num_mapped.get(), 3);
+    }
 }
@@ -1,10 +1,10 @@
 //! Provides types related to dequeuing buffers from a `Queue` object.
 use super::{
-    buffer::BufferInfo,
+    buffer::{BufferInfo, PlaneMappingRef},
     direction::{Capture, Direction},
     BufferStateFuse, BuffersAllocated, Queue,
 };
-use crate::ioctl::{self, PlaneMapping};
+use crate::ioctl;
 use crate::{
     device::Device,
     memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
@@ -81,11 +81,14 @@ where
     P: PrimitiveBufferHandles,
     P::HandleType: Mappable,
 {
-    // TODO returned mapping should be read-only!
-    pub fn get_plane_mapping(&self, plane_index: usize) -> Option<PlaneMapping> {
+    /// Returns a read-only CPU mapping of the data of plane `plane_index` of this buffer.
+    ///
+    /// The mapping is created the first time it is requested and kept by the queue until the
+    /// buffers are freed. The returned view borrows this buffer, so it cannot outlive it and the
+    /// buffer cannot be reused while the view is alive.
+    pub fn get_plane_mapping(&self, plane_index: usize) -> Option<PlaneMappingRef<'_>> {
         // We can only obtain a mapping if this buffer has not been deleted.
         let buffer_info = self.buffer_info.upgrade()?;
-        let plane = buffer_info.features.planes.get(plane_index)?;
         let plane_data = self.data.get_plane(plane_index)?;
         // If the buffer info was alive, then the device must also be.
         let device = self.device.upgrade()?;
@@ -93,7 +96,11 @@ where
         let start = plane_data.data_offset() as usize;
         let end = start + plane_data.bytesused() as usize;
 
-        Some(P::HandleType::map(device.as_ref(), plane)?.restrict(start, end))
+        let mapping = buffer_info.get_plane_mapping(plane_index, |plane_info| {
+            P::HandleType::map(device.as_ref(), plane_info)
+        })?;
+
+        Some(PlaneMappingRef::new(mapping, start, end))
     }
 }
 
@@ -5,8 +5,6 @@ use std::{
     task::Wake,
 };
 
-use log::error;
-
 use crate::{
     bindings,
     device::poller::Waker,
@@ -1,10 +1,14 @@
 //! Provides types related to queuing buffers on a `Queue` object.
-use super::{buffer::BufferInfo, Capture, Direction, Output};
+use super::{
+    buffer::{BufferInfo, PlaneMappingMut},
+    Capture, Direction, Output,
+};
 use super::{BufferState, BufferStateFuse, BuffersAllocated, Queue};
-use crate::ioctl;
+use crate::ioctl::{self, Request};
 use crate::memory::*;
 use std::{
     fmt::{self, Debug},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd},
     sync::Arc,
 };
 
@@ -65,6 +69,7 @@ pub struct QBuffer<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles
     index: usize,
     num_planes: usize,
     timestamp: TimeVal,
+    request: Option<BorrowedFd<'a>>,
     fuse: BufferStateFuse<Q>,
     _p: std::marker::PhantomData<P>,
 }
@@ -82,6 +87,7 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
             index: buffer.index,
             num_planes: buffer.planes.len(),
             timestamp: TimeVal::zero(),
+            request: None,
             fuse,
             _p: std::marker::PhantomData,
         }
@@ -103,6 +109,15 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         self
     }
 
+    /// Queue this buffer as part of `request`. Controls set on the request will then apply to
+    /// this buffer. The request still needs to be queued after the buffer.
+    ///
+    /// `request` must remain alive until this buffer is queued.
+    pub fn set_request(mut self, request: &'a Request) -> Self {
+        self.request = Some(request.as_fd());
+        self
+    }
+
     // R is meant to mean "either P or Q".
     // Caller is responsible for making sure that the number of planes and
     // plane_handles is the same as the number of expected planes for this
@@ -112,9 +127,11 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         planes: Vec<ioctl::QBufPlane>,
         plane_handles: R,
     ) -> QueueResult<(), R> {
+        let plane_lengths = planes.iter().map(|plane| plane.0.length).collect();
         let qbuffer = ioctl::QBuffer::<P::HandleType> {
             planes,
             timestamp: self.timestamp,
+            request: self.request.map(|request| request.as_raw_fd()),
             ..Default::default()
         };
 
@@ -136,14 +153,16 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         // We got this now.
         self.fuse.disarm();
 
-        self.queue
+        let buffer_info = self
+            .queue
             .state
             .buffer_info
             .get(self.index)
-            .expect("Inconsistent buffer state!")
-            .update_state(|state| {
-                *state = BufferState::Queued(plane_handles.into());
-            });
+            .expect("Inconsistent buffer state!");
+        buffer_info.set_queued_plane_lengths(plane_lengths);
+        buffer_info.update_state(|state| {
+            *state = BufferState::Queued(plane_handles.into());
+        });
 
         Ok(())
     }
@@ -155,10 +174,18 @@ where
     P::HandleType: Mappable,
     Q: BufferHandles + From<P>,
 {
-    pub fn get_plane_mapping(&self, plane: usize) -> Option<ioctl::PlaneMapping> {
+    /// Returns a writable CPU mapping of plane `plane` of this buffer.
+    ///
+    /// The mapping is created the first time it is requested and kept by the queue until the
+    /// buffers are freed. The returned view borrows this buffer, so it must be dropped before the
+    /// buffer can be queued.
+    pub fn get_plane_mapping(&mut self, plane: usize) -> Option<PlaneMappingMut<'_>> {
         let buffer_info = self.queue.state.buffer_info.get(self.index)?;
-        let plane_info = buffer_info.features.planes.get(plane)?;
-        P::HandleType::map(self.queue.inner.device.as_ref(), plane_info)
+        let device = self.queue.inner.device.as_ref();
+        let mapping = buffer_info
+            .get_plane_mapping(plane, |plane_info| P::HandleType::map(device, plane_info))?;
+
+        Some(PlaneMappingMut::new(mapping))
     }
 }
 
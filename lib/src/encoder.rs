@@ -1,9 +1,19 @@
 //! High-level interface for a [V4L2 video
 //! encoder](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html).
+pub mod config;
+pub mod format;
+
+use self::config::{EncoderConfig, EncoderConfigError};
 use crate::{
+    bindings::{self, v4l2_ext_control, v4l2_ext_control__bindgen_ty_1, v4l2_streamparm},
+    controls::{
+        codec::{HeaderMode, VideoBitrate, VideoBitratePeak, VideoForceKeyFrame, VideoGopSize},
+        ExtControlTrait,
+    },
     device::{
         poller::{DeviceEvent, PollError, PollEvent, Poller, Waker},
         queue::{
+            buffer::PlaneMappingRef,
             direction::{Capture, Output},
             dqbuf::DqBuffer,
             handles_provider::HandlesProvider,
@@ -16,21 +26,30 @@ use crate::{
             BuffersAllocated, CanceledBuffer, CreateQueueError, FormatBuilder, Queue, QueueInit,
             RequestBuffersError,
         },
-        AllocatedQueue, Device, DeviceConfig, DeviceOpenError, Stream, TryDequeue,
+        AllocatedQueue, Device, DeviceConfig, DeviceOpenError, FatalError, Stream, TryDequeue,
+    },
+    ioctl::{
+        self, BufferFlags, CtrlWhich, DqBufError, EncoderCommand, FormatFlags, GFmtError,
+        V4l2Buffer,
     },
-    ioctl::{self, DqBufError, EncoderCommand, FormatFlags, GFmtError, V4l2Buffer},
-    memory::{BufferHandles, PrimitiveBufferHandles},
+    memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
+    metadata::{FrameMark, FrameMetadataMap},
     Format,
 };
 
-use log::warn;
+use log::{debug, error, trace, warn};
+use nix::{errno::Errno, sys::time::TimeVal};
 use std::{
     any::Any,
+    convert::TryFrom,
+    fmt::Debug,
     io,
+    os::unix::io::{AsRawFd, RawFd},
     path::Path,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{atomic::AtomicUsize, mpsc, Arc, Mutex},
     task::Wake,
     thread::JoinHandle,
+    time::Duration,
 };
 use thiserror::Error;
 
@@ -59,6 +78,16 @@ pub enum EncoderOpenError {
     NotAnEncoder,
 }
 
+impl<S: EncoderState> Encoder<S> {
+    /// Validate `config` against the controls supported by the device, and apply it.
+    ///
+    /// Codec-specific controls like the profile or level are usually only available once the
+    /// CAPTURE format has been set.
+    pub fn apply_config(&self, config: &EncoderConfig) -> Result<(), EncoderConfigError> {
+        config.apply(&*self.device)
+    }
+}
+
 impl Encoder<AwaitingCaptureFormat> {
     pub fn open(path: &Path) -> Result<Self, EncoderOpenError> {
         let config = DeviceConfig::new().non_blocking_dqbuf();
@@ -138,11 +167,21 @@ pub struct AwaitingOutputBuffers {
 impl EncoderState for AwaitingOutputBuffers {}
 
 impl Encoder<AwaitingOutputBuffers> {
+    /// Returns the minimum number of OUTPUT buffers required by the driver for the current
+    /// format, or `None` if the driver does not report it.
+    pub fn get_min_num_output_buffers(&self) -> Result<Option<usize>, ioctl::GCtrlError> {
+        self.state.output_queue.get_min_num_buffers()
+    }
+
+    /// Allocates `num_output` OUTPUT buffers of `memory_type`. A `TooFewBuffers` error is
+    /// returned if `num_output` is lower than the minimum required by the driver.
     pub fn allocate_output_buffers_generic<OP: BufferHandles>(
         self,
         memory_type: OP::SupportedMemoryType,
         num_output: usize,
     ) -> Result<Encoder<AwaitingCaptureBuffers<OP>>, RequestBuffersError> {
+        self.state.output_queue.check_num_buffers(num_output)?;
+
         Ok(Encoder {
             device: self.device,
             state: AwaitingCaptureBuffers {
@@ -178,6 +217,14 @@ pub struct AwaitingCaptureBuffers<OP: BufferHandles> {
 impl<OP: BufferHandles> EncoderState for AwaitingCaptureBuffers<OP> {}
 
 impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
+    /// Returns the minimum number of CAPTURE buffers required by the driver for the current
+    /// format, or `None` if the driver does not report it.
+    pub fn get_min_num_capture_buffers(&self) -> Result<Option<usize>, ioctl::GCtrlError> {
+        self.state.capture_queue.get_min_num_buffers()
+    }
+
+    /// Allocates `num_capture` CAPTURE buffers of `memory_type`. A `TooFewBuffers` error is
+    /// returned if `num_capture` is lower than the minimum required by the driver.
     pub fn allocate_capture_buffers_generic<P: HandlesProvider>(
         self,
         memory_type: <P::HandleType as BufferHandles>::SupportedMemoryType,
@@ -188,6 +235,8 @@ impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
         for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
             GetFreeCaptureBuffer<'a, P::HandleType>,
     {
+        self.state.capture_queue.check_num_buffers(num_capture)?;
+
         Ok(Encoder {
             device: self.device,
             state: ReadyToEncode {
@@ -198,6 +247,8 @@ impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
                     .request_buffers_generic::<P::HandleType>(memory_type, num_capture as u32)?,
                 capture_memory_provider,
                 poll_wakeups_counter: None,
+                fatal_error_cb: None,
+                frame_dropped_cb: None,
             },
         })
     }
@@ -220,15 +271,51 @@ impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
     }
 }
 
-pub struct ReadyToEncode<OP: BufferHandles, P: HandlesProvider> {
+/// `M` is the type of the metadata the client associates with each frame to encode, set using
+/// [`Encoder::set_frame_dropped_cb`].
+pub struct ReadyToEncode<OP: BufferHandles, P: HandlesProvider, M = ()> {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
     capture_memory_provider: P,
     poll_wakeups_counter: Option<Arc<AtomicUsize>>,
+    fatal_error_cb: Option<FatalErrorCallback>,
+    frame_dropped_cb: Option<FrameDroppedCallback<M>>,
 }
-impl<OP: BufferHandles, P: HandlesProvider> EncoderState for ReadyToEncode<OP, P> {}
 
-impl<OP: BufferHandles, P: HandlesProvider> Encoder<ReadyToEncode<OP, P>>
+/// Callback invoked from the encoder thread when it meets an unrecoverable error.
+pub type FatalErrorCallback = Box<dyn FnOnce(FatalError) + Send>;
+/// Callback invoked with the metadata of the frames that will never produce an encoded frame.
+pub type FrameDroppedCallback<M> = Box<dyn FnMut(M) + Send>;
+impl<OP: BufferHandles, P: HandlesProvider, M> EncoderState for ReadyToEncode<OP, P, M> {}
+
+impl<OP: BufferHandles, P: HandlesProvider> Encoder<ReadyToEncode<OP, P>> {
+    /// Associate metadata of type `M` with the frames to encode, and set a callback to be invoked
+    /// with the metadata of the frames that will never produce an encoded frame, because the
+    /// encoder skipped them or because they have been canceled by [`Encoder::stop`].
+    ///
+    /// Metadata is then attached to each frame using [`Encoder::add_frame_metadata`], and returned
+    /// with the matching [`EncodedFrame`]. Frames skipped by the encoder are reported when the
+    /// drain sequence they have been queued before completes.
+    pub fn set_frame_dropped_cb<M, F>(self, cb: F) -> Encoder<ReadyToEncode<OP, P, M>>
+    where
+        F: FnMut(M) + Send + 'static,
+    {
+        let state = self.state;
+        Encoder {
+            device: self.device,
+            state: ReadyToEncode {
+                output_queue: state.output_queue,
+                capture_queue: state.capture_queue,
+                capture_memory_provider: state.capture_memory_provider,
+                poll_wakeups_counter: state.poll_wakeups_counter,
+                fatal_error_cb: state.fatal_error_cb,
+                frame_dropped_cb: Some(Box::new(cb)),
+            },
+        }
+    }
+}
+
+impl<OP: BufferHandles, P: HandlesProvider, M: Send + 'static> Encoder<ReadyToEncode<OP, P, M>>
 where
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
         GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
@@ -238,36 +325,111 @@ where
         self
     }
 
+    /// Set a callback to be invoked if the encoder meets an unrecoverable error, e.g. because the
+    /// device has been disconnected. No more frames are produced after this, and the encoder can
+    /// only be stopped.
+    pub fn set_fatal_error_cb<F: FnOnce(FatalError) + Send + 'static>(mut self, cb: F) -> Self {
+        self.state.fatal_error_cb = Some(Box::new(cb));
+        self
+    }
+
+    #[allow(clippy::type_complexity)]
     pub fn start<InputDoneCb, OutputReadyCb>(
         self,
         input_done_cb: InputDoneCb,
         output_ready_cb: OutputReadyCb,
-    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>>
+    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>>
     where
         InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-        OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send + 'static,
+        OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send + 'static,
     {
-        self.state.output_queue.stream_on().unwrap();
-        self.state.capture_queue.stream_on().unwrap();
+        self.start_with(input_done_cb, output_ready_cb, true)
+    }
+
+    /// Start encoding without spawning an encoder thread.
+    ///
+    /// The client is then responsible for driving the encoder by calling
+    /// [`Encoder::process_events`], typically when the file descriptor returned by
+    /// [`Encoder::event_fd`] becomes readable in its own event loop. The input done and output
+    /// ready callbacks are invoked from within [`Encoder::process_events`], or from the methods
+    /// that need to wait for the encoder, such as [`Encoder::drain`] or [`Encoder::stop`].
+    #[allow(clippy::type_complexity)]
+    pub fn start_external<InputDoneCb, OutputReadyCb>(
+        self,
+        input_done_cb: InputDoneCb,
+        output_ready_cb: OutputReadyCb,
+    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>>
+    where
+        InputDoneCb: Fn(CompletedOutputBuffer<OP>),
+        OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send + 'static,
+    {
+        self.start_with(input_done_cb, output_ready_cb, false)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn start_with<InputDoneCb, OutputReadyCb>(
+        self,
+        input_done_cb: InputDoneCb,
+        output_ready_cb: OutputReadyCb,
+        spawn_thread: bool,
+    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>>
+    where
+        InputDoneCb: Fn(CompletedOutputBuffer<OP>),
+        OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send + 'static,
+    {
+        let stream_on = self
+            .state
+            .output_queue
+            .stream_on()
+            .and_then(|()| self.state.capture_queue.stream_on());
 
         let mut output_poller = Poller::new(Arc::clone(&self.device))?;
         output_poller.enable_event(DeviceEvent::OutputReady)?;
 
+        let (command_sender, command_receiver) = mpsc::channel::<EncoderThreadCommand>();
+        let (response_sender, response_receiver) = mpsc::channel::<EncoderThreadResponse>();
+
+        let frame_metadata = Arc::new(Mutex::new(FrameMetadataMap::new()));
+
         let mut encoder_thread = EncoderThread::new(
             &self.device,
             self.state.capture_queue,
             self.state.capture_memory_provider,
+            Arc::clone(&frame_metadata),
             output_ready_cb,
+            self.state.fatal_error_cb,
+            self.state.frame_dropped_cb,
+            command_receiver,
+            response_sender,
         )?;
+        let command_waker = Arc::clone(&encoder_thread.command_waker);
+        // The encoder cannot do anything if its queues are not streaming. Report it like any
+        // other device error, so the client knows the encoder can only be stopped.
+        if let Err(e) = stream_on {
+            encoder_thread.report_fatal_error(e.into());
+        }
 
         if let Some(counter) = &self.state.poll_wakeups_counter {
             output_poller.set_poll_counter(Arc::clone(counter));
             encoder_thread.set_poll_counter(Arc::clone(counter));
         }
 
-        let handle = std::thread::Builder::new()
-            .name("V4L2 Encoder".into())
-            .spawn(move || encoder_thread.run())?;
+        let encoder_thread = if spawn_thread {
+            EncoderThreadHandle::Spawned(
+                std::thread::Builder::new()
+                    .name("V4L2 Encoder".into())
+                    .spawn(move || encoder_thread.run())?,
+            )
+        } else {
+            // Completed OUTPUT buffers must also make the event FD readable, so the client calls
+            // `process_events` to dequeue them.
+            encoder_thread.watch_output = true;
+            encoder_thread.prepare();
+            EncoderThreadHandle::External {
+                event_fd: encoder_thread.poller.as_raw_fd(),
+                encoder_thread: Mutex::new(encoder_thread),
+            }
+        };
 
         Ok(Encoder {
             device: self.device,
@@ -275,41 +437,212 @@ where
                 output_queue: self.state.output_queue,
                 input_done_cb,
                 output_poller,
-                handle,
+                command_waker,
+                command_sender,
+                response_receiver,
+                encoder_thread,
+                frame_metadata,
+                poll_wakeups_counter: self.state.poll_wakeups_counter,
             },
         })
     }
 }
 
-pub struct Encoding<OP: BufferHandles, P, InputDoneCb, OutputReadyCb>
+pub struct Encoding<OP: BufferHandles, P, InputDoneCb, OutputReadyCb, M = ()>
 where
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
 {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     input_done_cb: InputDoneCb,
     output_poller: Poller,
 
-    handle: JoinHandle<EncoderThread<P, OutputReadyCb>>,
+    command_waker: Arc<Waker>,
+    command_sender: mpsc::Sender<EncoderThreadCommand>,
+    response_receiver: mpsc::Receiver<EncoderThreadResponse>,
+
+    encoder_thread: EncoderThreadHandle<P, OutputReadyCb, M>,
+
+    frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
+    // Kept so it can be set again on the encoder returned by `stop`.
+    poll_wakeups_counter: Option<Arc<AtomicUsize>>,
 }
-impl<OP, P, InputDoneCb, OutputReadyCb> EncoderState for Encoding<OP, P, InputDoneCb, OutputReadyCb>
+impl<OP, P, InputDoneCb, OutputReadyCb, M> EncoderState
+    for Encoding<OP, P, InputDoneCb, OutputReadyCb, M>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
+{
+}
+
+#[derive(Debug)]
+enum EncoderThreadCommand {
+    Drain,
+}
+
+#[derive(Debug)]
+enum EncoderThreadResponse {
+    DrainDone(Result<(), EncoderDrainError>),
+}
+
+/// How the event loop of the encoder thread is run.
+enum EncoderThreadHandle<P, OutputReadyCb, M>
+where
+    P: HandlesProvider,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
 {
+    /// The event loop runs on its own thread.
+    Spawned(JoinHandle<EncoderThread<P, OutputReadyCb, M>>),
+    /// The event loop is run by the client through [`Encoder::process_events`].
+    External {
+        event_fd: RawFd,
+        encoder_thread: Mutex<EncoderThread<P, OutputReadyCb, M>>,
+    },
 }
 
 // Safe because all Rcs are internal and never leaked outside of the struct.
 unsafe impl<S: EncoderState> Send for Encoder<S> {}
 
+/// Type of an encoded frame, as reported by the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Key,
+    Predicted,
+    Bidirectional,
+    /// The driver did not report the frame type.
+    Unknown,
+}
+
+/// A frame produced by the encoder, passed to the output ready callback.
+///
+/// This wraps the dequeued CAPTURE buffer and exposes its properties without requiring knowledge
+/// of V4L2 buffer flags. The CAPTURE buffer is requeued once this frame is dropped.
+pub struct EncodedFrame<P: BufferHandles, M = ()> {
+    buffer: DqBuffer<Capture, P>,
+    is_codec_config: bool,
+    metadata: Option<M>,
+}
+
+impl<P: BufferHandles, M> EncodedFrame<P, M> {
+    /// Returns the type of the frame.
+    pub fn frame_type(&self) -> FrameType {
+        let flags = self.buffer.data.flags();
+        if flags.contains(BufferFlags::KEYFRAME) {
+            FrameType::Key
+        } else if flags.contains(BufferFlags::PFRAME) {
+            FrameType::Predicted
+        } else if flags.contains(BufferFlags::BFRAME) {
+            FrameType::Bidirectional
+        } else {
+            FrameType::Unknown
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type() == FrameType::Key
+    }
+
+    /// Whether this buffer contains the codec configuration (e.g. SPS/PPS for H.264, VPS/SPS/PPS
+    /// for HEVC) instead of a frame.
+    ///
+    /// This is only the case for the first buffer of the stream, and for the first buffer produced
+    /// after each drain, when the encoder has been configured with [`HeaderMode::Separate`].
+    /// Otherwise the configuration is part of the first keyframe.
+    pub fn is_codec_config(&self) -> bool {
+        self.is_codec_config
+    }
+
+    /// Whether the driver reported an error while encoding this frame. Its payload may be
+    /// corrupted.
+    pub fn has_error(&self) -> bool {
+        self.buffer.data.flags().contains(BufferFlags::ERROR)
+    }
+
+    /// Whether this is the last frame of the stream, or of a drain sequence. The payload of the
+    /// last frame may be empty.
+    pub fn is_last(&self) -> bool {
+        self.buffer.data.is_last()
+    }
+
+    /// Timestamp of the frame. Encoders copy the timestamp of the OUTPUT buffer a frame has been
+    /// encoded from, so this identifies the input frame this frame corresponds to.
+    pub fn timestamp(&self) -> TimeVal {
+        let timestamp = self.buffer.data.timestamp();
+        TimeVal::new(timestamp.tv_sec, timestamp.tv_usec)
+    }
+
+    /// Metadata associated with the input frame this frame has been encoded from using
+    /// [`Encoder::add_frame_metadata`], if any.
+    pub fn metadata(&self) -> Option<&M> {
+        self.metadata.as_ref()
+    }
+
+    /// Takes the metadata of this frame out, leaving `None` in its place.
+    pub fn take_metadata(&mut self) -> Option<M> {
+        self.metadata.take()
+    }
+
+    /// Size of the payload, in bytes.
+    pub fn payload_size(&self) -> usize {
+        self.buffer.data.get_first_plane().bytesused() as usize
+    }
+
+    /// Returns the underlying CAPTURE buffer.
+    pub fn buffer(&self) -> &DqBuffer<Capture, P> {
+        &self.buffer
+    }
+
+    /// Returns the underlying CAPTURE buffer, e.g. to take back its handles.
+    pub fn into_buffer(self) -> DqBuffer<Capture, P> {
+        self.buffer
+    }
+}
+
+impl<P, M> EncodedFrame<P, M>
+where
+    P: PrimitiveBufferHandles,
+    P::HandleType: Mappable,
+{
+    /// Returns a CPU mapping of the encoded data.
+    pub fn payload(&self) -> Option<PlaneMappingRef<'_>> {
+        self.buffer.get_plane_mapping(0)
+    }
+}
+
+impl<P: BufferHandles, M: Debug> Debug for EncodedFrame<P, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("EncodedFrame")
+            .field("frame_type", &self.frame_type())
+            .field("is_codec_config", &self.is_codec_config)
+            .field("has_error", &self.has_error())
+            .field("is_last", &self.is_last())
+            .field("timestamp", &self.timestamp())
+            .field("payload_size", &self.payload_size())
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum CompletedOutputBuffer<OP: BufferHandles> {
     Dequeued(DqBuffer<Output, OP>),
     Canceled(CanceledBuffer<OP>),
 }
 
+impl<OP: BufferHandles> CompletedOutputBuffer<OP> {
+    /// Whether the driver reported an error while processing this buffer. This is not fatal, and
+    /// the encoder keeps processing the next buffers.
+    pub fn has_error(&self) -> bool {
+        match self {
+            CompletedOutputBuffer::Dequeued(buf) => buf.data.flags().contains(BufferFlags::ERROR),
+            CompletedOutputBuffer::Canceled(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum GetBufferError {
     #[error("error while dequeueing buffer")]
@@ -318,6 +651,28 @@ pub enum GetBufferError {
     PollError(#[from] PollError),
     #[error("error while obtaining buffer")]
     GetFreeBufferError(#[from] GetFreeBufferError),
+    #[error("the encoder thread has stopped")]
+    ThreadStopped,
+}
+
+#[derive(Debug, Error)]
+pub enum EncoderDrainError {
+    #[error("error while sending the drain command to the encoder thread")]
+    SendCommand,
+    #[error("error while waiting for the encoder thread to drain")]
+    RecvError(#[from] mpsc::RecvError),
+    #[error("error while draining on the encoder thread")]
+    EncoderCmdError(#[from] ioctl::EncoderCmdError),
+    #[error("the encoder has met an unrecoverable error")]
+    FatalError,
+}
+
+#[derive(Debug, Error)]
+pub enum ProcessEventsError {
+    #[error("the encoder has not been started with `start_external`")]
+    NotExternallyDriven,
+    #[error("error while dequeueing OUTPUT buffer")]
+    DequeueError(#[from] DqBufError<V4l2Buffer>),
 }
 
 #[derive(Debug, Error)]
@@ -332,23 +687,313 @@ pub enum EncoderStopError {
     OutputQueueStreamoffError(ioctl::StreamOffError),
 }
 
-impl<OP, P, InputDoneCb, OutputReadyCb> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+/// Encoding parameters that can be changed while the encoder is running.
+///
+/// Only the parameters that are set are sent to the driver.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderParams {
+    /// Target bitrate, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Peak bitrate, in bits per second.
+    pub peak_bitrate: Option<u32>,
+    /// Distance between two key frames.
+    pub gop_size: Option<u32>,
+    /// Whether the next frame should be encoded as a key frame.
+    pub force_key_frame: bool,
+}
+
+impl EncoderParams {
+    fn as_ext_controls(&self) -> Result<Vec<v4l2_ext_control>, EncoderParamsError> {
+        let ctrl = |id, value: i32| v4l2_ext_control {
+            id,
+            __bindgen_anon_1: v4l2_ext_control__bindgen_ty_1 { value },
+            ..unsafe { std::mem::zeroed() }
+        };
+        // Controls values are signed, so larger values cannot be passed to the driver.
+        let value_ctrl = |id, value: Option<u32>| {
+            value
+                .map(|v| {
+                    i32::try_from(v)
+                        .map(|v| ctrl(id, v))
+                        .map_err(|_| EncoderParamsError::ValueOutOfRange(id, v))
+                })
+                .transpose()
+        };
+
+        Ok(vec![
+            value_ctrl(VideoBitrate::ID, self.bitrate)?,
+            value_ctrl(VideoBitratePeak::ID, self.peak_bitrate)?,
+            value_ctrl(VideoGopSize::ID, self.gop_size)?,
+            self.force_key_frame
+                .then(|| ctrl(VideoForceKeyFrame::ID, 1)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncoderParamsError {
+    #[error("error while setting encoder controls")]
+    ControlError(#[from] ioctl::ExtControlError),
+    #[error("error while setting stream parameters")]
+    ParmError(#[from] ioctl::GParmError),
+    #[error("the driver does not support changing the frame rate")]
+    FrameRateUnsupported,
+    #[error("value {1} of control {0:#x} is out of range")]
+    ValueOutOfRange(u32, u32),
+}
+
+impl<OP, P, InputDoneCb, OutputReadyCb, M> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
+    for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
+        GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
 {
+    /// Associate `metadata` with the next frame to encode.
+    ///
+    /// Returns the timestamp to set on the OUTPUT buffer containing the frame, e.g. using
+    /// [`QBuffer::set_timestamp`](crate::device::queue::qbuf::QBuffer::set_timestamp). The
+    /// metadata is then returned with the matching [`EncodedFrame`], or passed to the callback
+    /// set with [`Encoder::set_frame_dropped_cb`] if the frame is never encoded. Timestamps set by
+    /// the client and timestamps obtained from this method should not be mixed.
+    pub fn add_frame_metadata(&self, metadata: M) -> TimeVal {
+        self.state.frame_metadata.lock().unwrap().insert(metadata)
+    }
+
+    /// Apply `params` immediately. They will take effect from the next frame processed by the
+    /// driver, which may not be the next OUTPUT buffer queued if some are already pending.
+    pub fn set_params(&self, params: &EncoderParams) -> Result<(), EncoderParamsError> {
+        let mut controls = params.as_ext_controls()?;
+        if controls.is_empty() {
+            return Ok(());
+        }
+
+        ioctl::s_ext_ctrls(&*self.device, CtrlWhich::Current, controls.as_mut_slice())?;
+        Ok(())
+    }
+
+    /// Store `params` into `request`, so they take effect on the OUTPUT buffer queued with
+    /// [`QBuffer::set_request`](crate::device::queue::qbuf::QBuffer::set_request) using the same
+    /// request.
+    ///
+    /// This requires the driver to support the request API on its OUTPUT queue.
+    pub fn set_params_for_request(
+        &self,
+        params: &EncoderParams,
+        request: &ioctl::Request,
+    ) -> Result<(), EncoderParamsError> {
+        let mut controls = params.as_ext_controls()?;
+        if controls.is_empty() {
+            return Ok(());
+        }
+
+        ioctl::s_ext_ctrls(
+            &*self.device,
+            CtrlWhich::Request(request.as_raw_fd()),
+            controls.as_mut_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Set the target bitrate, in bits per second.
+    pub fn set_bitrate(&self, bitrate: u32) -> Result<(), EncoderParamsError> {
+        self.set_params(&EncoderParams {
+            bitrate: Some(bitrate),
+            ..Default::default()
+        })
+    }
+
+    /// Set the peak bitrate, in bits per second.
+    pub fn set_peak_bitrate(&self, peak_bitrate: u32) -> Result<(), EncoderParamsError> {
+        self.set_params(&EncoderParams {
+            peak_bitrate: Some(peak_bitrate),
+            ..Default::default()
+        })
+    }
+
+    /// Set the distance between two key frames.
+    pub fn set_gop_size(&self, gop_size: u32) -> Result<(), EncoderParamsError> {
+        self.set_params(&EncoderParams {
+            gop_size: Some(gop_size),
+            ..Default::default()
+        })
+    }
+
+    /// Request the next frame to be encoded as a key frame.
+    pub fn force_key_frame(&self) -> Result<(), EncoderParamsError> {
+        self.set_params(&EncoderParams {
+            force_key_frame: true,
+            ..Default::default()
+        })
+    }
+
+    /// Set the frame rate of the stream to `numerator / denominator` frames per second.
+    ///
+    /// Returns the frame rate actually applied by the driver, which may differ from the requested
+    /// one.
+    pub fn set_frame_rate(
+        &self,
+        numerator: u32,
+        denominator: u32,
+    ) -> Result<(u32, u32), EncoderParamsError> {
+        let queue_type = self.state.output_queue.get_type();
+        let mut parm: v4l2_streamparm = ioctl::g_parm(&*self.device, queue_type)?;
+        // SAFETY: the OUTPUT queue parameters are valid for an OUTPUT queue type.
+        let output = unsafe { &mut parm.parm.output };
+        if output.capability & bindings::V4L2_CAP_TIMEPERFRAME == 0 {
+            return Err(EncoderParamsError::FrameRateUnsupported);
+        }
+        // The time per frame is the inverse of the frame rate.
+        output.timeperframe.numerator = denominator;
+        output.timeperframe.denominator = numerator;
+
+        let parm: v4l2_streamparm = ioctl::s_parm(&*self.device, parm)?;
+        // SAFETY: the OUTPUT queue parameters are valid for an OUTPUT queue type.
+        let timeperframe = unsafe { parm.parm.output.timeperframe };
+        Ok((timeperframe.denominator, timeperframe.numerator))
+    }
+
+    /// Drain the encoder, i.e. wait until all the frames queued so far have been encoded, and
+    /// restart it so it can keep accepting frames.
+    ///
+    /// Contrary to [`Encoder::stop`], the queues and formats are left untouched. When this method
+    /// returns, the output ready callback has been called for all the frames queued before it.
+    /// The next encoded frame will typically be a key frame.
+    ///
+    /// This method is blocking, so make sure the output ready callback can complete while it is
+    /// waiting.
+    pub fn drain(&self) -> Result<(), EncoderDrainError> {
+        debug!("Drain requested");
+        // The encoder thread exits after a fatal error and would never reply.
+        if self.is_thread_finished() {
+            return Err(EncoderDrainError::FatalError);
+        }
+        self.state
+            .command_sender
+            .send(EncoderThreadCommand::Drain)
+            .map_err(|_| EncoderDrainError::SendCommand)?;
+        self.state.command_waker.wake_by_ref();
+
+        match self.recv_response()? {
+            EncoderThreadResponse::DrainDone(response) => response,
+        }
+    }
+
+    /// Returns whether the event loop of the encoder thread has stopped.
+    fn is_thread_finished(&self) -> bool {
+        match &self.state.encoder_thread {
+            EncoderThreadHandle::Spawned(handle) => handle.is_finished(),
+            EncoderThreadHandle::External { encoder_thread, .. } => {
+                encoder_thread.lock().unwrap().finished
+            }
+        }
+    }
+
+    /// Wait for the encoder thread to reply to a command. An externally-driven encoder thread is
+    /// run until it does.
+    fn recv_response(&self) -> Result<EncoderThreadResponse, mpsc::RecvError> {
+        match &self.state.encoder_thread {
+            EncoderThreadHandle::Spawned(_) => self.state.response_receiver.recv(),
+            EncoderThreadHandle::External { encoder_thread, .. } => loop {
+                match self.state.response_receiver.try_recv() {
+                    Ok(response) => return Ok(response),
+                    Err(mpsc::TryRecvError::Empty) => {
+                        // Completed OUTPUT buffers would otherwise keep waking us up.
+                        if let Err(e) = self.dequeue_output_buffers() {
+                            warn!("Error while dequeuing OUTPUT buffers: {}", e);
+                        }
+                        if !encoder_thread.lock().unwrap().process_events(None) {
+                            // A pending drain is replied to before the thread stops.
+                            return self
+                                .state
+                                .response_receiver
+                                .try_recv()
+                                .map_err(|_| mpsc::RecvError);
+                        }
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => return Err(mpsc::RecvError),
+                }
+            },
+        }
+    }
+
+    /// Returns the file descriptor to watch for readability in the client's event loop if the
+    /// encoder has been started with [`Encoder::start_external`], or `None` otherwise.
+    ///
+    /// [`Encoder::process_events`] should be called every time it becomes readable, which happens
+    /// when encoded frames are ready, when CAPTURE buffers are returned by the client, and when
+    /// OUTPUT buffers are done being processed and can be reused.
+    pub fn event_fd(&self) -> Option<RawFd> {
+        match &self.state.encoder_thread {
+            EncoderThreadHandle::Spawned(_) => None,
+            EncoderThreadHandle::External { event_fd, .. } => Some(*event_fd),
+        }
+    }
+
+    /// Process the pending events of an encoder started with [`Encoder::start_external`],
+    /// without blocking.
+    ///
+    /// This dequeues completed input buffers and encoded frames, requeues the CAPTURE buffers
+    /// returned by the client and processes pending commands, invoking the corresponding
+    /// callbacks. It must not be called from these callbacks.
+    pub fn process_events(&self) -> Result<(), ProcessEventsError> {
+        match &self.state.encoder_thread {
+            EncoderThreadHandle::Spawned(_) => Err(ProcessEventsError::NotExternallyDriven),
+            EncoderThreadHandle::External { encoder_thread, .. } => {
+                self.dequeue_output_buffers()?;
+                encoder_thread
+                    .lock()
+                    .unwrap()
+                    .process_events(Some(Duration::ZERO));
+                Ok(())
+            }
+        }
+    }
+
+    /// Pause the encoder. Frames queued while paused are not encoded until [`Encoder::resume`]
+    /// is called.
+    pub fn pause(&self) -> Result<(), ioctl::EncoderCmdError> {
+        ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Pause)
+    }
+
+    /// Resume an encoder previously paused with [`Encoder::pause`].
+    pub fn resume(&self) -> Result<(), ioctl::EncoderCmdError> {
+        ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Resume)
+    }
+
     /// Stop the encoder, and returns the encoder ready to be started again.
-    pub fn stop(self) -> Result<Encoder<ReadyToEncode<OP, P>>, EncoderStopError> {
-        ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false))?;
+    ///
+    /// The poll counter, frame dropped callback and frame metadata type are kept. So is the fatal
+    /// error callback, unless it has already been invoked.
+    pub fn stop(self) -> Result<Encoder<ReadyToEncode<OP, P, M>>, EncoderStopError> {
+        // If the encoder thread has exited after a fatal error, there is no LAST buffer to wait
+        // for.
+        if !self.is_thread_finished() {
+            ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false))?;
+        }
 
         // The encoder thread should receive the LAST buffer and exit on its own.
-        let encoding_thread = self
-            .state
-            .handle
-            .join()
-            .map_err(EncoderStopError::ThreadPanickedError)?;
+        let mut encoding_thread = match self.state.encoder_thread {
+            EncoderThreadHandle::Spawned(handle) => handle
+                .join()
+                .map_err(EncoderStopError::ThreadPanickedError)?,
+            EncoderThreadHandle::External { encoder_thread, .. } => {
+                let mut encoder_thread = encoder_thread.into_inner().unwrap();
+                // The OUTPUT buffers are canceled below, waiting for them would only spin.
+                encoder_thread.watch_output = false;
+                while encoder_thread.process_events(None) {}
+                encoder_thread
+            }
+        };
+
+        // The frames that have not been encoded by now never will.
+        let dropped = encoding_thread.frame_metadata.lock().unwrap().take_all();
+        encoding_thread.report_dropped_frames(dropped);
 
         encoding_thread
             .capture_queue
@@ -370,11 +1015,51 @@ where
                 output_queue: self.state.output_queue,
                 capture_queue: encoding_thread.capture_queue,
                 capture_memory_provider: encoding_thread.capture_memory_provider,
-                poll_wakeups_counter: None,
+                poll_wakeups_counter: self.state.poll_wakeups_counter,
+                fatal_error_cb: encoding_thread.fatal_error_cb.take(),
+                frame_dropped_cb: encoding_thread.frame_dropped_cb.take(),
             },
         })
     }
 
+    // Make this thread sleep until at least one OUTPUT buffer is ready to be
+    // obtained through `try_get_buffer()`, dequeuing buffers if necessary.
+    fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError> {
+        // An externally-driven encoder only makes progress while its encoder thread runs, so run
+        // it until an OUTPUT buffer completes instead of waiting on the OUTPUT queue alone.
+        if let EncoderThreadHandle::External { encoder_thread, .. } = &self.state.encoder_thread {
+            let output_queue = &self.state.output_queue;
+            loop {
+                self.dequeue_output_buffers()?;
+                if output_queue.num_queued_buffers() < output_queue.num_buffers() {
+                    return Ok(());
+                }
+                if !encoder_thread.lock().unwrap().process_events(None) {
+                    return Err(GetBufferError::ThreadStopped);
+                }
+            }
+        }
+
+        for event in self.state.output_poller.poll(None)? {
+            match event {
+                PollEvent::Device(DeviceEvent::OutputReady) => {
+                    self.dequeue_output_buffers()?;
+                }
+                _ => panic!("Unexpected return from OUTPUT queue poll!"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<OP, P, InputDoneCb, OutputReadyCb, M> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>
+where
+    OP: BufferHandles,
+    P: HandlesProvider,
+    InputDoneCb: Fn(CompletedOutputBuffer<OP>),
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
+{
     /// Attempts to dequeue and release output buffers that the driver is done with.
     fn dequeue_output_buffers(&self) -> Result<(), DqBufError<V4l2Buffer>> {
         let output_queue = &self.state.output_queue;
@@ -385,53 +1070,39 @@ where
                     (self.state.input_done_cb)(CompletedOutputBuffer::Dequeued(buf));
                 }
                 Err(DqBufError::NotReady) => break,
-                // TODO buffers with the error flag set should not result in
-                // a fatal error!
+                // Buffers with the error flag set are dequeued successfully and
+                // reported through `CompletedOutputBuffer::has_error`, so this is
+                // an actual device error.
                 Err(e) => return Err(e),
             }
         }
 
         Ok(())
     }
-
-    // Make this thread sleep until at least one OUTPUT buffer is ready to be
-    // obtained through `try_get_buffer()`, dequeuing buffers if necessary.
-    fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError> {
-        for event in self.state.output_poller.poll(None)? {
-            match event {
-                PollEvent::Device(DeviceEvent::OutputReady) => {
-                    self.dequeue_output_buffers()?;
-                }
-                _ => panic!("Unexpected return from OUTPUT queue poll!"),
-            }
-        }
-
-        Ok(())
-    }
 }
 
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> OutputQueueableProvider<'a, OP>
-    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, OutputReadyCb, M> OutputQueueableProvider<'a, OP>
+    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>
 where
     Queue<Output, BuffersAllocated<OP>>: OutputQueueableProvider<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
 {
     type Queueable =
         <Queue<Output, BuffersAllocated<OP>> as OutputQueueableProvider<'a, OP>>::Queueable;
 }
 
 /// Let the encoder provide the buffers from the OUTPUT queue.
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> GetFreeOutputBuffer<'a, OP, GetBufferError>
-    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, OutputReadyCb, M> GetFreeOutputBuffer<'a, OP, GetBufferError>
+    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>
 where
     Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode if one
     /// is available.
@@ -446,19 +1117,24 @@ where
 
 // If `GetFreeBuffer` is implemented, we can also provide a blocking `get_buffer`
 // method.
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, OutputReadyCb, M>
+    Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb, M>>
 where
     Self: GetFreeOutputBuffer<'a, OP, GetBufferError>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
+    for<'b> Queue<Capture, BuffersAllocated<P::HandleType>>:
+        GetFreeCaptureBuffer<'b, P::HandleType> + GetCaptureBufferByIndex<'b, P::HandleType>,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode, waiting for
     /// one to be available if needed.
     ///
     /// Contrary to `try_get_free_buffer(), this method will wait for a buffer
-    /// to be available if needed.
+    /// to be available if needed. If the encoder has been started with
+    /// [`Encoder::start_external`], its event loop is run while waiting and the encoder callbacks
+    /// may be invoked from this method.
     pub fn get_buffer(
         &'a mut self,
     ) -> Result<<Self as OutputQueueableProvider<'a, OP>>::Queueable, GetBufferError> {
@@ -473,128 +1149,407 @@ where
     }
 }
 
-struct EncoderThread<P, OutputReadyCb>
+struct EncoderThread<P, OutputReadyCb, M>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
 {
+    device: Arc<Device>,
     capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
     capture_memory_provider: P,
     poller: Poller,
     waker: Arc<Waker>,
     output_ready_cb: OutputReadyCb,
+
+    // Waker signaled when the main thread has commands pending for us.
+    command_waker: Arc<Waker>,
+    // Receiver we read commands from when `command_waker` is signaled.
+    command_receiver: mpsc::Receiver<EncoderThreadCommand>,
+    // Sender we use to send status messages after receiving commands from the
+    // main thread.
+    response_sender: mpsc::Sender<EncoderThreadResponse>,
+    // Whether the next LAST buffer signals the end of a drain, instead of the
+    // end of the stream.
+    drain_in_progress: bool,
+    // Whether the next non-empty buffer contains the codec configuration.
+    expect_codec_config: bool,
+    fatal_error_cb: Option<FatalErrorCallback>,
+    // Whether the event loop has stopped, after the end of the stream or a fatal error.
+    finished: bool,
+    // Whether to also poll for completed OUTPUT buffers, which are dequeued by the main thread.
+    watch_output: bool,
+
+    // Metadata of the frames being encoded, shared with the main thread.
+    frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
+    frame_dropped_cb: Option<FrameDroppedCallback<M>>,
+    // Frames registered before this mark are dropped if they have not been encoded by the end of
+    // the pending drain.
+    drain_mark: Option<FrameMark>,
+}
+
+/// Returns whether a dequeued CAPTURE buffer contains the codec configuration, given whether it
+/// is empty. `expect_codec_config` is cleared once a non-empty buffer has been dequeued, as only
+/// the first one produced after the encoder is started holds the configuration.
+fn take_codec_config(expect_codec_config: &mut bool, is_empty: bool) -> bool {
+    let is_codec_config = !is_empty && *expect_codec_config;
+    if !is_empty {
+        *expect_codec_config = false;
+    }
+    is_codec_config
 }
 
-impl<P, OutputReadyCb> EncoderThread<P, OutputReadyCb>
+const CAPTURE_READY: u32 = 0;
+const COMMAND_WAITING: u32 = 1;
+
+impl<P, OutputReadyCb, M> EncoderThread<P, OutputReadyCb, M>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    OutputReadyCb: FnMut(EncodedFrame<P::HandleType, M>) + Send,
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
         GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         device: &Arc<Device>,
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         capture_memory_provider: P,
+        frame_metadata: Arc<Mutex<FrameMetadataMap<M>>>,
         output_ready_cb: OutputReadyCb,
+        fatal_error_cb: Option<FatalErrorCallback>,
+        frame_dropped_cb: Option<FrameDroppedCallback<M>>,
+        command_receiver: mpsc::Receiver<EncoderThreadCommand>,
+        response_sender: mpsc::Sender<EncoderThreadResponse>,
     ) -> io::Result<Self> {
         let mut poller = Poller::new(Arc::clone(device))?;
 
         poller.enable_event(DeviceEvent::CaptureReady)?;
-        let waker = poller.add_waker(0)?;
+        let waker = poller.add_waker(CAPTURE_READY)?;
+        let command_waker = poller.add_waker(COMMAND_WAITING)?;
 
         Ok(EncoderThread {
+            device: Arc::clone(device),
             capture_queue,
             capture_memory_provider,
             poller,
             waker,
             output_ready_cb,
+            command_waker,
+            command_receiver,
+            response_sender,
+            drain_in_progress: false,
+            expect_codec_config: false,
+            fatal_error_cb,
+            finished: false,
+            watch_output: false,
+            frame_metadata,
+            frame_dropped_cb,
+            drain_mark: None,
         })
     }
 
+    fn drain(&mut self) {
+        trace!("Processing drain command");
+        // The end of the drain is signaled by the LAST buffer.
+        match ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Stop(false)) {
+            Ok(()) => {
+                self.drain_in_progress = true;
+                self.drain_mark = Some(self.frame_metadata.lock().unwrap().mark());
+            }
+            Err(e) => self.send_response(EncoderThreadResponse::DrainDone(Err(e.into()))),
+        }
+    }
+
+    /// Send `response` to the main thread. The main thread may have given up waiting for it, in
+    /// which case the response is dropped.
+    fn send_response(&self, response: EncoderThreadResponse) {
+        trace!("Sending response: {:?}", response);
+
+        if let Err(e) = self.response_sender.send(response) {
+            error!("Error while sending response: {}", e);
+        }
+    }
+
+    /// Called when the LAST buffer of a drain is dequeued: restart the encoder
+    /// and notify the main thread.
+    fn complete_drain(&mut self) {
+        debug!("Signaling end of drain");
+        self.drain_in_progress = false;
+        // Frames queued before the drain that have not been encoded by now never will.
+        if let Some(mark) = self.drain_mark.take() {
+            let dropped = self.frame_metadata.lock().unwrap().take_before(mark);
+            self.report_dropped_frames(dropped);
+        }
+        let response = ioctl::encoder_cmd::<_, ()>(&*self.device, &EncoderCommand::Start);
+        // The restarted encoder produces the codec configuration again before the next frame.
+        if response.is_ok() {
+            self.detect_codec_config();
+        }
+        self.send_response(EncoderThreadResponse::DrainDone(
+            response.map_err(Into::into),
+        ));
+    }
+
+    /// Pass the metadata of the frames in `dropped` to the frame dropped callback.
+    fn report_dropped_frames(&mut self, dropped: Vec<M>) {
+        if !dropped.is_empty() {
+            debug!("{} frames dropped", dropped.len());
+        }
+        if let Some(cb) = &mut self.frame_dropped_cb {
+            dropped.into_iter().for_each(cb);
+        }
+    }
+
+    /// Report `error` to the client. The encoder thread exits after this.
+    fn report_fatal_error(&mut self, error: FatalError) {
+        error!("Fatal error, exiting encoder thread: {}", error);
+        self.finished = true;
+        if self.drain_in_progress {
+            self.drain_in_progress = false;
+            self.send_response(EncoderThreadResponse::DrainDone(Err(
+                EncoderDrainError::FatalError,
+            )));
+        }
+        if let Some(cb) = self.fatal_error_cb.take() {
+            cb(error);
+        }
+        // We won't reply to commands anymore, so disconnect the response channel to make sure
+        // the client does not wait forever for a reply to a command sent in the meantime.
+        self.response_sender = mpsc::channel().0;
+    }
+
+    /// Dequeue a CAPTURE buffer and pass it to the client.
+    ///
+    /// Returns `true` if the encoder thread should exit, either because the end of the stream
+    /// has been reached or because of a fatal error.
+    fn dequeue_capture_buffer(&mut self) -> bool {
+        let mut cap_buf = match self.capture_queue.try_dequeue() {
+            Ok(cap_buf) => cap_buf,
+            Err(e @ (DqBufError::NotReady | DqBufError::Eos)) => {
+                warn!(
+                    "Expected a CAPTURE buffer but none available, possible driver bug: {}",
+                    e
+                );
+                return false;
+            }
+            Err(e) => {
+                self.report_fatal_error(e.into());
+                return true;
+            }
+        };
+
+        let is_last = cap_buf.data.is_last();
+        let is_empty = cap_buf.data.get_first_plane().bytesused() == 0;
+
+        // Add a drop callback to the dequeued buffer so we
+        // re-queue it as soon as it is dropped.
+        let cap_waker = Arc::clone(&self.waker);
+        cap_buf.add_drop_callback(move |_dqbuf| {
+            cap_waker.wake();
+        });
+
+        // Empty buffers do not need to be passed to the client, unless
+        // they signal the end of the stream or drain. Buffers with the error flag
+        // are passed as well, the client can check `EncodedFrame::has_error`.
+        if !is_empty || is_last {
+            let is_codec_config = take_codec_config(&mut self.expect_codec_config, is_empty);
+            // The codec configuration may carry the timestamp of the first frame, which must
+            // keep its metadata.
+            let metadata = if is_codec_config {
+                None
+            } else {
+                let timestamp = cap_buf.data.timestamp();
+                self.frame_metadata
+                    .lock()
+                    .unwrap()
+                    .take(&TimeVal::new(timestamp.tv_sec, timestamp.tv_usec))
+            };
+            (self.output_ready_cb)(EncodedFrame {
+                buffer: cap_buf,
+                is_codec_config,
+                metadata,
+            });
+        }
+
+        // Last buffer of a drain? Restart the encoder and keep going.
+        // Otherwise this is the end of the stream, and time for us to
+        // terminate.
+        if is_last {
+            if self.drain_in_progress {
+                self.complete_drain();
+            } else {
+                return true;
+            }
+        }
+
+        false
+    }
+
     fn set_poll_counter(&mut self, poll_wakeups_counter: Arc<AtomicUsize>) {
         self.poller.set_poll_counter(poll_wakeups_counter);
     }
 
-    fn run(mut self) -> Self {
+    /// With `HeaderMode::Separate`, the first buffer produced by the encoder after it is started
+    /// only contains the codec configuration.
+    fn detect_codec_config(&mut self) {
+        self.expect_codec_config = matches!(
+            ioctl::g_ctrl(&*self.device, bindings::V4L2_CID_MPEG_VIDEO_HEADER_MODE),
+            Ok(mode) if mode == HeaderMode::Separate as i32
+        );
+    }
+
+    /// Get ready to process events, before the first call to `process_events`.
+    fn prepare(&mut self) {
+        self.detect_codec_config();
         self.enqueue_capture_buffers();
+    }
 
-        'polling: loop {
-            match self.capture_queue.num_queued_buffers() {
-                // If there are no buffers on the CAPTURE queue, poll() will return
-                // immediately with EPOLLERR and we would loop indefinitely.
-                // Prevent this by temporarily disabling polling the device in such
-                // cases.
-                0 => {
-                    self.poller
-                        .disable_event(DeviceEvent::CaptureReady)
-                        .unwrap();
-                }
-                // If device polling was disabled and we have buffers queued, we
-                // can reenable it as poll will now wait for a CAPTURE buffer to
-                // be ready for dequeue.
-                _ => {
-                    self.poller.enable_event(DeviceEvent::CaptureReady).unwrap();
-                }
+    fn run(mut self) -> Self {
+        self.prepare();
+        while self.process_events(None) {}
+
+        self
+    }
+
+    /// Wait up to `timeout` for events and process them.
+    ///
+    /// Returns `false` if the encoder thread should stop, either because the end of the stream
+    /// has been reached or because of a fatal error.
+    fn process_events(&mut self, timeout: Option<Duration>) -> bool {
+        if self.finished {
+            return false;
+        }
+
+        // If there are no buffers on the CAPTURE queue, poll() may return immediately with
+        // EPOLLERR and we would loop indefinitely. Prevent this by temporarily disabling polling
+        // the device in such cases. Once buffers are queued again, poll will wait for a CAPTURE
+        // buffer to be ready for dequeue. The same applies to completed OUTPUT buffers, which
+        // the main thread dequeues.
+        let poll_device = self.capture_queue.num_queued_buffers() > 0;
+        for (event, enable) in [
+            (DeviceEvent::CaptureReady, poll_device),
+            (DeviceEvent::OutputReady, poll_device && self.watch_output),
+        ] {
+            let res = if enable {
+                self.poller.enable_event(event)
+            } else {
+                self.poller.disable_event(event)
+            };
+            if let Err(e) = res {
+                self.report_fatal_error(FatalError::Poller(e.into()));
+                return false;
+            }
+        }
+
+        let events = match self.poller.poll(timeout) {
+            Ok(events) => events,
+            // A signal interrupted us, just try again.
+            Err(PollError::EPollWait(Errno::EINTR)) => return true,
+            Err(e) => {
+                self.report_fatal_error(e.into());
+                return false;
             }
+        };
 
-            // TODO handle errors - this system call can be interrupted and we
-            // should leave in this case.
-            for event in self.poller.poll(None).unwrap() {
-                match event {
-                    // A CAPTURE buffer has been released by the client.
-                    PollEvent::Waker(0) => {
-                        // Requeue all available CAPTURE buffers.
-                        self.enqueue_capture_buffers();
+        for event in events {
+            match event {
+                // A CAPTURE buffer has been released by the client.
+                PollEvent::Waker(CAPTURE_READY) => {
+                    // Requeue all available CAPTURE buffers.
+                    self.enqueue_capture_buffers();
+                    if self.finished {
+                        return false;
                     }
-                    // A CAPTURE buffer is ready to be dequeued.
-                    PollEvent::Device(DeviceEvent::CaptureReady) => {
-                        // Get the encoded buffer
-                        // TODO Manage errors here, including corrupted buffers!
-                        if let Ok(mut cap_buf) = self.capture_queue.try_dequeue() {
-                            let is_last = cap_buf.data.is_last();
-                            let is_empty = cap_buf.data.get_first_plane().bytesused() == 0;
-
-                            // Add a drop callback to the dequeued buffer so we
-                            // re-queue it as soon as it is dropped.
-                            let cap_waker = Arc::clone(&self.waker);
-                            cap_buf.add_drop_callback(move |_dqbuf| {
-                                cap_waker.wake();
-                            });
-
-                            // Empty buffers do not need to be passed to the client.
-                            if !is_empty {
-                                (self.output_ready_cb)(cap_buf);
-                            }
-
-                            // Last buffer of the stream? Time for us to terminate.
-                            if is_last {
-                                break 'polling;
-                            }
-                        } else {
-                            // TODO we should not crash here.
-                            panic!("Expected a CAPTURE buffer but none available!");
+                }
+                // The main thread has sent us a command.
+                PollEvent::Waker(COMMAND_WAITING) => loop {
+                    match self.command_receiver.try_recv() {
+                        Ok(EncoderThreadCommand::Drain) => self.drain(),
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(e) => {
+                            error!("Error while reading encoder command: {}", e);
+                            break;
                         }
                     }
-                    _ => panic!("Unexpected return from CAPTURE queue poll!"),
+                },
+                // A CAPTURE buffer is ready to be dequeued.
+                PollEvent::Device(DeviceEvent::CaptureReady) => {
+                    if self.dequeue_capture_buffer() {
+                        self.finished = true;
+                        return false;
+                    }
                 }
+                // OUTPUT buffers are dequeued by `Encoder::process_events`, which owns the OUTPUT
+                // queue. The event only needs to wake the client up.
+                PollEvent::Device(DeviceEvent::OutputReady) if self.watch_output => (),
+                _ => panic!("Unexpected return from CAPTURE queue poll!"),
             }
         }
 
-        self
+        true
     }
 
     fn enqueue_capture_buffers(&mut self) {
         'enqueue: while let Some(handles) = self.capture_memory_provider.get_handles(&self.waker) {
-            if let Ok(buffer) = self
+            let buffer = match self
                 .capture_memory_provider
                 .get_suitable_buffer_for(&handles, &self.capture_queue)
             {
-                buffer.queue_with_handles(handles).unwrap();
-            } else {
-                warn!("Handles potentially lost due to no V4L2 buffer being available");
+                Ok(buffer) => buffer,
+                Err(_) => {
+                    warn!("Handles potentially lost due to no V4L2 buffer being available");
+                    break 'enqueue;
+                }
+            };
+            if let Err(e) = buffer.queue_with_handles(handles) {
+                self.report_fatal_error(e.error.into());
                 break 'enqueue;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_config_after_drain() {
+        let mut expect_codec_config = true;
+        // First encoded buffer after start: codec configuration.
+        assert!(take_codec_config(&mut expect_codec_config, false));
+        assert!(!take_codec_config(&mut expect_codec_config, false));
+        // Empty LAST buffer ending a drain.
+        assert!(!take_codec_config(&mut expect_codec_config, true));
+        // The encoder is restarted after the drain and emits the configuration again. The empty
+        // buffers dequeued before it do not consume the expectation.
+        expect_codec_config = true;
+        assert!(!take_codec_config(&mut expect_codec_config, true));
+        assert!(take_codec_config(&mut expect_codec_config, false));
+        assert!(!take_codec_config(&mut expect_codec_config, false));
+    }
+
+    #[test]
+    fn test_params_out_of_range() {
+        let params = EncoderParams {
+            bitrate: Some(4_000_000),
+            force_key_frame: true,
+            ..Default::default()
+        };
+        let controls = params.as_ext_controls().unwrap();
+        assert_eq!(controls.len(), 2);
+        // The fields of `v4l2_ext_control` are packed, so copy them before comparing.
+        let (id, value) = (controls[0].id, unsafe {
+            controls[0].__bindgen_anon_1.value
+        });
+        assert_eq!((id, value), (VideoBitrate::ID, 4_000_000));
+
+        let params = EncoderParams {
+            gop_size: Some(u32::MAX),
+            ..Default::default()
+        };
+        assert!(matches!(
+            params.as_ext_controls(),
+            Err(EncoderParamsError::ValueOutOfRange(id, u32::MAX)) if id == VideoGopSize::ID
+        ));
+    }
+}
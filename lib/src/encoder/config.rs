@@ -0,0 +1,628 @@
+//! Typed configuration of the rate control and codec parameters of an encoder.
+//!
+//! An [`EncoderConfig`] gathers the controls that typically need to be set before encoding, and
+//! translates them into the right `V4L2_CID_MPEG_VIDEO_*` controls for the selected codec. Before
+//! being applied, each value is validated against the range or menu reported by the driver, so
+//! unsupported options are reported individually instead of making the whole `S_EXT_CTRLS` call
+//! fail.
+use std::convert::TryFrom;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use thiserror::Error;
+
+use crate::bindings;
+use crate::bindings::v4l2_ext_control;
+use crate::bindings::v4l2_ext_control__bindgen_ty_1;
+use crate::bindings::v4l2_query_ext_ctrl;
+use crate::bindings::v4l2_querymenu;
+use crate::controls::codec::{
+    BitrateMode, H264Level, H264Profile, HeaderMode, HevcLevel, HevcProfile, Vp8Profile, Vp9Level,
+    Vp9Profile,
+};
+use crate::ioctl::{self, CtrlId, CtrlWhich, QueryCtrlFlags};
+
+/// Rate control strategy of the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateControlMode {
+    /// Variable bitrate, targeting `EncoderConfig::bitrate` and bounded by
+    /// `EncoderConfig::peak_bitrate`.
+    Vbr,
+    /// Constant bitrate, targeting `EncoderConfig::bitrate`.
+    Cbr,
+    /// Constant quality, with the given quality factor (driver-defined range, usually 1-100).
+    ConstantQuality(u32),
+    /// Frame-level rate control disabled: frames are encoded with the QPs of
+    /// `EncoderConfig::frame_qp`.
+    ConstantQp,
+}
+
+/// Inclusive range of quantization parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QpRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// A value that can be specified independently for each frame type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerFrameType<T> {
+    pub i: Option<T>,
+    pub p: Option<T>,
+    pub b: Option<T>,
+}
+
+// Deriving would require `T: Default`.
+impl<T> Default for PerFrameType<T> {
+    fn default() -> Self {
+        PerFrameType {
+            i: None,
+            p: None,
+            b: None,
+        }
+    }
+}
+
+/// Codec-specific part of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecConfig {
+    H264 {
+        profile: Option<H264Profile>,
+        level: Option<H264Level>,
+    },
+    Hevc {
+        profile: Option<HevcProfile>,
+        level: Option<HevcLevel>,
+    },
+    Vp8 {
+        profile: Option<Vp8Profile>,
+    },
+    Vp9 {
+        profile: Option<Vp9Profile>,
+        level: Option<Vp9Level>,
+    },
+}
+
+impl CodecConfig {
+    fn name(&self) -> &'static str {
+        match self {
+            CodecConfig::H264 { .. } => "H.264",
+            CodecConfig::Hevc { .. } => "HEVC",
+            CodecConfig::Vp8 { .. } => "VP8",
+            CodecConfig::Vp9 { .. } => "VP9",
+        }
+    }
+}
+
+/// Configuration of an encoder.
+///
+/// Only the parameters that are set are sent to the driver, the others keep their current value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub codec: CodecConfig,
+    pub rate_control: Option<RateControlMode>,
+    /// Target bitrate, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Peak bitrate, in bits per second.
+    pub peak_bitrate: Option<u32>,
+    /// QP range used by the rate control for all frame types.
+    pub qp_range: Option<QpRange>,
+    /// QP ranges used by the rate control for each frame type.
+    pub frame_qp_ranges: PerFrameType<QpRange>,
+    /// QPs of each frame type when the rate control is disabled.
+    pub frame_qp: PerFrameType<u32>,
+    /// Distance between two key frames.
+    pub gop_size: Option<u32>,
+    /// Number of B-frames between two reference frames.
+    pub b_frames: Option<u32>,
+    /// Distance between two IDR frames.
+    pub idr_period: Option<u32>,
+    pub header_mode: Option<HeaderMode>,
+}
+
+impl EncoderConfig {
+    /// Create a new configuration for `codec` that leaves all other parameters untouched.
+    pub fn new(codec: CodecConfig) -> Self {
+        EncoderConfig {
+            codec,
+            rate_control: None,
+            bitrate: None,
+            peak_bitrate: None,
+            qp_range: None,
+            frame_qp_ranges: Default::default(),
+            frame_qp: Default::default(),
+            gop_size: None,
+            b_frames: None,
+            idr_period: None,
+            header_mode: None,
+        }
+    }
+
+    /// Returns the list of controls to set in order to apply this configuration.
+    pub fn controls(&self) -> Result<Vec<ConfigControl>, EncoderConfigError> {
+        let mut controls = Vec::new();
+        // Controls values are signed, so larger values cannot be passed to the driver.
+        let mut push = |name, id, value: u32| -> Result<(), EncoderConfigError> {
+            let value = i32::try_from(value).map_err(|_| EncoderConfigError::ValueTooLarge {
+                control: name,
+                value,
+            })?;
+            controls.push(ConfigControl { name, id, value });
+            Ok(())
+        };
+        let unsupported = |option| EncoderConfigError::UnsupportedByCodec {
+            option,
+            codec: self.codec.name(),
+        };
+
+        match self.rate_control {
+            Some(RateControlMode::ConstantQp) => push(
+                "frame rate control",
+                bindings::V4L2_CID_MPEG_VIDEO_FRAME_RC_ENABLE,
+                0,
+            )?,
+            Some(mode) => {
+                let (bitrate_mode, quality) = match mode {
+                    RateControlMode::Vbr => (BitrateMode::Vbr, None),
+                    RateControlMode::Cbr => (BitrateMode::Cbr, None),
+                    RateControlMode::ConstantQuality(quality) => (BitrateMode::Cq, Some(quality)),
+                    RateControlMode::ConstantQp => unreachable!(),
+                };
+                // Rate control may have been disabled by a previous configuration, in which case
+                // the bitrate mode would be ignored.
+                push(
+                    "frame rate control",
+                    bindings::V4L2_CID_MPEG_VIDEO_FRAME_RC_ENABLE,
+                    1,
+                )?;
+                push(
+                    "bitrate mode",
+                    bindings::V4L2_CID_MPEG_VIDEO_BITRATE_MODE,
+                    bitrate_mode as u32,
+                )?;
+                if let Some(quality) = quality {
+                    push(
+                        "constant quality",
+                        bindings::V4L2_CID_MPEG_VIDEO_CONSTANT_QUALITY,
+                        quality,
+                    )?;
+                }
+            }
+            None => (),
+        }
+
+        if let Some(bitrate) = self.bitrate {
+            push("bitrate", bindings::V4L2_CID_MPEG_VIDEO_BITRATE, bitrate)?;
+        }
+        if let Some(peak_bitrate) = self.peak_bitrate {
+            push(
+                "peak bitrate",
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE_PEAK,
+                peak_bitrate,
+            )?;
+        }
+        if let Some(gop_size) = self.gop_size {
+            push("GOP size", bindings::V4L2_CID_MPEG_VIDEO_GOP_SIZE, gop_size)?;
+        }
+        if let Some(b_frames) = self.b_frames {
+            push("B-frames", bindings::V4L2_CID_MPEG_VIDEO_B_FRAMES, b_frames)?;
+        }
+        if let Some(header_mode) = self.header_mode {
+            push(
+                "header mode",
+                bindings::V4L2_CID_MPEG_VIDEO_HEADER_MODE,
+                header_mode as u32,
+            )?;
+        }
+
+        let qp = QpControls::for_codec(&self.codec);
+
+        if let Some(range) = self.qp_range {
+            push("min QP", qp.min, range.min)?;
+            push("max QP", qp.max, range.max)?;
+        }
+
+        let frame_qp_ranges = [
+            (
+                ("I-frame min QP", "I-frame max QP"),
+                self.frame_qp_ranges.i,
+                qp.i_range,
+            ),
+            (
+                ("P-frame min QP", "P-frame max QP"),
+                self.frame_qp_ranges.p,
+                qp.p_range,
+            ),
+            (
+                ("B-frame min QP", "B-frame max QP"),
+                self.frame_qp_ranges.b,
+                qp.b_range,
+            ),
+        ];
+        for ((min_name, max_name), range, ids) in frame_qp_ranges {
+            if let Some(range) = range {
+                let (min_id, max_id) = ids.ok_or_else(|| unsupported("per-frame QP range"))?;
+                push(min_name, min_id, range.min)?;
+                push(max_name, max_id, range.max)?;
+            }
+        }
+
+        let frame_qp = [
+            ("I-frame QP", self.frame_qp.i, Some(qp.i)),
+            ("P-frame QP", self.frame_qp.p, Some(qp.p)),
+            ("B-frame QP", self.frame_qp.b, qp.b),
+        ];
+        for (name, value, id) in frame_qp {
+            if let Some(value) = value {
+                let id = id.ok_or_else(|| unsupported(name))?;
+                push(name, id, value)?;
+            }
+        }
+
+        if let Some(idr_period) = self.idr_period {
+            let id = match self.codec {
+                CodecConfig::H264 { .. } => bindings::V4L2_CID_MPEG_VIDEO_H264_I_PERIOD,
+                CodecConfig::Hevc { .. } => bindings::V4L2_CID_MPEG_VIDEO_HEVC_REFRESH_PERIOD,
+                CodecConfig::Vp8 { .. } | CodecConfig::Vp9 { .. } => {
+                    return Err(unsupported("IDR period"))
+                }
+            };
+            push("IDR period", id, idr_period)?;
+        }
+
+        let (profile, level) = match self.codec {
+            CodecConfig::H264 { profile, level } => (
+                profile.map(|p| (bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE, p as u32)),
+                level.map(|l| (bindings::V4L2_CID_MPEG_VIDEO_H264_LEVEL, l as u32)),
+            ),
+            CodecConfig::Hevc { profile, level } => (
+                profile.map(|p| (bindings::V4L2_CID_MPEG_VIDEO_HEVC_PROFILE, p as u32)),
+                level.map(|l| (bindings::V4L2_CID_MPEG_VIDEO_HEVC_LEVEL, l as u32)),
+            ),
+            CodecConfig::Vp8 { profile } => (
+                profile.map(|p| (bindings::V4L2_CID_MPEG_VIDEO_VP8_PROFILE, p as u32)),
+                None,
+            ),
+            CodecConfig::Vp9 { profile, level } => (
+                profile.map(|p| (bindings::V4L2_CID_MPEG_VIDEO_VP9_PROFILE, p as u32)),
+                level.map(|l| (bindings::V4L2_CID_MPEG_VIDEO_VP9_LEVEL, l as u32)),
+            ),
+        };
+        if let Some((id, value)) = profile {
+            push("profile", id, value)?;
+        }
+        if let Some((id, value)) = level {
+            push("level", id, value)?;
+        }
+
+        Ok(controls)
+    }
+
+    /// Check that every parameter of this configuration is supported by the driver behind `fd`.
+    pub fn validate(&self, fd: &impl AsRawFd) -> Result<Vec<ConfigControl>, EncoderConfigError> {
+        let controls = self.controls()?;
+        for control in &controls {
+            control.validate(fd)?;
+        }
+
+        Ok(controls)
+    }
+
+    /// Validate and apply this configuration to the driver behind `fd`.
+    pub fn apply(&self, fd: &impl AsRawFd) -> Result<(), EncoderConfigError> {
+        let controls = self.validate(fd)?;
+        if controls.is_empty() {
+            return Ok(());
+        }
+
+        let mut ext_controls = controls
+            .iter()
+            .map(|control| v4l2_ext_control {
+                id: control.id,
+                __bindgen_anon_1: v4l2_ext_control__bindgen_ty_1 {
+                    value: control.value,
+                },
+                ..unsafe { std::mem::zeroed() }
+            })
+            .collect::<Vec<_>>();
+
+        ioctl::s_ext_ctrls(fd, CtrlWhich::Current, ext_controls.as_mut_slice()).map_err(|e| {
+            EncoderConfigError::ApplyError {
+                control: controls
+                    .get(e.error_idx as usize)
+                    .map(|c| c.name)
+                    .unwrap_or("unknown"),
+                error: e,
+            }
+        })
+    }
+}
+
+/// IDs of the QP controls of a given codec.
+struct QpControls {
+    min: u32,
+    max: u32,
+    i: u32,
+    p: u32,
+    b: Option<u32>,
+    i_range: Option<(u32, u32)>,
+    p_range: Option<(u32, u32)>,
+    b_range: Option<(u32, u32)>,
+}
+
+impl QpControls {
+    fn for_codec(codec: &CodecConfig) -> Self {
+        match codec {
+            CodecConfig::H264 { .. } => QpControls {
+                min: bindings::V4L2_CID_MPEG_VIDEO_H264_MIN_QP,
+                max: bindings::V4L2_CID_MPEG_VIDEO_H264_MAX_QP,
+                i: bindings::V4L2_CID_MPEG_VIDEO_H264_I_FRAME_QP,
+                p: bindings::V4L2_CID_MPEG_VIDEO_H264_P_FRAME_QP,
+                b: Some(bindings::V4L2_CID_MPEG_VIDEO_H264_B_FRAME_QP),
+                i_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_I_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_I_FRAME_MAX_QP,
+                )),
+                p_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_P_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_P_FRAME_MAX_QP,
+                )),
+                b_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_B_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_B_FRAME_MAX_QP,
+                )),
+            },
+            CodecConfig::Hevc { .. } => QpControls {
+                min: bindings::V4L2_CID_MPEG_VIDEO_HEVC_MIN_QP,
+                max: bindings::V4L2_CID_MPEG_VIDEO_HEVC_MAX_QP,
+                i: bindings::V4L2_CID_MPEG_VIDEO_HEVC_I_FRAME_QP,
+                p: bindings::V4L2_CID_MPEG_VIDEO_HEVC_P_FRAME_QP,
+                b: Some(bindings::V4L2_CID_MPEG_VIDEO_HEVC_B_FRAME_QP),
+                i_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_I_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_I_FRAME_MAX_QP,
+                )),
+                p_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_P_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_P_FRAME_MAX_QP,
+                )),
+                b_range: Some((
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_B_FRAME_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_B_FRAME_MAX_QP,
+                )),
+            },
+            // VP8 and VP9 share the VPX controls and have no B-frames.
+            CodecConfig::Vp8 { .. } | CodecConfig::Vp9 { .. } => QpControls {
+                min: bindings::V4L2_CID_MPEG_VIDEO_VPX_MIN_QP,
+                max: bindings::V4L2_CID_MPEG_VIDEO_VPX_MAX_QP,
+                i: bindings::V4L2_CID_MPEG_VIDEO_VPX_I_FRAME_QP,
+                p: bindings::V4L2_CID_MPEG_VIDEO_VPX_P_FRAME_QP,
+                b: None,
+                i_range: None,
+                p_range: None,
+                b_range: None,
+            },
+        }
+    }
+}
+
+/// A single control resulting from an [`EncoderConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigControl {
+    /// Human-readable name of the configured option, used for error reporting.
+    pub name: &'static str,
+    /// One of `V4L2_CID_*`.
+    pub id: u32,
+    pub value: i32,
+}
+
+impl ConfigControl {
+    /// Check that the driver behind `fd` supports this control and value.
+    pub fn validate(&self, fd: &impl AsRawFd) -> Result<(), EncoderConfigError> {
+        let unsupported = || EncoderConfigError::UnsupportedControl {
+            control: self.name,
+            id: self.id,
+        };
+
+        let ctrl_id = CtrlId::new(self.id).map_err(|_| unsupported())?;
+        let query: v4l2_query_ext_ctrl =
+            match ioctl::query_ext_ctrl(fd, ctrl_id, QueryCtrlFlags::empty()) {
+                Ok(query) => query,
+                Err(ioctl::QueryCtrlError::IoctlError(Errno::EINVAL)) => return Err(unsupported()),
+                Err(e) => return Err(EncoderConfigError::QueryError(e)),
+            };
+
+        if query.flags & (bindings::V4L2_CTRL_FLAG_DISABLED | bindings::V4L2_CTRL_FLAG_READ_ONLY)
+            != 0
+        {
+            return Err(unsupported());
+        }
+
+        let value = self.value as i64;
+        if value < query.minimum || value > query.maximum {
+            return Err(EncoderConfigError::OutOfRange {
+                control: self.name,
+                value: self.value,
+                min: query.minimum,
+                max: query.maximum,
+            });
+        }
+
+        match query.type_ {
+            bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_MENU
+            | bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU => {
+                // Menus can have holes, so check that the requested entry exists.
+                match ioctl::querymenu::<v4l2_querymenu>(fd, self.id, self.value as u32) {
+                    Ok(_) => Ok(()),
+                    Err(ioctl::QueryMenuError::InvalidIdOrIndex) => {
+                        Err(EncoderConfigError::UnsupportedMenuValue {
+                            control: self.name,
+                            value: self.value,
+                        })
+                    }
+                    Err(e) => Err(EncoderConfigError::QueryMenuError(e)),
+                }
+            }
+            _ => {
+                if query.step > 1 && !((value - query.minimum) as u64).is_multiple_of(query.step) {
+                    Err(EncoderConfigError::InvalidStep {
+                        control: self.name,
+                        value: self.value,
+                        step: query.step,
+                    })
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncoderConfigError {
+    #[error("{option} is not supported for {codec}")]
+    UnsupportedByCodec {
+        option: &'static str,
+        codec: &'static str,
+    },
+    #[error("{control} (control 0x{id:08x}) is not supported by the driver")]
+    UnsupportedControl { control: &'static str, id: u32 },
+    #[error("{control} value {value} is outside of the supported range [{min}, {max}]")]
+    OutOfRange {
+        control: &'static str,
+        value: i32,
+        min: i64,
+        max: i64,
+    },
+    #[error("{control} value {value} is too large to be passed to the driver")]
+    ValueTooLarge { control: &'static str, value: u32 },
+    #[error("{control} value {value} is not a multiple of step {step}")]
+    InvalidStep {
+        control: &'static str,
+        value: i32,
+        step: u64,
+    },
+    #[error("{control} value {value} is not supported by the driver")]
+    UnsupportedMenuValue { control: &'static str, value: i32 },
+    #[error("error while querying control")]
+    QueryError(#[from] ioctl::QueryCtrlError),
+    #[error("error while querying menu")]
+    QueryMenuError(#[from] ioctl::QueryMenuError),
+    #[error("error while applying {control}: {error}")]
+    ApplyError {
+        control: &'static str,
+        error: ioctl::ExtControlError,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_qp() {
+        let config = EncoderConfig {
+            rate_control: Some(RateControlMode::ConstantQp),
+            frame_qp: PerFrameType {
+                i: Some(20),
+                p: Some(24),
+                b: None,
+            },
+            ..EncoderConfig::new(CodecConfig::H264 {
+                profile: Some(H264Profile::High),
+                level: Some(H264Level::L4_1),
+            })
+        };
+
+        let controls = config
+            .controls()
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            controls,
+            vec![
+                (bindings::V4L2_CID_MPEG_VIDEO_FRAME_RC_ENABLE, 0),
+                (bindings::V4L2_CID_MPEG_VIDEO_H264_I_FRAME_QP, 20),
+                (bindings::V4L2_CID_MPEG_VIDEO_H264_P_FRAME_QP, 24),
+                (
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE,
+                    H264Profile::High as i32
+                ),
+                (
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_LEVEL,
+                    H264Level::L4_1 as i32
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cbr() {
+        let config = EncoderConfig {
+            rate_control: Some(RateControlMode::Cbr),
+            bitrate: Some(2_000_000),
+            ..EncoderConfig::new(CodecConfig::Vp8 { profile: None })
+        };
+
+        let controls = config
+            .controls()
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.id, c.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            controls,
+            vec![
+                (bindings::V4L2_CID_MPEG_VIDEO_FRAME_RC_ENABLE, 1),
+                (
+                    bindings::V4L2_CID_MPEG_VIDEO_BITRATE_MODE,
+                    BitrateMode::Cbr as i32
+                ),
+                (bindings::V4L2_CID_MPEG_VIDEO_BITRATE, 2_000_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_by_codec() {
+        let config = EncoderConfig {
+            frame_qp: PerFrameType {
+                b: Some(30),
+                ..Default::default()
+            },
+            ..EncoderConfig::new(CodecConfig::Vp8 { profile: None })
+        };
+        assert!(matches!(
+            config.controls(),
+            Err(EncoderConfigError::UnsupportedByCodec { codec: "VP8", .. })
+        ));
+
+        let config = EncoderConfig {
+            idr_period: Some(30),
+            ..EncoderConfig::new(CodecConfig::Vp9 {
+                profile: None,
+                level: None,
+            })
+        };
+        assert!(matches!(
+            config.controls(),
+            Err(EncoderConfigError::UnsupportedByCodec { codec: "VP9", .. })
+        ));
+    }
+    #[test]
+    fn test_value_too_large() {
+        let config = EncoderConfig {
+            bitrate: Some(u32::MAX),
+            ..EncoderConfig::new(CodecConfig::Vp8 { profile: None })
+        };
+        assert!(matches!(
+            config.controls(),
+            Err(EncoderConfigError::ValueTooLarge {
+                control: "bitrate",
+                value: u32::MAX
+            })
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+//! Sinks writing the output of an encoder into a playable file.
+//!
+//! Each sink receives the encoded frames in the order the encoder produces them, along with the
+//! [`FrameInfo`] describing their timing and type, and writes them into a specific format.
+pub mod annexb;
+pub mod ivf;
+pub mod mp4;
+
+use super::EncodedFrame;
+use crate::{
+    decoder::format::ivf::IvfError,
+    memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
+    PixelFormat,
+};
+use nix::sys::time::TimeValLike;
+use std::{io, time::Duration};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StreamSinkError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("IVF error: {0}")]
+    Ivf(#[from] IvfError),
+    #[error("failed to map the encoded frame")]
+    MappingFailed,
+    #[error("codec {0} is not supported by this sink")]
+    UnsupportedCodec(PixelFormat),
+    #[error("frame does not start with a start code")]
+    NotAnnexB,
+    #[error("frame received before the codec configuration")]
+    MissingCodecConfig,
+    #[error("invalid parameter set")]
+    InvalidParameterSet,
+}
+
+/// Properties of an encoded frame that sinks rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Presentation timestamp of the frame.
+    pub timestamp: Duration,
+    /// Whether decoding can start from this frame.
+    pub is_keyframe: bool,
+    /// Whether this frame only contains the codec configuration. See
+    /// [`EncodedFrame::is_codec_config`].
+    pub is_codec_config: bool,
+}
+
+impl FrameInfo {
+    /// Build the information of `frame` from its buffer's timestamp and flags.
+    ///
+    /// The timestamp of the OUTPUT buffer a frame has been encoded from is used as its
+    /// presentation timestamp. When frame metadata is in use, buffer timestamps are frame
+    /// identifiers allocated by the encoder, so the real timestamp must be taken from the
+    /// metadata instead.
+    pub fn from_encoded_frame<P: BufferHandles, M>(frame: &EncodedFrame<P, M>) -> Self {
+        FrameInfo {
+            timestamp: Duration::from_micros(frame.timestamp().num_microseconds().max(0) as u64),
+            is_keyframe: frame.is_keyframe(),
+            is_codec_config: frame.is_codec_config(),
+        }
+    }
+}
+
+/// Trait for classes able to write the frames produced by an encoder into a stream.
+pub trait StreamSink {
+    /// Write the encoded `data` of a frame. Empty frames, like the last one of a drain sequence,
+    /// are ignored.
+    fn write_frame(&mut self, data: &[u8], info: FrameInfo) -> Result<(), StreamSinkError>;
+
+    /// Write any pending data once the last frame has been written.
+    fn finish(&mut self) -> Result<(), StreamSinkError>;
+
+    /// Map `frame` and write its payload along with its buffer's timestamp and flags.
+    fn write_encoded_frame<P, M>(
+        &mut self,
+        frame: &EncodedFrame<P, M>,
+    ) -> Result<(), StreamSinkError>
+    where
+        Self: Sized,
+        P: PrimitiveBufferHandles,
+        P::HandleType: Mappable,
+    {
+        if frame.payload_size() == 0 {
+            return Ok(());
+        }
+
+        let payload = frame.payload().ok_or(StreamSinkError::MappingFailed)?;
+        self.write_frame(payload.as_ref(), FrameInfo::from_encoded_frame(frame))
+    }
+}
+
+impl<S: StreamSink + ?Sized> StreamSink for Box<S> {
+    fn write_frame(&mut self, data: &[u8], info: FrameInfo) -> Result<(), StreamSinkError> {
+        (**self).write_frame(data, info)
+    }
+
+    fn finish(&mut self) -> Result<(), StreamSinkError> {
+        (**self).finish()
+    }
+}
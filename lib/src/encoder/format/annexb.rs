@@ -0,0 +1,72 @@
+//! Writer for H.264 and HEVC Annex-B byte streams.
+use super::{FrameInfo, StreamSink, StreamSinkError};
+use std::io;
+
+/// Writes the output of a H.264 or HEVC encoder as an Annex-B byte stream, i.e. its frames back
+/// to back, codec configuration included.
+///
+/// Byte streams carry no timing information, so the timestamps of the frames are ignored.
+pub struct AnnexBWriter<W: io::Write> {
+    writer: W,
+}
+
+impl<W: io::Write> AnnexBWriter<W> {
+    pub fn new(writer: W) -> Self {
+        AnnexBWriter { writer }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: io::Write> StreamSink for AnnexBWriter<W> {
+    fn write_frame(&mut self, data: &[u8], _info: FrameInfo) -> Result<(), StreamSinkError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        // Zero bytes may precede the start code of the first NAL unit.
+        let start = data.iter().position(|b| *b != 0).unwrap_or(data.len());
+        if start < 2 || data.get(start) != Some(&1) {
+            return Err(StreamSinkError::NotAnnexB);
+        }
+
+        self.writer.write_all(data)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), StreamSinkError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_annex_b_writer() {
+        let info = FrameInfo {
+            timestamp: Duration::ZERO,
+            is_keyframe: true,
+            is_codec_config: false,
+        };
+        let mut writer = AnnexBWriter::new(Vec::new());
+
+        writer.write_frame(&[0, 0, 0, 1, 0x67], info).unwrap();
+        writer.write_frame(&[], info).unwrap();
+        writer.write_frame(&[0, 0, 1, 0x65], info).unwrap();
+        assert!(matches!(
+            writer.write_frame(&[0, 1, 0x65], info),
+            Err(StreamSinkError::NotAnnexB)
+        ));
+        writer.finish().unwrap();
+
+        assert_eq!(writer.into_inner(), vec![0, 0, 0, 1, 0x67, 0, 0, 1, 0x65]);
+    }
+}
@@ -0,0 +1,87 @@
+//! Sink writing the output of a VP8, VP9 or AV1 encoder into an IVF file.
+use super::{FrameInfo, StreamSink, StreamSinkError};
+use crate::decoder::format::ivf::{IvfHeader, IvfWriter};
+use std::{convert::TryFrom, io};
+
+/// Writes encoded frames into an IVF file, converting their timestamps into units of the time
+/// base of the file.
+pub struct IvfSink<W: io::Write> {
+    writer: IvfWriter<W>,
+    timebase: (u32, u32),
+}
+
+impl<W: io::Write> IvfSink<W> {
+    /// Create a new sink, and write the file header described by `header` to `writer`.
+    pub fn new(writer: W, header: IvfHeader) -> Result<Self, StreamSinkError> {
+        Ok(IvfSink {
+            writer: IvfWriter::new(writer, header)?,
+            timebase: header.timebase,
+        })
+    }
+
+    /// Returns the underlying IVF writer, e.g. to update the number of frames of the file with
+    /// [`IvfWriter::finish`] if it is seekable.
+    pub fn into_inner(self) -> IvfWriter<W> {
+        self.writer
+    }
+}
+
+impl<W: io::Write> StreamSink for IvfSink<W> {
+    fn write_frame(&mut self, data: &[u8], info: FrameInfo) -> Result<(), StreamSinkError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // Round to the nearest unit, so timestamps slightly below a multiple of the time base
+        // do not end up sharing the pts of the previous frame.
+        let (num, den) = self.timebase;
+        let unit_ns = num as u128 * 1_000_000_000;
+        let pts = (info.timestamp.as_nanos() * den as u128 + unit_ns / 2) / unit_ns;
+        self.writer
+            .write_frame(data, u64::try_from(pts).unwrap_or(u64::MAX))?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), StreamSinkError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoder::format::ivf::IvfReader, PixelFormat};
+    use std::{io::Cursor, time::Duration};
+
+    #[test]
+    fn test_ivf_sink_timestamps() {
+        let header = IvfHeader {
+            fourcc: PixelFormat::VP8,
+            width: 320,
+            height: 240,
+            timebase: (1, 30),
+            num_frames: 0,
+        };
+        let mut sink = IvfSink::new(Cursor::new(Vec::new()), header).unwrap();
+        for (timestamp_ms, data) in [(0, &[1u8][..]), (20, &[]), (33, &[2, 3]), (1000, &[4])] {
+            let info = FrameInfo {
+                timestamp: Duration::from_millis(timestamp_ms),
+                is_keyframe: timestamp_ms == 0,
+                is_codec_config: false,
+            };
+            sink.write_frame(data, info).unwrap();
+        }
+        sink.finish().unwrap();
+        let file = sink.into_inner().finish().unwrap().into_inner();
+
+        let mut reader = IvfReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.header().num_frames, 3);
+        let pts = std::iter::from_fn(|| reader.next_frame().unwrap())
+            .map(|frame| frame.pts)
+            .collect::<Vec<_>>();
+        assert_eq!(pts, vec![0, 1, 30]);
+    }
+}
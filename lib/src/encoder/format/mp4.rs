@@ -0,0 +1,743 @@
+//! Fragmented MP4 muxer for the output of H.264 and HEVC encoders.
+//!
+//! The file starts with a `moov` box describing a single video track, which sample entry embeds
+//! an `avcC` or `hvcC` configuration record built from the parameter sets produced by the
+//! encoder. Each frame is then written into its own `moof`/`mdat` fragment as soon as the
+//! timestamp of the next frame gives its duration, so the file remains playable if writing is
+//! interrupted and never needs to be seekable.
+//!
+//! Frames are expected in presentation order, which is the case of encoders which do not produce
+//! B-frames.
+use super::{FrameInfo, StreamSink, StreamSinkError};
+use crate::{
+    decoder::format::{
+        bitreader::BitReader,
+        h264::{self, H264NalType},
+        hevc::{read_profile_tier_level, HevcNalType},
+        nal::NalReader,
+    },
+    PixelFormat,
+};
+use std::{convert::TryFrom, io, time::Duration};
+
+/// Timestamps are written in microseconds, the precision of V4L2 buffer timestamps.
+const TIMESCALE: u32 = 1_000_000;
+const TRACK_ID: u32 = 1;
+/// Duration given to the last sample if it is also the first one.
+const DEFAULT_SAMPLE_DURATION: u32 = TIMESCALE / 30;
+/// Unity transformation matrix of the `mvhd` and `tkhd` boxes.
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+/// Sample flags of sync samples: the sample does not depend on others.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// Sample flags of other samples: the sample depends on others and is not a sync sample.
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+/// H.264 profiles which configuration record includes the chroma format and bit depths.
+const H264_HIGH_PROFILES: [u8; 4] = [100, 110, 122, 144];
+
+fn mp4_box(box_type: &[u8; 4], content: &[&[u8]]) -> Vec<u8> {
+    let content = content.concat();
+    [
+        &(content.len() as u32 + 8).to_be_bytes(),
+        &box_type[..],
+        &content,
+    ]
+    .concat()
+}
+
+fn full_box(box_type: &[u8; 4], version: u8, flags: u32, content: &[&[u8]]) -> Vec<u8> {
+    let header = (version as u32) << 24 | (flags & 0xff_ffff);
+    mp4_box(box_type, &[&header.to_be_bytes(), &content.concat()])
+}
+
+/// Append `nal` to `data`, preceded by its length on `len_size` bytes.
+fn push_with_length(
+    data: &mut Vec<u8>,
+    nal: &[u8],
+    len_size: usize,
+) -> Result<(), StreamSinkError> {
+    let len = nal.len() as u64;
+    if len >> (len_size * 8) != 0 {
+        return Err(StreamSinkError::InvalidParameterSet);
+    }
+    data.extend_from_slice(&len.to_be_bytes()[8 - len_size..]);
+    data.extend_from_slice(nal);
+
+    Ok(())
+}
+
+/// Read the chroma format and bit depths of a H.264 SPS, starting at `seq_parameter_set_id`.
+fn read_h264_chroma_info(reader: &mut BitReader) -> Option<(u32, u32, u32)> {
+    // seq_parameter_set_id
+    reader.read_ue()?;
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        // separate_colour_plane_flag
+        reader.skip_bits(1)?;
+    }
+    let bit_depth_luma_minus8 = reader.read_ue()?;
+    let bit_depth_chroma_minus8 = reader.read_ue()?;
+
+    Some((
+        chroma_format_idc,
+        bit_depth_luma_minus8,
+        bit_depth_chroma_minus8,
+    ))
+}
+
+/// Build the AVCDecoderConfigurationRecord of a H.264 stream.
+fn avcc(sps: &[Vec<u8>], pps: &[Vec<u8>]) -> Result<Vec<u8>, StreamSinkError> {
+    let first_sps = sps.first().ok_or(StreamSinkError::MissingCodecConfig)?;
+    if pps.is_empty() {
+        return Err(StreamSinkError::MissingCodecConfig);
+    }
+    if sps.len() > 31 || pps.len() > 255 {
+        return Err(StreamSinkError::InvalidParameterSet);
+    }
+
+    let mut reader = BitReader::from_nal(&first_sps[1..]);
+    let mut header = [0u8; 3];
+    for b in header.iter_mut() {
+        *b = reader
+            .read_bits(8)
+            .ok_or(StreamSinkError::InvalidParameterSet)? as u8;
+    }
+    let [profile_idc, constraint_flags, level_idc] = header;
+
+    // NAL units lengths are coded on 4 bytes.
+    let mut avcc = vec![
+        1,
+        profile_idc,
+        constraint_flags,
+        level_idc,
+        0xff,
+        0xe0 | sps.len() as u8,
+    ];
+    for nal in sps {
+        push_with_length(&mut avcc, nal, 2)?;
+    }
+    avcc.push(pps.len() as u8);
+    for nal in pps {
+        push_with_length(&mut avcc, nal, 2)?;
+    }
+
+    if H264_HIGH_PROFILES.contains(&profile_idc) {
+        let (chroma_format_idc, bit_depth_luma_minus8, bit_depth_chroma_minus8) =
+            if h264::SPS_CHROMA_PROFILES.contains(&profile_idc) {
+                read_h264_chroma_info(&mut reader).ok_or(StreamSinkError::InvalidParameterSet)?
+            } else {
+                (1, 0, 0)
+            };
+        avcc.extend_from_slice(&[
+            0xfc | (chroma_format_idc & 0x3) as u8,
+            0xf8 | (bit_depth_luma_minus8 & 0x7) as u8,
+            0xf8 | (bit_depth_chroma_minus8 & 0x7) as u8,
+            // No SPS extension.
+            0,
+        ]);
+    }
+
+    Ok(mp4_box(b"avcC", &[&avcc]))
+}
+
+/// Fields of a HEVC SPS copied into the HEVCDecoderConfigurationRecord.
+struct HevcSpsInfo {
+    max_sub_layers_minus1: u32,
+    temporal_id_nesting_flag: u32,
+    general_profile_tier_level: [u8; 12],
+    chroma_format_idc: u32,
+    bit_depth_luma_minus8: u32,
+    bit_depth_chroma_minus8: u32,
+}
+
+/// Parse the content of a HEVC SPS NAL unit, following its header.
+fn parse_hevc_sps(sps_content: &[u8]) -> Option<HevcSpsInfo> {
+    let mut reader = BitReader::from_nal(sps_content);
+    // sps_video_parameter_set_id
+    reader.skip_bits(4)?;
+    let max_sub_layers_minus1 = reader.read_bits(3)?;
+    let temporal_id_nesting_flag = reader.read_bits(1)?;
+    let general_profile_tier_level =
+        read_profile_tier_level(&mut reader, max_sub_layers_minus1 as usize)?;
+    // sps_seq_parameter_set_id
+    reader.read_ue()?;
+    let chroma_format_idc = reader.read_ue()?;
+    if chroma_format_idc == 3 {
+        // separate_colour_plane_flag
+        reader.skip_bits(1)?;
+    }
+    // pic_width_in_luma_samples, pic_height_in_luma_samples
+    reader.read_ue()?;
+    reader.read_ue()?;
+    if reader.read_bit()? {
+        // Conformance window offsets.
+        for _ in 0..4 {
+            reader.read_ue()?;
+        }
+    }
+    let bit_depth_luma_minus8 = reader.read_ue()?;
+    let bit_depth_chroma_minus8 = reader.read_ue()?;
+
+    Some(HevcSpsInfo {
+        max_sub_layers_minus1,
+        temporal_id_nesting_flag,
+        general_profile_tier_level,
+        chroma_format_idc,
+        bit_depth_luma_minus8,
+        bit_depth_chroma_minus8,
+    })
+}
+
+/// Build the HEVCDecoderConfigurationRecord of a HEVC stream.
+fn hvcc(vps: &[Vec<u8>], sps: &[Vec<u8>], pps: &[Vec<u8>]) -> Result<Vec<u8>, StreamSinkError> {
+    let first_sps = sps.first().ok_or(StreamSinkError::MissingCodecConfig)?;
+    if vps.is_empty() || pps.is_empty() {
+        return Err(StreamSinkError::MissingCodecConfig);
+    }
+
+    let info = first_sps
+        .get(2..)
+        .and_then(parse_hevc_sps)
+        .ok_or(StreamSinkError::InvalidParameterSet)?;
+
+    let mut hvcc = vec![1];
+    hvcc.extend_from_slice(&info.general_profile_tier_level);
+    hvcc.extend_from_slice(&[
+        // min_spatial_segmentation_idc
+        0xf0,
+        0x00,
+        // parallelismType
+        0xfc,
+        0xfc | (info.chroma_format_idc & 0x3) as u8,
+        0xf8 | (info.bit_depth_luma_minus8 & 0x7) as u8,
+        0xf8 | (info.bit_depth_chroma_minus8 & 0x7) as u8,
+        // avgFrameRate
+        0,
+        0,
+        // NAL units lengths are coded on 4 bytes.
+        ((info.max_sub_layers_minus1 + 1) << 3 | info.temporal_id_nesting_flag << 2 | 0x3) as u8,
+        3,
+    ]);
+    for (nal_unit_type, nals) in [(32u8, vps), (33, sps), (34, pps)] {
+        // array_completeness is set: all the parameter sets are in the record.
+        hvcc.push(0x80 | nal_unit_type);
+        let num_nalus =
+            u16::try_from(nals.len()).map_err(|_| StreamSinkError::InvalidParameterSet)?;
+        hvcc.extend_from_slice(&num_nalus.to_be_bytes());
+        for nal in nals {
+            push_with_length(&mut hvcc, nal, 2)?;
+        }
+    }
+
+    Ok(mp4_box(b"hvcC", &[&hvcc]))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    H264,
+    Hevc,
+}
+
+/// Role of a NAL unit when muxing.
+enum NalRole {
+    Vps,
+    Sps,
+    Pps,
+    /// NAL unit that is not written into the samples.
+    Dropped,
+    Sample,
+}
+
+impl Codec {
+    fn nal_role(self, payload: &[u8]) -> NalRole {
+        match self {
+            Codec::H264 => match H264NalType::from(payload[0] & 0x1f) {
+                H264NalType::Sps => NalRole::Sps,
+                H264NalType::Pps => NalRole::Pps,
+                H264NalType::AccessUnitDelimiter => NalRole::Dropped,
+                _ => NalRole::Sample,
+            },
+            Codec::Hevc => match HevcNalType::from((payload[0] >> 1) & 0x3f) {
+                HevcNalType::Vps => NalRole::Vps,
+                HevcNalType::Sps => NalRole::Sps,
+                HevcNalType::Pps => NalRole::Pps,
+                HevcNalType::AccessUnitDelimiter => NalRole::Dropped,
+                _ => NalRole::Sample,
+            },
+        }
+    }
+}
+
+/// A sample waiting for the timestamp of the next one to be written.
+struct PendingSample {
+    data: Vec<u8>,
+    timestamp: Duration,
+    is_keyframe: bool,
+}
+
+/// Writes the output of a H.264 or HEVC encoder into a fragmented MP4 file.
+///
+/// The parameter sets are taken from the frames preceding the first picture, which is the codec
+/// configuration frame if the encoder produces one, or the first keyframe otherwise. They are
+/// removed from the samples, so parameter sets changing during the stream are not supported.
+pub struct FragmentedMp4Writer<W: io::Write> {
+    writer: W,
+    codec: Codec,
+    width: u16,
+    height: u16,
+    vps: Vec<Vec<u8>>,
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>,
+    header_written: bool,
+    /// Timestamp of the first sample, which is given decoding time 0.
+    first_timestamp: Option<Duration>,
+    pending_sample: Option<PendingSample>,
+    last_duration: u32,
+    sequence_number: u32,
+}
+
+impl<W: io::Write> FragmentedMp4Writer<W> {
+    /// Create a new muxer for a stream of `fourcc` (H.264 or HEVC) frames of `width`x`height`
+    /// pixels. Nothing is written until the first frame is received.
+    pub fn new(
+        writer: W,
+        fourcc: PixelFormat,
+        width: u16,
+        height: u16,
+    ) -> Result<Self, StreamSinkError> {
+        let codec = match fourcc {
+            PixelFormat::H264 => Codec::H264,
+            PixelFormat::HEVC => Codec::Hevc,
+            _ => return Err(StreamSinkError::UnsupportedCodec(fourcc)),
+        };
+
+        Ok(FragmentedMp4Writer {
+            writer,
+            codec,
+            width,
+            height,
+            vps: Vec::new(),
+            sps: Vec::new(),
+            pps: Vec::new(),
+            header_written: false,
+            first_timestamp: None,
+            pending_sample: None,
+            last_duration: DEFAULT_SAMPLE_DURATION,
+            sequence_number: 1,
+        })
+    }
+
+    /// Returns the underlying writer. Call [`StreamSink::finish`] before this to make sure the
+    /// last frame is written.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self) -> Result<(), StreamSinkError> {
+        let (entry_type, config) = match self.codec {
+            Codec::H264 => (b"avc1", avcc(&self.sps, &self.pps)?),
+            Codec::Hevc => (b"hvc1", hvcc(&self.vps, &self.sps, &self.pps)?),
+        };
+        let matrix = MATRIX
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect::<Vec<_>>();
+        let (width, height) = (self.width, self.height);
+
+        let sample_entry = mp4_box(
+            entry_type,
+            &[
+                // Reserved, then data_reference_index.
+                &[0, 0, 0, 0, 0, 0, 0, 1],
+                &[0; 16],
+                &width.to_be_bytes(),
+                &height.to_be_bytes(),
+                // 72 dpi horizontal and vertical resolutions.
+                &0x0048_0000u32.to_be_bytes(),
+                &0x0048_0000u32.to_be_bytes(),
+                &[0; 4],
+                // frame_count
+                &1u16.to_be_bytes(),
+                // compressorname
+                &[0; 32],
+                // depth, then pre_defined
+                &[0x00, 0x18, 0xff, 0xff],
+                &config,
+            ],
+        );
+        let stbl = mp4_box(
+            b"stbl",
+            &[
+                &full_box(b"stsd", 0, 0, &[&1u32.to_be_bytes(), &sample_entry]),
+                // All samples are described by the fragments.
+                &full_box(b"stts", 0, 0, &[&[0; 4]]),
+                &full_box(b"stsc", 0, 0, &[&[0; 4]]),
+                &full_box(b"stsz", 0, 0, &[&[0; 8]]),
+                &full_box(b"stco", 0, 0, &[&[0; 4]]),
+            ],
+        );
+        let minf = mp4_box(
+            b"minf",
+            &[
+                &full_box(b"vmhd", 0, 1, &[&[0; 8]]),
+                &mp4_box(
+                    b"dinf",
+                    &[&full_box(
+                        b"dref",
+                        0,
+                        0,
+                        &[&1u32.to_be_bytes(), &full_box(b"url ", 0, 1, &[])],
+                    )],
+                ),
+                &stbl,
+            ],
+        );
+        let mdia = mp4_box(
+            b"mdia",
+            &[
+                &full_box(
+                    b"mdhd",
+                    0,
+                    0,
+                    &[
+                        &[0; 8],
+                        &TIMESCALE.to_be_bytes(),
+                        &[0; 4],
+                        // "und" language.
+                        &[0x55, 0xc4, 0, 0],
+                    ],
+                ),
+                &full_box(
+                    b"hdlr",
+                    0,
+                    0,
+                    &[&[0; 4], b"vide", &[0; 12], b"VideoHandler\0"],
+                ),
+                &minf,
+            ],
+        );
+        let tkhd = full_box(
+            b"tkhd",
+            0,
+            // Track enabled and used in the presentation.
+            0x3,
+            &[
+                &[0; 8],
+                &TRACK_ID.to_be_bytes(),
+                &[0; 4 + 4 + 8 + 8],
+                &matrix,
+                &((width as u32) << 16).to_be_bytes(),
+                &((height as u32) << 16).to_be_bytes(),
+            ],
+        );
+        let mvhd = full_box(
+            b"mvhd",
+            0,
+            0,
+            &[
+                &[0; 8],
+                &TIMESCALE.to_be_bytes(),
+                &[0; 4],
+                // Normal rate and volume.
+                &[0x00, 0x01, 0x00, 0x00, 0x01, 0x00],
+                &[0; 10],
+                &matrix,
+                &[0; 24],
+                // next_track_ID
+                &(TRACK_ID + 1).to_be_bytes(),
+            ],
+        );
+        let trex = full_box(
+            b"trex",
+            0,
+            0,
+            &[&TRACK_ID.to_be_bytes(), &1u32.to_be_bytes(), &[0; 12]],
+        );
+
+        let ftyp = mp4_box(
+            b"ftyp",
+            &[b"isom", &0x200u32.to_be_bytes(), b"isom", b"iso6", b"mp41"],
+        );
+        let moov = mp4_box(
+            b"moov",
+            &[
+                &mvhd,
+                &mp4_box(b"trak", &[&tkhd, &mdia]),
+                &mp4_box(b"mvex", &[&trex]),
+            ],
+        );
+
+        self.writer.write_all(&ftyp)?;
+        self.writer.write_all(&moov)?;
+        self.header_written = true;
+
+        Ok(())
+    }
+
+    /// Write `sample` into its own fragment.
+    fn write_fragment(
+        &mut self,
+        sample: PendingSample,
+        duration: u32,
+    ) -> Result<(), StreamSinkError> {
+        let first_timestamp = *self.first_timestamp.get_or_insert(sample.timestamp);
+        let decode_time = sample.timestamp.saturating_sub(first_timestamp).as_micros() as u64;
+        let sample_flags = if sample.is_keyframe {
+            SYNC_SAMPLE_FLAGS
+        } else {
+            NON_SYNC_SAMPLE_FLAGS
+        };
+
+        let moof = |data_offset: u32| {
+            mp4_box(
+                b"moof",
+                &[
+                    &full_box(b"mfhd", 0, 0, &[&self.sequence_number.to_be_bytes()]),
+                    &mp4_box(
+                        b"traf",
+                        &[
+                            // Data offsets are relative to the moof box.
+                            &full_box(b"tfhd", 0, 0x02_0000, &[&TRACK_ID.to_be_bytes()]),
+                            &full_box(b"tfdt", 1, 0, &[&decode_time.to_be_bytes()]),
+                            // Data offset, sample duration, size and flags present.
+                            &full_box(
+                                b"trun",
+                                0,
+                                0x701,
+                                &[
+                                    &1u32.to_be_bytes(),
+                                    &data_offset.to_be_bytes(),
+                                    &duration.to_be_bytes(),
+                                    &(sample.data.len() as u32).to_be_bytes(),
+                                    &sample_flags.to_be_bytes(),
+                                ],
+                            ),
+                        ],
+                    ),
+                ],
+            )
+        };
+        // The size of the moof box does not depend on the data offset.
+        let moof = moof(moof(0).len() as u32 + 8);
+
+        self.writer.write_all(&moof)?;
+        self.writer.write_all(&mp4_box(b"mdat", &[&sample.data]))?;
+        self.sequence_number += 1;
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> StreamSink for FragmentedMp4Writer<W> {
+    fn write_frame(&mut self, data: &[u8], info: FrameInfo) -> Result<(), StreamSinkError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let nals = NalReader::new(data).ok_or(StreamSinkError::NotAnnexB)?;
+        let mut sample = Vec::with_capacity(data.len());
+        for nal in nals {
+            let payload = nal.payload();
+            let parameter_sets = match self.codec.nal_role(payload) {
+                NalRole::Vps => &mut self.vps,
+                NalRole::Sps => &mut self.sps,
+                NalRole::Pps => &mut self.pps,
+                NalRole::Dropped => continue,
+                NalRole::Sample => {
+                    push_with_length(&mut sample, payload, 4)?;
+                    continue;
+                }
+            };
+            if !self.header_written && !parameter_sets.iter().any(|p| p == payload) {
+                parameter_sets.push(payload.to_vec());
+            }
+        }
+
+        // Codec configuration only.
+        if sample.is_empty() {
+            return Ok(());
+        }
+
+        if !self.header_written {
+            self.write_header()?;
+        }
+
+        let sample = PendingSample {
+            data: sample,
+            timestamp: info.timestamp,
+            is_keyframe: info.is_keyframe,
+        };
+        if let Some(previous) = self.pending_sample.replace(sample) {
+            let duration = info
+                .timestamp
+                .saturating_sub(previous.timestamp)
+                .as_micros();
+            self.last_duration = u32::try_from(duration).unwrap_or(u32::MAX);
+            let duration = self.last_duration;
+            self.write_fragment(previous, duration)?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), StreamSinkError> {
+        if let Some(sample) = self.pending_sample.take() {
+            let duration = self.last_duration;
+            self.write_fragment(sample, duration)?;
+        }
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::format::bitreader::tests::{escape, BitWriter};
+    use std::convert::TryInto;
+
+    fn frame_info(timestamp_ms: u64, is_keyframe: bool) -> FrameInfo {
+        FrameInfo {
+            timestamp: Duration::from_millis(timestamp_ms),
+            is_keyframe,
+            is_codec_config: false,
+        }
+    }
+
+    /// Returns the type and content of the top-level boxes of `data`.
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            boxes.push(([data[4], data[5], data[6], data[7]], &data[8..size]));
+            data = &data[size..];
+        }
+
+        boxes
+    }
+
+    /// Returns the content of `data` following the first instance of `pattern`.
+    fn find<'a>(data: &'a [u8], pattern: &[u8]) -> &'a [u8] {
+        let pos = data
+            .windows(pattern.len())
+            .position(|w| w == pattern)
+            .unwrap();
+        &data[pos + pattern.len()..]
+    }
+
+    #[test]
+    fn test_h264_fragments() {
+        const SPS: [u8; 6] = [0x67, 0x42, 0xc0, 0x1e, 0xd9, 0x40];
+        const PPS: [u8; 3] = [0x68, 0xce, 0x38];
+        let mut muxer = FragmentedMp4Writer::new(Vec::new(), PixelFormat::H264, 320, 240).unwrap();
+
+        let config = [&[0, 0, 0, 1][..], &SPS, &[0, 0, 0, 1], &PPS].concat();
+        muxer
+            .write_frame(
+                &config,
+                FrameInfo {
+                    is_codec_config: true,
+                    ..frame_info(0, false)
+                },
+            )
+            .unwrap();
+        muxer
+            .write_frame(&[0, 0, 0, 1, 0x65, 0x88, 0x84], frame_info(0, true))
+            .unwrap();
+        // The access unit delimiter is removed.
+        muxer
+            .write_frame(
+                &[0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x41, 0x9a],
+                frame_info(40, false),
+            )
+            .unwrap();
+        muxer.write_frame(&[], frame_info(80, false)).unwrap();
+        muxer.finish().unwrap();
+        let file = muxer.into_inner();
+
+        let boxes = boxes(&file);
+        assert_eq!(
+            boxes.iter().map(|(t, _)| t).collect::<Vec<_>>(),
+            vec![b"ftyp", b"moov", b"moof", b"mdat", b"moof", b"mdat"]
+        );
+
+        let avcc = find(boxes[1].1, b"avcC");
+        let expected_avcc = [
+            &[1, 0x42, 0xc0, 0x1e, 0xff, 0xe1, 0, 6][..],
+            &SPS,
+            &[1, 0, 3],
+            &PPS,
+        ]
+        .concat();
+        assert_eq!(&avcc[..expected_avcc.len()], expected_avcc.as_slice());
+
+        assert_eq!(boxes[3].1, &[0, 0, 0, 3, 0x65, 0x88, 0x84]);
+        assert_eq!(boxes[5].1, &[0, 0, 0, 2, 0x41, 0x9a]);
+
+        // Decode time, then sample count, data offset, duration, size and flags.
+        let second_moof = boxes[4].1;
+        assert_eq!(&find(second_moof, b"tfdt")[4..12], &40_000u64.to_be_bytes());
+        let trun = &find(second_moof, b"trun")[4..];
+        assert_eq!(&trun[8..12], &40_000u32.to_be_bytes());
+        assert_eq!(&trun[12..16], &6u32.to_be_bytes());
+        assert_eq!(&trun[16..20], &NON_SYNC_SAMPLE_FLAGS.to_be_bytes());
+        // The data offset points to the content of the mdat box.
+        let data_offset = u32::from_be_bytes(trun[4..8].try_into().unwrap()) as usize;
+        assert_eq!(data_offset, second_moof.len() + 16);
+    }
+
+    #[test]
+    fn test_frame_before_codec_config() {
+        let mut muxer = FragmentedMp4Writer::new(Vec::new(), PixelFormat::HEVC, 320, 240).unwrap();
+        assert!(matches!(
+            muxer.write_frame(&[0, 0, 0, 1, 0x26, 0x01, 0xaf], frame_info(0, true)),
+            Err(StreamSinkError::MissingCodecConfig)
+        ));
+        assert!(matches!(
+            FragmentedMp4Writer::new(Vec::new(), PixelFormat::VP8, 320, 240),
+            Err(StreamSinkError::UnsupportedCodec(_))
+        ));
+    }
+
+    #[test]
+    fn test_hvcc() {
+        let mut sps = BitWriter::default();
+        // NAL unit header.
+        sps.write_bits(0x4201, 16);
+        // vps id, max_sub_layers_minus1 and temporal_id_nesting_flag.
+        sps.write_bits(0x01, 8);
+        // Main profile, compatible with Main and Main10, level 3.1.
+        sps.write_bits(0x01, 8);
+        sps.write_bits(0x6000_0000, 32);
+        sps.write_bits(0x9000_0000_0000, 48);
+        sps.write_bits(93, 8);
+        // sps id, 4:2:0 chroma, 320x240 pixels, no conformance window, 10 bits.
+        sps.write_ue(0);
+        sps.write_ue(1);
+        sps.write_ue(320);
+        sps.write_ue(240);
+        sps.write_bits(0, 1);
+        sps.write_ue(2);
+        sps.write_ue(2);
+        let sps = escape(sps.into_rbsp());
+        // The constraint flags are zeros and require emulation prevention.
+        assert_ne!(sps.len(), 20);
+
+        let vps = vec![0x40, 0x01, 0x0c];
+        let pps = vec![0x44, 0x01, 0xc1];
+        let hvcc = hvcc(&[vps], &[sps], &[pps]).unwrap();
+
+        assert_eq!(&hvcc[4..8], b"hvcC");
+        let record = &hvcc[8..];
+        assert_eq!(
+            &record[..13],
+            &[1, 0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 93]
+        );
+        assert_eq!(
+            &record[13..23],
+            &[0xf0, 0, 0xfc, 0xfd, 0xfa, 0xfa, 0, 0, 0x0f, 3]
+        );
+        // VPS array.
+        assert_eq!(&record[23..30], &[0xa0, 0, 1, 0, 3, 0x40, 0x01]);
+    }
+}
@@ -0,0 +1,501 @@
+//! Typed 2D views over the planes of a mapped image.
+//!
+//! A mapped buffer only provides raw bytes. The `ImageView` and `ImageViewMut` types use a
+//! `Format` and the layout information from the `pixel_format` module to split these bytes into
+//! one view per component plane (e.g. the Y and interleaved CbCr planes of NV12, or the three
+//! planes of YUV420M), restricted to the visible part of the image.
+//!
+//! # Examples
+//!
+//! ```
+//! # use v4l2r::{Format, PixelFormat, PlaneLayout, Rect};
+//! # use v4l2r::image::ImageView;
+//! let format = Format {
+//!     width: 4,
+//!     height: 4,
+//!     pixelformat: PixelFormat::NV12,
+//!     plane_fmt: vec![PlaneLayout {
+//!         bytesperline: 4,
+//!         sizeimage: 24,
+//!     }],
+//!     ..Default::default()
+//! };
+//! let planes = [(0..24).collect::<Vec<u8>>()];
+//!
+//! let image = ImageView::new(&format, Some(&Rect::new(0, 0, 2, 2)), &planes).unwrap();
+//! let (y, uv) = (&image.planes()[0], &image.planes()[1]);
+//! assert_eq!(y.rows().collect::<Vec<_>>(), [&[0u8, 1][..], &[4, 5][..]]);
+//! assert_eq!(uv.rows().collect::<Vec<_>>(), [&[16u8, 17][..]]);
+//! ```
+pub mod y4m;
+pub mod yuv;
+
+use thiserror::Error;
+
+use crate::pixel_format::PixelFormatInfo;
+use crate::{Format, PixelFormat, Rect};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ImageViewError {
+    #[error("pixel format {0} is not supported")]
+    UnsupportedFormat(PixelFormat),
+    #[error("invalid number of planes: got {0}, expected {1}")]
+    NumPlanesMismatch(usize, usize),
+    #[error("memory plane {0} is too small for the format")]
+    PlaneTooSmall(usize),
+    #[error("visible rectangle does not fit within the image")]
+    InvalidVisibleRect,
+}
+
+/// Position and size of a component plane within the memory planes of an image.
+struct PlaneGeometry {
+    /// Index of the memory plane containing this component plane.
+    mem_plane: usize,
+    /// Offset of the first visible sample in the memory plane.
+    offset: usize,
+    /// Number of bytes between the start of two consecutive lines.
+    stride: usize,
+    /// Number of visible samples per line.
+    width: usize,
+    /// Number of visible lines.
+    height: usize,
+    /// Number of bytes per sample.
+    bpp: usize,
+}
+
+impl PlaneGeometry {
+    /// Number of bytes spanned by the visible part of the plane.
+    fn len(&self) -> usize {
+        match self.height {
+            0 => 0,
+            height => (height - 1) * self.stride + self.width * self.bpp,
+        }
+    }
+}
+
+/// Compute the geometry of each component plane of `format`, restricted to `visible_rect`, for
+/// memory planes of sizes `mem_plane_sizes`.
+fn plane_geometries(
+    format: &Format,
+    visible_rect: Option<&Rect>,
+    mem_plane_sizes: &[usize],
+) -> Result<Vec<PlaneGeometry>, ImageViewError> {
+    let info: &PixelFormatInfo = format
+        .pixelformat
+        .info()
+        .filter(|info| !info.is_compressed() && info.block.iter().all(|b| *b == (1, 1)))
+        .ok_or(ImageViewError::UnsupportedFormat(format.pixelformat))?;
+
+    if format.plane_fmt.len() < info.mem_planes {
+        return Err(ImageViewError::NumPlanesMismatch(
+            format.plane_fmt.len(),
+            info.mem_planes,
+        ));
+    }
+    if mem_plane_sizes.len() != info.mem_planes {
+        return Err(ImageViewError::NumPlanesMismatch(
+            mem_plane_sizes.len(),
+            info.mem_planes,
+        ));
+    }
+
+    let (width, height) = (format.width as usize, format.height as usize);
+    let (left, top, visible_width, visible_height) = match visible_rect {
+        None => (0, 0, width, height),
+        Some(rect) => {
+            if rect.left < 0 || rect.top < 0 {
+                return Err(ImageViewError::InvalidVisibleRect);
+            }
+            (
+                rect.left as usize,
+                rect.top as usize,
+                rect.width as usize,
+                rect.height as usize,
+            )
+        }
+    };
+    if left + visible_width > width || top + visible_height > height {
+        return Err(ImageViewError::InvalidVisibleRect);
+    }
+
+    // Offset of the next component plane within its memory plane, for contiguous formats.
+    let mut contiguous_offset = 0;
+
+    (0..info.comp_planes)
+        .map(|plane| {
+            let (mem_plane, stride, plane_offset) = if info.mem_planes == 1 {
+                let stride =
+                    info.plane_bytesperline(plane, format.plane_fmt[0].bytesperline as usize);
+                let plane_offset = contiguous_offset;
+                contiguous_offset += stride * info.plane_height(plane, height);
+                (0, stride, plane_offset)
+            } else {
+                (plane, format.plane_fmt[plane].bytesperline as usize, 0)
+            };
+
+            let (hdiv, vdiv) = info.subsampling(plane);
+            let (x, y) = (left / hdiv, top / vdiv);
+            let geometry = PlaneGeometry {
+                mem_plane,
+                offset: plane_offset + y * stride + x * info.bpp[plane],
+                stride,
+                width: (left + visible_width).div_ceil(hdiv) - x,
+                height: (top + visible_height).div_ceil(vdiv) - y,
+                bpp: info.bpp[plane],
+            };
+
+            if geometry.width * geometry.bpp > stride
+                || geometry.offset + geometry.len() > mem_plane_sizes[mem_plane]
+            {
+                return Err(ImageViewError::PlaneTooSmall(mem_plane));
+            }
+
+            Ok(geometry)
+        })
+        .collect()
+}
+
+/// Read-only 2D view of one component plane of an image.
+pub struct PlaneView<'a> {
+    data: &'a [u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    bpp: usize,
+}
+
+impl<'a> PlaneView<'a> {
+    /// Number of visible samples per line.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Number of visible lines.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Number of bytes between the start of two consecutive lines.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Number of bytes per sample.
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bpp
+    }
+
+    /// Returns the visible part of line `y`, or `None` if `y` is out of bounds.
+    pub fn row(&self, y: usize) -> Option<&'a [u8]> {
+        if y >= self.height {
+            return None;
+        }
+
+        let start = y * self.stride;
+        Some(&self.data[start..start + self.width * self.bpp])
+    }
+
+    /// Iterate over the visible part of each line of the plane.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.height).filter_map(move |y| self.row(y))
+    }
+}
+
+/// Writable 2D view of one component plane of an image.
+pub struct PlaneViewMut<'a> {
+    data: &'a mut [u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    bpp: usize,
+}
+
+impl<'a> PlaneViewMut<'a> {
+    /// Number of visible samples per line.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Number of visible lines.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Number of bytes between the start of two consecutive lines.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Number of bytes per sample.
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bpp
+    }
+
+    /// Returns the visible part of line `y`, or `None` if `y` is out of bounds.
+    pub fn row(&self, y: usize) -> Option<&[u8]> {
+        if y >= self.height {
+            return None;
+        }
+
+        let start = y * self.stride;
+        Some(&self.data[start..start + self.width * self.bpp])
+    }
+
+    /// Returns the visible part of line `y` for writing, or `None` if `y` is out of bounds.
+    pub fn row_mut(&mut self, y: usize) -> Option<&mut [u8]> {
+        if y >= self.height {
+            return None;
+        }
+
+        let start = y * self.stride;
+        Some(&mut self.data[start..start + self.width * self.bpp])
+    }
+
+    /// Iterate over the visible part of each line of the plane for writing.
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        let row_len = self.width * self.bpp;
+
+        self.data
+            .chunks_mut(self.stride)
+            .take(self.height)
+            .map(move |line| &mut line[..row_len])
+    }
+
+    /// Returns a read-only view of this plane.
+    pub fn as_view(&self) -> PlaneView<'_> {
+        PlaneView {
+            data: self.data,
+            stride: self.stride,
+            width: self.width,
+            height: self.height,
+            bpp: self.bpp,
+        }
+    }
+}
+
+/// Read-only view of all the component planes of an image.
+pub struct ImageView<'a> {
+    pixelformat: PixelFormat,
+    planes: Vec<PlaneView<'a>>,
+}
+
+impl<'a> ImageView<'a> {
+    /// Create a view of the image stored in `mem_planes` using `format`. `mem_planes` must
+    /// contain one entry per memory plane of the format, e.g. one for NV12 and two for NV12M.
+    ///
+    /// If `visible_rect` is set, the views only cover that part of the image. It is typically
+    /// obtained using `get_selection` on the queue.
+    pub fn new<P: AsRef<[u8]>>(
+        format: &Format,
+        visible_rect: Option<&Rect>,
+        mem_planes: &'a [P],
+    ) -> Result<Self, ImageViewError> {
+        let sizes = mem_planes
+            .iter()
+            .map(|p| p.as_ref().len())
+            .collect::<Vec<_>>();
+
+        let planes = plane_geometries(format, visible_rect, &sizes)?
+            .into_iter()
+            .map(|g| PlaneView {
+                data: &mem_planes[g.mem_plane].as_ref()[g.offset..g.offset + g.len()],
+                stride: g.stride,
+                width: g.width,
+                height: g.height,
+                bpp: g.bpp,
+            })
+            .collect();
+
+        Ok(ImageView {
+            pixelformat: format.pixelformat,
+            planes,
+        })
+    }
+
+    pub fn pixelformat(&self) -> PixelFormat {
+        self.pixelformat
+    }
+
+    /// Returns the views of the component planes, in the order they are stored in memory.
+    pub fn planes(&self) -> &[PlaneView<'a>] {
+        &self.planes
+    }
+}
+
+/// Writable view of all the component planes of an image.
+pub struct ImageViewMut<'a> {
+    pixelformat: PixelFormat,
+    planes: Vec<PlaneViewMut<'a>>,
+}
+
+impl<'a> ImageViewMut<'a> {
+    /// Create a writable view of the image stored in `mem_planes` using `format`. See
+    /// `ImageView::new` for the meaning of the parameters.
+    pub fn new<P: AsMut<[u8]>>(
+        format: &Format,
+        visible_rect: Option<&Rect>,
+        mem_planes: &'a mut [P],
+    ) -> Result<Self, ImageViewError> {
+        let mut mem_planes = mem_planes
+            .iter_mut()
+            .map(|p| p.as_mut())
+            .collect::<Vec<&'a mut [u8]>>();
+        let sizes = mem_planes.iter().map(|p| p.len()).collect::<Vec<_>>();
+        let geometries = plane_geometries(format, visible_rect, &sizes)?;
+
+        // Component planes sharing a memory plane are sorted by increasing offset and do not
+        // overlap, so we can split them off one after the other.
+        let mut consumed = vec![0; mem_planes.len()];
+        let planes = geometries
+            .into_iter()
+            .map(|g| {
+                let mem_plane = std::mem::take(&mut mem_planes[g.mem_plane]);
+                let (_, rest) = mem_plane.split_at_mut(g.offset - consumed[g.mem_plane]);
+                let (data, rest) = rest.split_at_mut(g.len());
+                mem_planes[g.mem_plane] = rest;
+                consumed[g.mem_plane] = g.offset + g.len();
+
+                PlaneViewMut {
+                    data,
+                    stride: g.stride,
+                    width: g.width,
+                    height: g.height,
+                    bpp: g.bpp,
+                }
+            })
+            .collect();
+
+        Ok(ImageViewMut {
+            pixelformat: format.pixelformat,
+            planes,
+        })
+    }
+
+    pub fn pixelformat(&self) -> PixelFormat {
+        self.pixelformat
+    }
+
+    /// Returns the views of the component planes, in the order they are stored in memory.
+    pub fn planes(&self) -> &[PlaneViewMut<'a>] {
+        &self.planes
+    }
+
+    /// Returns the writable views of the component planes, in the order they are stored in
+    /// memory.
+    pub fn planes_mut(&mut self) -> &mut [PlaneViewMut<'a>] {
+        &mut self.planes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PlaneLayout;
+
+    fn format(pixelformat: PixelFormat, width: u32, height: u32, align: u32) -> Format {
+        Format {
+            width,
+            height,
+            pixelformat,
+            plane_fmt: pixelformat
+                .info()
+                .unwrap()
+                .plane_layouts(width, height, align)
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_contiguous_view() {
+        let format = format(PixelFormat::YUV420, 4, 4, 8);
+        assert_eq!(
+            format.plane_fmt,
+            [PlaneLayout {
+                bytesperline: 8,
+                sizeimage: 48
+            }]
+        );
+        let planes = [(0..48).collect::<Vec<u8>>()];
+
+        let image = ImageView::new(&format, None, &planes).unwrap();
+        let planes = image.planes();
+        assert_eq!(planes.len(), 3);
+        assert_eq!((planes[0].width(), planes[0].height()), (4, 4));
+        assert_eq!(planes[0].row(3), Some(&[24u8, 25, 26, 27][..]));
+        assert_eq!(planes[0].row(4), None);
+        assert_eq!(planes[1].stride(), 4);
+        assert_eq!(
+            planes[1].rows().collect::<Vec<_>>(),
+            [&[32u8, 33][..], &[36, 37][..]]
+        );
+        assert_eq!(
+            planes[2].rows().collect::<Vec<_>>(),
+            [&[40u8, 41][..], &[44, 45][..]]
+        );
+    }
+
+    #[test]
+    fn test_visible_rect() {
+        let format = format(PixelFormat::NV12, 4, 4, 1);
+        let planes = [(0..24).collect::<Vec<u8>>()];
+
+        let image = ImageView::new(&format, Some(&Rect::new(1, 1, 3, 3)), &planes).unwrap();
+        let (y, uv) = (&image.planes()[0], &image.planes()[1]);
+        assert_eq!(
+            y.rows().collect::<Vec<_>>().concat(),
+            [5, 6, 7, 9, 10, 11, 13, 14, 15]
+        );
+        assert_eq!((uv.width(), uv.height()), (2, 2));
+        assert_eq!(
+            uv.rows().collect::<Vec<_>>().concat(),
+            [16, 17, 18, 19, 20, 21, 22, 23]
+        );
+
+        assert_eq!(
+            ImageView::new(&format, Some(&Rect::new(2, 0, 3, 4)), &planes).err(),
+            Some(ImageViewError::InvalidVisibleRect)
+        );
+    }
+
+    #[test]
+    fn test_multiplanar_view_mut() {
+        let format = format(PixelFormat::YUV420M, 4, 2, 1);
+        let mut planes = vec![vec![0u8; 8], vec![0u8; 2], vec![0u8; 2]];
+
+        let mut image = ImageViewMut::new(&format, None, &mut planes).unwrap();
+        for (i, plane) in image.planes_mut().iter_mut().enumerate() {
+            plane.rows_mut().for_each(|row| row.fill(i as u8 + 1));
+        }
+        assert_eq!(planes, [vec![1u8; 8], vec![2u8; 2], vec![3u8; 2]]);
+
+        let mut too_few = vec![vec![0u8; 8], vec![0u8; 2]];
+        assert_eq!(
+            ImageViewMut::new(&format, None, &mut too_few).err(),
+            Some(ImageViewError::NumPlanesMismatch(2, 3))
+        );
+
+        let mut too_small = vec![vec![0u8; 7], vec![0u8; 2], vec![0u8; 2]];
+        assert_eq!(
+            ImageViewMut::new(&format, None, &mut too_small).err(),
+            Some(ImageViewError::PlaneTooSmall(0))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_formats() {
+        let mut format = format(PixelFormat::NV12, 4, 4, 1);
+        let planes = [vec![0u8; 24]];
+
+        format.pixelformat = PixelFormat::H264;
+        assert!(matches!(
+            ImageView::new(&format, None, &planes),
+            Err(ImageViewError::UnsupportedFormat(_))
+        ));
+        format.pixelformat = PixelFormat::NV12_4L4;
+        assert!(matches!(
+            ImageView::new(&format, None, &planes),
+            Err(ImageViewError::UnsupportedFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,525 @@
+//! Reader and writer for YUV4MPEG2 (Y4M) files.
+//!
+//! Y4M files start with a text header giving the size, frame rate and chroma format of the
+//! video, followed by frames made of a `FRAME` line and the frame data in the raw planar layout
+//! described in the [`yuv`](super::yuv) module.
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use super::yuv::{read_frame_data, ChromaFormat, YuvError, YuvLayout};
+use super::{ImageView, ImageViewMut};
+use crate::{Colorimetry, Format, PixelFormat, Quantization};
+
+const FILE_MAGIC: &[u8] = b"YUV4MPEG2";
+const FRAME_MAGIC: &[u8] = b"FRAME";
+/// Maximum length of the file and frame header lines we accept.
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum Y4mError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a Y4M file")]
+    NotY4m,
+    #[error("header line is truncated or too long")]
+    InvalidLine,
+    #[error("invalid header parameter {0:?}")]
+    InvalidParameter(String),
+    #[error("frame size is missing from the header")]
+    MissingSize,
+    #[error("colorspace {0:?} is not supported")]
+    UnsupportedColorspace(String),
+    #[error("pixel format {0} cannot be stored in a Y4M file")]
+    UnsupportedFormat(PixelFormat),
+    #[error("invalid frame header")]
+    InvalidFrameHeader,
+    #[error("frame error: {0}")]
+    Yuv(#[from] YuvError),
+}
+
+/// Chroma format and chroma sample location, as given by the `C` parameter of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Y4mColorspace {
+    /// 4:2:0 with chroma samples centered between luma samples. This is the default.
+    C420Jpeg,
+    /// 4:2:0 with chroma samples co-sited with the top-left luma sample.
+    C420,
+    /// 4:2:0 with chroma samples horizontally co-sited and vertically centered.
+    C420Mpeg2,
+    /// 4:2:0 with PAL-DV chroma sample location.
+    C420Paldv,
+    C422,
+    C444,
+    Mono,
+}
+
+impl Y4mColorspace {
+    fn parse(tag: &str) -> Result<Self, Y4mError> {
+        Ok(match tag {
+            "420jpeg" => Y4mColorspace::C420Jpeg,
+            "420" => Y4mColorspace::C420,
+            "420mpeg2" => Y4mColorspace::C420Mpeg2,
+            "420paldv" => Y4mColorspace::C420Paldv,
+            "422" => Y4mColorspace::C422,
+            "444" => Y4mColorspace::C444,
+            "mono" => Y4mColorspace::Mono,
+            _ => return Err(Y4mError::UnsupportedColorspace(tag.to_string())),
+        })
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            Y4mColorspace::C420Jpeg => "420jpeg",
+            Y4mColorspace::C420 => "420",
+            Y4mColorspace::C420Mpeg2 => "420mpeg2",
+            Y4mColorspace::C420Paldv => "420paldv",
+            Y4mColorspace::C422 => "422",
+            Y4mColorspace::C444 => "444",
+            Y4mColorspace::Mono => "mono",
+        }
+    }
+
+    pub fn chroma_format(&self) -> ChromaFormat {
+        match self {
+            Y4mColorspace::C420Jpeg
+            | Y4mColorspace::C420
+            | Y4mColorspace::C420Mpeg2
+            | Y4mColorspace::C420Paldv => ChromaFormat::Yuv420,
+            Y4mColorspace::C422 => ChromaFormat::Yuv422,
+            Y4mColorspace::C444 => ChromaFormat::Yuv444,
+            Y4mColorspace::Mono => ChromaFormat::Mono,
+        }
+    }
+
+    /// Returns the colorspace to use for frames of chroma format `chroma`.
+    pub fn from_chroma_format(chroma: ChromaFormat) -> Self {
+        match chroma {
+            ChromaFormat::Mono => Y4mColorspace::Mono,
+            ChromaFormat::Yuv420 => Y4mColorspace::C420Jpeg,
+            ChromaFormat::Yuv422 => Y4mColorspace::C422,
+            ChromaFormat::Yuv444 => Y4mColorspace::C444,
+        }
+    }
+}
+
+/// Field order of the frames, as given by the `I` parameter of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Y4mInterlacing {
+    Progressive,
+    TopFieldFirst,
+    BottomFieldFirst,
+    Mixed,
+}
+
+impl Y4mInterlacing {
+    fn tag(&self) -> char {
+        match self {
+            Y4mInterlacing::Progressive => 'p',
+            Y4mInterlacing::TopFieldFirst => 't',
+            Y4mInterlacing::BottomFieldFirst => 'b',
+            Y4mInterlacing::Mixed => 'm',
+        }
+    }
+}
+
+/// Header of a Y4M file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Y4mHeader {
+    pub width: u32,
+    pub height: u32,
+    /// Frame rate, as a numerator and denominator.
+    pub frame_rate: (u32, u32),
+    /// Pixel aspect ratio, as a numerator and denominator. `(0, 0)` if unknown.
+    pub pixel_aspect: (u32, u32),
+    pub interlacing: Y4mInterlacing,
+    pub colorspace: Y4mColorspace,
+    /// Range of the samples, as given by the `XCOLORRANGE` extension.
+    pub color_range: Quantization,
+}
+
+fn parse_ratio(value: &str) -> Option<(u32, u32)> {
+    let (num, den) = value.split_once(':')?;
+
+    Some((num.parse().ok()?, den.parse().ok()?))
+}
+
+impl Y4mHeader {
+    /// Returns the header to use to store frames of `format`, provided it is a supported 8-bit
+    /// YUV format.
+    pub fn from_format(format: &Format, frame_rate: (u32, u32)) -> Result<Self, Y4mError> {
+        let chroma = ChromaFormat::from_pixel_format(format.pixelformat)
+            .ok_or(Y4mError::UnsupportedFormat(format.pixelformat))?;
+
+        Ok(Y4mHeader {
+            width: format.width,
+            height: format.height,
+            frame_rate,
+            pixel_aspect: (1, 1),
+            interlacing: Y4mInterlacing::Progressive,
+            colorspace: Y4mColorspace::from_chroma_format(chroma),
+            color_range: format
+                .colorimetry
+                .map(|colorimetry| colorimetry.quantization)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Parse the parameters following the file magic.
+    fn parse(params: &str) -> Result<Self, Y4mError> {
+        let mut width = None;
+        let mut height = None;
+        let mut header = Y4mHeader {
+            width: 0,
+            height: 0,
+            frame_rate: (25, 1),
+            pixel_aspect: (0, 0),
+            interlacing: Y4mInterlacing::Progressive,
+            colorspace: Y4mColorspace::C420Jpeg,
+            color_range: Quantization::Default,
+        };
+
+        for param in params.split(' ').filter(|p| !p.is_empty()) {
+            let invalid = || Y4mError::InvalidParameter(param.to_string());
+            // Tags are single ASCII characters, so the value must start at the second byte.
+            let value = param.get(1..).ok_or_else(invalid)?;
+            match param.as_bytes()[0] {
+                b'W' => width = Some(value.parse::<u32>().map_err(|_| invalid())?),
+                b'H' => height = Some(value.parse::<u32>().map_err(|_| invalid())?),
+                b'F' => header.frame_rate = parse_ratio(value).ok_or_else(invalid)?,
+                b'A' => header.pixel_aspect = parse_ratio(value).ok_or_else(invalid)?,
+                b'I' => {
+                    header.interlacing = match value {
+                        "p" | "?" => Y4mInterlacing::Progressive,
+                        "t" => Y4mInterlacing::TopFieldFirst,
+                        "b" => Y4mInterlacing::BottomFieldFirst,
+                        "m" => Y4mInterlacing::Mixed,
+                        _ => return Err(invalid()),
+                    }
+                }
+                b'C' => header.colorspace = Y4mColorspace::parse(value)?,
+                b'X' => match value {
+                    "COLORRANGE=FULL" => header.color_range = Quantization::FullRange,
+                    "COLORRANGE=LIMITED" => header.color_range = Quantization::LimRange,
+                    // Other extensions are not relevant to us.
+                    _ => (),
+                },
+                _ => return Err(invalid()),
+            }
+        }
+
+        match (width, height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => {
+                header.width = width;
+                header.height = height;
+                // Reject frames too large to be buffered.
+                header.layout().frame_size()?;
+                Ok(header)
+            }
+            _ => Err(Y4mError::MissingSize),
+        }
+    }
+
+    /// Layout of the frames of the file.
+    pub fn layout(&self) -> YuvLayout {
+        YuvLayout::new(
+            self.colorspace.chroma_format(),
+            self.width as usize,
+            self.height as usize,
+        )
+    }
+
+    /// Returns a `Format` with tightly packed planes able to hold the frames of the file.
+    pub fn format(&self) -> Result<Format, Y4mError> {
+        let colorimetry = match self.color_range {
+            Quantization::Default => None,
+            quantization => Some(Colorimetry {
+                quantization,
+                ..Default::default()
+            }),
+        };
+
+        Ok(Format {
+            colorimetry,
+            ..self.layout().format()?
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:{} I{} A{}:{} C{}",
+            self.width,
+            self.height,
+            self.frame_rate.0,
+            self.frame_rate.1,
+            self.interlacing.tag(),
+            self.pixel_aspect.0,
+            self.pixel_aspect.1,
+            self.colorspace.tag()
+        )?;
+        match self.color_range {
+            Quantization::FullRange => write!(writer, " XCOLORRANGE=FULL")?,
+            Quantization::LimRange => write!(writer, " XCOLORRANGE=LIMITED")?,
+            Quantization::Default => (),
+        }
+
+        writeln!(writer)
+    }
+}
+
+/// Read a header line from `reader`, without its terminating newline. Returns `None` if the
+/// end of the stream has been reached before anything could be read.
+fn read_line<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Y4mError> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => return Err(Y4mError::InvalidLine),
+            Ok(_) if byte[0] == b'\n' => return Ok(Some(line)),
+            Ok(_) if line.len() == MAX_LINE_LENGTH => return Err(Y4mError::InvalidLine),
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Reads the frames of a Y4M file.
+///
+/// Header lines are read one byte at a time, so `reader` should be buffered.
+pub struct Y4mReader<R: Read> {
+    reader: R,
+    header: Y4mHeader,
+    layout: YuvLayout,
+    frame: Vec<u8>,
+}
+
+impl<R: Read> Y4mReader<R> {
+    /// Create a new reader, parsing the file header from `reader`.
+    pub fn new(mut reader: R) -> Result<Self, Y4mError> {
+        let line = match read_line(&mut reader) {
+            Ok(Some(line)) if line.starts_with(FILE_MAGIC) => line,
+            Ok(_) | Err(Y4mError::InvalidLine) => return Err(Y4mError::NotY4m),
+            Err(e) => return Err(e),
+        };
+        let params = std::str::from_utf8(&line[FILE_MAGIC.len()..])
+            .map_err(|_| Y4mError::InvalidParameter(String::from_utf8_lossy(&line).into()))?;
+        if !params.is_empty() && !params.starts_with(' ') {
+            return Err(Y4mError::NotY4m);
+        }
+
+        let header = Y4mHeader::parse(params)?;
+        let layout = header.layout();
+
+        Ok(Y4mReader {
+            reader,
+            header,
+            layout,
+            frame: vec![0u8; layout.frame_size()?],
+        })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    /// Read the data of the next frame, in the raw planar layout given by
+    /// [`Y4mHeader::layout`], or return `None` if the end of the file has been reached.
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, Y4mError> {
+        let line = match read_line(&mut self.reader)? {
+            None => return Ok(None),
+            Some(line) => line,
+        };
+        // Frame parameters may follow the magic, but none of them affects the frame data.
+        if !line.starts_with(FRAME_MAGIC)
+            || !matches!(line.get(FRAME_MAGIC.len()), None | Some(b' '))
+        {
+            return Err(Y4mError::InvalidFrameHeader);
+        }
+
+        if !read_frame_data(&mut self.reader, &mut self.frame)? {
+            return Err(YuvError::TruncatedFrame.into());
+        }
+
+        Ok(Some(&self.frame))
+    }
+
+    /// Read the next frame into `image`, which can use any pixel format with the same chroma
+    /// subsampling as the file. Returns `false` if the end of the file has been reached.
+    pub fn read_frame_into(&mut self, image: &mut ImageViewMut) -> Result<bool, Y4mError> {
+        let layout = self.layout;
+
+        match self.next_frame()? {
+            None => Ok(false),
+            Some(frame) => {
+                layout.copy_to_image(frame, image)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Writes frames into a Y4M file.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    header: Y4mHeader,
+    layout: YuvLayout,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Create a new writer, and write the file header described by `header` to `writer`.
+    pub fn new(mut writer: W, header: Y4mHeader) -> Result<Self, Y4mError> {
+        let layout = header.layout();
+        let frame = vec![0u8; layout.frame_size()?];
+        header.write_to(&mut writer)?;
+
+        Ok(Y4mWriter {
+            writer,
+            header,
+            layout,
+            frame,
+        })
+    }
+
+    pub fn header(&self) -> &Y4mHeader {
+        &self.header
+    }
+
+    /// Write `image` as the next frame of the file. `image` can use any pixel format with the
+    /// same chroma subsampling as the file.
+    pub fn write_frame(&mut self, image: &ImageView) -> Result<(), Y4mError> {
+        self.layout.copy_from_image(image, &mut self.frame)?;
+        self.writer.write_all(FRAME_MAGIC)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.write_all(&self.frame)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Y4mError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_header() {
+        let file = b"YUV4MPEG2 W4 H2 F30000:1001 Ip A1:1 C422 XYSCSS=422 XCOLORRANGE=FULL\n";
+        let reader = Y4mReader::new(Cursor::new(&file[..])).unwrap();
+        let header = reader.header();
+        assert_eq!(
+            *header,
+            Y4mHeader {
+                width: 4,
+                height: 2,
+                frame_rate: (30000, 1001),
+                pixel_aspect: (1, 1),
+                interlacing: Y4mInterlacing::Progressive,
+                colorspace: Y4mColorspace::C422,
+                color_range: Quantization::FullRange,
+            }
+        );
+        let format = header.format().unwrap();
+        assert_eq!(format.pixelformat, PixelFormat::YUV422P);
+        assert_eq!(format.plane_fmt[0].sizeimage, 16);
+        assert_eq!(
+            format.colorimetry.map(|c| c.quantization),
+            Some(Quantization::FullRange)
+        );
+
+        assert!(matches!(
+            Y4mReader::new(Cursor::new(&b"YUV4MPEG2 W4 H2 C420p10\n"[..])),
+            Err(Y4mError::UnsupportedColorspace(c)) if c == "420p10"
+        ));
+        assert!(matches!(
+            Y4mReader::new(Cursor::new(&b"YUV4MPEG2 W4 F25:1\n"[..])),
+            Err(Y4mError::MissingSize)
+        ));
+        assert!(matches!(
+            Y4mReader::new(Cursor::new(&b"RIFF"[..])),
+            Err(Y4mError::NotY4m)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        assert!(matches!(
+            Y4mReader::new(Cursor::new("YUV4MPEG2 W4 H2 \u{e9}t\n".as_bytes())),
+            Err(Y4mError::InvalidParameter(p)) if p == "\u{e9}t"
+        ));
+        assert!(matches!(
+            Y4mReader::new(Cursor::new(&b"YUV4MPEG2 W4294967295 H4294967295\n"[..])),
+            Err(Y4mError::Yuv(YuvError::InvalidSize(..)))
+        ));
+
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::PermissionDenied.into())
+            }
+        }
+        assert!(matches!(
+            Y4mReader::new(FailingReader),
+            Err(Y4mError::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied
+        ));
+    }
+
+    #[test]
+    fn test_y4m_round_trip() {
+        let format = Format {
+            width: 4,
+            height: 2,
+            pixelformat: PixelFormat::NV12,
+            plane_fmt: vec![crate::PlaneLayout {
+                bytesperline: 8,
+                sizeimage: 24,
+            }],
+            ..Default::default()
+        };
+        let header = Y4mHeader::from_format(&format, (30, 1)).unwrap();
+        assert_eq!(header.colorspace, Y4mColorspace::C420Jpeg);
+        let planes = [(0..24).collect::<Vec<u8>>()];
+        let image = ImageView::new(&format, None, &planes).unwrap();
+
+        let mut writer = Y4mWriter::new(Vec::new(), header).unwrap();
+        writer.write_frame(&image).unwrap();
+        writer.write_frame(&image).unwrap();
+        let file = writer.into_inner();
+        let expected_frame = [0, 1, 2, 3, 8, 9, 10, 11, 16, 18, 17, 19];
+        assert!(file.starts_with(b"YUV4MPEG2 W4 H2 F30:1 Ip A1:1 C420jpeg\nFRAME\n"));
+        assert!(file.ends_with(&expected_frame));
+
+        let mut reader = Y4mReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(*reader.header(), header);
+        let mut planes = [vec![0u8; 24]];
+        for _ in 0..2 {
+            let mut image = ImageViewMut::new(&format, None, &mut planes).unwrap();
+            assert!(reader.read_frame_into(&mut image).unwrap());
+        }
+        assert!(reader.next_frame().unwrap().is_none());
+        assert_eq!(
+            planes[0],
+            [0, 1, 2, 3, 0, 0, 0, 0, 8, 9, 10, 11, 0, 0, 0, 0, 16, 17, 18, 19, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,614 @@
+//! Reader and writer for raw planar YUV files.
+//!
+//! Raw YUV files store frames back to back, each frame being made of its tightly packed Y plane
+//! followed by its Cb and Cr planes, if any. This is the layout used by the `yuv420p`, `yuv422p`,
+//! `yuv444p` and `gray` formats of most tools, and by the frames of Y4M files.
+//!
+//! Frames are copied from and to `ImageView`s, so they can be exchanged with mapped buffers of
+//! any 8-bit planar or semi-planar YUV format with matching chroma subsampling, whatever their
+//! number of memory planes and strides.
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+
+use thiserror::Error;
+
+use super::{ImageView, ImageViewMut, PlaneView, PlaneViewMut};
+use crate::pixel_format::PixelEncoding;
+use crate::{Format, PixelFormat};
+
+/// Value of the chroma samples of a colorless image.
+const NEUTRAL_CHROMA: u8 = 128;
+/// Maximum width of the frames we accept, which bounds the size of the frame buffers.
+pub const MAX_WIDTH: usize = 8192;
+/// Maximum height of the frames we accept, which bounds the size of the frame buffers.
+pub const MAX_HEIGHT: usize = 8192;
+
+#[derive(Debug, Error)]
+pub enum YuvError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("pixel format {0} is not supported")]
+    UnsupportedFormat(PixelFormat),
+    #[error("chroma subsampling of the image does not match the one of the frame")]
+    ChromaMismatch,
+    #[error("image size does not match the one of the frame")]
+    SizeMismatch,
+    #[error("frame buffer is too small")]
+    FrameTooSmall,
+    #[error("frame is truncated")]
+    TruncatedFrame,
+    #[error("invalid frame size {0}x{1}")]
+    InvalidSize(usize, usize),
+}
+
+/// Chroma subsampling of a planar YUV frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaFormat {
+    /// Luma only.
+    Mono,
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl ChromaFormat {
+    /// Returns the horizontal and vertical subsampling factors of the chroma planes, or `None`
+    /// if there are no chroma planes.
+    pub fn subsampling(&self) -> Option<(usize, usize)> {
+        match self {
+            ChromaFormat::Mono => None,
+            ChromaFormat::Yuv420 => Some((2, 2)),
+            ChromaFormat::Yuv422 => Some((2, 1)),
+            ChromaFormat::Yuv444 => Some((1, 1)),
+        }
+    }
+
+    /// Returns the V4L2 pixel format storing frames of this chroma format with the same plane
+    /// order as raw YUV files, preferring single memory plane formats.
+    pub fn pixel_format(&self) -> PixelFormat {
+        match self {
+            ChromaFormat::Mono => PixelFormat::GREY,
+            ChromaFormat::Yuv420 => PixelFormat::YUV420,
+            ChromaFormat::Yuv422 => PixelFormat::YUV422P,
+            ChromaFormat::Yuv444 => PixelFormat::YUV444M,
+        }
+    }
+
+    /// Returns the chroma format of `pixelformat`, if it is a supported 8-bit planar or
+    /// semi-planar YUV format.
+    pub fn from_pixel_format(pixelformat: PixelFormat) -> Option<Self> {
+        PlaneArrangement::of(pixelformat).map(|arrangement| arrangement.chroma)
+    }
+}
+
+/// How the components of a YUV pixel format are distributed among its planes.
+struct PlaneArrangement {
+    chroma: ChromaFormat,
+    /// Whether the Cr component comes before the Cb one.
+    cr_first: bool,
+}
+
+impl PlaneArrangement {
+    fn of(pixelformat: PixelFormat) -> Option<Self> {
+        const CR_FIRST: [PixelFormat; 9] = [
+            PixelFormat::NV21,
+            PixelFormat::NV61,
+            PixelFormat::NV42,
+            PixelFormat::NV21M,
+            PixelFormat::NV61M,
+            PixelFormat::YVU420,
+            PixelFormat::YVU420M,
+            PixelFormat::YVU422M,
+            PixelFormat::YVU444M,
+        ];
+
+        let info = pixelformat.info()?;
+        let supported = info.encoding == PixelEncoding::Yuv
+            && info.bpc == 8
+            && info.bpp[0] == 1
+            && info.block.iter().all(|b| *b == (1, 1));
+        if !supported {
+            return None;
+        }
+
+        let chroma = match (info.comp_planes, info.hdiv, info.vdiv) {
+            (1, _, _) => ChromaFormat::Mono,
+            (_, 2, 2) => ChromaFormat::Yuv420,
+            (_, 2, 1) => ChromaFormat::Yuv422,
+            (_, 1, 1) => ChromaFormat::Yuv444,
+            _ => return None,
+        };
+
+        Some(PlaneArrangement {
+            chroma,
+            cr_first: CR_FIRST.contains(&pixelformat),
+        })
+    }
+}
+
+/// Layout of a raw planar YUV frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YuvLayout {
+    pub chroma: ChromaFormat,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl YuvLayout {
+    pub fn new(chroma: ChromaFormat, width: usize, height: usize) -> Self {
+        YuvLayout {
+            chroma,
+            width,
+            height,
+        }
+    }
+
+    /// Returns the width and height of each chroma plane, or `(0, 0)` for `Mono` frames.
+    pub fn chroma_size(&self) -> (usize, usize) {
+        match self.chroma.subsampling() {
+            None => (0, 0),
+            Some((hdiv, vdiv)) => (self.width.div_ceil(hdiv), self.height.div_ceil(vdiv)),
+        }
+    }
+
+    /// Size in bytes of one frame. Fails if the frame is empty or larger than [`MAX_WIDTH`] x
+    /// [`MAX_HEIGHT`].
+    pub fn frame_size(&self) -> Result<usize, YuvError> {
+        let invalid_size = || YuvError::InvalidSize(self.width, self.height);
+        if !(1..=MAX_WIDTH).contains(&self.width) || !(1..=MAX_HEIGHT).contains(&self.height) {
+            return Err(invalid_size());
+        }
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let luma_size = self.width.checked_mul(self.height);
+        let chroma_size = chroma_width
+            .checked_mul(chroma_height)
+            .and_then(|size| size.checked_mul(2));
+
+        luma_size
+            .zip(chroma_size)
+            .and_then(|(luma_size, chroma_size)| luma_size.checked_add(chroma_size))
+            .ok_or_else(invalid_size)
+    }
+
+    /// Returns a `Format` with tightly packed planes able to hold a frame of this layout.
+    pub fn format(&self) -> Result<Format, YuvError> {
+        let invalid_size = || YuvError::InvalidSize(self.width, self.height);
+        self.frame_size()?;
+        let pixelformat = self.chroma.pixel_format();
+        let width = u32::try_from(self.width).map_err(|_| invalid_size())?;
+        let height = u32::try_from(self.height).map_err(|_| invalid_size())?;
+        let plane_fmt = pixelformat
+            .info()
+            .and_then(|info| info.plane_layouts(width, height, 1))
+            .ok_or_else(invalid_size)?;
+
+        Ok(Format {
+            width,
+            height,
+            pixelformat,
+            plane_fmt,
+            ..Default::default()
+        })
+    }
+
+    /// Split `frame` into its Y, Cb and Cr planes.
+    fn split<'a>(&self, frame: &'a [u8]) -> Option<[&'a [u8]; 3]> {
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let luma_size = self.width * self.height;
+        let chroma_size = chroma_width * chroma_height;
+        let (y, rest) = frame.get(..self.frame_size().ok()?)?.split_at(luma_size);
+        let (cb, cr) = rest.split_at(chroma_size);
+
+        Some([y, cb, cr])
+    }
+
+    /// Split `frame` into its Y, Cb and Cr planes for writing.
+    fn split_mut<'a>(&self, frame: &'a mut [u8]) -> Option<[&'a mut [u8]; 3]> {
+        let (chroma_width, chroma_height) = self.chroma_size();
+        let luma_size = self.width * self.height;
+        let chroma_size = chroma_width * chroma_height;
+        let (y, rest) = frame
+            .get_mut(..self.frame_size().ok()?)?
+            .split_at_mut(luma_size);
+        let (cb, cr) = rest.split_at_mut(chroma_size);
+
+        Some([y, cb, cr])
+    }
+
+    /// Checks that an image of pixel format `pixelformat` with planes of sizes `plane_sizes`
+    /// can be exchanged with frames of this layout, and returns the arrangement of its planes.
+    ///
+    /// Images and frames must have the same chroma subsampling, except if one of them is
+    /// `Mono`: chroma is then either dropped, or filled with a neutral value.
+    fn check_image(
+        &self,
+        pixelformat: PixelFormat,
+        plane_sizes: impl Iterator<Item = (usize, usize)>,
+    ) -> Result<PlaneArrangement, YuvError> {
+        let arrangement =
+            PlaneArrangement::of(pixelformat).ok_or(YuvError::UnsupportedFormat(pixelformat))?;
+        if arrangement.chroma != self.chroma
+            && arrangement.chroma != ChromaFormat::Mono
+            && self.chroma != ChromaFormat::Mono
+        {
+            return Err(YuvError::ChromaMismatch);
+        }
+
+        let chroma_size = match arrangement.chroma.subsampling() {
+            None => (0, 0),
+            Some((hdiv, vdiv)) => (self.width.div_ceil(hdiv), self.height.div_ceil(vdiv)),
+        };
+        for (plane, size) in plane_sizes.enumerate() {
+            let expected = if plane == 0 {
+                (self.width, self.height)
+            } else {
+                chroma_size
+            };
+            if size != expected {
+                return Err(YuvError::SizeMismatch);
+            }
+        }
+
+        Ok(arrangement)
+    }
+
+    /// Copy `frame` into `image`, converting it to the plane arrangement of the image's format.
+    pub fn copy_to_image(&self, frame: &[u8], image: &mut ImageViewMut) -> Result<(), YuvError> {
+        let arrangement = self.check_image(
+            image.pixelformat(),
+            image.planes().iter().map(|p| (p.width(), p.height())),
+        )?;
+        let [y, cb, cr] = self.split(frame).ok_or(YuvError::FrameTooSmall)?;
+        let (cb, cr) = match self.chroma {
+            ChromaFormat::Mono => (None, None),
+            _ => (Some(cb), Some(cr)),
+        };
+        let (first, second) = if arrangement.cr_first {
+            (cr, cb)
+        } else {
+            (cb, cr)
+        };
+
+        match image.planes_mut() {
+            [luma] => copy_to_plane(luma, 0, Some(y)),
+            [luma, chroma] => {
+                copy_to_plane(luma, 0, Some(y));
+                copy_to_plane(chroma, 0, first);
+                copy_to_plane(chroma, 1, second);
+            }
+            [luma, chroma_1, chroma_2] => {
+                copy_to_plane(luma, 0, Some(y));
+                copy_to_plane(chroma_1, 0, first);
+                copy_to_plane(chroma_2, 0, second);
+            }
+            // `check_image` only accepts formats with 1 to 3 component planes.
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Copy `image` into `frame`, converting it from the plane arrangement of the image's
+    /// format.
+    pub fn copy_from_image(&self, image: &ImageView, frame: &mut [u8]) -> Result<(), YuvError> {
+        let arrangement = self.check_image(
+            image.pixelformat(),
+            image.planes().iter().map(|p| (p.width(), p.height())),
+        )?;
+        let [y, cb, cr] = self.split_mut(frame).ok_or(YuvError::FrameTooSmall)?;
+        let (first, second) = if arrangement.cr_first {
+            (cr, cb)
+        } else {
+            (cb, cr)
+        };
+
+        match image.planes() {
+            [luma] => {
+                copy_from_plane(luma, 0, y);
+                first.fill(NEUTRAL_CHROMA);
+                second.fill(NEUTRAL_CHROMA);
+            }
+            [luma, chroma] => {
+                copy_from_plane(luma, 0, y);
+                if self.chroma != ChromaFormat::Mono {
+                    copy_from_plane(chroma, 0, first);
+                    copy_from_plane(chroma, 1, second);
+                }
+            }
+            [luma, chroma_1, chroma_2] => {
+                copy_from_plane(luma, 0, y);
+                if self.chroma != ChromaFormat::Mono {
+                    copy_from_plane(chroma_1, 0, first);
+                    copy_from_plane(chroma_2, 0, second);
+                }
+            }
+            // `check_image` only accepts formats with 1 to 3 component planes.
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
+
+/// Write the tightly packed plane `src` into component `component` of the samples of `dst`, or
+/// fill that component with neutral chroma if `src` is `None`. `src` must have the same size as
+/// `dst`.
+fn copy_to_plane(dst: &mut PlaneViewMut, component: usize, src: Option<&[u8]>) {
+    let (width, bpp) = (dst.width(), dst.bytes_per_sample());
+
+    match src {
+        Some(src) if bpp == 1 => dst
+            .rows_mut()
+            .zip(src.chunks_exact(width.max(1)))
+            .for_each(|(row, line)| row.copy_from_slice(line)),
+        Some(src) => dst
+            .rows_mut()
+            .zip(src.chunks_exact(width.max(1)))
+            .for_each(|(row, line)| {
+                row.chunks_exact_mut(bpp)
+                    .zip(line)
+                    .for_each(|(sample, value)| sample[component] = *value)
+            }),
+        None => dst.rows_mut().for_each(|row| {
+            row.chunks_exact_mut(bpp)
+                .for_each(|sample| sample[component] = NEUTRAL_CHROMA)
+        }),
+    }
+}
+
+/// Read component `component` of the samples of `src` into the tightly packed plane `dst`,
+/// which must have the same size as `src`.
+fn copy_from_plane(src: &PlaneView, component: usize, dst: &mut [u8]) {
+    let (width, bpp) = (src.width(), src.bytes_per_sample());
+
+    src.rows()
+        .zip(dst.chunks_exact_mut(width.max(1)))
+        .for_each(|(row, line)| {
+            if bpp == 1 {
+                line.copy_from_slice(row);
+            } else {
+                row.chunks_exact(bpp)
+                    .zip(line)
+                    .for_each(|(sample, value)| *value = sample[component]);
+            }
+        });
+}
+
+/// Fill `buf` from `reader`. Returns `false` if the end of the stream has been reached before
+/// anything could be read, and an error if it is reached in the middle of `buf`.
+pub(super) fn read_frame_data<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, YuvError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(YuvError::TruncatedFrame),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+/// Reads frames from a raw planar YUV stream.
+pub struct RawYuvReader<R: Read> {
+    reader: R,
+    layout: YuvLayout,
+    frame: Vec<u8>,
+}
+
+impl<R: Read> RawYuvReader<R> {
+    /// Create a reader for a stream of frames of layout `layout`, which raw YUV files do not
+    /// record.
+    pub fn new(reader: R, layout: YuvLayout) -> Result<Self, YuvError> {
+        Ok(RawYuvReader {
+            reader,
+            layout,
+            frame: vec![0u8; layout.frame_size()?],
+        })
+    }
+
+    pub fn layout(&self) -> &YuvLayout {
+        &self.layout
+    }
+
+    /// Read the next frame of the stream, or return `None` if its end has been reached.
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, YuvError> {
+        if read_frame_data(&mut self.reader, &mut self.frame)? {
+            Ok(Some(&self.frame))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read the next frame of the stream into `image`. Returns `false` if the end of the stream
+    /// has been reached.
+    pub fn read_frame_into(&mut self, image: &mut ImageViewMut) -> Result<bool, YuvError> {
+        let layout = self.layout;
+
+        match self.next_frame()? {
+            None => Ok(false),
+            Some(frame) => {
+                layout.copy_to_image(frame, image)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Writes frames into a raw planar YUV stream.
+pub struct RawYuvWriter<W: Write> {
+    writer: W,
+    layout: YuvLayout,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> RawYuvWriter<W> {
+    pub fn new(writer: W, layout: YuvLayout) -> Result<Self, YuvError> {
+        Ok(RawYuvWriter {
+            writer,
+            layout,
+            frame: vec![0u8; layout.frame_size()?],
+        })
+    }
+
+    pub fn layout(&self) -> &YuvLayout {
+        &self.layout
+    }
+
+    /// Write `image` as the next frame of the stream.
+    pub fn write_frame(&mut self, image: &ImageView) -> Result<(), YuvError> {
+        self.layout.copy_from_image(image, &mut self.frame)?;
+        self.writer.write_all(&self.frame)?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), YuvError> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PlaneLayout;
+    use std::io::Cursor;
+
+    /// 4x2 YUV 4:2:0 frame with luma 0..8, Cb 10..12 and Cr 20..22.
+    const FRAME: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 20, 21];
+
+    #[test]
+    fn test_copy_to_semiplanar() {
+        let layout = YuvLayout::new(ChromaFormat::Yuv420, 4, 2);
+        // NV21 with a padded stride.
+        let format = Format {
+            width: 4,
+            height: 2,
+            pixelformat: PixelFormat::NV21,
+            plane_fmt: vec![PlaneLayout {
+                bytesperline: 6,
+                sizeimage: 18,
+            }],
+            ..Default::default()
+        };
+        let mut planes = [vec![0xffu8; 18]];
+
+        let mut image = ImageViewMut::new(&format, None, &mut planes).unwrap();
+        layout.copy_to_image(&FRAME, &mut image).unwrap();
+        assert_eq!(
+            planes[0],
+            [0, 1, 2, 3, 0xff, 0xff, 4, 5, 6, 7, 0xff, 0xff, 20, 10, 21, 11, 0xff, 0xff]
+        );
+
+        let image = ImageView::new(&format, None, &planes).unwrap();
+        let mut frame = [0u8; 12];
+        layout.copy_from_image(&image, &mut frame).unwrap();
+        assert_eq!(frame, FRAME);
+    }
+
+    #[test]
+    fn test_raw_yuv_round_trip() {
+        let layout = YuvLayout::new(ChromaFormat::Yuv420, 4, 2);
+        let format = Format {
+            width: 4,
+            height: 2,
+            pixelformat: PixelFormat::YUV420M,
+            plane_fmt: vec![
+                PlaneLayout {
+                    bytesperline: 8,
+                    sizeimage: 16,
+                },
+                PlaneLayout {
+                    bytesperline: 4,
+                    sizeimage: 4,
+                },
+                PlaneLayout {
+                    bytesperline: 4,
+                    sizeimage: 4,
+                },
+            ],
+            ..Default::default()
+        };
+        let mut planes = vec![vec![0u8; 16], vec![0u8; 4], vec![0u8; 4]];
+
+        let mut reader = RawYuvReader::new(Cursor::new(FRAME.repeat(2)), layout).unwrap();
+        let mut writer = RawYuvWriter::new(Vec::new(), layout).unwrap();
+        for _ in 0..2 {
+            let mut image = ImageViewMut::new(&format, None, &mut planes).unwrap();
+            assert!(reader.read_frame_into(&mut image).unwrap());
+            assert_eq!(image.planes()[2].row(0), Some(&[20u8, 21][..]));
+            writer
+                .write_frame(&ImageView::new(&format, None, &planes).unwrap())
+                .unwrap();
+        }
+        assert!(reader.next_frame().unwrap().is_none());
+        assert_eq!(writer.into_inner(), FRAME.repeat(2));
+
+        let mut truncated = RawYuvReader::new(Cursor::new(&FRAME[..10]), layout).unwrap();
+        assert!(matches!(
+            truncated.next_frame(),
+            Err(YuvError::TruncatedFrame)
+        ));
+    }
+
+    #[test]
+    fn test_mono_conversions() {
+        let layout = YuvLayout::new(ChromaFormat::Mono, 4, 2);
+        let format = layout.format().unwrap();
+        assert_eq!(format.pixelformat, PixelFormat::GREY);
+
+        // Mono frames get neutral chroma when copied into a color image.
+        let mut nv12 = YuvLayout::new(ChromaFormat::Yuv420, 4, 2).format().unwrap();
+        nv12.pixelformat = PixelFormat::NV12;
+        let mut planes = [vec![0u8; 12]];
+        let mut image = ImageViewMut::new(&nv12, None, &mut planes).unwrap();
+        layout.copy_to_image(&FRAME[..8], &mut image).unwrap();
+        assert_eq!(planes[0][8..], [128, 128, 128, 128]);
+
+        // Color images lose their chroma when copied into a mono frame.
+        let image = ImageView::new(&nv12, None, &planes).unwrap();
+        let mut frame = [0u8; 8];
+        layout.copy_from_image(&image, &mut frame).unwrap();
+        assert_eq!(frame, FRAME[..8]);
+
+        let layout_422 = YuvLayout::new(ChromaFormat::Yuv422, 4, 2);
+        assert!(matches!(
+            layout_422.copy_from_image(&image, &mut [0u8; 16]),
+            Err(YuvError::ChromaMismatch)
+        ));
+    }
+    #[test]
+    fn test_invalid_sizes() {
+        assert_eq!(
+            YuvLayout::new(ChromaFormat::Yuv420, 5, 3)
+                .frame_size()
+                .unwrap(),
+            15 + 2 * 6
+        );
+        for (width, height) in [(0, 2), (4, 0), (MAX_WIDTH + 1, 2), (usize::MAX, usize::MAX)] {
+            let layout = YuvLayout::new(ChromaFormat::Yuv444, width, height);
+            assert!(matches!(
+                layout.frame_size(),
+                Err(YuvError::InvalidSize(w, h)) if (w, h) == (width, height)
+            ));
+            assert!(layout.format().is_err());
+            assert!(RawYuvReader::new(Cursor::new(&FRAME[..]), layout).is_err());
+        }
+    }
+}
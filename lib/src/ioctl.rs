@@ -9,7 +9,7 @@
 //!
 //! * The parameters of the function correspond to the input values of the ioctl.
 //! * The returned value of the function is a value that can be constructed from the ioctl's
-//! structure parameter (i.e. it can be the parameter itself)
+//!   structure parameter (i.e. it can be the parameter itself)
 //!
 //! For instance, the `VIDIOC_G_FMT` ioctl takes a `struct v4l2_format` as argument, but only the
 //! its `type` field is set by user-space - the rest of the structure is to be filled by the
@@ -329,7 +329,7 @@ impl V4l2Buffer {
 
     /// Returns the first plane of the buffer. This method is guaranteed to
     /// succeed because every buffer has at least one plane.
-    pub fn get_first_plane(&self) -> V4l2BufferPlane {
+    pub fn get_first_plane(&self) -> V4l2BufferPlane<'_> {
         V4l2BufferPlane {
             buffer: &self.buffer,
             plane: &self.planes[0],
@@ -338,7 +338,7 @@ impl V4l2Buffer {
 
     /// Returns plane `index` of the buffer, or `None` if `index` is larger than
     /// the number of planes in this buffer.
-    pub fn get_plane(&self, index: usize) -> Option<V4l2BufferPlane> {
+    pub fn get_plane(&self, index: usize) -> Option<V4l2BufferPlane<'_>> {
         if index < self.num_planes() {
             Some(V4l2BufferPlane {
                 buffer: &self.buffer,
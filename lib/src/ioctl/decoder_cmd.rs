@@ -1,11 +1,7 @@
 use crate::bindings;
 use crate::bindings::v4l2_decoder_cmd;
 use nix::errno::Errno;
-use std::{
-    convert::{Infallible, TryFrom},
-    mem,
-    os::unix::io::AsRawFd,
-};
+use std::{convert::TryFrom, mem, os::unix::io::AsRawFd};
 use thiserror::Error;
 
 #[doc(hidden)]
@@ -72,12 +68,8 @@ impl From<DecoderCommand> for v4l2_decoder_cmd {
     }
 }
 
-impl TryFrom<v4l2_decoder_cmd> for () {
-    type Error = Infallible;
-
-    fn try_from(_: v4l2_decoder_cmd) -> Result<Self, Self::Error> {
-        Ok(())
-    }
+impl From<v4l2_decoder_cmd> for () {
+    fn from(_: v4l2_decoder_cmd) -> Self {}
 }
 
 /// Safe wrapper around the `VIDIOC_DECODER_CMD` ioctl.
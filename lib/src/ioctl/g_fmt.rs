@@ -36,6 +36,13 @@ impl TryFrom<(QueueType, &Format)> for v4l2_format {
                                 ..unsafe { mem::zeroed() }
                             };
 
+                            if let Some(colorimetry) = &format.colorimetry {
+                                pix_mp.colorspace = colorimetry.colorspace as u32;
+                                pix_mp.__bindgen_anon_1.ycbcr_enc = colorimetry.ycbcr_enc as u8;
+                                pix_mp.quantization = colorimetry.quantization as u8;
+                                pix_mp.xfer_func = colorimetry.xfer_func as u8;
+                            }
+
                             for (plane, v4l2_plane) in
                                 format.plane_fmt.iter().zip(pix_mp.plane_fmt.iter_mut())
                             {
@@ -61,14 +68,25 @@ impl TryFrom<(QueueType, &Format)> for v4l2_format {
                             Default::default()
                         };
 
-                        bindings::v4l2_pix_format {
+                        let mut pix = bindings::v4l2_pix_format {
                             width: format.width,
                             height: format.height,
                             pixelformat: format.pixelformat.into(),
                             bytesperline,
                             sizeimage,
                             ..unsafe { mem::zeroed() }
+                        };
+
+                        if let Some(colorimetry) = &format.colorimetry {
+                            pix.colorspace = colorimetry.colorspace as u32;
+                            // Signals that the extended fields below are valid.
+                            pix.priv_ = bindings::V4L2_PIX_FMT_PRIV_MAGIC;
+                            pix.__bindgen_anon_1.ycbcr_enc = colorimetry.ycbcr_enc as u32;
+                            pix.quantization = colorimetry.quantization as u32;
+                            pix.xfer_func = colorimetry.xfer_func as u32;
                         }
+
+                        pix
                     },
                 },
             },
@@ -219,6 +237,7 @@ pub fn try_fmt<I: TryInto<v4l2_format>, O: TryFrom<v4l2_format>>(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{Colorimetry, Colorspace, Quantization, XferFunc, YCbCrEncoding};
     use std::convert::TryInto;
 
     #[test]
@@ -243,6 +262,12 @@ mod test {
                     bytesperline: 160,
                 },
             ],
+            colorimetry: Some(Colorimetry {
+                colorspace: Colorspace::Srgb,
+                xfer_func: XferFunc::Srgb,
+                ycbcr_enc: YCbCrEncoding::E601,
+                quantization: Quantization::FullRange,
+            }),
         };
         let v4l2_format = v4l2_format {
             ..(QueueType::VideoCaptureMplane, &mplane).try_into().unwrap()
@@ -263,6 +288,12 @@ mod test {
                 sizeimage: 307200,
                 bytesperline: 640,
             }],
+            colorimetry: Some(Colorimetry {
+                colorspace: Colorspace::Rec709,
+                xfer_func: XferFunc::F709,
+                ycbcr_enc: YCbCrEncoding::E709,
+                quantization: Quantization::LimRange,
+            }),
         };
         // Conversion to/from single-planar format.
         let v4l2_format = v4l2_format {
@@ -271,6 +302,15 @@ mod test {
         let splane2: Format = v4l2_format.try_into().unwrap();
         assert_eq!(splane, splane2);
 
+        // Without colorimetry, the extended fields must not be marked as valid.
+        let splane = Format {
+            colorimetry: None,
+            ..splane
+        };
+        let v4l2_format: v4l2_format = (QueueType::VideoCapture, &splane).try_into().unwrap();
+        assert_eq!(unsafe { v4l2_format.fmt.pix.priv_ }, 0);
+        assert_eq!(unsafe { v4l2_format.fmt.pix.colorspace }, 0);
+
         // Trying to use a multi-planar format with the single-planar API should
         // fail.
         let mplane = Format {
@@ -292,6 +332,7 @@ mod test {
                     bytesperline: 160,
                 },
             ],
+            ..Default::default()
         };
         assert_eq!(
             TryInto::<v4l2_format>::try_into((QueueType::VideoCapture, &mplane)).err(),
@@ -224,9 +224,10 @@ mod ioctl {
 /// TODO: `qbuf` should be unsafe! The following invariants need to be guaranteed
 /// by the caller:
 ///
-/// For MMAP buffers, any mapping must not be accessed by the caller (or any
-/// mapping must be unmapped before queueing?). Also if the buffer has been
-/// DMABUF-exported, its consumers must likewise not access it.
+/// For MMAP buffers, any mapping must not be accessed by the caller while the
+/// buffer is queued. Also if the buffer has been DMABUF-exported, its consumers
+/// must likewise not access it. The `device::queue` module enforces the former
+/// by only handing out mappings that borrow the buffer they belong to.
 ///
 /// For DMABUF buffers, the FD must not be duplicated and accessed anywhere else.
 ///
@@ -76,7 +76,12 @@ impl QueryBuf for V4l2Buffer {
                     reserved: Default::default(),
                     // Safe because both unions have the same members and
                     // layout in single-plane mode.
-                    m: unsafe { std::mem::transmute(v4l2_buf.m) },
+                    m: unsafe {
+                        std::mem::transmute::<
+                            bindings::v4l2_buffer__bindgen_ty_1,
+                            bindings::v4l2_plane__bindgen_ty_1,
+                        >(v4l2_buf.m)
+                    },
                 };
 
                 pdata
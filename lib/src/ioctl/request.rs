@@ -1,7 +1,7 @@
 use nix::libc::c_int;
 use nix::poll::{poll, PollFd};
 use std::fs::File;
-use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::os::unix::prelude::FromRawFd;
 use thiserror::Error;
 
@@ -90,3 +90,9 @@ impl AsRawFd for Request {
         self.fd.as_raw_fd()
     }
 }
+
+impl AsFd for Request {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -23,8 +23,11 @@ pub mod controls;
 pub mod decoder;
 pub mod device;
 pub mod encoder;
+pub mod image;
 pub mod ioctl;
 pub mod memory;
+pub mod metadata;
+pub mod pixel_format;
 
 use std::convert::TryFrom;
 use std::fmt;
@@ -257,6 +260,22 @@ pub struct Format {
     /// Individual layout of each plane in this format. The exact number of planes
     /// is defined by `pixelformat`.
     pub plane_fmt: Vec<PlaneLayout>,
+    /// Colorimetry of the image. Always set on formats returned by the driver. When setting a
+    /// format, it is only passed to the driver if set, otherwise the driver picks its own.
+    pub colorimetry: Option<Colorimetry>,
+}
+
+/// Description of how the values of an image map to colors.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Colorimetry {
+    /// Colorspace of the image.
+    pub colorspace: Colorspace,
+    /// Transfer function used to encode the image.
+    pub xfer_func: XferFunc,
+    /// Encoding used to convert the image between RGB and Y'CbCr.
+    pub ycbcr_enc: YCbCrEncoding,
+    /// Range of the values of the image components.
+    pub quantization: Quantization,
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -275,6 +294,19 @@ impl TryFrom<bindings::v4l2_format> for Format {
             bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE
             | bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_OUTPUT => {
                 let pix = unsafe { &fmt.fmt.pix };
+                // The extended fields are only valid if `priv_` contains the magic value.
+                let (xfer_func, ycbcr_enc, quantization) =
+                    if pix.priv_ == bindings::V4L2_PIX_FMT_PRIV_MAGIC {
+                        (
+                            XferFunc::n(pix.xfer_func).unwrap_or_default(),
+                            YCbCrEncoding::n(unsafe { pix.__bindgen_anon_1.ycbcr_enc })
+                                .unwrap_or_default(),
+                            Quantization::n(pix.quantization).unwrap_or_default(),
+                        )
+                    } else {
+                        Default::default()
+                    };
+
                 Ok(Format {
                     width: pix.width,
                     height: pix.height,
@@ -283,6 +315,12 @@ impl TryFrom<bindings::v4l2_format> for Format {
                         bytesperline: pix.bytesperline,
                         sizeimage: pix.sizeimage,
                     }],
+                    colorimetry: Some(Colorimetry {
+                        colorspace: Colorspace::n(pix.colorspace).unwrap_or_default(),
+                        xfer_func,
+                        ycbcr_enc,
+                        quantization,
+                    }),
                 })
             }
             bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE
@@ -308,6 +346,16 @@ impl TryFrom<bindings::v4l2_format> for Format {
                     height: pix_mp.height,
                     pixelformat: PixelFormat::from(pix_mp.pixelformat),
                     plane_fmt,
+                    colorimetry: Some(Colorimetry {
+                        colorspace: Colorspace::n(pix_mp.colorspace).unwrap_or_default(),
+                        xfer_func: XferFunc::n(pix_mp.xfer_func as u32).unwrap_or_default(),
+                        ycbcr_enc: YCbCrEncoding::n(
+                            unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc } as u32
+                        )
+                        .unwrap_or_default(),
+                        quantization: Quantization::n(pix_mp.quantization as u32)
+                            .unwrap_or_default(),
+                    }),
                 })
             }
             t => Err(Self::Error::InvalidBufferType(t)),
@@ -339,7 +387,7 @@ impl<T: Into<PixelFormat>> From<(T, (usize, usize))> for Format {
 }
 
 /// A more elegant representation for `v4l2_rect`.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub left: i32,
     pub top: i32,
@@ -398,8 +446,9 @@ impl Display for Rect {
 
 /// Equivalent of `enum v4l2_colorspace`.
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, N)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, N)]
 pub enum Colorspace {
+    #[default]
     Default = bindings::v4l2_colorspace_V4L2_COLORSPACE_DEFAULT,
     Smpte170M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE170M,
     Smpte240M = bindings::v4l2_colorspace_V4L2_COLORSPACE_SMPTE240M,
@@ -417,8 +466,9 @@ pub enum Colorspace {
 
 /// Equivalent of `enum v4l2_xfer_func`.
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, N)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, N)]
 pub enum XferFunc {
+    #[default]
     Default = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_DEFAULT,
     F709 = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_709,
     Srgb = bindings::v4l2_xfer_func_V4L2_XFER_FUNC_SRGB,
@@ -431,8 +481,9 @@ pub enum XferFunc {
 
 /// Equivalent of `enum v4l2_ycbcr_encoding`.
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, N)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, N)]
 pub enum YCbCrEncoding {
+    #[default]
     Default = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_DEFAULT,
     E601 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_601,
     E709 = bindings::v4l2_ycbcr_encoding_V4L2_YCBCR_ENC_709,
@@ -446,8 +497,9 @@ pub enum YCbCrEncoding {
 
 /// Equivalent of `enum v4l2_quantization`.
 #[repr(u32)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, N)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, N)]
 pub enum Quantization {
+    #[default]
     Default = bindings::v4l2_quantization_V4L2_QUANTIZATION_DEFAULT,
     FullRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_FULL_RANGE,
     LimRange = bindings::v4l2_quantization_V4L2_QUANTIZATION_LIM_RANGE,
@@ -80,7 +80,12 @@ pub trait PlaneHandle: Debug + Send + 'static {
     /// into the `v4l2_buffer`, for queues using the single-planar API.
     fn fill_v4l2_splane_buffer(plane: &bindings::v4l2_plane, buffer: &mut bindings::v4l2_buffer) {
         // Safe because both fields have the same size.
-        buffer.m = unsafe { std::mem::transmute(plane.m) };
+        buffer.m = unsafe {
+            std::mem::transmute::<
+                bindings::v4l2_plane__bindgen_ty_1,
+                bindings::v4l2_buffer__bindgen_ty_1,
+            >(plane.m)
+        };
         buffer.length = plane.length;
     }
 }
@@ -0,0 +1,116 @@
+//! Association of client-defined metadata with the frames going through a codec.
+//!
+//! V4L2 codecs copy the timestamp of an OUTPUT buffer into the CAPTURE buffers produced from it.
+//! [`FrameMetadataMap`] uses this to let the decoder and encoder carry arbitrary data alongside
+//! each frame: it allocates a unique timestamp for every frame and remembers the metadata attached
+//! to it until the corresponding frame comes out of the codec.
+use std::collections::BTreeMap;
+
+use nix::sys::time::{TimeVal, TimeValLike};
+
+/// Keeps track of the metadata attached to the frames currently being processed by a codec.
+///
+/// Frames are identified by the unique timestamps returned by [`FrameMetadataMap::insert`], which
+/// must be set on the OUTPUT buffer containing the frame. Timestamps are allocated in increasing
+/// order, starting from 1 microsecond so a zero timestamp never matches any frame.
+pub struct FrameMetadataMap<M> {
+    next_id: i64,
+    frames: BTreeMap<i64, M>,
+}
+
+impl<M> Default for FrameMetadataMap<M> {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            frames: Default::default(),
+        }
+    }
+}
+
+impl<M> FrameMetadataMap<M> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register `metadata` for a new frame and return the timestamp identifying that frame.
+    pub fn insert(&mut self, metadata: M) -> TimeVal {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.frames.insert(id, metadata);
+
+        TimeVal::microseconds(id)
+    }
+
+    /// Remove and return the metadata of the frame identified by `timestamp`, if any.
+    pub fn take(&mut self, timestamp: &TimeVal) -> Option<M> {
+        self.frames.remove(&timestamp.num_microseconds())
+    }
+
+    /// Returns a mark that [`FrameMetadataMap::take_before`] can use to select all the frames
+    /// registered so far.
+    pub fn mark(&self) -> FrameMark {
+        FrameMark(self.next_id)
+    }
+
+    /// Remove and return the metadata of all the frames registered before `mark` was obtained,
+    /// in registration order.
+    pub fn take_before(&mut self, mark: FrameMark) -> Vec<M> {
+        let remaining = self.frames.split_off(&mark.0);
+        std::mem::replace(&mut self.frames, remaining)
+            .into_values()
+            .collect()
+    }
+
+    /// Remove and return the metadata of all the frames, in registration order.
+    pub fn take_all(&mut self) -> Vec<M> {
+        std::mem::take(&mut self.frames).into_values().collect()
+    }
+
+    /// Returns the number of frames which metadata has not been taken yet.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Position in the sequence of frames registered into a [`FrameMetadataMap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameMark(i64);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut map = FrameMetadataMap::new();
+        let ts0 = map.insert("frame0");
+        let ts1 = map.insert("frame1");
+        assert_ne!(ts0, ts1);
+        assert_ne!(ts0, TimeVal::zero());
+
+        assert_eq!(map.take(&ts1), Some("frame1"));
+        assert_eq!(map.take(&ts1), None);
+        assert_eq!(map.take(&TimeVal::zero()), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.take(&ts0), Some("frame0"));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_take_before() {
+        let mut map = FrameMetadataMap::new();
+        let ts0 = map.insert(0);
+        map.insert(1);
+        let mark = map.mark();
+        map.insert(2);
+
+        map.take(&ts0);
+        assert_eq!(map.take_before(mark), vec![1]);
+        assert_eq!(map.take_all(), vec![2]);
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+//! Description of the memory layout of pixel formats.
+//!
+//! This module provides a database of the most common V4L2 pixel formats, describing how many
+//! planes they use, how their chroma components are subsampled, and how many bytes each sample
+//! takes. This information is enough to compute the layout of a frame, in the same way the
+//! kernel's `v4l2_fill_pixfmt` does.
+//!
+//! # Examples
+//!
+//! ```
+//! # use v4l2r::PixelFormat;
+//! let info = PixelFormat::NV12.info().unwrap();
+//! assert_eq!(info.comp_planes, 2);
+//!
+//! let layout = info.plane_layouts(640, 480, 1).unwrap();
+//! assert_eq!(layout.len(), 1);
+//! assert_eq!(layout[0].bytesperline, 640);
+//! assert_eq!(layout[0].sizeimage, 640 * 480 * 3 / 2);
+//! ```
+use std::convert::TryFrom;
+
+use crate::{PixelFormat, PlaneLayout};
+
+/// Named constants for common pixel formats.
+impl PixelFormat {
+    // Packed RGB formats. The name describes the order of the components in memory.
+    pub const RGB565: PixelFormat = PixelFormat::from_fourcc(b"RGBP");
+    pub const RGB24: PixelFormat = PixelFormat::from_fourcc(b"RGB3");
+    pub const BGR24: PixelFormat = PixelFormat::from_fourcc(b"BGR3");
+    pub const ABGR32: PixelFormat = PixelFormat::from_fourcc(b"AR24");
+    pub const XBGR32: PixelFormat = PixelFormat::from_fourcc(b"XR24");
+    pub const BGRA32: PixelFormat = PixelFormat::from_fourcc(b"RA24");
+    pub const BGRX32: PixelFormat = PixelFormat::from_fourcc(b"RX24");
+    pub const RGBA32: PixelFormat = PixelFormat::from_fourcc(b"AB24");
+    pub const RGBX32: PixelFormat = PixelFormat::from_fourcc(b"XB24");
+    pub const ARGB32: PixelFormat = PixelFormat::from_fourcc(b"BA24");
+    pub const XRGB32: PixelFormat = PixelFormat::from_fourcc(b"BX24");
+
+    // Luma-only formats.
+    pub const GREY: PixelFormat = PixelFormat::from_fourcc(b"GREY");
+    pub const Y10: PixelFormat = PixelFormat::from_fourcc(b"Y10 ");
+    pub const Y16: PixelFormat = PixelFormat::from_fourcc(b"Y16 ");
+
+    // Packed YUV formats.
+    pub const YUYV: PixelFormat = PixelFormat::from_fourcc(b"YUYV");
+    pub const YVYU: PixelFormat = PixelFormat::from_fourcc(b"YVYU");
+    pub const UYVY: PixelFormat = PixelFormat::from_fourcc(b"UYVY");
+    pub const VYUY: PixelFormat = PixelFormat::from_fourcc(b"VYUY");
+
+    // Semi-planar YUV formats.
+    pub const NV12: PixelFormat = PixelFormat::from_fourcc(b"NV12");
+    pub const NV21: PixelFormat = PixelFormat::from_fourcc(b"NV21");
+    pub const NV16: PixelFormat = PixelFormat::from_fourcc(b"NV16");
+    pub const NV61: PixelFormat = PixelFormat::from_fourcc(b"NV61");
+    pub const NV24: PixelFormat = PixelFormat::from_fourcc(b"NV24");
+    pub const NV42: PixelFormat = PixelFormat::from_fourcc(b"NV42");
+    pub const P010: PixelFormat = PixelFormat::from_fourcc(b"P010");
+    pub const NV12M: PixelFormat = PixelFormat::from_fourcc(b"NM12");
+    pub const NV21M: PixelFormat = PixelFormat::from_fourcc(b"NM21");
+    pub const NV16M: PixelFormat = PixelFormat::from_fourcc(b"NM16");
+    pub const NV61M: PixelFormat = PixelFormat::from_fourcc(b"NM61");
+
+    // Tiled semi-planar YUV formats.
+    pub const NV12MT: PixelFormat = PixelFormat::from_fourcc(b"TM12");
+    pub const NV12_4L4: PixelFormat = PixelFormat::from_fourcc(b"VT12");
+    pub const NV12_16L16: PixelFormat = PixelFormat::from_fourcc(b"HM12");
+    pub const NV12_32L32: PixelFormat = PixelFormat::from_fourcc(b"ST12");
+    pub const MM21: PixelFormat = PixelFormat::from_fourcc(b"MM21");
+
+    // Planar YUV formats.
+    pub const YUV420: PixelFormat = PixelFormat::from_fourcc(b"YU12");
+    pub const YVU420: PixelFormat = PixelFormat::from_fourcc(b"YV12");
+    pub const YUV422P: PixelFormat = PixelFormat::from_fourcc(b"422P");
+    pub const YUV420M: PixelFormat = PixelFormat::from_fourcc(b"YM12");
+    pub const YVU420M: PixelFormat = PixelFormat::from_fourcc(b"YM21");
+    pub const YUV422M: PixelFormat = PixelFormat::from_fourcc(b"YM16");
+    pub const YVU422M: PixelFormat = PixelFormat::from_fourcc(b"YM61");
+    pub const YUV444M: PixelFormat = PixelFormat::from_fourcc(b"YM24");
+    pub const YVU444M: PixelFormat = PixelFormat::from_fourcc(b"YM42");
+
+    // Compressed formats.
+    pub const MJPEG: PixelFormat = PixelFormat::from_fourcc(b"MJPG");
+    pub const JPEG: PixelFormat = PixelFormat::from_fourcc(b"JPEG");
+    pub const MPEG2: PixelFormat = PixelFormat::from_fourcc(b"MPG2");
+    pub const MPEG2_SLICE: PixelFormat = PixelFormat::from_fourcc(b"MG2S");
+    pub const MPEG4: PixelFormat = PixelFormat::from_fourcc(b"MPG4");
+    pub const H264: PixelFormat = PixelFormat::from_fourcc(b"H264");
+    pub const H264_SLICE: PixelFormat = PixelFormat::from_fourcc(b"S264");
+    pub const HEVC: PixelFormat = PixelFormat::from_fourcc(b"HEVC");
+    pub const HEVC_SLICE: PixelFormat = PixelFormat::from_fourcc(b"S265");
+    pub const VP8: PixelFormat = PixelFormat::from_fourcc(b"VP80");
+    pub const VP8_FRAME: PixelFormat = PixelFormat::from_fourcc(b"VP8F");
+    pub const VP9: PixelFormat = PixelFormat::from_fourcc(b"VP90");
+    pub const VP9_FRAME: PixelFormat = PixelFormat::from_fourcc(b"VP9F");
+    pub const AV1_FRAME: PixelFormat = PixelFormat::from_fourcc(b"AV1F");
+    pub const FWHT: PixelFormat = PixelFormat::from_fourcc(b"FWHT");
+    pub const FWHT_STATELESS: PixelFormat = PixelFormat::from_fourcc(b"SFWH");
+
+    /// Returns the layout description of this format, if it is known.
+    pub fn info(self) -> Option<&'static PixelFormatInfo> {
+        find(self)
+    }
+}
+
+/// How the pixels of a format are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelEncoding {
+    Rgb,
+    Yuv,
+    /// Encoded data that needs to go through a codec before it can be displayed.
+    Compressed,
+}
+
+/// Description of the memory layout of a pixel format. This is the equivalent of the kernel's
+/// `struct v4l2_format_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PixelFormatInfo {
+    pub format: PixelFormat,
+    pub encoding: PixelEncoding,
+    /// Number of memory planes, i.e. of distinct buffers used to store a frame.
+    pub mem_planes: usize,
+    /// Number of component planes. Can be larger than `mem_planes` if several planes are stored
+    /// contiguously in the same buffer.
+    pub comp_planes: usize,
+    /// Bytes taken by one sample of each component plane. For planes interleaving several
+    /// components (e.g. the CbCr plane of NV12), a sample includes all the components.
+    pub bpp: [usize; 4],
+    /// Number of significant bits per color component.
+    pub bpc: usize,
+    /// Horizontal subsampling factor of the chroma planes.
+    pub hdiv: usize,
+    /// Vertical subsampling factor of the chroma planes.
+    pub vdiv: usize,
+    /// Width and height of the tiles of each component plane, in samples of that plane. Formats
+    /// that are not tiled use `(1, 1)`.
+    pub block: [(usize, usize); 4],
+}
+
+const fn raw(
+    fourcc: &[u8; 4],
+    encoding: PixelEncoding,
+    mem_planes: usize,
+    comp_planes: usize,
+    bpp: [usize; 4],
+    bpc: usize,
+    (hdiv, vdiv): (usize, usize),
+) -> PixelFormatInfo {
+    PixelFormatInfo {
+        format: PixelFormat::from_fourcc(fourcc),
+        encoding,
+        mem_planes,
+        comp_planes,
+        bpp,
+        bpc,
+        hdiv,
+        vdiv,
+        block: [(1, 1); 4],
+    }
+}
+
+const fn tiled(info: PixelFormatInfo, block: [(usize, usize); 4]) -> PixelFormatInfo {
+    PixelFormatInfo { block, ..info }
+}
+
+const fn compressed(fourcc: &[u8; 4]) -> PixelFormatInfo {
+    raw(fourcc, PixelEncoding::Compressed, 1, 1, [0; 4], 0, (1, 1))
+}
+
+use PixelEncoding::{Rgb, Yuv};
+
+const FORMATS: &[PixelFormatInfo] = &[
+    // Packed RGB formats.
+    raw(b"RGBP", Rgb, 1, 1, [2, 0, 0, 0], 5, (1, 1)),
+    raw(b"RGB3", Rgb, 1, 1, [3, 0, 0, 0], 8, (1, 1)),
+    raw(b"BGR3", Rgb, 1, 1, [3, 0, 0, 0], 8, (1, 1)),
+    raw(b"AR24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"XR24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"RA24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"RX24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"AB24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"XB24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"BA24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    raw(b"BX24", Rgb, 1, 1, [4, 0, 0, 0], 8, (1, 1)),
+    // Luma-only formats.
+    raw(b"GREY", Yuv, 1, 1, [1, 0, 0, 0], 8, (1, 1)),
+    raw(b"Y10 ", Yuv, 1, 1, [2, 0, 0, 0], 10, (1, 1)),
+    raw(b"Y16 ", Yuv, 1, 1, [2, 0, 0, 0], 16, (1, 1)),
+    // Packed YUV formats.
+    raw(b"YUYV", Yuv, 1, 1, [2, 0, 0, 0], 8, (2, 1)),
+    raw(b"YVYU", Yuv, 1, 1, [2, 0, 0, 0], 8, (2, 1)),
+    raw(b"UYVY", Yuv, 1, 1, [2, 0, 0, 0], 8, (2, 1)),
+    raw(b"VYUY", Yuv, 1, 1, [2, 0, 0, 0], 8, (2, 1)),
+    // Semi-planar YUV formats.
+    raw(b"NV12", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 2)),
+    raw(b"NV21", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 2)),
+    raw(b"NV16", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 1)),
+    raw(b"NV61", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 1)),
+    raw(b"NV24", Yuv, 1, 2, [1, 2, 0, 0], 8, (1, 1)),
+    raw(b"NV42", Yuv, 1, 2, [1, 2, 0, 0], 8, (1, 1)),
+    raw(b"P010", Yuv, 1, 2, [2, 4, 0, 0], 10, (2, 2)),
+    raw(b"NM12", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 2)),
+    raw(b"NM21", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 2)),
+    raw(b"NM16", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 1)),
+    raw(b"NM61", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 1)),
+    // Tiled semi-planar YUV formats.
+    tiled(
+        raw(b"TM12", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 2)),
+        [(64, 32), (32, 16), (1, 1), (1, 1)],
+    ),
+    tiled(
+        raw(b"VT12", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 2)),
+        [(4, 4), (2, 2), (1, 1), (1, 1)],
+    ),
+    tiled(
+        raw(b"HM12", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 2)),
+        [(16, 16), (8, 8), (1, 1), (1, 1)],
+    ),
+    tiled(
+        raw(b"ST12", Yuv, 1, 2, [1, 2, 0, 0], 8, (2, 2)),
+        [(32, 32), (16, 16), (1, 1), (1, 1)],
+    ),
+    tiled(
+        raw(b"MM21", Yuv, 2, 2, [1, 2, 0, 0], 8, (2, 2)),
+        [(16, 32), (8, 16), (1, 1), (1, 1)],
+    ),
+    // Planar YUV formats.
+    raw(b"YU12", Yuv, 1, 3, [1, 1, 1, 0], 8, (2, 2)),
+    raw(b"YV12", Yuv, 1, 3, [1, 1, 1, 0], 8, (2, 2)),
+    raw(b"422P", Yuv, 1, 3, [1, 1, 1, 0], 8, (2, 1)),
+    raw(b"YM12", Yuv, 3, 3, [1, 1, 1, 0], 8, (2, 2)),
+    raw(b"YM21", Yuv, 3, 3, [1, 1, 1, 0], 8, (2, 2)),
+    raw(b"YM16", Yuv, 3, 3, [1, 1, 1, 0], 8, (2, 1)),
+    raw(b"YM61", Yuv, 3, 3, [1, 1, 1, 0], 8, (2, 1)),
+    raw(b"YM24", Yuv, 3, 3, [1, 1, 1, 0], 8, (1, 1)),
+    raw(b"YM42", Yuv, 3, 3, [1, 1, 1, 0], 8, (1, 1)),
+    // Compressed formats.
+    compressed(b"MJPG"),
+    compressed(b"JPEG"),
+    compressed(b"MPG2"),
+    compressed(b"MG2S"),
+    compressed(b"MPG4"),
+    compressed(b"H264"),
+    compressed(b"S264"),
+    compressed(b"HEVC"),
+    compressed(b"S265"),
+    compressed(b"VP80"),
+    compressed(b"VP8F"),
+    compressed(b"VP90"),
+    compressed(b"VP9F"),
+    compressed(b"AV1F"),
+    compressed(b"FWHT"),
+    compressed(b"SFWH"),
+];
+
+/// Returns the layout description of `format`, if it is known.
+pub fn find(format: PixelFormat) -> Option<&'static PixelFormatInfo> {
+    FORMATS.iter().find(|info| info.format == format)
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    let alignment = std::cmp::max(alignment, 1);
+    value.div_ceil(alignment) * alignment
+}
+
+impl PixelFormatInfo {
+    pub fn is_compressed(&self) -> bool {
+        self.encoding == PixelEncoding::Compressed
+    }
+
+    /// Returns the horizontal and vertical subsampling factors of component plane `plane`.
+    pub fn subsampling(&self, plane: usize) -> (usize, usize) {
+        // Only the chroma planes of YUV formats are subsampled.
+        if plane == 0 || self.encoding != PixelEncoding::Yuv {
+            (1, 1)
+        } else {
+            (self.hdiv, self.vdiv)
+        }
+    }
+
+    /// Returns the width, in samples, of component plane `plane` for a frame `width` pixels wide.
+    /// Padding required by tiled formats is included.
+    pub fn plane_width(&self, plane: usize, width: usize) -> usize {
+        let (hdiv, _) = self.subsampling(plane);
+        let width = align(width, self.block[0].0);
+
+        align(width.div_ceil(hdiv), self.block[plane].0)
+    }
+
+    /// Returns the height, in lines, of component plane `plane` for a frame `height` pixels
+    /// high. Padding required by tiled formats is included.
+    pub fn plane_height(&self, plane: usize, height: usize) -> usize {
+        let (_, vdiv) = self.subsampling(plane);
+        let height = align(height, self.block[0].1);
+
+        align(height.div_ceil(vdiv), self.block[plane].1)
+    }
+
+    /// Returns the number of bytes per line of component plane `plane`, given the number of bytes
+    /// per line `bytesperline` of the first plane.
+    pub fn plane_bytesperline(&self, plane: usize, bytesperline: usize) -> usize {
+        if plane == 0 || self.bpp[0] == 0 {
+            return bytesperline;
+        }
+
+        let (hdiv, _) = self.subsampling(plane);
+        let width = bytesperline / self.bpp[0];
+
+        std::cmp::max(
+            width.div_ceil(hdiv) * self.bpp[plane],
+            align(width.div_ceil(hdiv), self.block[plane].0) * self.bpp[plane],
+        )
+    }
+
+    /// Computes the layout of the memory planes of a `width`x`height` frame, with the bytes per
+    /// line of the first plane aligned to `stride_align`. This is similar to what the kernel's
+    /// `v4l2_fill_pixfmt_mp` does.
+    ///
+    /// Returns `None` for compressed formats, since their layout depends on their content, and if
+    /// the size of a plane does not fit in 32 bits.
+    pub fn plane_layouts(
+        &self,
+        width: u32,
+        height: u32,
+        stride_align: u32,
+    ) -> Option<Vec<PlaneLayout>> {
+        if self.is_compressed() {
+            return None;
+        }
+
+        let (width, height) = (width as usize, height as usize);
+        let bytesperline = align(
+            self.plane_width(0, width) * self.bpp[0],
+            stride_align as usize,
+        );
+
+        let comp_planes = (0..self.comp_planes)
+            .map(|plane| {
+                let plane_bytesperline = self.plane_bytesperline(plane, bytesperline);
+                let plane_size =
+                    plane_bytesperline.checked_mul(self.plane_height(plane, height))?;
+
+                Some((plane_bytesperline, plane_size))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let to_u32 = |value: usize| u32::try_from(value).ok();
+
+        let layouts = if self.mem_planes == 1 {
+            // All the planes are stored contiguously in the same buffer.
+            let sizeimage = comp_planes
+                .iter()
+                .try_fold(0usize, |total, (_, size)| total.checked_add(*size))
+                .and_then(to_u32)?;
+            vec![PlaneLayout {
+                bytesperline: to_u32(bytesperline)?,
+                sizeimage,
+            }]
+        } else {
+            comp_planes
+                .into_iter()
+                .map(|(bytesperline, size)| {
+                    Some(PlaneLayout {
+                        bytesperline: to_u32(bytesperline)?,
+                        sizeimage: to_u32(size)?,
+                    })
+                })
+                .collect::<Option<_>>()?
+        };
+
+        Some(layouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layouts(format: PixelFormat, width: u32, height: u32, align: u32) -> Vec<(u32, u32)> {
+        format
+            .info()
+            .unwrap()
+            .plane_layouts(width, height, align)
+            .unwrap()
+            .into_iter()
+            .map(|l| (l.bytesperline, l.sizeimage))
+            .collect()
+    }
+
+    #[test]
+    fn test_plane_layouts() {
+        assert_eq!(layouts(PixelFormat::RGB24, 640, 480, 1), [(1920, 921600)]);
+        assert_eq!(layouts(PixelFormat::XRGB32, 641, 3, 64), [(2624, 7872)]);
+        assert_eq!(layouts(PixelFormat::YUYV, 640, 480, 1), [(1280, 614400)]);
+
+        assert_eq!(layouts(PixelFormat::NV12, 640, 480, 1), [(640, 460800)]);
+        assert_eq!(layouts(PixelFormat::NV12, 641, 481, 1), [(641, 463043)]);
+        assert_eq!(layouts(PixelFormat::NV12, 641, 481, 64), [(704, 508288)]);
+        assert_eq!(
+            layouts(PixelFormat::NV12M, 640, 480, 1),
+            [(640, 307200), (640, 153600)]
+        );
+        assert_eq!(layouts(PixelFormat::P010, 640, 480, 1), [(1280, 921600)]);
+
+        assert_eq!(layouts(PixelFormat::YUV420, 640, 480, 1), [(640, 460800)]);
+        assert_eq!(
+            layouts(PixelFormat::YUV420M, 641, 481, 1),
+            [(641, 308321), (321, 77361), (321, 77361)]
+        );
+        assert_eq!(
+            layouts(PixelFormat::YUV444M, 64, 64, 1),
+            [(64, 4096), (64, 4096), (64, 4096)]
+        );
+    }
+
+    #[test]
+    fn test_tiled_plane_layouts() {
+        assert_eq!(layouts(PixelFormat::NV12_4L4, 638, 478, 1), [(640, 460800)]);
+        assert_eq!(
+            layouts(PixelFormat::NV12MT, 640, 480, 1),
+            [(640, 307200), (640, 153600)]
+        );
+        assert_eq!(layouts(PixelFormat::MM21, 1, 1, 1), [(16, 512), (16, 256)]);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let info = PixelFormat::H264.info().unwrap();
+        assert!(info.is_compressed());
+        assert_eq!(info.plane_layouts(640, 480, 1), None);
+        assert_eq!(PixelFormat::from(b"ABCD").info(), None);
+    }
+
+    #[test]
+    fn test_oversized_plane_layouts() {
+        let info = PixelFormat::RGB24.info().unwrap();
+        assert_eq!(info.plane_layouts(u32::MAX, 1, 1), None);
+        assert_eq!(info.plane_layouts(65536, 65536, 1), None);
+        let info = PixelFormat::NV12M.info().unwrap();
+        assert_eq!(info.plane_layouts(u32::MAX, u32::MAX, 1), None);
+    }
+}
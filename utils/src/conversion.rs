@@ -0,0 +1,521 @@
+//! Software conversion between common pixel formats.
+//!
+//! This is slow and only meant for debugging and testing purposes, e.g. to turn the output of a
+//! decoder into something that can easily be viewed, whatever the format produced by the
+//! hardware. The Y'CbCr encoding and quantization of YUV formats are taken from their `Format`.
+//! RGB formats are always assumed to be full range.
+use thiserror::Error;
+use v4l2r::image::{ImageView, ImageViewError, ImageViewMut};
+use v4l2r::{Colorimetry, Colorspace, Format, PixelFormat, Quantization, Rect, YCbCrEncoding};
+
+#[derive(Debug, Error)]
+pub enum ConversionError {
+    #[error("conversion from or to {0} is not supported")]
+    UnsupportedFormat(PixelFormat),
+    #[error("cannot convert a {0}x{1} image into a {2}x{3} one")]
+    SizeMismatch(usize, usize, usize, usize),
+    #[error("visible rectangle is not aligned to the chroma subsampling of the format")]
+    UnalignedRect,
+    #[error("invalid image: {0}")]
+    ImageView(#[from] ImageViewError),
+}
+
+/// How the components of a pixel format are laid out in memory.
+#[derive(Clone, Copy)]
+enum Layout {
+    /// Packed RGB, with the offsets of the R, G and B components within a pixel of `bpp` bytes.
+    Rgb { offsets: [usize; 3], bpp: usize },
+    /// Luma only.
+    Grey,
+    /// Packed 4:2:2 YUV, with the offsets of the Y0, U, Y1 and V components within a 4-bytes
+    /// macropixel.
+    Packed422 { offsets: [usize; 4] },
+    /// A luma plane followed by an interleaved chroma plane. `swap` is set if V comes before U.
+    SemiPlanar { swap: bool },
+    /// Three separate planes. `swap` is set if the V plane comes before the U plane.
+    Planar { swap: bool },
+}
+
+impl Layout {
+    fn from_pixelformat(format: PixelFormat) -> Option<Self> {
+        let layout = match format {
+            PixelFormat::RGB24 => Layout::Rgb {
+                offsets: [0, 1, 2],
+                bpp: 3,
+            },
+            PixelFormat::BGR24 => Layout::Rgb {
+                offsets: [2, 1, 0],
+                bpp: 3,
+            },
+            PixelFormat::XBGR32 | PixelFormat::ABGR32 => Layout::Rgb {
+                offsets: [2, 1, 0],
+                bpp: 4,
+            },
+            PixelFormat::BGRX32 | PixelFormat::BGRA32 => Layout::Rgb {
+                offsets: [3, 2, 1],
+                bpp: 4,
+            },
+            PixelFormat::RGBX32 | PixelFormat::RGBA32 => Layout::Rgb {
+                offsets: [0, 1, 2],
+                bpp: 4,
+            },
+            PixelFormat::XRGB32 | PixelFormat::ARGB32 => Layout::Rgb {
+                offsets: [1, 2, 3],
+                bpp: 4,
+            },
+            PixelFormat::GREY => Layout::Grey,
+            PixelFormat::YUYV => Layout::Packed422 {
+                offsets: [0, 1, 2, 3],
+            },
+            PixelFormat::YVYU => Layout::Packed422 {
+                offsets: [0, 3, 2, 1],
+            },
+            PixelFormat::UYVY => Layout::Packed422 {
+                offsets: [1, 0, 3, 2],
+            },
+            PixelFormat::VYUY => Layout::Packed422 {
+                offsets: [1, 2, 3, 0],
+            },
+            PixelFormat::NV12
+            | PixelFormat::NV16
+            | PixelFormat::NV24
+            | PixelFormat::NV12M
+            | PixelFormat::NV16M => Layout::SemiPlanar { swap: false },
+            PixelFormat::NV21
+            | PixelFormat::NV61
+            | PixelFormat::NV42
+            | PixelFormat::NV21M
+            | PixelFormat::NV61M => Layout::SemiPlanar { swap: true },
+            PixelFormat::YUV420
+            | PixelFormat::YUV422P
+            | PixelFormat::YUV420M
+            | PixelFormat::YUV422M
+            | PixelFormat::YUV444M => Layout::Planar { swap: false },
+            PixelFormat::YVU420
+            | PixelFormat::YVU420M
+            | PixelFormat::YVU422M
+            | PixelFormat::YVU444M => Layout::Planar { swap: true },
+            _ => return None,
+        };
+
+        Some(layout)
+    }
+
+    fn is_yuv(&self) -> bool {
+        !matches!(self, Layout::Rgb { .. })
+    }
+}
+
+/// Coefficients used to convert between RGB and Y'CbCr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct YuvMatrix {
+    kr: f32,
+    kb: f32,
+    full_range: bool,
+}
+
+impl YuvMatrix {
+    /// Returns the matrix to use for `format`, resolving default values the same way the kernel's
+    /// `V4L2_MAP_YCBCR_ENC_DEFAULT` and `V4L2_MAP_QUANTIZATION_DEFAULT` macros do.
+    fn new(format: &Format) -> Self {
+        let colorimetry = format.colorimetry.unwrap_or_default();
+        let ycbcr_enc = match colorimetry.ycbcr_enc {
+            YCbCrEncoding::Default => match colorimetry.colorspace {
+                Colorspace::Rec709 | Colorspace::DciP3 => YCbCrEncoding::E709,
+                Colorspace::Bt2020 => YCbCrEncoding::Bt2020,
+                Colorspace::Smpte240M => YCbCrEncoding::Smpte240M,
+                _ => YCbCrEncoding::E601,
+            },
+            ycbcr_enc => ycbcr_enc,
+        };
+
+        let (kr, kb) = match ycbcr_enc {
+            YCbCrEncoding::E709 | YCbCrEncoding::Xv709 => (0.2126, 0.0722),
+            YCbCrEncoding::Bt2020 | YCbCrEncoding::Bt2020ConstLum => (0.2627, 0.0593),
+            YCbCrEncoding::Smpte240M => (0.212, 0.087),
+            _ => (0.299, 0.114),
+        };
+
+        let full_range = match colorimetry.quantization {
+            Quantization::FullRange => true,
+            Quantization::LimRange => false,
+            Quantization::Default => colorimetry.colorspace == Colorspace::Jpeg,
+        };
+
+        YuvMatrix { kr, kb, full_range }
+    }
+
+    fn yuv_to_rgb(&self, [y, u, v]: [u8; 3]) -> [u8; 3] {
+        let (y, u, v) = if self.full_range {
+            (
+                y as f32 / 255.0,
+                (u as f32 - 128.0) / 255.0,
+                (v as f32 - 128.0) / 255.0,
+            )
+        } else {
+            (
+                (y as f32 - 16.0) / 219.0,
+                (u as f32 - 128.0) / 224.0,
+                (v as f32 - 128.0) / 224.0,
+            )
+        };
+        let kg = 1.0 - self.kr - self.kb;
+
+        let r = y + 2.0 * (1.0 - self.kr) * v;
+        let b = y + 2.0 * (1.0 - self.kb) * u;
+        let g = (y - self.kr * r - self.kb * b) / kg;
+
+        [r, g, b].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8)
+    }
+
+    fn rgb_to_yuv(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let [r, g, b] = rgb.map(|c| c as f32 / 255.0);
+        let kg = 1.0 - self.kr - self.kb;
+
+        let y = self.kr * r + kg * g + self.kb * b;
+        let u = (b - y) / (2.0 * (1.0 - self.kb));
+        let v = (r - y) / (2.0 * (1.0 - self.kr));
+
+        let (y, u, v) = if self.full_range {
+            (y * 255.0, u * 255.0 + 128.0, v * 255.0 + 128.0)
+        } else {
+            (y * 219.0 + 16.0, u * 224.0 + 128.0, v * 224.0 + 128.0)
+        };
+
+        [y, u, v].map(|c| c.round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+/// Image storing all the components of each of its pixels.
+struct Picture {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Picture {
+    fn map(self, f: impl Fn([u8; 3]) -> [u8; 3]) -> Self {
+        Picture {
+            pixels: self.pixels.into_iter().map(f).collect(),
+            ..self
+        }
+    }
+}
+
+/// Read the part of the image `planes` of format `format` within `visible_rect`.
+fn read_picture<P: AsRef<[u8]>>(
+    format: &Format,
+    layout: Layout,
+    visible_rect: Option<&Rect>,
+    planes: &[P],
+) -> Result<Picture, ConversionError> {
+    let image = ImageView::new(format, visible_rect, planes)?;
+    let views = image.planes();
+    let rect = visible_rect
+        .copied()
+        .unwrap_or_else(|| Rect::new(0, 0, format.width, format.height));
+    let (left, top) = (rect.left as usize, rect.top as usize);
+    let (width, height) = (rect.width as usize, rect.height as usize);
+    // `ImageView` has validated the format, so it is guaranteed to be known.
+    let (hdiv, vdiv) = format.pixelformat.info().unwrap().subsampling(1);
+
+    // Position of the chroma sample of pixel (`x`, `y`) within the chroma planes.
+    let chroma_pos = |x: usize, y: usize| {
+        (
+            (left + x) / hdiv - left / hdiv,
+            (top + y) / vdiv - top / vdiv,
+        )
+    };
+
+    if let Layout::Packed422 { .. } = layout {
+        if left % 2 != 0 || width % 2 != 0 {
+            return Err(ConversionError::UnalignedRect);
+        }
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let luma = views[0].row(y).unwrap_or_default();
+        for x in 0..width {
+            let pixel = match layout {
+                Layout::Rgb { offsets, bpp } => offsets.map(|o| luma[x * bpp + o]),
+                Layout::Grey => [luma[x], 128, 128],
+                Layout::Packed422 { offsets } => {
+                    let macropixel = &luma[x / 2 * 4..x / 2 * 4 + 4];
+                    let y_offset = if x % 2 == 0 { offsets[0] } else { offsets[2] };
+                    [
+                        macropixel[y_offset],
+                        macropixel[offsets[1]],
+                        macropixel[offsets[3]],
+                    ]
+                }
+                Layout::SemiPlanar { swap } => {
+                    let (cx, cy) = chroma_pos(x, y);
+                    let chroma = &views[1].row(cy).unwrap_or_default()[cx * 2..cx * 2 + 2];
+                    let (u, v) = if swap {
+                        (chroma[1], chroma[0])
+                    } else {
+                        (chroma[0], chroma[1])
+                    };
+                    [luma[x], u, v]
+                }
+                Layout::Planar { swap } => {
+                    let (cx, cy) = chroma_pos(x, y);
+                    let (u_plane, v_plane) = if swap { (2, 1) } else { (1, 2) };
+                    [
+                        luma[x],
+                        views[u_plane].row(cy).unwrap_or_default()[cx],
+                        views[v_plane].row(cy).unwrap_or_default()[cx],
+                    ]
+                }
+            };
+            pixels.push(pixel);
+        }
+    }
+
+    Ok(Picture {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Write `picture` into `planes` using format `format`. Chroma samples are obtained by averaging
+/// the pixels they cover.
+fn write_picture<P: AsMut<[u8]>>(
+    picture: &Picture,
+    format: &Format,
+    layout: Layout,
+    planes: &mut [P],
+) -> Result<(), ConversionError> {
+    if (picture.width, picture.height) != (format.width as usize, format.height as usize) {
+        return Err(ConversionError::SizeMismatch(
+            picture.width,
+            picture.height,
+            format.width as usize,
+            format.height as usize,
+        ));
+    }
+
+    let mut image = ImageViewMut::new(format, None, planes)?;
+    let views = image.planes_mut();
+    let (hdiv, vdiv) = format.pixelformat.info().unwrap().subsampling(1);
+    let pixel = |x: usize, y: usize| picture.pixels[y * picture.width + x];
+
+    // Average chroma of the pixels covered by the chroma sample at (`cx`, `cy`).
+    let chroma = |cx: usize, cy: usize| {
+        let xs = cx * hdiv..std::cmp::min((cx + 1) * hdiv, picture.width);
+        let ys = cy * vdiv..std::cmp::min((cy + 1) * vdiv, picture.height);
+        let count = (xs.len() * ys.len()) as u32;
+        let (u, v) = ys.flat_map(|y| xs.clone().map(move |x| (x, y))).fold(
+            (0u32, 0u32),
+            |(u, v), (x, y)| {
+                let [_, pu, pv] = pixel(x, y);
+                (u + pu as u32, v + pv as u32)
+            },
+        );
+        ((u / count) as u8, (v / count) as u8)
+    };
+
+    for (y, row) in views[0].rows_mut().enumerate() {
+        match layout {
+            Layout::Rgb { offsets, bpp } => {
+                for (x, dst) in row.chunks_exact_mut(bpp).enumerate() {
+                    let rgb = pixel(x, y);
+                    for (c, o) in offsets.iter().enumerate() {
+                        dst[*o] = rgb[c];
+                    }
+                }
+            }
+            Layout::Packed422 { offsets } => {
+                for (mx, dst) in row.chunks_exact_mut(4).enumerate() {
+                    let (u, v) = chroma(mx, y);
+                    dst[offsets[0]] = pixel(mx * 2, y)[0];
+                    dst[offsets[1]] = u;
+                    dst[offsets[2]] = pixel(mx * 2 + 1, y)[0];
+                    dst[offsets[3]] = v;
+                }
+            }
+            Layout::Grey | Layout::SemiPlanar { .. } | Layout::Planar { .. } => {
+                for (x, dst) in row.iter_mut().enumerate() {
+                    *dst = pixel(x, y)[0];
+                }
+            }
+        }
+    }
+
+    match layout {
+        Layout::SemiPlanar { swap } => {
+            for (cy, row) in views[1].rows_mut().enumerate() {
+                for (cx, dst) in row.chunks_exact_mut(2).enumerate() {
+                    let (u, v) = chroma(cx, cy);
+                    dst.copy_from_slice(&if swap { [v, u] } else { [u, v] });
+                }
+            }
+        }
+        Layout::Planar { swap } => {
+            let (u_plane, v_plane) = if swap { (2, 1) } else { (1, 2) };
+            for (cy, row) in views[u_plane].rows_mut().enumerate() {
+                for (cx, dst) in row.iter_mut().enumerate() {
+                    *dst = chroma(cx, cy).0;
+                }
+            }
+            for (cy, row) in views[v_plane].rows_mut().enumerate() {
+                for (cx, dst) in row.iter_mut().enumerate() {
+                    *dst = chroma(cx, cy).1;
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Convert the part of the image `src` (of format `src_format`) within `visible_rect` into `dst`
+/// (of format `dst_format`). The size of the visible rectangle must match the size of
+/// `dst_format`.
+///
+/// `src` and `dst` must contain one entry per memory plane of their format, e.g. one for NV12 and
+/// two for NV12M.
+pub fn convert<S: AsRef<[u8]>, D: AsMut<[u8]>>(
+    src_format: &Format,
+    visible_rect: Option<&Rect>,
+    src: &[S],
+    dst_format: &Format,
+    dst: &mut [D],
+) -> Result<(), ConversionError> {
+    let src_layout = Layout::from_pixelformat(src_format.pixelformat)
+        .ok_or(ConversionError::UnsupportedFormat(src_format.pixelformat))?;
+    let dst_layout = Layout::from_pixelformat(dst_format.pixelformat)
+        .ok_or(ConversionError::UnsupportedFormat(dst_format.pixelformat))?;
+    let src_matrix = YuvMatrix::new(src_format);
+    let dst_matrix = YuvMatrix::new(dst_format);
+
+    let picture = read_picture(src_format, src_layout, visible_rect, src)?;
+    let picture = match (src_layout.is_yuv(), dst_layout.is_yuv()) {
+        (false, false) => picture,
+        (true, false) => picture.map(|p| src_matrix.yuv_to_rgb(p)),
+        (false, true) => picture.map(|p| dst_matrix.rgb_to_yuv(p)),
+        (true, true) if src_matrix == dst_matrix => picture,
+        (true, true) => picture.map(|p| dst_matrix.rgb_to_yuv(src_matrix.yuv_to_rgb(p))),
+    };
+
+    write_picture(&picture, dst_format, dst_layout, dst)
+}
+
+/// Convert the part of the image `src` (of format `src_format`) within `visible_rect` into a
+/// tightly packed RGB24 frame. Returns the format and data of the converted frame.
+pub fn to_rgb24<S: AsRef<[u8]>>(
+    src_format: &Format,
+    visible_rect: Option<&Rect>,
+    src: &[S],
+) -> Result<(Format, Vec<u8>), ConversionError> {
+    let (width, height) = match visible_rect {
+        Some(rect) => (rect.width, rect.height),
+        None => (src_format.width, src_format.height),
+    };
+    let rgb_format = Format {
+        width,
+        height,
+        pixelformat: PixelFormat::RGB24,
+        // Unwrap is safe since the layout of RGB24 is always known.
+        plane_fmt: PixelFormat::RGB24
+            .info()
+            .and_then(|info| info.plane_layouts(width, height, 1))
+            .unwrap(),
+        colorimetry: Some(Colorimetry {
+            colorspace: Colorspace::Srgb,
+            ..Default::default()
+        }),
+    };
+
+    let mut data = [vec![0u8; rgb_format.plane_fmt[0].sizeimage as usize]];
+    convert(src_format, visible_rect, src, &rgb_format, &mut data)?;
+    let [data] = data;
+
+    Ok((rgb_format, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(pixelformat: PixelFormat, width: u32, height: u32) -> Format {
+        Format {
+            width,
+            height,
+            pixelformat,
+            plane_fmt: pixelformat
+                .info()
+                .unwrap()
+                .plane_layouts(width, height, 1)
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn alloc(format: &Format) -> Vec<Vec<u8>> {
+        format
+            .plane_fmt
+            .iter()
+            .map(|p| vec![0u8; p.sizeimage as usize])
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // 4x2 image made of four 2x2 blocks of solid colors.
+        let colors = [[255u8, 0, 0], [0, 255, 0], [0, 0, 255], [128, 128, 128]];
+        let rgb_format = format(PixelFormat::RGB24, 8, 2);
+        let rgb = [(0..2)
+            .flat_map(|_| (0..8).flat_map(|x| colors[x / 2]))
+            .collect::<Vec<u8>>()];
+
+        for pixelformat in [
+            PixelFormat::NV12,
+            PixelFormat::NV21M,
+            PixelFormat::YUYV,
+            PixelFormat::UYVY,
+            PixelFormat::YVU420,
+            PixelFormat::YUV420M,
+            PixelFormat::XRGB32,
+            PixelFormat::BGR24,
+        ] {
+            let yuv_format = format(pixelformat, 8, 2);
+            let mut yuv = alloc(&yuv_format);
+            convert(&rgb_format, None, &rgb, &yuv_format, &mut yuv).unwrap();
+
+            let (_, rgb2) = to_rgb24(&yuv_format, None, &yuv).unwrap();
+            for (a, b) in rgb[0].iter().zip(rgb2.iter()) {
+                assert!(
+                    (*a as i32 - *b as i32).abs() <= 2,
+                    "{}: {:?} != {:?}",
+                    pixelformat,
+                    rgb[0],
+                    rgb2
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantization() {
+        let mut nv12_format = format(PixelFormat::NV12, 2, 2);
+        let nv12 = [vec![16u8, 16, 235, 235, 128, 128]];
+
+        nv12_format.colorimetry = Some(Colorimetry {
+            quantization: Quantization::LimRange,
+            ..Default::default()
+        });
+        let (_, rgb) = to_rgb24(&nv12_format, None, &nv12).unwrap();
+        assert_eq!(rgb, [0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255]);
+
+        nv12_format.colorimetry = Some(Colorimetry {
+            quantization: Quantization::FullRange,
+            ..Default::default()
+        });
+        let (_, rgb) = to_rgb24(&nv12_format, None, &nv12).unwrap();
+        assert_eq!(rgb, [16, 16, 16, 16, 16, 16, 235, 235, 235, 235, 235, 235]);
+    }
+}
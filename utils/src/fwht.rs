@@ -0,0 +1,1139 @@
+//! Software implementation of the FWHT codec of the vicodec driver.
+//!
+//! This is a port of vicodec's `codec-fwht.c`: frames are split into 8x8 blocks that are either
+//! intra-coded or coded as the difference with the same block of the previous frame, transformed
+//! using a Walsh-Hadamard transform, quantized and run-length encoded. The arithmetic of the
+//! driver is reproduced exactly, so the output of [`FwhtEncoder`] and [`FwhtDecoder`] can be
+//! compared bit-for-bit with the output of vicodec, as long as the components of the frame have
+//! dimensions that are multiples of 8.
+//!
+//! Components which dimensions are not multiples of 8 are padded by repeating their last column
+//! and line, whereas vicodec encodes whatever follows the component in memory. Only the visible
+//! part of the decoded frames is guaranteed to match in that case.
+use std::convert::TryInto;
+
+use thiserror::Error;
+use v4l2r::decoder::format::fwht::{FwhtFlags, FwhtHeader, FwhtPixelEncoding, FWHT_VERSION};
+use v4l2r::image::{ImageView, ImageViewMut};
+use v4l2r::{Format, PixelFormat};
+
+/// Default number of frames between two I-frames, as in vicodec.
+pub const DEFAULT_GOP_SIZE: u32 = 10;
+/// Default quantization parameter of I-frames, as in vicodec.
+pub const DEFAULT_I_FRAME_QP: u16 = 20;
+/// Default quantization parameter of P-frames, as in vicodec.
+pub const DEFAULT_P_FRAME_QP: u16 = 20;
+/// Largest frame width supported by the decoder, as with the vicodec driver.
+pub const MAX_WIDTH: u32 = 4096;
+/// Largest frame height supported by the decoder, as with the vicodec driver.
+pub const MAX_HEIGHT: u32 = 2160;
+
+/// Returned by `derlc` if the compressed data ends before the block.
+const OVERFLOW_BIT: u16 = 1 << 14;
+/// Set in the header of the blocks coded as a difference with the reference frame.
+const PFRAME_BIT: u16 = 1 << 15;
+/// Number of repetitions of a block, times 2, in its header.
+const DUPS_MASK: u16 = 0x1ffe;
+/// Run length marking that all the remaining coefficients of a block are zero.
+const ALL_ZEROS: u16 = 15;
+
+/// Per-plane encoding results.
+const FRAME_PCODED: u32 = 1 << 0;
+const FRAME_UNENCODED: u32 = 1 << 1;
+
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+    0,
+    1,  8,
+    2,  9, 16,
+    3, 10, 17, 24,
+    4, 11, 18, 25, 32,
+    5, 12, 19, 26, 33, 40,
+    6, 13, 20, 27, 34, 41, 48,
+    7, 14, 21, 28, 35, 42, 49, 56,
+    15, 22, 29, 36, 43, 50, 57,
+    23, 30, 37, 44, 51, 58,
+    31, 38, 45, 52, 59,
+    39, 46, 53, 60,
+    47, 54, 61,
+    55, 62,
+    63,
+];
+
+#[rustfmt::skip]
+const QUANT_TABLE: [u32; 64] = [
+    2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 3,
+    2, 2, 2, 2, 2, 2, 3, 6,
+    2, 2, 2, 2, 2, 3, 6, 6,
+    2, 2, 2, 2, 3, 6, 6, 6,
+    2, 2, 2, 3, 6, 6, 6, 6,
+    2, 2, 3, 6, 6, 6, 6, 8,
+];
+
+#[rustfmt::skip]
+const QUANT_TABLE_P: [u32; 64] = [
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 6,
+    3, 3, 3, 3, 3, 3, 6, 6,
+    3, 3, 3, 3, 3, 6, 6, 9,
+    3, 3, 3, 3, 6, 6, 9, 9,
+    3, 3, 3, 6, 6, 9, 9, 10,
+];
+
+type Block = [i16; 64];
+
+#[derive(Debug, Error)]
+pub enum FwhtError {
+    #[error("pixel format {0} is not supported by the FWHT codec")]
+    UnsupportedFormat(PixelFormat),
+    #[error("image is {0}x{1} but {2}x{3} was expected")]
+    SizeMismatch(usize, usize, usize, usize),
+    #[error("invalid FWHT frame header")]
+    InvalidHeader,
+    #[error("unsupported FWHT version {0}")]
+    UnsupportedVersion(u32),
+    #[error("frame size {0}x{1} is not supported")]
+    UnsupportedSize(u32, u32),
+    #[error("frame has {0} components of encoding {1:?}, which is not supported")]
+    UnsupportedComponents(usize, Option<FwhtPixelEncoding>),
+    #[error("frame is smaller than the size given in its header")]
+    TruncatedFrame,
+    #[error("compressed data is corrupted")]
+    CorruptedData,
+    #[error("no frame has been decoded yet")]
+    NoFrame,
+    #[error("pixel format {0} does not match the layout of the decoded frame")]
+    FormatMismatch(PixelFormat),
+}
+
+/// Location of one component (e.g. luma) in the planes of an `ImageView`.
+#[derive(Clone, Copy)]
+struct Component {
+    /// Index of the component plane containing the component.
+    plane: usize,
+    /// Offset of the component within a sample of the plane.
+    offset: usize,
+    /// Number of samples between two values of the component.
+    step: usize,
+}
+
+/// How a pixel format maps to the components of a FWHT frame.
+struct FormatLayout {
+    encoding: FwhtPixelEncoding,
+    /// Horizontal and vertical subsampling of the chroma components.
+    subsampling: (usize, usize),
+    /// Luma (or G), Cb (or B), Cr (or R) and alpha components, in that order.
+    components: Vec<Component>,
+}
+
+impl FormatLayout {
+    fn from_pixelformat(format: PixelFormat) -> Option<Self> {
+        use FwhtPixelEncoding::{Rgb, Yuv};
+
+        // Components as (plane, offset, step).
+        let (encoding, subsampling, components): (_, _, &[(usize, usize, usize)]) = match format {
+            PixelFormat::GREY => (Yuv, (1, 1), &[(0, 0, 1)]),
+            PixelFormat::YUV420 | PixelFormat::YUV420M => {
+                (Yuv, (2, 2), &[(0, 0, 1), (1, 0, 1), (2, 0, 1)])
+            }
+            PixelFormat::YVU420 | PixelFormat::YVU420M => {
+                (Yuv, (2, 2), &[(0, 0, 1), (2, 0, 1), (1, 0, 1)])
+            }
+            PixelFormat::YUV422P | PixelFormat::YUV422M => {
+                (Yuv, (2, 1), &[(0, 0, 1), (1, 0, 1), (2, 0, 1)])
+            }
+            PixelFormat::YVU422M => (Yuv, (2, 1), &[(0, 0, 1), (2, 0, 1), (1, 0, 1)]),
+            PixelFormat::YUV444M => (Yuv, (1, 1), &[(0, 0, 1), (1, 0, 1), (2, 0, 1)]),
+            PixelFormat::YVU444M => (Yuv, (1, 1), &[(0, 0, 1), (2, 0, 1), (1, 0, 1)]),
+            PixelFormat::NV12 | PixelFormat::NV12M => {
+                (Yuv, (2, 2), &[(0, 0, 1), (1, 0, 1), (1, 1, 1)])
+            }
+            PixelFormat::NV21 | PixelFormat::NV21M => {
+                (Yuv, (2, 2), &[(0, 0, 1), (1, 1, 1), (1, 0, 1)])
+            }
+            PixelFormat::NV16 | PixelFormat::NV16M => {
+                (Yuv, (2, 1), &[(0, 0, 1), (1, 0, 1), (1, 1, 1)])
+            }
+            PixelFormat::NV61 | PixelFormat::NV61M => {
+                (Yuv, (2, 1), &[(0, 0, 1), (1, 1, 1), (1, 0, 1)])
+            }
+            PixelFormat::NV24 => (Yuv, (1, 1), &[(0, 0, 1), (1, 0, 1), (1, 1, 1)]),
+            PixelFormat::NV42 => (Yuv, (1, 1), &[(0, 0, 1), (1, 1, 1), (1, 0, 1)]),
+            PixelFormat::YUYV => (Yuv, (2, 1), &[(0, 0, 1), (0, 1, 2), (0, 3, 2)]),
+            PixelFormat::YVYU => (Yuv, (2, 1), &[(0, 0, 1), (0, 3, 2), (0, 1, 2)]),
+            PixelFormat::UYVY => (Yuv, (2, 1), &[(0, 1, 1), (0, 0, 2), (0, 2, 2)]),
+            PixelFormat::VYUY => (Yuv, (2, 1), &[(0, 1, 1), (0, 2, 2), (0, 0, 2)]),
+            PixelFormat::RGB24 => (Rgb, (1, 1), &[(0, 1, 1), (0, 2, 1), (0, 0, 1)]),
+            PixelFormat::BGR24 => (Rgb, (1, 1), &[(0, 1, 1), (0, 0, 1), (0, 2, 1)]),
+            PixelFormat::XRGB32 => (Rgb, (1, 1), &[(0, 2, 1), (0, 3, 1), (0, 1, 1)]),
+            PixelFormat::ARGB32 => (Rgb, (1, 1), &[(0, 2, 1), (0, 3, 1), (0, 1, 1), (0, 0, 1)]),
+            PixelFormat::XBGR32 => (Rgb, (1, 1), &[(0, 1, 1), (0, 0, 1), (0, 2, 1)]),
+            PixelFormat::ABGR32 => (Rgb, (1, 1), &[(0, 1, 1), (0, 0, 1), (0, 2, 1), (0, 3, 1)]),
+            PixelFormat::BGRX32 => (Rgb, (1, 1), &[(0, 2, 1), (0, 1, 1), (0, 3, 1)]),
+            PixelFormat::BGRA32 => (Rgb, (1, 1), &[(0, 2, 1), (0, 1, 1), (0, 3, 1), (0, 0, 1)]),
+            PixelFormat::RGBX32 => (Rgb, (1, 1), &[(0, 1, 1), (0, 2, 1), (0, 0, 1)]),
+            PixelFormat::RGBA32 => (Rgb, (1, 1), &[(0, 1, 1), (0, 2, 1), (0, 0, 1), (0, 3, 1)]),
+            _ => return None,
+        };
+
+        Some(FormatLayout {
+            encoding,
+            subsampling,
+            components: components
+                .iter()
+                .map(|&(plane, offset, step)| Component {
+                    plane,
+                    offset,
+                    step,
+                })
+                .collect(),
+        })
+    }
+
+    /// Whether frames using this layout can be decoded from or into frames described by
+    /// `header`.
+    fn matches(&self, header: &FwhtHeader) -> bool {
+        self.components.len() == header.components_num()
+            && Some(self.encoding) == header.pixel_encoding()
+            && (self.components.len() < 3 || self.subsampling == header.chroma_subsampling())
+    }
+}
+
+/// Returns a pixel format that frames described by `header` can be decoded into: GREY, YUV420,
+/// YUV422P or YUV444M for YUV frames, and RGB24 or ARGB32 for RGB frames.
+pub fn default_pixelformat(header: &FwhtHeader) -> Option<PixelFormat> {
+    [
+        PixelFormat::GREY,
+        PixelFormat::YUV420,
+        PixelFormat::YUV422P,
+        PixelFormat::YUV444M,
+        PixelFormat::RGB24,
+        PixelFormat::ARGB32,
+    ]
+    .iter()
+    .copied()
+    .find(|f| FormatLayout::from_pixelformat(*f).is_some_and(|l| l.matches(header)))
+}
+
+/// Whether frames described by `header` can be decoded into images of format `pixelformat`.
+pub fn is_compatible(pixelformat: PixelFormat, header: &FwhtHeader) -> bool {
+    FormatLayout::from_pixelformat(pixelformat).is_some_and(|l| l.matches(header))
+}
+
+/// One component of a frame, with its dimensions rounded up to a multiple of 8.
+#[derive(Clone)]
+struct Plane {
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        let (width, height) = (round_up(width), round_up(height));
+        Plane {
+            data: vec![0; width * height],
+            width,
+            height,
+        }
+    }
+
+    /// Fill the plane with `component` of `image`, repeating the last column and line to fill
+    /// the padding.
+    fn copy_from_image(&mut self, image: &ImageView, component: &Component) {
+        let plane = &image.planes()[component.plane];
+        let sample_size = plane.bytes_per_sample() * component.step;
+        let width = plane.width().div_ceil(component.step);
+        let rows = plane.rows().collect::<Vec<_>>();
+
+        for (y, line) in self.data.chunks_exact_mut(self.width).enumerate() {
+            let row = rows[y.min(rows.len() - 1)];
+            for (x, value) in line.iter_mut().enumerate() {
+                *value = row[x.min(width - 1) * sample_size + component.offset];
+            }
+        }
+    }
+
+    /// Write the plane into `component` of `image`.
+    fn copy_to_image(&self, image: &mut ImageViewMut, component: &Component) {
+        let plane = &mut image.planes_mut()[component.plane];
+        let sample_size = plane.bytes_per_sample() * component.step;
+        let width = plane.width().div_ceil(component.step);
+
+        for (y, row) in plane.rows_mut().enumerate() {
+            let line = &self.data[y.min(self.height - 1) * self.width..][..self.width];
+            for x in 0..width {
+                row[x * sample_size + component.offset] = line[x.min(self.width - 1)];
+            }
+        }
+    }
+}
+
+fn round_up(dim: usize) -> usize {
+    dim.div_ceil(8) * 8
+}
+
+/// Dimensions of each component of a `width`x`height` frame with `num_components` components,
+/// before rounding.
+fn component_sizes(
+    width: usize,
+    height: usize,
+    num_components: usize,
+    (hdiv, vdiv): (usize, usize),
+) -> Vec<(usize, usize)> {
+    (0..num_components)
+        .map(|c| match c {
+            1 | 2 => (width / hdiv, height / vdiv),
+            _ => (width, height),
+        })
+        .collect()
+}
+
+/// Copy the 8x8 block of `plane` at (`x`, `y`).
+fn read_block(plane: &Plane, x: usize, y: usize) -> Block {
+    let mut block = [0; 64];
+    for (j, line) in block.chunks_exact_mut(8).enumerate() {
+        let start = (y + j) * plane.width + x;
+        for (value, pixel) in line.iter_mut().zip(&plane.data[start..start + 8]) {
+            *value = *pixel as i16;
+        }
+    }
+    block
+}
+
+/// Write `block` at (`x`, `y`) of `plane`, clamping its values to the range of a pixel.
+fn write_block(plane: &mut Plane, x: usize, y: usize, block: &Block) {
+    for (j, line) in block.chunks_exact(8).enumerate() {
+        let start = (y + j) * plane.width + x;
+        for (pixel, value) in plane.data[start..start + 8].iter_mut().zip(line) {
+            *pixel = (*value).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Add the block of `reference` at (`x`, `y`) to `deltas`, clamping the result.
+fn add_deltas(deltas: &mut Block, reference: &Plane, x: usize, y: usize) {
+    let reference = read_block(reference, x, y);
+    for (delta, value) in deltas.iter_mut().zip(reference.iter()) {
+        *delta = delta.wrapping_add(*value).clamp(0, 255);
+    }
+}
+
+/// One butterfly pass of the transform over the 8 values `input(0..8)`.
+fn butterfly(input: impl Fn(usize) -> i32, add: i32) -> [i32; 8] {
+    let w1 = [
+        input(0) + input(1) - add,
+        input(0) - input(1),
+        input(2) + input(3) - add,
+        input(2) - input(3),
+        input(4) + input(5) - add,
+        input(4) - input(5),
+        input(6) + input(7) - add,
+        input(6) - input(7),
+    ];
+    let w2 = [
+        w1[0] + w1[2],
+        w1[0] - w1[2],
+        w1[1] - w1[3],
+        w1[1] + w1[3],
+        w1[4] + w1[6],
+        w1[4] - w1[6],
+        w1[5] - w1[7],
+        w1[5] + w1[7],
+    ];
+    [
+        w2[0] + w2[4],
+        w2[0] - w2[4],
+        w2[1] - w2[5],
+        w2[1] + w2[5],
+        w2[2] + w2[6],
+        w2[2] - w2[6],
+        w2[3] - w2[7],
+        w2[3] + w2[7],
+    ]
+}
+
+/// Forward transform of `block`. Intra blocks are centered around 0 by subtracting 128 from
+/// each pixel.
+fn fwht(block: &Block, intra: bool) -> Block {
+    let add = if intra { 256 } else { 0 };
+    let mut out = [0i16; 64];
+
+    for i in 0..8 {
+        let row = butterfly(|x| block[i * 8 + x] as i32, add);
+        for (x, value) in row.iter().enumerate() {
+            out[i * 8 + x] = *value as i16;
+        }
+    }
+    for i in 0..8 {
+        let column = butterfly(|y| out[y * 8 + i] as i32, 0);
+        for (y, value) in column.iter().enumerate() {
+            out[y * 8 + i] = *value as i16;
+        }
+    }
+
+    out
+}
+
+/// Inverse transform of `block`.
+fn ifwht(block: &Block, intra: bool) -> Block {
+    let mut out = [0i16; 64];
+
+    for i in 0..8 {
+        let row = butterfly(|x| block[i * 8 + x] as i32, 0);
+        for (x, value) in row.iter().enumerate() {
+            out[i * 8 + x] = *value as i16;
+        }
+    }
+    for i in 0..8 {
+        let column = butterfly(|y| out[y * 8 + i] as i32, 0);
+        for (y, value) in column.iter().enumerate() {
+            let value = (*value as i16 as i32 >> 6) as i16;
+            out[y * 8 + i] = if intra {
+                (value as i32 + 128) as i16
+            } else {
+                value
+            };
+        }
+    }
+
+    out
+}
+
+/// Quantize `coeffs` in place, and return the coefficients the decoder will reconstruct from
+/// them.
+fn quantize(coeffs: &mut Block, qp: u16, intra: bool) -> Block {
+    let table = if intra { &QUANT_TABLE } else { &QUANT_TABLE_P };
+    let qp = qp as i32;
+    let mut de_coeffs = [0i16; 64];
+
+    for ((coeff, de_coeff), quant) in coeffs.iter_mut().zip(de_coeffs.iter_mut()).zip(table) {
+        *coeff >>= quant;
+        if (-qp..=qp).contains(&(*coeff as i32)) {
+            *coeff = 0;
+        } else {
+            *de_coeff = ((*coeff as i32) << quant) as i16;
+        }
+    }
+
+    de_coeffs
+}
+
+fn dequantize(coeffs: &mut Block, intra: bool) {
+    let table = if intra { &QUANT_TABLE } else { &QUANT_TABLE_P };
+    for (coeff, quant) in coeffs.iter_mut().zip(table) {
+        *coeff = ((*coeff as i32) << quant) as i16;
+    }
+}
+
+/// Sum of the absolute differences between `block` and its mean.
+fn var_intra(block: &Block) -> i32 {
+    let mean = block.iter().map(|v| *v as i32).sum::<i32>() / 64;
+    block.iter().map(|v| (*v as i32 - mean).abs()).sum()
+}
+
+/// Sum of the absolute differences between `old` and `new`.
+fn var_inter(old: &Block, new: &Block) -> i32 {
+    old.iter()
+        .zip(new.iter())
+        .map(|(o, n)| (*o as i32 - *n as i32).abs())
+        .sum()
+}
+
+/// Run-length encode the coefficients of `block` in zigzag order into `output`.
+fn rlc(block: &Block, output: &mut Vec<u16>, intra: bool) {
+    let lastzero_run = ZIGZAG.iter().rev().take_while(|&&i| block[i] == 0).count();
+    let to_encode = 64 - if lastzero_run > 14 { lastzero_run } else { 0 };
+
+    output.push(if intra { 0 } else { PFRAME_BIT });
+
+    let mut i = 0;
+    while i < to_encode {
+        let mut cnt = 0;
+        let mut coeff;
+        loop {
+            coeff = block[ZIGZAG[i]];
+            if coeff != 0 || cnt >= 14 {
+                break;
+            }
+            cnt += 1;
+            i += 1;
+            if i == to_encode {
+                cnt -= 1;
+                break;
+            }
+        }
+        // 4 bits for the run of zeros, 12 for the coefficient.
+        output.push((cnt | ((coeff as i32) << 4)) as u16);
+        i += 1;
+    }
+
+    if lastzero_run > 14 {
+        output.push(ALL_ZEROS);
+    }
+}
+
+/// Reads the 16-bit words of compressed data.
+struct RlcReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RlcReader<'a> {
+    fn read(&mut self) -> Option<u16> {
+        let word = self.data.get(self.pos * 2..self.pos * 2 + 2)?;
+        self.pos += 1;
+        Some(u16::from_be_bytes(word.try_into().unwrap()))
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() / 2 - self.pos
+    }
+
+    /// Take the next `len` bytes of uncompressed data.
+    fn read_raw(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.remaining() < len / 2 {
+            return None;
+        }
+        let raw = &self.data[self.pos * 2..self.pos * 2 + len];
+        self.pos += len / 2;
+        Some(raw)
+    }
+}
+
+/// Decode the next block of `input` into `block`, and return its header or `OVERFLOW_BIT` if the
+/// data ends before the block.
+fn derlc(input: &mut RlcReader, block: &mut Block) -> u16 {
+    let stat = match input.read() {
+        Some(stat) => stat,
+        None => return OVERFLOW_BIT,
+    };
+
+    // A run can go up to 14 values past the end of the block if the data is malformed.
+    let mut decoded = [0i16; 64 + 16];
+    let mut dec_count = 0;
+    while dec_count < 64 {
+        let value = match input.read() {
+            Some(value) => value as i16,
+            None => return OVERFLOW_BIT,
+        };
+        let length = (value & 0xf) as usize;
+        if length == ALL_ZEROS as usize {
+            break;
+        }
+        decoded[dec_count + length] = value >> 4;
+        dec_count += length + 1;
+    }
+
+    for (i, pos) in ZIGZAG.iter().enumerate() {
+        block[*pos] = decoded[i];
+    }
+
+    stat
+}
+
+/// Encode `input` into `output`, using and updating `reference`. Falls back to storing the plane
+/// uncompressed if its compressed data would reach `rlco_max` words.
+#[allow(clippy::too_many_arguments)]
+fn encode_plane(
+    input: &Plane,
+    reference: &mut Plane,
+    output: &mut Vec<u16>,
+    rlco_max: isize,
+    qp: (u16, u16),
+    is_intra: bool,
+    next_is_intra: bool,
+) -> u32 {
+    let rlco_start = output.len();
+    let mut encoding = 0;
+    let mut last_size = 0;
+
+    'blocks: for y in (0..input.height).step_by(8) {
+        for x in (0..input.width).step_by(8) {
+            let block = read_block(input, x, y);
+            let mut intra = true;
+            let mut deltas = [0i16; 64];
+
+            if !is_intra {
+                let old = read_block(reference, x, y);
+                for (delta, (new, old)) in deltas.iter_mut().zip(block.iter().zip(old.iter())) {
+                    *delta = new - old;
+                }
+                intra = var_intra(&block) <= var_inter(&old, &block);
+            }
+
+            let (coeffs, de_coeffs) = if intra {
+                let mut coeffs = fwht(&block, true);
+                let de_coeffs = quantize(&mut coeffs, qp.0, true);
+                (coeffs, de_coeffs)
+            } else {
+                encoding |= FRAME_PCODED;
+                let mut coeffs = fwht(&deltas, false);
+                let de_coeffs = quantize(&mut coeffs, qp.1, false);
+                (coeffs, de_coeffs)
+            };
+
+            if !next_is_intra {
+                let mut de_fwht = ifwht(&de_coeffs, intra);
+                if !intra {
+                    add_deltas(&mut de_fwht, reference, x, y);
+                }
+                write_block(reference, x, y, &de_fwht);
+            }
+
+            let start = output.len();
+            rlc(&coeffs, output, intra);
+            let size = output.len() - start;
+
+            // Identical consecutive blocks are only stored once, with a repetition count in the
+            // header of the first one.
+            if last_size == size && output[start + 1..] == output[start + 1 - size..start] {
+                let last_header = output[start - size];
+                if (last_header ^ output[start]) & PFRAME_BIT == 0
+                    && last_header & DUPS_MASK < DUPS_MASK
+                {
+                    output.truncate(start);
+                    output[start - size] = last_header + 2;
+                }
+            }
+            if output.len() as isize >= rlco_max {
+                encoding |= FRAME_UNENCODED;
+                break 'blocks;
+            }
+            last_size = size;
+        }
+    }
+
+    if encoding & FRAME_UNENCODED != 0 {
+        // The compressed data must never contain the frame magic, so 0xff is replaced by 0xfe.
+        // YUV data is limited range anyway, so this value should not appear.
+        output.truncate(rlco_start);
+        output.extend(input.data.chunks_exact(2).map(|pair| {
+            let byte = |b: u8| if b == 0xff { 0xfe } else { b } as u16;
+            (byte(pair[0]) << 8) | byte(pair[1])
+        }));
+        encoding &= !FRAME_PCODED;
+    }
+
+    encoding
+}
+
+/// Decode a plane from `input` into `plane`, which also contains the reference frame.
+fn decode_plane(
+    input: &mut RlcReader,
+    plane: &mut Plane,
+    uncompressed: bool,
+) -> Result<(), FwhtError> {
+    if uncompressed {
+        let raw = input
+            .read_raw(plane.data.len())
+            .ok_or(FwhtError::CorruptedData)?;
+        plane.data.copy_from_slice(raw);
+        return Ok(());
+    }
+
+    let mut copies = 0;
+    let mut copy = [0i16; 64];
+    let mut stat = 0;
+
+    for y in (0..plane.height).step_by(8) {
+        for x in (0..plane.width).step_by(8) {
+            let mut de_fwht = if copies > 0 {
+                copies -= 1;
+                copy
+            } else {
+                let mut coeffs = [0i16; 64];
+                stat = derlc(input, &mut coeffs);
+                if stat & OVERFLOW_BIT != 0 {
+                    return Err(FwhtError::CorruptedData);
+                }
+                let intra = stat & PFRAME_BIT == 0;
+                dequantize(&mut coeffs, intra);
+                let de_fwht = ifwht(&coeffs, intra);
+
+                copies = (stat & DUPS_MASK) >> 1;
+                copy = de_fwht;
+                de_fwht
+            };
+
+            if stat & PFRAME_BIT != 0 {
+                add_deltas(&mut de_fwht, plane, x, y);
+            }
+            write_block(plane, x, y, &de_fwht);
+        }
+    }
+
+    Ok(())
+}
+
+/// Software FWHT encoder producing the same stream as vicodec's stateful encoder.
+pub struct FwhtEncoder {
+    pixelformat: PixelFormat,
+    layout: FormatLayout,
+    width: usize,
+    height: usize,
+    colorimetry: [u32; 4],
+    gop_size: u32,
+    gop_cnt: u32,
+    i_frame_qp: u16,
+    p_frame_qp: u16,
+    frame: Vec<Plane>,
+    reference: Vec<Plane>,
+}
+
+impl FwhtEncoder {
+    /// Create an encoder for images of `format`. The size of the encoded frames is the size of
+    /// `format`, and its colorimetry is recorded in the frame headers.
+    pub fn new(format: &Format) -> Result<Self, FwhtError> {
+        let layout = FormatLayout::from_pixelformat(format.pixelformat)
+            .ok_or(FwhtError::UnsupportedFormat(format.pixelformat))?;
+        let (width, height) = (format.width as usize, format.height as usize);
+        let frame = component_sizes(width, height, layout.components.len(), layout.subsampling)
+            .into_iter()
+            .map(|(w, h)| Plane::new(w, h))
+            .collect::<Vec<_>>();
+        let colorimetry = format.colorimetry.unwrap_or_default();
+
+        Ok(FwhtEncoder {
+            pixelformat: format.pixelformat,
+            layout,
+            width,
+            height,
+            colorimetry: [
+                colorimetry.colorspace as u32,
+                colorimetry.xfer_func as u32,
+                colorimetry.ycbcr_enc as u32,
+                colorimetry.quantization as u32,
+            ],
+            gop_size: DEFAULT_GOP_SIZE,
+            gop_cnt: 0,
+            i_frame_qp: DEFAULT_I_FRAME_QP,
+            p_frame_qp: DEFAULT_P_FRAME_QP,
+            reference: frame.clone(),
+            frame,
+        })
+    }
+
+    /// Set the number of frames between two I-frames. A value of 1 encodes all frames as
+    /// I-frames.
+    pub fn set_gop_size(&mut self, gop_size: u32) {
+        self.gop_size = gop_size.max(1);
+        self.gop_cnt = 0;
+    }
+
+    /// Set the quantization parameters of I-frames and P-frames. Larger values give smaller and
+    /// lower quality frames. vicodec accepts values between 1 and 31.
+    pub fn set_qp(&mut self, i_frame_qp: u16, p_frame_qp: u16) {
+        self.i_frame_qp = i_frame_qp;
+        self.p_frame_qp = p_frame_qp;
+    }
+
+    /// Encode `image`, which must have the format and size given at creation time, and return
+    /// the compressed frame, header included.
+    pub fn encode(&mut self, image: &ImageView) -> Result<Vec<u8>, FwhtError> {
+        if image.pixelformat() != self.pixelformat {
+            return Err(FwhtError::UnsupportedFormat(image.pixelformat()));
+        }
+        let luma = &image.planes()[self.layout.components[0].plane];
+        let image_width = luma.width().div_ceil(self.layout.components[0].step);
+        if (image_width, luma.height()) != (self.width, self.height) {
+            return Err(FwhtError::SizeMismatch(
+                image_width,
+                luma.height(),
+                self.width,
+                self.height,
+            ));
+        }
+
+        for (plane, component) in self.frame.iter_mut().zip(&self.layout.components) {
+            plane.copy_from_image(image, component);
+        }
+
+        let is_intra = self.gop_cnt == 0;
+        let next_is_intra = self.gop_cnt == self.gop_size - 1;
+        let sizes = component_sizes(
+            self.width,
+            self.height,
+            self.layout.components.len(),
+            self.layout.subsampling,
+        );
+        let unencoded_flags = [
+            FwhtFlags::LUMA_IS_UNCOMPRESSED,
+            FwhtFlags::CB_IS_UNCOMPRESSED,
+            FwhtFlags::CR_IS_UNCOMPRESSED,
+            FwhtFlags::ALPHA_IS_UNCOMPRESSED,
+        ];
+
+        let mut data = Vec::new();
+        let mut flags = FwhtFlags::empty();
+        let mut pcoded = false;
+        for (i, (input, reference)) in self.frame.iter().zip(&mut self.reference).enumerate() {
+            let (w, h) = sizes[i];
+            let rlco_max = data.len() as isize + (w * h / 2) as isize - 256;
+            let encoding = encode_plane(
+                input,
+                reference,
+                &mut data,
+                rlco_max,
+                (self.i_frame_qp, self.p_frame_qp),
+                is_intra,
+                next_is_intra,
+            );
+            if encoding & FRAME_UNENCODED != 0 {
+                flags |= unencoded_flags[i];
+            }
+            pcoded |= encoding & FRAME_PCODED != 0;
+        }
+
+        if !pcoded {
+            self.gop_cnt = 0;
+            flags |= FwhtFlags::I_FRAME;
+        }
+        self.gop_cnt += 1;
+        if self.gop_cnt >= self.gop_size {
+            self.gop_cnt = 0;
+        }
+
+        flags |= FwhtFlags::from_bits_retain(
+            ((self.layout.components.len() as u32 - 1) << 16)
+                | ((self.layout.encoding as u32) << 19),
+        );
+        if self.layout.subsampling.0 == 1 {
+            flags |= FwhtFlags::CHROMA_FULL_WIDTH;
+        }
+        if self.layout.subsampling.1 == 1 {
+            flags |= FwhtFlags::CHROMA_FULL_HEIGHT;
+        }
+
+        let header = FwhtHeader {
+            version: FWHT_VERSION,
+            width: self.width as u32,
+            height: self.height as u32,
+            flags,
+            colorspace: self.colorimetry[0],
+            xfer_func: self.colorimetry[1],
+            ycbcr_enc: self.colorimetry[2],
+            quantization: self.colorimetry[3],
+            size: (data.len() * 2) as u32,
+        };
+
+        let mut frame = Vec::with_capacity(FwhtHeader::SIZE + data.len() * 2);
+        frame.extend_from_slice(&header.to_bytes());
+        frame.extend(data.iter().flat_map(|w| w.to_be_bytes()));
+        Ok(frame)
+    }
+}
+
+/// Software FWHT decoder, producing the same frames as vicodec's stateful decoder.
+#[derive(Default)]
+pub struct FwhtDecoder {
+    /// Header and planes of the last decoded frame, which is also the reference frame of the
+    /// next one.
+    frame: Option<(FwhtHeader, Vec<Plane>)>,
+}
+
+impl FwhtDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Decode `frame`, header included, and return its header. The decoded frame can then be
+    /// retrieved using `copy_to_image`.
+    ///
+    /// The reference frame is reset if the size or layout of the frames changes. P-frames
+    /// decoded without a reference frame are predicted from a black frame.
+    pub fn decode(&mut self, frame: &[u8]) -> Result<FwhtHeader, FwhtError> {
+        let header = FwhtHeader::parse(frame).ok_or(FwhtError::InvalidHeader)?;
+        if header.version == 0 || header.version > FWHT_VERSION {
+            return Err(FwhtError::UnsupportedVersion(header.version));
+        }
+        if !(1..=MAX_WIDTH).contains(&header.width) || !(1..=MAX_HEIGHT).contains(&header.height) {
+            return Err(FwhtError::UnsupportedSize(header.width, header.height));
+        }
+        let num_components = header.components_num();
+        let encoding = header.pixel_encoding();
+        if !matches!(num_components, 1 | 3 | 4) || encoding.is_none() {
+            return Err(FwhtError::UnsupportedComponents(num_components, encoding));
+        }
+        let data = frame
+            .get(FwhtHeader::SIZE..FwhtHeader::SIZE + header.size as usize)
+            .ok_or(FwhtError::TruncatedFrame)?;
+
+        let same_layout = |h: &FwhtHeader| {
+            (
+                h.width,
+                h.height,
+                h.components_num(),
+                h.chroma_subsampling(),
+            ) == (
+                header.width,
+                header.height,
+                num_components,
+                header.chroma_subsampling(),
+            )
+        };
+        let mut planes = match self.frame.take() {
+            Some((previous, planes)) if same_layout(&previous) => planes,
+            _ => component_sizes(
+                header.width as usize,
+                header.height as usize,
+                num_components,
+                header.chroma_subsampling(),
+            )
+            .into_iter()
+            .map(|(w, h)| Plane::new(w, h))
+            .collect(),
+        };
+
+        let uncompressed_flags = [
+            FwhtFlags::LUMA_IS_UNCOMPRESSED,
+            FwhtFlags::CB_IS_UNCOMPRESSED,
+            FwhtFlags::CR_IS_UNCOMPRESSED,
+            FwhtFlags::ALPHA_IS_UNCOMPRESSED,
+        ];
+        let mut input = RlcReader { data, pos: 0 };
+        for (plane, flag) in planes.iter_mut().zip(uncompressed_flags.iter()) {
+            decode_plane(&mut input, plane, header.flags.contains(*flag))?;
+        }
+
+        self.frame = Some((header, planes));
+        Ok(header)
+    }
+
+    /// Copy the last decoded frame into `image`, which must have the size of the frame and a
+    /// pixel format compatible with it (see `is_compatible`).
+    pub fn copy_to_image(&self, image: &mut ImageViewMut) -> Result<(), FwhtError> {
+        let (header, planes) = self.frame.as_ref().ok_or(FwhtError::NoFrame)?;
+        let layout = FormatLayout::from_pixelformat(image.pixelformat())
+            .filter(|l| l.matches(header))
+            .ok_or(FwhtError::FormatMismatch(image.pixelformat()))?;
+        let luma = &image.planes()[layout.components[0].plane];
+        let image_width = luma.width().div_ceil(layout.components[0].step);
+        if (image_width, luma.height()) != (header.width as usize, header.height as usize) {
+            return Err(FwhtError::SizeMismatch(
+                image_width,
+                luma.height(),
+                header.width as usize,
+                header.height as usize,
+            ));
+        }
+
+        for (plane, component) in planes.iter().zip(&layout.components) {
+            plane.copy_to_image(image, component);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use v4l2r::decoder::format::fwht::FwhtFrameParser;
+
+    fn format(pixelformat: PixelFormat, width: u32, height: u32) -> Format {
+        Format {
+            width,
+            height,
+            pixelformat,
+            plane_fmt: pixelformat
+                .info()
+                .unwrap()
+                .plane_layouts(width, height, 1)
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn alloc(format: &Format) -> Vec<Vec<u8>> {
+        format
+            .plane_fmt
+            .iter()
+            .map(|p| vec![0u8; p.sizeimage as usize])
+            .collect()
+    }
+
+    /// Fill `planes` with a smooth moving pattern.
+    fn gen_frame(format: &Format, planes: &mut [Vec<u8>], step: usize) {
+        let mut image = ImageViewMut::new(format, None, planes).unwrap();
+        for plane in image.planes_mut() {
+            for (y, row) in plane.rows_mut().enumerate() {
+                for (x, b) in row.iter_mut().enumerate() {
+                    let t = ((x + y * 2 + step * 3) % 256) as u8;
+                    *b = 64 + t.min(255 - t) / 2;
+                }
+            }
+        }
+    }
+
+    fn psnr(a: &[Vec<u8>], b: &[Vec<u8>]) -> f64 {
+        let (sum, count) = a.iter().flatten().zip(b.iter().flatten()).fold(
+            (0f64, 0usize),
+            |(sum, count), (a, b)| {
+                let diff = *a as f64 - *b as f64;
+                (sum + diff * diff, count + 1)
+            },
+        );
+        if sum == 0.0 {
+            return f64::INFINITY;
+        }
+        10.0 * (255.0 * 255.0 / (sum / count as f64)).log10()
+    }
+
+    #[test]
+    fn test_transform_roundtrip() {
+        let mut block = [0i16; 64];
+        for (i, v) in block.iter_mut().enumerate() {
+            *v = (i * 3 % 256) as i16;
+        }
+        // Without quantization the transform is lossless.
+        assert_eq!(ifwht(&fwht(&block, true), true), block);
+        let deltas = block.map(|v| v / 4 - 30);
+        assert_eq!(ifwht(&fwht(&deltas, false), false), deltas);
+    }
+
+    #[test]
+    fn test_rlc_roundtrip() {
+        let mut block = [0i16; 64];
+        block[0] = -300;
+        block[9] = 12;
+        block[40] = -1;
+        let mut data = Vec::new();
+        rlc(&block, &mut data, false);
+        assert_eq!(data[0], PFRAME_BIT);
+        assert_eq!(*data.last().unwrap(), ALL_ZEROS);
+
+        let bytes = data
+            .iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect::<Vec<_>>();
+        let mut decoded = [0i16; 64];
+        let mut input = RlcReader {
+            data: &bytes,
+            pos: 0,
+        };
+        assert_eq!(derlc(&mut input, &mut decoded), PFRAME_BIT);
+        assert_eq!(decoded, block);
+        assert_eq!(input.remaining(), 0);
+        assert_eq!(derlc(&mut input, &mut decoded), OVERFLOW_BIT);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        for &pixelformat in &[PixelFormat::YUV420, PixelFormat::NV12, PixelFormat::RGB24] {
+            let format = format(pixelformat, 64, 48);
+            let mut encoder = FwhtEncoder::new(&format).unwrap();
+            encoder.set_gop_size(3);
+            let mut decoder = FwhtDecoder::new();
+            let mut input = alloc(&format);
+            let mut output = alloc(&format);
+
+            for step in 0..5 {
+                gen_frame(&format, &mut input, step);
+                let frame = encoder
+                    .encode(&ImageView::new(&format, None, &input).unwrap())
+                    .unwrap();
+                assert!(frame.len() < input.iter().map(Vec::len).sum());
+
+                let header = decoder.decode(&frame).unwrap();
+                assert_eq!((header.width, header.height), (64, 48));
+                assert_eq!(header.is_i_frame(), step % 3 == 0);
+                assert!(is_compatible(pixelformat, &header));
+                let mut image = ImageViewMut::new(&format, None, &mut output).unwrap();
+                decoder.copy_to_image(&mut image).unwrap();
+
+                let psnr = psnr(&input, &output);
+                assert!(
+                    psnr > 30.0,
+                    "{}: PSNR of frame {} is {}",
+                    pixelformat,
+                    step,
+                    psnr
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_uncompressed_fallback() {
+        // Noise does not compress, so all planes are stored as-is.
+        let format = format(PixelFormat::YUV420, 64, 64);
+        let mut input = alloc(&format);
+        let mut seed = 1u32;
+        for b in input.iter_mut().flatten() {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *b = (seed >> 16) as u8 % 0xf0;
+        }
+
+        let mut encoder = FwhtEncoder::new(&format).unwrap();
+        let frame = encoder
+            .encode(&ImageView::new(&format, None, &input).unwrap())
+            .unwrap();
+        let mut decoder = FwhtDecoder::new();
+        let header = decoder.decode(&frame).unwrap();
+        assert!(header.flags.contains(
+            FwhtFlags::LUMA_IS_UNCOMPRESSED
+                | FwhtFlags::CB_IS_UNCOMPRESSED
+                | FwhtFlags::CR_IS_UNCOMPRESSED
+        ));
+
+        let mut output = alloc(&format);
+        let mut image = ImageViewMut::new(&format, None, &mut output).unwrap();
+        decoder.copy_to_image(&mut image).unwrap();
+        assert_eq!(input, output);
+
+        assert!(matches!(
+            decoder.decode(&frame[..frame.len() - 1]),
+            Err(FwhtError::TruncatedFrame)
+        ));
+        assert_eq!(default_pixelformat(&header), Some(PixelFormat::YUV420));
+    }
+
+    #[test]
+    fn test_unsupported_size() {
+        let format = format(PixelFormat::YUV420, 64, 64);
+        let mut encoder = FwhtEncoder::new(&format).unwrap();
+        let input = alloc(&format);
+        let mut frame = encoder
+            .encode(&ImageView::new(&format, None, &input).unwrap())
+            .unwrap();
+        // Make the frame claim a huge height.
+        let mut header = FwhtHeader::parse(&frame).unwrap();
+        header.height = 0x10_0000;
+        frame[..FwhtHeader::SIZE].copy_from_slice(&header.to_bytes());
+
+        assert!(matches!(
+            FwhtDecoder::new().decode(&frame),
+            Err(FwhtError::UnsupportedSize(64, 0x10_0000))
+        ));
+    }
+
+    #[test]
+    fn test_decode_sample() {
+        let stream: &[u8] = include_bytes!("../../ffi/examples/c_fwht_decode/sample.fwht");
+        let mut decoder = FwhtDecoder::new();
+        let mut num_frames = 0;
+        // FNV-1a hash of all the decoded frames.
+        let mut checksum = 0xcbf2_9ce4_8422_2325u64;
+        for frame in FwhtFrameParser::new(stream).unwrap() {
+            let header = decoder.decode(&frame).unwrap();
+            let pixelformat = default_pixelformat(&header).unwrap();
+            let format = format(pixelformat, header.width, header.height);
+            let mut output = alloc(&format);
+            let mut image = ImageViewMut::new(&format, None, &mut output).unwrap();
+            decoder.copy_to_image(&mut image).unwrap();
+
+            for b in output.iter().flatten() {
+                checksum = (checksum ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+            }
+            num_frames += 1;
+        }
+
+        assert_eq!(num_frames, 20);
+        assert_eq!(checksum, 0xace5_b999_d21d_75a5);
+    }
+}
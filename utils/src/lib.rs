@@ -1,2 +1,4 @@
+pub mod conversion;
 pub mod dmabuf_exporter;
 pub mod framegen;
+pub mod fwht;